@@ -1,17 +1,26 @@
 mod mysql;
 
-use aidb_core::Aidb;
-use futures::lock::Mutex;
-use mysql::MySQLShim;
+use aidb_core::{Aidb, split_statements, with_retry};
+use mysql::{Credentials, MySQLShim, Session, next_connection_id};
 
-use std::sync::Arc;
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
 
 use clap::Parser;
-use eyre::{OptionExt, Result};
+use eyre::{OptionExt, Result, eyre};
 use opendal::{Operator, Scheme, layers::LoggingLayer};
 use opensrv_mysql::AsyncMysqlIntermediary;
-use tokio::{net::TcpListener, select, sync::Notify};
-use tracing::{error, info};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select,
+    sync::{Notify, OwnedSemaphorePermit, RwLock, Semaphore},
+};
+use tracing::{debug, error, info, warn};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -31,6 +40,45 @@ struct Args {
     /// Enable Block IO Logging
     #[arg(short = 'l', long, default_value_t = false)]
     io_log: bool,
+    /// Retry block reads and writes failing with temporary errors this many times, waiting
+    /// exponentially longer from 1 second
+    #[arg(long, default_value_t = 3)]
+    retries: usize,
+    /// Layout of blocks of a new database, `flat` or `sharded` into subdirectories, an existing
+    /// database keeps its own
+    #[arg(long, default_value = "flat")]
+    layout: String,
+    /// Keep a copy of the superblock to open the database from when block 0 is damaged, the
+    /// database remembers it once enabled
+    #[arg(long, default_value_t = false)]
+    superblock_mirror: bool,
+    /// Reject statements that modify the database
+    #[arg(long, default_value_t = false)]
+    read_only: bool,
+    /// Pad or truncate blocks of the wrong size instead of failing, to salvage a damaged database
+    #[arg(long, default_value_t = false)]
+    recovery: bool,
+    /// Cancel statements running longer than this many seconds
+    #[arg(long)]
+    statement_timeout: Option<f64>,
+    /// Serve at most this many connections at once, further ones wait until one disconnects
+    #[arg(long, default_value_t = 151)]
+    max_connections: usize,
+    /// Require clients to log in as this user, anyone may connect without it
+    #[arg(long)]
+    user: Option<String>,
+    /// Password of --user, empty by default
+    #[arg(long, requires = "user")]
+    password: Option<String>,
+    /// Run statements from a SQL script on startup
+    #[arg(long, value_name = "FILE")]
+    init_sql: Option<String>,
+    /// Load a CSV file into an existing table on startup, given as `file:table`
+    #[arg(long, value_name = "FILE:TABLE")]
+    load_csv: Vec<String>,
+    /// Skip the first line of files given to --load-csv
+    #[arg(long, default_value_t = false)]
+    csv_header: bool,
     #[command(flatten)]
     verbose: clap_verbosity_flag::Verbosity<clap_verbosity_flag::InfoLevel>,
 }
@@ -39,6 +87,7 @@ fn init_storage(
     opendal_scheme: impl AsRef<str>,
     opendal_config: Vec<String>,
     io_log: bool,
+    retries: usize,
 ) -> Result<Operator> {
     let map: Option<Vec<(String, String)>> = opendal_config
         .into_iter()
@@ -51,20 +100,134 @@ fn init_storage(
     } else {
         op
     };
-    Ok(op)
+    // outside of logging so that every attempt is logged
+    Ok(with_retry(op, retries))
 }
 
 async fn init_core(args: &Args) -> Result<Aidb> {
-    Ok(Aidb::from_op(init_storage(
-        &args.scheme,
-        args.config.clone(),
-        args.io_log,
-    )?)
-    .await?)
+    let op = init_storage(&args.scheme, args.config.clone(), args.io_log, args.retries)?;
+    let mut core = if args.recovery {
+        Aidb::from_op_recovery(op, args.read_only).await?
+    } else if args.read_only {
+        Aidb::from_op_read_only(op).await?
+    } else {
+        Aidb::from_op_with_layout(op, args.layout.parse()?).await?
+    };
+    // clients expect the full result set and streamed results aren't capped either
+    core.set_max_rows(None);
+    if args.superblock_mirror && !args.read_only {
+        core.set_superblock_mirror(true).await?;
+    }
+    if let Some(path) = &args.init_sql {
+        init_sql(&mut core, path).await?;
+    }
+    for spec in &args.load_csv {
+        load_csv(&mut core, spec, args.csv_header).await?;
+    }
+    Ok(core)
+}
+
+async fn init_sql(core: &mut Aidb, path: &str) -> Result<()> {
+    let script = fs::read_to_string(path).map_err(|e| eyre!("{path}: {e}"))?;
+    let stmts = split_statements(&script);
+    info!("running {} statements from {path}", stmts.len());
+    for (i, stmt) in stmts.iter().enumerate() {
+        debug!("{stmt}");
+        core.query(stmt)
+            .await
+            .map_err(|e| eyre!("{path}: statement {}: {e}", i + 1))?;
+    }
+    Ok(())
+}
+
+async fn load_csv(core: &mut Aidb, spec: &str, has_header: bool) -> Result<()> {
+    let (path, table) = spec
+        .rsplit_once(':')
+        .ok_or_eyre("--load-csv should be file:table")?;
+    let file = File::open(path).map_err(|e| eyre!("{path}: {e}"))?;
+    let r = core
+        .load_csv(table, BufReader::new(file), has_header, false)
+        .await
+        .map_err(|e| eyre!("{path}: {e}"))?;
+    info!("loaded {} rows from {path} into {table}", r.inserted);
+    Ok(())
+}
+
+fn get_shim(
+    core: Arc<RwLock<Aidb>>,
+    transaction_ended: Arc<Notify>,
+    statement_timeout: Option<Duration>,
+    credentials: Option<Arc<Credentials>>,
+) -> MySQLShim {
+    MySQLShim {
+        connection_id: next_connection_id(),
+        core,
+        reader: Default::default(),
+        transaction_ended,
+        session: Session::default(),
+        statement_timeout,
+        credentials,
+    }
 }
 
-fn get_shim(core: Arc<Mutex<Aidb>>) -> MySQLShim {
-    MySQLShim { core }
+/// Serve a connection in the background, releasing `permit` once it disconnects.
+fn spawn_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    shim: MySQLShim,
+    permit: OwnedSemaphorePermit,
+) {
+    tokio::spawn(async move {
+        let (r, w) = stream.into_split();
+        let closing = shim.clone();
+        match AsyncMysqlIntermediary::run_on(shim, r, w).await {
+            Ok(()) => info!("{addr} disconnected"),
+            Err(e) => error!("{addr} disconnected with error: {e}"),
+        }
+        closing.disconnect().await;
+        drop(permit);
+    });
+}
+
+/// Accept connections until terminated, serving at most `max_connections` at once. Further
+/// connections are left in the backlog of the listener until one disconnects.
+async fn serve(
+    listener: TcpListener,
+    core: Arc<RwLock<Aidb>>,
+    statement_timeout: Option<Duration>,
+    credentials: Option<Arc<Credentials>>,
+    max_connections: usize,
+    terminating: Arc<Notify>,
+) -> Result<()> {
+    let connections = Arc::new(Semaphore::new(max_connections));
+    let transaction_ended = Arc::new(Notify::new());
+    loop {
+        let permit = match connections.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!("{max_connections} connections reached, waiting for one to disconnect");
+                select! {
+                    permit = connections.clone().acquire_owned() => permit?,
+                    _ = terminating.notified() => break,
+                }
+            }
+        };
+        select! {
+            result = listener.accept() => {
+                let (stream, addr) = result?;
+                info!("{addr} connected");
+                let shim = get_shim(
+                    core.clone(),
+                    transaction_ended.clone(),
+                    statement_timeout,
+                    credentials.clone(),
+                );
+                spawn_connection(stream, addr, shim, permit);
+            }
+            _ = terminating.notified() => break,
+        }
+    }
+    Ok(())
 }
 
 #[tokio::main]
@@ -79,36 +242,131 @@ async fn main() -> Result<()> {
     info!("log level is {}", log_level.to_string());
 
     info!("initializing aidb");
-    let core = Arc::new(Mutex::new(init_core(&args).await?));
+    let core = Arc::new(RwLock::new(init_core(&args).await?));
+    let statement_timeout = args
+        .statement_timeout
+        .map(Duration::try_from_secs_f64)
+        .transpose()?;
+    let credentials = args.user.map(|user| {
+        Arc::new(Credentials {
+            user,
+            password: args.password.unwrap_or_default(),
+        })
+    });
 
     let terminating = Arc::new(Notify::new());
     ctrlc::set_handler({
         let t = terminating.clone();
-        move || t.notify_waiters()
+        // stores a permit, so that the signal isn't lost while serve is between waits
+        move || t.notify_one()
     })?;
     let addr = format!("{}:{}", args.address, args.port);
     let listener = TcpListener::bind(&addr).await?;
     info!("listening on {addr}");
 
-    loop {
-        select! {
-            result = listener.accept() => {
-                let (stream, addr) = result?;
-                info!("{addr} connected");
-                let core = core.clone();
-                tokio::spawn(async move {
-                    let (r, w) = stream.into_split();
-                    let shim = get_shim(core);
-                    match AsyncMysqlIntermediary::run_on(shim, r, w).await {
-                        Ok(()) => info!("{addr} disconnected"),
-                        Err(e) => error!("{addr} disconnected with error: {e}"),
-                    }
-                });
-            }
-            _ = terminating.notified() => {
-                break;
-            }
-        }
-    }
+    serve(
+        listener,
+        core.clone(),
+        statement_timeout,
+        credentials,
+        args.max_connections,
+        terminating,
+    )
+    .await?;
+    info!("flushing aidb");
+    core.write().await.flush().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use aidb_core::{Response, Value};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_init_sql() {
+        let dir = std::env::temp_dir().join(format!("aidb-init-sql-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("init.sql");
+        fs::write(
+            &script,
+            "-- demo\nCREATE TABLE t (id INTEGER, s TEXT);\nINSERT INTO t VALUES (1, 'a;b');\nINSERT INTO t VALUES (2, 'c');\n",
+        )
+        .unwrap();
+        let args = Args::parse_from([
+            "aidb-cli".to_owned(),
+            "--config".to_owned(),
+            format!("root={}", dir.join("data").display()),
+            "--init-sql".to_owned(),
+            script.display().to_string(),
+        ]);
+        let core = Arc::new(RwLock::new(init_core(&args).await.unwrap()));
+        let mut shim = get_shim(core, Default::default(), None, None);
+        let (Response::Rows { rows, .. }, _) = shim.run("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::Integer(1), Value::Text("a;b".to_owned())],
+                vec![Value::Integer(2), Value::Text("c".to_owned())],
+            ]
+        );
+
+        // a failing script aborts startup
+        fs::write(
+            &script,
+            "INSERT INTO t VALUES (3, 'd');\nSELECT * FROM u;\n",
+        )
+        .unwrap();
+        let e = init_core(&args).await.unwrap_err();
+        assert!(
+            e.to_string().ends_with(": statement 2: table not found"),
+            "{e}"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Whether the server greets `stream` with a handshake, i.e. serves it
+    async fn greeted(stream: &mut TcpStream) -> bool {
+        use tokio::{io::AsyncReadExt, time::timeout};
+
+        let mut buf = [0; 1];
+        timeout(Duration::from_millis(200), stream.read(&mut buf))
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let core = Arc::new(RwLock::new(Aidb::new_memory().await));
+        let terminating = Arc::new(Notify::new());
+        let server = tokio::spawn(serve(listener, core, None, None, 2, terminating.clone()));
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert!(greeted(&mut first).await);
+        assert!(greeted(&mut second).await);
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        assert!(!greeted(&mut third).await);
+
+        drop(first);
+        assert!(greeted(&mut third).await);
+        terminating.notify_one();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_terminated_before_serving() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let core = Arc::new(RwLock::new(Aidb::new_memory().await));
+        let terminating = Arc::new(Notify::new());
+        // a signal received before serve waits for it isn't lost
+        terminating.notify_one();
+        serve(listener, core, None, None, 2, terminating)
+            .await
+            .unwrap();
+    }
+}
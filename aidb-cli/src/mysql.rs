@@ -1,19 +1,353 @@
-use std::{io, sync::Arc};
+use std::{
+    collections::HashMap,
+    io,
+    pin::pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
-use aidb_core::{Aidb, DataType, Response, Row, Value};
+use aidb_core::{Aidb, CancelToken, Collation, DataType, Response, Row, Value};
 use async_trait::async_trait;
-use futures::lock::Mutex;
+use eyre::eyre;
+use futures::{Stream, StreamExt};
 use itertools::Itertools;
 use opensrv_mysql::{
     AsyncMysqlShim, Column, ColumnFlags, ColumnType, ErrorKind, InitWriter, OkResponse,
-    QueryResultWriter, StatementMetaWriter, ToMysqlValue,
+    QueryResultWriter, RowWriter, StatementMetaWriter, ToMysqlValue,
+};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::AsyncWrite,
+    sync::{Mutex, Notify, RwLock, RwLockWriteGuard},
+    time::timeout_at,
 };
-use tokio::io::AsyncWrite;
 use tracing::{debug, info, trace};
 
 #[derive(Debug, Clone)]
 pub struct MySQLShim {
-    pub core: Arc<Mutex<Aidb>>,
+    /// identifies the connection to the client and as the owner of the transaction it starts
+    pub connection_id: u32,
+    /// statements that only read share the lock, others take it exclusively
+    pub core: Arc<RwLock<Aidb>>,
+    /// read-only instance of the connection opened by its first statement that only reads
+    pub reader: Arc<Mutex<Option<Aidb>>>,
+    /// shared by all connections, notified whenever a transaction ends
+    pub transaction_ended: Arc<Notify>,
+    pub session: Session,
+    pub statement_timeout: Option<Duration>,
+    /// account clients must log in as, anyone is let in without one
+    pub credentials: Option<Arc<Credentials>>,
+}
+
+/// How long a statement waits for the transaction of another connection to end unless the
+/// session sets `innodb_lock_wait_timeout` in seconds, the default of MySQL.
+const LOCK_WAIT_TIMEOUT: Duration = Duration::from_secs(50);
+
+/// Id of a new connection, unique within the process.
+pub fn next_connection_id() -> u32 {
+    static NEXT_CONNECTION_ID: AtomicU32 = AtomicU32::new(1);
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Account checked against the `mysql_native_password` handshake.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub user: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// Check the auth response of a client, which is `SHA1(password) XOR
+    /// SHA1(salt + SHA1(SHA1(password)))` or empty for an empty password.
+    pub fn verify(&self, user: &[u8], salt: &[u8], auth_data: &[u8]) -> bool {
+        if user != self.user.as_bytes() {
+            return false;
+        }
+        if self.password.is_empty() {
+            return auth_data.is_empty();
+        }
+        let hash = Sha1::digest(self.password.as_bytes());
+        let double_hash = Sha1::digest(hash);
+        let mask = Sha1::new()
+            .chain_update(salt)
+            .chain_update(double_hash)
+            .finalize();
+        let expected = hash.iter().zip(mask).map(|(h, m)| h ^ m).collect_vec();
+        auth_data == expected
+    }
+}
+
+/// Per-connection state set by `SET` statements, which never reach the engine.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// lowercase names without `@@`, `SESSION.` or `GLOBAL.`, user variables keep their `@`
+    pub variables: HashMap<String, String>,
+    /// when off, every statement runs inside a transaction until COMMIT or ROLLBACK
+    pub autocommit: bool,
+    /// value of `LAST_INSERT_ID()`
+    pub last_insert_id: i64,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            variables: HashMap::new(),
+            autocommit: true,
+            last_insert_id: 0,
+        }
+    }
+}
+
+impl Session {
+    /// Apply a `SET` statement, returns `None` if the query is not one.
+    pub fn set(&mut self, query: &str) -> Option<Result<(), String>> {
+        let query = query.trim().trim_end_matches(';').trim_end();
+        let (set, assignments) = query.split_once(char::is_whitespace)?;
+        if !set.eq_ignore_ascii_case("SET") {
+            return None;
+        }
+        if let Some(r) = self.set_transaction(assignments) {
+            return Some(r);
+        }
+        Some(
+            split_assignments(assignments)
+                .into_iter()
+                .try_for_each(|assignment| self.assign(assignment.trim())),
+        )
+    }
+
+    /// Apply `SET [GLOBAL | SESSION] TRANSACTION characteristic, ...`, returns `None` for other
+    /// assignments. The characteristics are only kept to be reported back as
+    /// `transaction_isolation` and `transaction_read_only`.
+    fn set_transaction(&mut self, assignments: &str) -> Option<Result<(), String>> {
+        let mut words = assignments.split_whitespace().peekable();
+        if words.peek().is_some_and(|scope| {
+            ["GLOBAL", "SESSION", "LOCAL"]
+                .iter()
+                .any(|s| scope.eq_ignore_ascii_case(s))
+        }) {
+            words.next();
+        }
+        if !words.next()?.eq_ignore_ascii_case("TRANSACTION") {
+            return None;
+        }
+        let characteristics = words.join(" ").to_uppercase();
+        let mut variables = vec![];
+        for characteristic in characteristics.split(',') {
+            let words = characteristic.split_whitespace().collect_vec();
+            variables.push(match words[..] {
+                [
+                    "ISOLATION",
+                    "LEVEL",
+                    "READ",
+                    level @ ("UNCOMMITTED" | "COMMITTED"),
+                ] => ("transaction_isolation", format!("READ-{level}")),
+                ["ISOLATION", "LEVEL", "REPEATABLE", "READ"] => {
+                    ("transaction_isolation", "REPEATABLE-READ".to_owned())
+                }
+                ["ISOLATION", "LEVEL", "SERIALIZABLE"] => {
+                    ("transaction_isolation", "SERIALIZABLE".to_owned())
+                }
+                ["READ", "ONLY"] => ("transaction_read_only", "1".to_owned()),
+                ["READ", "WRITE"] => ("transaction_read_only", "0".to_owned()),
+                _ => {
+                    return Some(Err(format!(
+                        "invalid SET near \"{}\"",
+                        characteristic.trim()
+                    )));
+                }
+            });
+        }
+        for (name, value) in variables {
+            self.variables.insert(name.to_owned(), value);
+        }
+        Some(Ok(()))
+    }
+
+    fn assign(&mut self, assignment: &str) -> Result<(), String> {
+        let words = assignment.split_whitespace().collect_vec();
+        match &words[..] {
+            [names, charset, ..] if names.eq_ignore_ascii_case("NAMES") => {
+                for name in [
+                    "character_set_client",
+                    "character_set_connection",
+                    "character_set_results",
+                ] {
+                    self.variables
+                        .insert(name.to_owned(), unquote(charset).to_owned());
+                }
+                return Ok(());
+            }
+            [character, set, charset]
+                if character.eq_ignore_ascii_case("CHARACTER")
+                    && set.eq_ignore_ascii_case("SET") =>
+            {
+                self.variables.insert(
+                    "character_set_client".to_owned(),
+                    unquote(charset).to_owned(),
+                );
+                return Ok(());
+            }
+            _ => {}
+        }
+        let (name, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| format!("invalid SET near \"{assignment}\""))?;
+        let (name, value) = (name.trim_end_matches(':').trim(), unquote(value.trim()));
+        let name = name.to_lowercase();
+        let name = match name.strip_prefix("@@") {
+            Some(name) => name,
+            None if name.starts_with('@') => &name,
+            None => name
+                .split_once(char::is_whitespace)
+                .filter(|(scope, _)| ["session", "global", "local"].contains(scope))
+                .map_or(&name[..], |(_, name)| name.trim_start()),
+        };
+        let name = ["session.", "global.", "local."]
+            .iter()
+            .find_map(|scope| name.strip_prefix(scope))
+            .unwrap_or(name);
+        if name.is_empty() {
+            return Err(format!("invalid SET near \"{assignment}\""));
+        }
+        if name == "collation" || name == "collation_connection" {
+            value.parse::<Collation>().map_err(|e| e.to_string())?;
+        }
+        if name == "autocommit" {
+            self.autocommit = match value.to_lowercase().as_str() {
+                "1" | "on" | "true" => true,
+                "0" | "off" | "false" => false,
+                _ => return Err(format!("invalid value for autocommit: {value}")),
+            };
+        }
+        self.variables.insert(name.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    /// Replace variable overrides of the engine with those of this session.
+    pub fn sync(&self, core: &mut Aidb) {
+        core.clear_variables();
+        for (name, value) in &self.variables {
+            let value = value
+                .parse()
+                .map_or_else(|_| Value::Text(value.clone()), Value::Integer);
+            core.set_variable(name, value);
+        }
+        core.set_variable("autocommit", Value::Integer(self.autocommit as i64));
+        core.set_last_insert_id(self.last_insert_id);
+    }
+}
+
+impl Session {
+    /// Answer `SHOW VARIABLES [LIKE pattern]` and `SHOW DATABASES` probed by clients,
+    /// returns `None` for other queries.
+    pub fn show(&self, query: &str, core: &Aidb) -> Option<Response> {
+        let query = query.trim().trim_end_matches(';').trim_end();
+        let words = query.split_whitespace().collect_vec();
+        let is = |i: usize, kw: &str| words.get(i).is_some_and(|w| w.eq_ignore_ascii_case(kw));
+        if !is(0, "SHOW") {
+            return None;
+        }
+        if is(1, "DATABASES") && words.len() == 2 {
+            return Some(Response::Rows {
+                columns: vec![text_column("Database")],
+                rows: vec![vec![Value::Text(DATABASE.to_owned())]],
+                truncated: false,
+            });
+        }
+        let i = if is(1, "SESSION") || is(1, "GLOBAL") {
+            2
+        } else {
+            1
+        };
+        if !is(i, "VARIABLES") {
+            return None;
+        }
+        let pattern = match &words[i + 1..] {
+            [] => None,
+            [like, pattern] if like.eq_ignore_ascii_case("LIKE") => Some(unquote(pattern)),
+            _ => return None,
+        };
+        let variables = core.variables().into_iter().map(|(name, value)| {
+            let value = match (name.as_str(), value) {
+                ("autocommit", Value::Integer(0)) => "OFF".to_owned(),
+                ("autocommit", _) => "ON".to_owned(),
+                (_, Value::Text(value)) => value,
+                (_, value) => value.to_string(),
+            };
+            (name, value)
+        });
+        Some(Response::Rows {
+            columns: vec![text_column("Variable_name"), text_column("Value")],
+            rows: variables
+                .filter(|(name, _)| pattern.is_none_or(|pattern| like(pattern, name)))
+                .map(|(name, value)| vec![Value::Text(name), Value::Text(value)])
+                .collect(),
+            truncated: false,
+        })
+    }
+}
+
+/// The only database namespace, tables are not qualified by database.
+const DATABASE: &str = "aidb";
+
+fn text_column(name: &str) -> aidb_core::Column {
+    aidb_core::Column {
+        name: name.to_owned(),
+        datatype: DataType::Text,
+    }
+}
+
+/// Case-insensitive match with `%` for any sequence and `_` for any character.
+fn like(pattern: &str, s: &str) -> bool {
+    let pattern = pattern.to_lowercase().chars().collect_vec();
+    let s = s.to_lowercase().chars().collect_vec();
+    fn matches(pattern: &[char], s: &[char]) -> bool {
+        match pattern {
+            [] => s.is_empty(),
+            ['%', rest @ ..] => (0..=s.len()).any(|i| matches(rest, &s[i..])),
+            ['_', rest @ ..] => !s.is_empty() && matches(rest, &s[1..]),
+            [c, rest @ ..] => s.first() == Some(c) && matches(rest, &s[1..]),
+        }
+    }
+    matches(&pattern, &s)
+}
+
+/// Split assignments of `SET` on commas outside of quoted values.
+fn split_assignments(s: &str) -> Vec<&str> {
+    let mut assignments = vec![];
+    let mut start = 0;
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\'' | '"' | '`' => {
+                while let Some((_, d)) = chars.next() {
+                    if d == '\\' {
+                        chars.next();
+                    } else if d == c {
+                        break;
+                    }
+                }
+            }
+            ',' => {
+                assignments.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    assignments.push(&s[start..]);
+    assignments
+}
+
+fn unquote(s: &str) -> &str {
+    ['\'', '"', '`']
+        .iter()
+        .find_map(|q| s.strip_prefix(*q).and_then(|s| s.strip_suffix(*q)))
+        .unwrap_or(s)
 }
 
 // error message of ER_MTS_INCONSISTENT_DATA is simply "%s"
@@ -24,11 +358,28 @@ impl<W: AsyncWrite + Send + Unpin> AsyncMysqlShim<W> for MySQLShim {
     type Error = io::Error;
 
     fn version(&self) -> String {
-        env!("CARGO_PKG_VERSION").to_owned()
+        Aidb::version()
     }
 
     fn connect_id(&self) -> u32 {
-        0
+        self.connection_id
+    }
+
+    async fn authenticate(
+        &self,
+        _auth_plugin: &str,
+        username: &[u8],
+        salt: &[u8],
+        auth_data: &[u8],
+    ) -> bool {
+        let Some(credentials) = &self.credentials else {
+            return true;
+        };
+        let ok = credentials.verify(username, salt, auth_data);
+        if !ok {
+            info!("access denied for {}", String::from_utf8_lossy(username));
+        }
+        ok
     }
 
     async fn on_prepare<'a>(
@@ -71,9 +422,12 @@ impl<W: AsyncWrite + Send + Unpin> AsyncMysqlShim<W> for MySQLShim {
         results: QueryResultWriter<'a, W>,
     ) -> Result<(), Self::Error> {
         trace!(query);
-        let mut lock = self.core.lock().await;
-        match lock.query(query).await {
-            Ok(Response::Rows { columns, rows }) => {
+        let results = match self.stream(query, results).await {
+            Ok(r) => return r,
+            Err(results) => results,
+        };
+        match self.run(query).await {
+            Ok((Response::Rows { columns, rows, .. }, _)) => {
                 let columns = columns.into_iter().map(aidb_column_to_mysql).collect_vec();
                 let mut r = results.start(&columns).await?;
                 for row in rows {
@@ -81,10 +435,11 @@ impl<W: AsyncWrite + Send + Unpin> AsyncMysqlShim<W> for MySQLShim {
                 }
                 r.finish().await?;
             }
-            Ok(Response::Meta { affected_rows }) => {
+            Ok((Response::Meta { affected_rows }, insert_id)) => {
                 results
                     .completed(OkResponse {
                         affected_rows: affected_rows as u64,
+                        last_insert_id: insert_id.unwrap_or(0) as u64,
                         ..Default::default()
                     })
                     .await?;
@@ -110,6 +465,245 @@ impl<W: AsyncWrite + Send + Unpin> AsyncMysqlShim<W> for MySQLShim {
     }
 }
 
+impl MySQLShim {
+    /// Run a query within the session, returns the response with the AUTO_INCREMENT id generated
+    /// by it. With autocommit off, the first statement that writes starts a transaction which
+    /// accumulates the following ones until COMMIT, reads before it run on the reader.
+    pub async fn run(&mut self, query: &str) -> eyre::Result<(Response, Option<i64>)> {
+        let autocommit = self.session.autocommit;
+        let mut insert_id = None;
+        let r = if let Some(r) = self.session.set(query) {
+            match r {
+                Ok(()) if !autocommit && self.session.autocommit => {
+                    // turning autocommit back on commits the pending transaction
+                    let mut lock = self.core.write().await;
+                    if lock.transaction_owner() == Some(self.connection_id) {
+                        let r = lock.query("COMMIT;").await;
+                        self.transaction_ended.notify_waiters();
+                        r
+                    } else {
+                        Ok(Response::Meta { affected_rows: 0 })
+                    }
+                }
+                Ok(()) => Ok(Response::Meta { affected_rows: 0 }),
+                Err(e) => Err(eyre!(e)),
+            }
+        } else if let Some(r) = self.read(query).await {
+            r
+        } else {
+            let core = self.core.clone();
+            let mut lock = core.write().await;
+            self.session.sync(&mut lock);
+            if let Some(response) = self.session.show(query, &lock) {
+                Ok(response)
+            } else if Aidb::ends_transaction(query)
+                && lock.transaction_owner() != Some(self.connection_id)
+            {
+                // the connection has no transaction to end
+                Ok(Response::Meta { affected_rows: 0 })
+            } else {
+                match self.wait_for_transaction(&core, lock).await {
+                    Ok(mut lock) => {
+                        self.session.sync(&mut lock);
+                        lock.set_cancel_token(self.cancel_token());
+                        let r = if self.session.autocommit {
+                            lock.query(query).await
+                        } else {
+                            match lock.query("START TRANSACTION;").await {
+                                Ok(_) => lock.query(query).await,
+                                Err(e) => Err(e),
+                            }
+                        };
+                        lock.set_cancel_token(None);
+                        lock.set_transaction_owner(self.connection_id);
+                        if !lock.in_transaction() {
+                            self.transaction_ended.notify_waiters();
+                        }
+                        insert_id = lock.insert_id();
+                        self.session.last_insert_id = lock.last_insert_id();
+                        r
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        r.map(|response| (response, insert_id))
+    }
+
+    /// Give the lock back once no other connection has a transaction in progress, waiting for it
+    /// to end for at most `innodb_lock_wait_timeout` seconds of the session.
+    async fn wait_for_transaction<'a>(
+        &self,
+        core: &'a RwLock<Aidb>,
+        mut lock: RwLockWriteGuard<'a, Aidb>,
+    ) -> eyre::Result<RwLockWriteGuard<'a, Aidb>> {
+        let timeout = self
+            .session
+            .variables
+            .get("innodb_lock_wait_timeout")
+            .and_then(|secs| secs.parse().ok())
+            .map_or(LOCK_WAIT_TIMEOUT, Duration::from_secs);
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if !lock.in_transaction() || lock.transaction_owner() == Some(self.connection_id) {
+                return Ok(lock);
+            }
+            // registered before the lock is released so that the end of the transaction isn't
+            // missed
+            let mut ended = pin!(self.transaction_ended.notified());
+            ended.as_mut().enable();
+            drop(lock);
+            if timeout_at(deadline, ended).await.is_err() {
+                return Err(eyre!(
+                    "Lock wait timeout exceeded; try restarting transaction"
+                ));
+            }
+            lock = core.write().await;
+        }
+    }
+
+    /// Run a statement that only reads on the reader of the connection while holding the lock
+    /// shared, so that it runs concurrently with reads of other connections and sees committed
+    /// data only. Returns `None` for other statements and within a transaction of the
+    /// connection, whose changes are only visible to the shared instance.
+    async fn read(&mut self, query: &str) -> Option<eyre::Result<Response>> {
+        if !Aidb::is_read(query) {
+            return None;
+        }
+        let core = self.core.read().await;
+        let mut reader = self.reader.lock().await;
+        let aidb = match self.prepare_reader(&core, &mut reader).await? {
+            Ok(aidb) => aidb,
+            Err(e) => return Some(Err(e)),
+        };
+        let r = aidb.query(query).await;
+        aidb.set_cancel_token(None);
+        Some(r)
+    }
+
+    /// Write the rows of a statement that only reads to the client as the reader of the
+    /// connection produces them, holding the lock shared until the last one is written. A failed
+    /// write, e.g. to a disconnected client, stops the plan and releases the lock at once. Gives
+    /// the writer back where [`MySQLShim::read`] wouldn't run the statement.
+    async fn stream<'a, W: AsyncWrite + Send + Unpin>(
+        &mut self,
+        query: &str,
+        results: QueryResultWriter<'a, W>,
+    ) -> Result<io::Result<()>, QueryResultWriter<'a, W>> {
+        if !Aidb::is_read(query) {
+            return Err(results);
+        }
+        let core = self.core.read().await;
+        let mut reader = self.reader.lock().await;
+        let Some(aidb) = self.prepare_reader(&core, &mut reader).await else {
+            return Err(results);
+        };
+        let stream = match aidb {
+            Ok(aidb) => aidb.query_stream(query).await,
+            Err(e) => Err(e),
+        };
+        let (columns, rows) = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                trace!(?e);
+                return Ok(results.error(GENERAL_ERROR, e.to_string().as_bytes()).await);
+            }
+        };
+        let columns = columns.into_iter().map(aidb_column_to_mysql).collect_vec();
+        Ok(async {
+            let mut w = results.start(&columns).await?;
+            match write_rows(rows, &mut w).await? {
+                Ok(()) => w.finish().await,
+                Err(e) => {
+                    trace!(?e);
+                    w.finish_error(GENERAL_ERROR, &e.to_string().into_bytes())
+                        .await
+                }
+            }
+        }
+        .await)
+    }
+
+    /// Refresh or open the reader of the connection and sync it with the session. Returns
+    /// `None` within a transaction of the connection, whose changes are only visible to the
+    /// shared instance. Readers of other connections only see what it committed.
+    async fn prepare_reader<'r>(
+        &self,
+        core: &Aidb,
+        reader: &'r mut Option<Aidb>,
+    ) -> Option<eyre::Result<&'r mut Aidb>> {
+        if core.transaction_owner() == Some(self.connection_id) {
+            return None;
+        }
+        let aidb = match reader {
+            Some(aidb) => match aidb.refresh().await {
+                Ok(()) => aidb,
+                Err(e) => return Some(Err(e)),
+            },
+            None => match core.open_reader().await {
+                Ok(aidb) => reader.insert(aidb),
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        self.session.sync(aidb);
+        // the connection is as read-only as the shared instance, not as its reader, unless the
+        // session says it is
+        let read_only = core.variable("@@transaction_read_only");
+        if read_only == Value::Integer(1)
+            || !self.session.variables.contains_key("transaction_read_only")
+        {
+            aidb.set_variable("transaction_read_only", read_only);
+        }
+        aidb.set_cancel_token(self.cancel_token());
+        Some(Ok(aidb))
+    }
+
+    fn cancel_token(&self) -> Option<CancelToken> {
+        self.statement_timeout.map(|timeout| {
+            let start = Instant::now();
+            CancelToken::with_check(move || start.elapsed() > timeout)
+        })
+    }
+
+    /// Roll back the transaction left open by the connection if it was closed without COMMIT.
+    pub async fn disconnect(&self) {
+        let mut lock = self.core.write().await;
+        if lock.transaction_owner() == Some(self.connection_id)
+            && lock.query("ROLLBACK;").await.is_ok()
+        {
+            info!("rolled back uncommitted transaction");
+            self.transaction_ended.notify_waiters();
+        }
+    }
+}
+
+/// Where streamed rows are written, the client outside tests.
+trait RowSink {
+    fn write(&mut self, row: Row) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+impl<W: AsyncWrite + Send + Unpin> RowSink for RowWriter<'_, W> {
+    async fn write(&mut self, row: Row) -> io::Result<()> {
+        self.write_row(aidb_row_to_mysql(row)).await
+    }
+}
+
+/// Write rows one by one, stopping at the first failed write without pulling further rows. The
+/// outer error is that of the write, the inner one that of the statement.
+async fn write_rows(
+    rows: impl Stream<Item = eyre::Result<Row>>,
+    sink: &mut impl RowSink,
+) -> io::Result<eyre::Result<()>> {
+    let mut rows = pin!(rows);
+    while let Some(row) = rows.next().await {
+        match row {
+            Ok(row) => sink.write(row).await?,
+            Err(e) => return Ok(Err(e)),
+        }
+    }
+    Ok(Ok(()))
+}
+
 fn aidb_column_to_mysql(column: aidb_core::Column) -> Column {
     Column {
         table: "".to_owned(),
@@ -119,6 +713,7 @@ fn aidb_column_to_mysql(column: aidb_core::Column) -> Column {
             DataType::Integer => ColumnType::MYSQL_TYPE_LONGLONG,
             DataType::Real => ColumnType::MYSQL_TYPE_DOUBLE,
             DataType::Text => ColumnType::MYSQL_TYPE_VAR_STRING,
+            DataType::Blob => ColumnType::MYSQL_TYPE_BLOB,
         },
         colflags: ColumnFlags::empty(),
     }
@@ -130,19 +725,21 @@ struct ValueWrapper(Value);
 impl ToMysqlValue for ValueWrapper {
     fn to_mysql_text<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
         match &self.0 {
-            Value::Null => None::<u64>.to_mysql_text(w),
+            Value::Null | Value::Placeholder(_) => None::<u64>.to_mysql_text(w),
             Value::Integer(v) => v.to_mysql_text(w),
             Value::Real(v) => v.to_mysql_text(w),
             Value::Text(s) => s.to_mysql_text(w),
+            Value::Blob(b) => b.to_mysql_text(w),
         }
     }
 
     fn to_mysql_bin<W: io::Write>(&self, w: &mut W, c: &Column) -> io::Result<()> {
         match &self.0 {
-            Value::Null => None::<u64>.to_mysql_bin(w, c),
+            Value::Null | Value::Placeholder(_) => None::<u64>.to_mysql_bin(w, c),
             Value::Integer(v) => v.to_mysql_bin(w, c),
             Value::Real(v) => v.to_mysql_bin(w, c),
             Value::Text(s) => s.to_mysql_bin(w, c),
+            Value::Blob(b) => b.to_mysql_bin(w, c),
         }
     }
 }
@@ -150,3 +747,628 @@ impl ToMysqlValue for ValueWrapper {
 fn aidb_row_to_mysql(row: Row) -> Vec<ValueWrapper> {
     row.into_iter().map(ValueWrapper).collect()
 }
+
+#[cfg(test)]
+mod test {
+    use opendal::{Operator, services::MemoryConfig};
+
+    use super::*;
+
+    #[test]
+    fn test_session_set() {
+        let mut session = Session::default();
+        assert_eq!(session.set("SET NAMES utf8mb4;"), Some(Ok(())));
+        assert_eq!(session.variables["character_set_connection"], "utf8mb4");
+        assert_eq!(
+            session.set("set @@SESSION.sql_mode = 'ANSI', character_set_results = NULL"),
+            Some(Ok(()))
+        );
+        assert_eq!(session.variables["sql_mode"], "ANSI");
+        assert_eq!(session.variables["character_set_results"], "NULL");
+        assert_eq!(
+            session.set("SET sql_mode='STRICT_TRANS_TABLES,NO_ZERO_DATE', @y = \"a\\\",b\""),
+            Some(Ok(()))
+        );
+        assert_eq!(
+            session.variables["sql_mode"],
+            "STRICT_TRANS_TABLES,NO_ZERO_DATE"
+        );
+        assert_eq!(session.variables["@y"], "a\\\",b");
+        assert_eq!(session.set("SET @x := 1"), Some(Ok(())));
+        assert_eq!(session.variables["@x"], "1");
+
+        assert!(session.autocommit);
+        assert_eq!(session.set("SET autocommit=0"), Some(Ok(())));
+        assert!(!session.autocommit);
+        assert_eq!(session.set("SET SESSION autocommit = ON"), Some(Ok(())));
+        assert!(session.autocommit);
+        assert!(matches!(session.set("SET autocommit = 2"), Some(Err(_))));
+        assert!(matches!(session.set("SET x"), Some(Err(_))));
+        assert_eq!(session.set("SET collation = utf8_general_ci"), Some(Ok(())));
+        assert_eq!(session.variables["collation"], "utf8_general_ci");
+        assert!(matches!(
+            session.set("SET collation_connection = latin1_swedish"),
+            Some(Err(_))
+        ));
+
+        assert_eq!(
+            session.set("SET SESSION TRANSACTION ISOLATION LEVEL READ COMMITTED"),
+            Some(Ok(()))
+        );
+        assert_eq!(session.variables["transaction_isolation"], "READ-COMMITTED");
+        assert_eq!(session.set("SET TRANSACTION READ ONLY;"), Some(Ok(())));
+        assert_eq!(session.variables["transaction_read_only"], "1");
+        assert_eq!(
+            session.set("set global transaction isolation level repeatable read, read write"),
+            Some(Ok(()))
+        );
+        assert_eq!(
+            session.variables["transaction_isolation"],
+            "REPEATABLE-READ"
+        );
+        assert_eq!(session.variables["transaction_read_only"], "0");
+        assert!(matches!(
+            session.set("SET TRANSACTION ISOLATION LEVEL SNAPSHOT, READ ONLY"),
+            Some(Err(_))
+        ));
+        assert_eq!(session.variables["transaction_read_only"], "0");
+        assert_eq!(
+            session.set("SET transaction_isolation = 'SERIALIZABLE'"),
+            Some(Ok(()))
+        );
+        assert_eq!(session.variables["transaction_isolation"], "SERIALIZABLE");
+
+        assert_eq!(session.set("SELECT 1;"), None);
+        assert_eq!(session.set("SETTINGS"), None);
+    }
+
+    #[tokio::test]
+    async fn test_session_show() {
+        let mut core = Aidb::new_memory().await;
+        let mut session = Session::default();
+        let Some(Response::Rows { columns, rows, .. }) = session.show("show databases;", &core)
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(columns.len(), 1);
+        assert_eq!(rows, vec![vec![Value::Text("aidb".to_owned())]]);
+
+        session
+            .set("SET autocommit = 0, NAMES latin1")
+            .unwrap()
+            .unwrap();
+        session.sync(&mut core);
+        let Some(Response::Rows { columns, rows, .. }) =
+            session.show("SHOW SESSION VARIABLES LIKE 'autocommit'", &core)
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(columns.len(), 2);
+        assert_eq!(
+            rows,
+            vec![vec![
+                Value::Text("autocommit".to_owned()),
+                Value::Text("OFF".to_owned())
+            ]]
+        );
+
+        let Some(Response::Rows { rows, .. }) =
+            session.show("SHOW VARIABLES LIKE 'CHARACTER_SET_%';", &core)
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows.len(), 4);
+        assert_eq!(
+            rows[0],
+            vec![
+                Value::Text("character_set_client".to_owned()),
+                Value::Text("latin1".to_owned())
+            ]
+        );
+        assert_eq!(rows[3][1], Value::Text("utf8mb4".to_owned()));
+        let Some(Response::Rows { rows, .. }) = session.show("SHOW VARIABLES", &core) else {
+            panic!("rows expected");
+        };
+        assert!(rows.len() > 4);
+        assert_eq!(core.variable("@@autocommit"), Value::Integer(0));
+
+        assert!(session.show("SHOW TABLES;", &core).is_none());
+        assert!(session.show("SHOW VARIABLES WHERE 1", &core).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_autocommit_off_disconnect() {
+        let op = Operator::from_config(MemoryConfig::default())
+            .unwrap()
+            .finish();
+        let core = Arc::new(RwLock::new(Aidb::from_op(op.clone()).await.unwrap()));
+        let transaction_ended = Arc::new(Notify::new());
+        let connect = || MySQLShim {
+            connection_id: next_connection_id(),
+            core: core.clone(),
+            reader: Default::default(),
+            transaction_ended: transaction_ended.clone(),
+            session: Session::default(),
+            statement_timeout: None,
+            credentials: None,
+        };
+        let mut shim = connect();
+        shim.run("CREATE TABLE t (id INTEGER);").await.unwrap();
+        shim.run("INSERT INTO t VALUES (1);").await.unwrap();
+        shim.run("SET autocommit = 0").await.unwrap();
+        shim.run("INSERT INTO t VALUES (2);").await.unwrap();
+        let (Response::Rows { rows, .. }, _) = shim.run("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows.len(), 2);
+        // nothing is persisted before COMMIT
+        let mut stored = Aidb::from_op(op.clone()).await.unwrap();
+        let Response::Rows { rows, .. } = stored.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, vec![vec![Value::Integer(1)]]);
+        shim.disconnect().await;
+
+        let mut shim = connect();
+        let (Response::Rows { rows, .. }, _) = shim.run("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, vec![vec![Value::Integer(1)]]);
+
+        shim.run("SET autocommit = 0").await.unwrap();
+        shim.run("INSERT INTO t VALUES (3);").await.unwrap();
+        shim.run("COMMIT;").await.unwrap();
+        shim.disconnect().await;
+        let mut stored = Aidb::from_op(op).await.unwrap();
+        let Response::Rows { rows, .. } = stored.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_transaction_owner() {
+        let core = Arc::new(RwLock::new(Aidb::new_memory().await));
+        let transaction_ended = Arc::new(Notify::new());
+        let connect = || MySQLShim {
+            connection_id: next_connection_id(),
+            core: core.clone(),
+            reader: Default::default(),
+            transaction_ended: transaction_ended.clone(),
+            session: Session::default(),
+            statement_timeout: None,
+            credentials: None,
+        };
+        let mut owner = connect();
+        owner.run("CREATE TABLE t (id INTEGER);").await.unwrap();
+        owner.run("SET autocommit = 0").await.unwrap();
+        owner.run("INSERT INTO t VALUES (1);").await.unwrap();
+        assert_eq!(
+            core.read().await.transaction_owner(),
+            Some(owner.connection_id)
+        );
+
+        // other connections can neither join, end nor discard the transaction, writes wait for it
+        let mut other = connect();
+        other.run("SET innodb_lock_wait_timeout = 0").await.unwrap();
+        for query in ["INSERT INTO t VALUES (2);", "START TRANSACTION;"] {
+            let e = other.run(query).await.unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "Lock wait timeout exceeded; try restarting transaction"
+            );
+        }
+        other.run("COMMIT;").await.unwrap();
+        other.run("ROLLBACK;").await.unwrap();
+        other.run("SET autocommit = 0").await.unwrap();
+        other.run("SET autocommit = 1").await.unwrap();
+        other.disconnect().await;
+        assert_eq!(
+            core.read().await.transaction_owner(),
+            Some(owner.connection_id)
+        );
+
+        let mut other = connect();
+        let insert = tokio::spawn(async move {
+            other.run("INSERT INTO t VALUES (2);").await.unwrap();
+            other
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!insert.is_finished());
+        owner.run("COMMIT;").await.unwrap();
+        let mut other = insert.await.unwrap();
+        assert_eq!(core.read().await.transaction_owner(), None);
+        let (Response::Rows { rows, .. }, _) = other.run("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, [vec![Value::Integer(1)], vec![Value::Integer(2)]]);
+
+        // as well as for the owner to disconnect
+        owner.run("INSERT INTO t VALUES (3);").await.unwrap();
+        let insert = tokio::spawn(async move { other.run("INSERT INTO t VALUES (4);").await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!insert.is_finished());
+        owner.disconnect().await;
+        insert.await.unwrap().unwrap();
+        let (Response::Rows { rows, .. }, _) = connect().run("SELECT * FROM t;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, [1, 2, 4].map(|id| vec![Value::Integer(id)]));
+    }
+
+    #[tokio::test]
+    async fn test_autocommit_off_read() {
+        let core = Arc::new(RwLock::new(Aidb::new_memory().await));
+        let transaction_ended = Arc::new(Notify::new());
+        let connect = || MySQLShim {
+            connection_id: next_connection_id(),
+            core: core.clone(),
+            reader: Default::default(),
+            transaction_ended: transaction_ended.clone(),
+            session: Session::default(),
+            statement_timeout: None,
+            credentials: None,
+        };
+        let count = |r: eyre::Result<(Response, Option<i64>)>| {
+            let (Response::Rows { rows, .. }, _) = r.unwrap() else {
+                panic!("rows expected");
+            };
+            rows.len()
+        };
+        let mut idle = connect();
+        idle.run("CREATE TABLE t (id INTEGER);").await.unwrap();
+        idle.run("SET autocommit = 0").await.unwrap();
+        assert_eq!(count(idle.run("SELECT * FROM t;").await), 0);
+        assert!(!core.read().await.in_transaction());
+
+        // reading didn't start a transaction that writes of others would wait for
+        let mut other = connect();
+        other.run("SET innodb_lock_wait_timeout = 0").await.unwrap();
+        other.run("INSERT INTO t VALUES (1);").await.unwrap();
+        assert_eq!(count(idle.run("SELECT * FROM t;").await), 1);
+        idle.run("COMMIT;").await.unwrap();
+
+        // the first write does
+        idle.run("INSERT INTO t VALUES (2);").await.unwrap();
+        assert_eq!(
+            core.read().await.transaction_owner(),
+            Some(idle.connection_id)
+        );
+        assert_eq!(count(idle.run("SELECT * FROM t;").await), 2);
+        assert_eq!(count(other.run("SELECT * FROM t;").await), 1);
+        assert!(other.run("INSERT INTO t VALUES (3);").await.is_err());
+        idle.run("COMMIT;").await.unwrap();
+        assert_eq!(count(other.run("SELECT * FROM t;").await), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_reads() {
+        let core = Arc::new(RwLock::new(Aidb::new_memory().await));
+        let transaction_ended = Arc::new(Notify::new());
+        let connect = || MySQLShim {
+            connection_id: next_connection_id(),
+            core: core.clone(),
+            reader: Default::default(),
+            transaction_ended: transaction_ended.clone(),
+            session: Session::default(),
+            statement_timeout: None,
+            credentials: None,
+        };
+        let count = |r: eyre::Result<(Response, Option<i64>)>| {
+            let (Response::Rows { rows, .. }, _) = r.unwrap() else {
+                panic!("rows expected");
+            };
+            rows.len()
+        };
+        let mut writer = connect();
+        writer.run("CREATE TABLE t (id INTEGER);").await.unwrap();
+        writer.run("INSERT INTO t VALUES (1);").await.unwrap();
+
+        // a long SELECT of another connection is holding the lock
+        let running = core.read().await;
+        let mut shim = connect();
+        let r = tokio::time::timeout(Duration::from_secs(5), shim.run("SELECT * FROM t;"))
+            .await
+            .expect("SELECT waits for another SELECT");
+        assert_eq!(count(r), 1);
+        let (Response::Rows { rows, .. }, _) = shim
+            .run("SELECT @@session.transaction_read_only;")
+            .await
+            .unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, [vec![Value::Integer(0)]]);
+        let insert = tokio::spawn(async move {
+            writer.run("INSERT INTO t VALUES (2);").await.unwrap();
+            writer
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!insert.is_finished());
+        drop(running);
+        let mut writer = insert.await.unwrap();
+        assert_eq!(count(shim.run("SELECT * FROM t;").await), 2);
+
+        // a transaction is only visible to the connection that started it until COMMIT
+        writer.run("START TRANSACTION;").await.unwrap();
+        writer.run("INSERT INTO t VALUES (3);").await.unwrap();
+        assert_eq!(count(writer.run("SELECT * FROM t;").await), 3);
+        assert_eq!(count(shim.run("SELECT * FROM t;").await), 2);
+        writer.run("COMMIT;").await.unwrap();
+        assert!(!core.read().await.in_transaction());
+        assert_eq!(count(shim.run("SELECT * FROM t;").await), 3);
+    }
+
+    /// Takes some rows, then fails like the socket of a disconnected client.
+    struct ClosedSink(usize);
+
+    impl RowSink for ClosedSink {
+        async fn write(&mut self, _row: Row) -> io::Result<()> {
+            if self.0 == 0 {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.0 -= 1;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_rows_closed() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER);").await.unwrap();
+        let values = (0..5000).map(|i| format!("({i})")).join(", ");
+        aidb.query(format!("INSERT INTO t VALUES {values};"))
+            .await
+            .unwrap();
+
+        let mut pulled = 0;
+        let (_, rows) = aidb.query_stream("SELECT * FROM t;").await.unwrap();
+        let rows = rows.inspect(|_| pulled += 1);
+        let r = write_rows(rows, &mut ClosedSink(10)).await;
+        assert_eq!(r.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        // the row whose write failed is the last one taken from the plan
+        assert_eq!(pulled, 11);
+
+        let (_, rows) = aidb.query_stream("SELECT * FROM t;").await.unwrap();
+        assert!(matches!(
+            write_rows(rows, &mut ClosedSink(usize::MAX)).await,
+            Ok(Ok(()))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_column_types() {
+        let mut shim = MySQLShim {
+            connection_id: next_connection_id(),
+            core: Arc::new(RwLock::new(Aidb::new_memory().await)),
+            reader: Default::default(),
+            transaction_ended: Default::default(),
+            session: Session::default(),
+            statement_timeout: None,
+            credentials: None,
+        };
+        shim.run("CREATE TABLE t (k INTEGER, x INTEGER, r REAL, s TEXT);")
+            .await
+            .unwrap();
+        shim.run("INSERT INTO t VALUES (1, 1, 0.5, 'a'), (1, 2, 1.5, 'b'), (2, 4, 2.5, 'c');")
+            .await
+            .unwrap();
+        for (sql, types) in [
+            (
+                "SELECT COUNT(*), AVG(x), SUM(x), SUM(r), MIN(s), MAX(x) * 2 FROM t;",
+                vec![
+                    ColumnType::MYSQL_TYPE_LONGLONG,
+                    ColumnType::MYSQL_TYPE_DOUBLE,
+                    ColumnType::MYSQL_TYPE_LONGLONG,
+                    ColumnType::MYSQL_TYPE_DOUBLE,
+                    ColumnType::MYSQL_TYPE_VAR_STRING,
+                    ColumnType::MYSQL_TYPE_LONGLONG,
+                ],
+            ),
+            (
+                "SELECT k, COUNT(x), AVG(r), MAX(x) + 0.5 FROM t GROUP BY k;",
+                vec![
+                    ColumnType::MYSQL_TYPE_LONGLONG,
+                    ColumnType::MYSQL_TYPE_LONGLONG,
+                    ColumnType::MYSQL_TYPE_DOUBLE,
+                    ColumnType::MYSQL_TYPE_DOUBLE,
+                ],
+            ),
+        ] {
+            let (Response::Rows { columns, rows, .. }, _) = shim.run(sql).await.unwrap() else {
+                panic!("rows expected");
+            };
+            // values are of the datatype announced to the client
+            for row in &rows {
+                for (value, column) in row.iter().zip(&columns) {
+                    assert_eq!(value.datatype(), Some(column.datatype), "{sql}");
+                }
+            }
+            let coltypes = columns
+                .into_iter()
+                .map(|column| aidb_column_to_mysql(column).coltype)
+                .collect_vec();
+            assert_eq!(coltypes, types, "{sql}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_last_insert_id() {
+        let mut core = Aidb::new_memory().await;
+        let mut session = Session {
+            last_insert_id: 5,
+            ..Default::default()
+        };
+        session.sync(&mut core);
+        let Response::Rows { rows, .. } = core.query("SELECT LAST_INSERT_ID();").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, vec![vec![Value::Integer(5)]]);
+
+        // another session doesn't see the id
+        session = Session::default();
+        session.sync(&mut core);
+        assert_eq!(core.last_insert_id(), 0);
+    }
+
+    /// Minimal client speaking the protocol to a shim over an in-memory pipe.
+    struct Client {
+        stream: tokio::io::DuplexStream,
+        seq: u8,
+    }
+
+    impl Client {
+        fn connect(shim: MySQLShim) -> Self {
+            let (client, server) = tokio::io::duplex(4096);
+            tokio::spawn(async move {
+                let (r, w) = tokio::io::split(server);
+                let _ = opensrv_mysql::AsyncMysqlIntermediary::run_on(shim, r, w).await;
+            });
+            Self {
+                stream: client,
+                seq: 0,
+            }
+        }
+
+        async fn read_packet(&mut self) -> Vec<u8> {
+            use tokio::io::AsyncReadExt;
+
+            let mut header = [0; 4];
+            self.stream.read_exact(&mut header).await.unwrap();
+            let len = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+            self.seq = header[3].wrapping_add(1);
+            let mut payload = vec![0; len];
+            self.stream.read_exact(&mut payload).await.unwrap();
+            payload
+        }
+
+        async fn write_packet(&mut self, payload: &[u8]) {
+            use tokio::io::AsyncWriteExt;
+
+            let len = (payload.len() as u32).to_le_bytes();
+            self.stream
+                .write_all(&[len[0], len[1], len[2], self.seq])
+                .await
+                .unwrap();
+            self.stream.write_all(payload).await.unwrap();
+            self.seq = self.seq.wrapping_add(1);
+        }
+
+        /// Answer the greeting with `mysql_native_password`, returns whether the server accepted
+        /// the login.
+        async fn login(&mut self, user: &str, auth_response: &[u8]) -> bool {
+            self.read_packet().await;
+            // CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH
+            let capabilities: u32 = 0x200 | 0x8000 | 0x80000;
+            let mut payload = capabilities.to_le_bytes().to_vec();
+            payload.extend((1u32 << 24).to_le_bytes());
+            payload.push(0x21);
+            payload.extend([0; 23]);
+            payload.extend(user.as_bytes());
+            payload.push(0);
+            payload.push(auth_response.len() as u8);
+            payload.extend(auth_response);
+            payload.extend(b"mysql_native_password\0");
+            self.write_packet(&payload).await;
+            self.read_packet().await[0] == 0x00
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let core = Arc::new(RwLock::new(Aidb::new_memory().await));
+        let shim = |password: &str| MySQLShim {
+            connection_id: next_connection_id(),
+            core: core.clone(),
+            reader: Default::default(),
+            transaction_ended: Default::default(),
+            session: Session::default(),
+            statement_timeout: None,
+            credentials: Some(Arc::new(Credentials {
+                user: "root".to_owned(),
+                password: password.to_owned(),
+            })),
+        };
+        // "secret" scrambled with the default salt of opensrv
+        let secret = [
+            0xee, 0x09, 0xbb, 0x1b, 0x44, 0x1f, 0x68, 0x9e, 0x28, 0x19, 0x33, 0xac, 0x03, 0xba,
+            0xfb, 0x62, 0xef, 0xcf, 0xb2, 0x41,
+        ];
+        assert!(Client::connect(shim("secret")).login("root", &secret).await);
+        assert!(!Client::connect(shim("wrong")).login("root", &secret).await);
+        assert!(
+            !Client::connect(shim("secret"))
+                .login("admin", &secret)
+                .await
+        );
+        assert!(!Client::connect(shim("secret")).login("root", &[]).await);
+        assert!(Client::connect(shim("")).login("root", &[]).await);
+
+        let open = MySQLShim {
+            credentials: None,
+            ..shim("")
+        };
+        assert!(Client::connect(open).login("anyone", &secret).await);
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let core = Arc::new(RwLock::new(Aidb::new_memory().await));
+        let mut client = Client::connect(MySQLShim {
+            connection_id: next_connection_id(),
+            core,
+            reader: Default::default(),
+            transaction_ended: Default::default(),
+            session: Session::default(),
+            statement_timeout: None,
+            credentials: None,
+        });
+        assert!(client.login("root", &[]).await);
+        // COM_PING, COM_STATISTICS then COM_PING again, opensrv has no hook for them and
+        // answers both with OK, keeping the connection usable for pools checking liveness
+        for command in [0x0e, 0x09, 0x0e] {
+            client.seq = 0;
+            client.write_packet(&[command]).await;
+            assert_eq!(client.read_packet().await[0], 0x00, "{command:#x}");
+        }
+        client.seq = 0;
+        client.write_packet(b"\x03SELECT 1;").await;
+        // column count of the result set
+        assert_eq!(client.read_packet().await, [1]);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_columns() {
+        let mut core = Aidb::new_memory().await;
+        core.query("CREATE TABLE t (id INTEGER);").await.unwrap();
+        core.query("INSERT INTO t VALUES (7);").await.unwrap();
+        let mut client = Client::connect(MySQLShim {
+            connection_id: next_connection_id(),
+            core: Arc::new(RwLock::new(core)),
+            reader: Default::default(),
+            transaction_ended: Default::default(),
+            session: Session::default(),
+            statement_timeout: None,
+            credentials: None,
+        });
+        assert!(client.login("root", &[]).await);
+        client.seq = 0;
+        client.write_packet(b"\x03SELECT id, id FROM t;").await;
+        assert_eq!(client.read_packet().await, [2]);
+        for _ in 0..2 {
+            // catalog, schema, table and original table come before the name
+            assert!(
+                client
+                    .read_packet()
+                    .await
+                    .starts_with(b"\x03def\x00\x00\x00\x02id")
+            );
+        }
+        assert_eq!(client.read_packet().await[0], 0xfe);
+        // both values of the row, one for each column
+        assert_eq!(client.read_packet().await, b"\x017\x017");
+        assert_eq!(client.read_packet().await[0], 0xfe);
+    }
+}
@@ -1,24 +1,56 @@
-use std::{mem::swap, ops::Bound};
+use std::{collections::HashSet, mem::swap, ops::Bound};
 
 use binrw::{BinRead, BinWrite, binrw};
 use eyre::{OptionExt, Result, eyre};
 
 use crate::{
     Aidb,
-    storage::{BLOCK_SIZE, BlockIndex, DataPointer},
+    storage::{BLOCK_SIZE, BlockIndex, BlockOffset, BlockPtr, DataPointer},
 };
 
-const BTREE_N: usize = ((BLOCK_SIZE - 10) / 20) - 1;
+/// serialized size of `len` in nodes
+const BTREE_NODE_HEADER_SIZE: usize = size_of::<u16>();
+/// serialized size of a `(BlockIndex, i64)` child in roots and nodes
+const BTREE_NODE_ENTRY_SIZE: usize = size_of::<BlockIndex>() + size_of::<i64>();
+/// max children a root or node block could hold
+const BTREE_NODE_CAPACITY: usize = (BLOCK_SIZE - BTREE_NODE_HEADER_SIZE) / BTREE_NODE_ENTRY_SIZE;
+
+/// serialized size of `next`, `prev` and `len` in leaves
+const BTREE_LEAF_HEADER_SIZE: usize = 2 * size_of::<BlockIndex>() + size_of::<u16>();
+/// serialized size of a `(i64, DataPointer)` record in leaves
+const BTREE_LEAF_ENTRY_SIZE: usize =
+    size_of::<i64>() + size_of::<BlockIndex>() + size_of::<BlockOffset>();
+/// max records a leaf block could hold
+const BTREE_LEAF_CAPACITY: usize = (BLOCK_SIZE - BTREE_LEAF_HEADER_SIZE) / BTREE_LEAF_ENTRY_SIZE;
+
+/// Max children of a root or node and max records of a leaf, as many as a block holds unless
+/// lowered so that trees grow quickly in tests.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BTreeFanout {
+    pub(crate) node: usize,
+    pub(crate) leaf: usize,
+}
+
+impl Default for BTreeFanout {
+    fn default() -> Self {
+        Self {
+            node: BTREE_NODE_CAPACITY,
+            leaf: BTREE_LEAF_CAPACITY,
+        }
+    }
+}
 
 #[binrw]
 #[brw(little)]
 #[derive(Debug)]
 struct BTreeRoot {
+    /// levels of nodes between root and leaves
+    height: u16,
     #[br(temp)]
     #[bw(calc = children.len() as u16)]
     len: u16,
     #[br(count = len)]
-    #[bw(assert(!children.is_empty() && children.len() <= BTREE_N + 1))]
+    #[bw(assert(!children.is_empty() && children.len() <= BTREE_NODE_CAPACITY))]
     children: Vec<(BlockIndex, i64)>,
 }
 
@@ -30,7 +62,7 @@ struct BTreeNode {
     #[bw(calc = children.len() as u16)]
     len: u16,
     #[br(count = len)]
-    #[bw(assert(!children.is_empty() && children.len() <= BTREE_N + 1))]
+    #[bw(assert(!children.is_empty() && children.len() <= BTREE_NODE_CAPACITY))]
     children: Vec<(BlockIndex, i64)>,
 }
 
@@ -39,17 +71,24 @@ struct BTreeNode {
 #[derive(Debug)]
 struct BTreeLeaf {
     next: BlockIndex,
+    /// 0 for the first leaf
+    prev: BlockIndex,
     #[br(temp)]
     #[bw(calc = records.len() as u16)]
     len: u16,
+    /// empty only after removals
     #[br(count = len)]
-    #[bw(assert(!records.is_empty() && records.len() <= BTREE_N + 1))]
+    #[bw(assert(records.len() <= BTREE_LEAF_CAPACITY))]
     records: Vec<(i64, DataPointer)>,
 }
 
 #[derive(Debug)]
 pub(crate) enum BTreeExactState {
     Initialized,
+    Running {
+        next: BlockIndex,
+        stream: std::vec::IntoIter<(i64, DataPointer)>,
+    },
     Done,
 }
 
@@ -66,6 +105,7 @@ pub(crate) enum BTreeRangeState {
         next: BlockIndex,
         stream: std::vec::IntoIter<(i64, DataPointer)>,
     },
+    Done,
 }
 
 impl Default for BTreeRangeState {
@@ -74,11 +114,54 @@ impl Default for BTreeRangeState {
     }
 }
 
+impl BTreeLeaf {
+    /// The adjacent leaf and records in the direction of a scan.
+    fn into_scan(mut self, reverse: bool) -> (BlockIndex, std::vec::IntoIter<(i64, DataPointer)>) {
+        if reverse {
+            self.records.reverse();
+            (self.prev, self.records.into_iter())
+        } else {
+            (self.next, self.records.into_iter())
+        }
+    }
+}
+
+/// Child whose range contains the key, the criteria of each child is the exclusive upper bound of
+/// its keys except for the last one which is unbounded. Duplicated keys of a non-unique index may
+/// also equal the criteria after a split, seek the leftmost child to find them all.
+fn seek_child(children: &[(BlockIndex, i64)], key: i64, leftmost: bool) -> Result<BlockIndex> {
+    let (last, init) = children.split_last().ok_or_eyre("invalid btree index")?;
+    Ok(init
+        .iter()
+        .find(|(_, criteria)| key < *criteria || (leftmost && key == *criteria))
+        .unwrap_or(last)
+        .0)
+}
+
+/// Insert a new child right after the one it was split from, keys from the new criteria on move
+/// to the new child.
+fn insert_child(children: &mut Vec<(BlockIndex, i64)>, mut key: i64, child: BlockIndex) {
+    let index = children[..children.len() - 1]
+        .iter()
+        .position(|(_, criteria)| key < *criteria)
+        .unwrap_or(children.len() - 1);
+    swap(&mut children[index].1, &mut key);
+    children.insert(index + 1, (child, key));
+}
+
+/// Split off the upper half of children, returns the criteria separating both halves.
+fn split_children(children: &mut Vec<(BlockIndex, i64)>) -> (i64, Vec<(BlockIndex, i64)>) {
+    let next_children = children.split_off(children.len().div_ceil(2));
+    // keys below the criteria of the last remaining child stay in this half
+    (children.last().unwrap().1, next_children)
+}
+
 impl Aidb {
     pub(crate) async fn new_btree(&mut self, key: i64, record: DataPointer) -> Result<BlockIndex> {
         let (leaf_i, mut leaf_b) = self.new_block();
         BTreeLeaf {
             next: 0,
+            prev: 0,
             records: vec![(key, record)],
         }
         .write(&mut leaf_b.cursor())?;
@@ -95,6 +178,7 @@ impl Aidb {
 
         let (root_i, mut root_b) = self.new_block();
         BTreeRoot {
+            height: 1,
             children: vec![(node_i, 0)],
         }
         .write(&mut root_b.cursor())?;
@@ -104,20 +188,76 @@ impl Aidb {
         Ok(root_i)
     }
 
+    /// Build a tree bottom-up from all records at once, leaves and nodes are packed full. Returns
+    /// 0 if there is no record.
+    pub(crate) async fn build_btree(
+        &mut self,
+        mut records: Vec<(i64, DataPointer)>,
+        unique: bool,
+    ) -> Result<BlockPtr> {
+        if records.is_empty() {
+            return Ok(BlockPtr::NONE);
+        }
+        records.sort_by_key(|(key, _)| *key);
+        if unique && records.windows(2).any(|w| w[0].0 == w[1].0) {
+            return Err(eyre!("unique key exists"));
+        }
+
+        // leaves are allocated up front so that each of them knows its neighbours
+        let chunks = records.chunks(self.btree_fanout.leaf).collect::<Vec<_>>();
+        let leaves = chunks.iter().map(|_| self.new_block()).collect::<Vec<_>>();
+        let leaf_indices = leaves.iter().map(|(leaf_i, _)| *leaf_i).collect::<Vec<_>>();
+        let mut level = vec![];
+        for (i, (leaf_i, mut leaf_b)) in leaves.into_iter().enumerate() {
+            BTreeLeaf {
+                next: leaf_indices.get(i + 1).copied().unwrap_or(0),
+                prev: i.checked_sub(1).map_or(0, |i| leaf_indices[i]),
+                records: chunks[i].to_vec(),
+            }
+            .write(&mut leaf_b.cursor())?;
+            self.put_block(leaf_i, leaf_b);
+            self.mark_block_dirty(leaf_i);
+            // criteria is the first key of the next leaf, which is meaningless for the last one
+            let criteria = chunks.get(i + 1).map_or(0, |next| next[0].0);
+            level.push((leaf_i, criteria));
+        }
+
+        let mut height = 0;
+        loop {
+            let mut next_level = vec![];
+            for children in level.chunks(self.btree_fanout.node) {
+                let criteria = children.last().unwrap().1;
+                let node_i = self.new_node(BTreeNode {
+                    children: children.to_vec(),
+                })?;
+                next_level.push((node_i, criteria));
+            }
+            level = next_level;
+            height += 1;
+            if level.len() <= self.btree_fanout.node {
+                break;
+            }
+        }
+
+        let (root_i, mut root_b) = self.new_block();
+        BTreeRoot {
+            height,
+            children: level,
+        }
+        .write(&mut root_b.cursor())?;
+        self.put_block(root_i, root_b);
+        self.mark_block_dirty(root_i);
+        Ok(BlockPtr::to(root_i))
+    }
+
     pub(crate) async fn insert_btree(
         &mut self,
         root: BlockIndex,
         key: i64,
         record: DataPointer,
+        unique: bool,
     ) -> Result<()> {
-        if self
-            .select_btree(root, key, &mut BTreeExactState::Initialized)
-            .await?
-            .is_some()
-        {
-            return Err(eyre!("unique key exists"));
-        }
-        self.insert_leaf(root, key, record).await
+        self.insert_leaf(root, key, record, unique).await
     }
 
     async fn read_root(&mut self, root: BlockIndex) -> Result<BTreeRoot> {
@@ -135,43 +275,41 @@ impl Aidb {
         Ok(())
     }
 
-    async fn insert_root(
-        &mut self,
-        root: BlockIndex,
-        mut key: i64,
-        child: BlockIndex,
-    ) -> Result<()> {
+    async fn insert_root(&mut self, root: BlockIndex, key: i64, child: BlockIndex) -> Result<()> {
         let mut btree_root = self.read_root(root).await?;
-        let mut index = btree_root.children.len() - 1;
-        for (i, (_, criteria)) in btree_root.children[..btree_root.children.len() - 1]
-            .iter()
-            .enumerate()
-        {
-            if key < *criteria {
-                index = i;
-                break;
-            }
+        insert_child(&mut btree_root.children, key, child);
+        if btree_root.children.len() > self.btree_fanout.node {
+            // grow a level by moving both halves of the root into new nodes, so that the
+            // root block index referenced by the schema stays the same
+            let (next_key, next_children) = split_children(&mut btree_root.children);
+            let last_key = next_children.last().unwrap().1;
+            let children = std::mem::take(&mut btree_root.children);
+            let node_i = self.new_node(BTreeNode { children })?;
+            let next_node_i = self.new_node(BTreeNode {
+                children: next_children,
+            })?;
+            btree_root.height += 1;
+            btree_root.children = vec![(node_i, next_key), (next_node_i, last_key)];
         }
-        swap(&mut btree_root.children[index].1, &mut key);
-        btree_root.children.insert(index + 1, (child, key));
         self.write_root(root, btree_root).await?;
         Ok(())
     }
 
-    async fn seek_node(&mut self, root: BlockIndex, key: i64) -> Result<BlockIndex> {
+    /// Find the path from the root to the leaf that may contain the key, nodes are listed top-down
+    /// and the leaf comes last.
+    async fn seek_path(
+        &mut self,
+        root: BlockIndex,
+        key: i64,
+        leftmost: bool,
+    ) -> Result<Vec<BlockIndex>> {
         let btree_root = self.read_root(root).await?;
-        let mut node_i = btree_root
-            .children
-            .last()
-            .ok_or_eyre("invalid btree index")?
-            .0;
-        for (child, criteria) in btree_root.children[..btree_root.children.len() - 1].iter() {
-            if key < *criteria {
-                node_i = *child;
-                break;
-            }
+        let mut path = vec![seek_child(&btree_root.children, key, leftmost)?];
+        for _ in 0..btree_root.height {
+            let btree_node = self.read_node(*path.last().unwrap()).await?;
+            path.push(seek_child(&btree_node.children, key, leftmost)?);
         }
-        Ok(node_i)
+        Ok(path)
     }
 
     async fn read_node(&mut self, node_i: BlockIndex) -> Result<BTreeNode> {
@@ -189,59 +327,41 @@ impl Aidb {
         Ok(())
     }
 
+    fn new_node(&mut self, btree_node: BTreeNode) -> Result<BlockIndex> {
+        let (node_i, mut node_b) = self.new_block();
+        btree_node.write(&mut node_b.cursor())?;
+        self.put_block(node_i, node_b);
+        self.mark_block_dirty(node_i);
+        Ok(node_i)
+    }
+
+    /// Insert a child into the last node of path, splitting nodes bottom-up as needed.
     async fn insert_node(
         &mut self,
         root: BlockIndex,
+        mut path: Vec<BlockIndex>,
         mut key: i64,
-        child: BlockIndex,
+        mut child: BlockIndex,
     ) -> Result<()> {
-        let node_i = self.seek_node(root, key).await?;
-        let mut btree_node = self.read_node(node_i).await?;
-        let mut index = btree_node.children.len() - 1;
-        for (i, (_, criteria)) in btree_node.children[..btree_node.children.len() - 1]
-            .iter()
-            .enumerate()
-        {
-            if key < *criteria {
-                index = i;
-                break;
+        while let Some(node_i) = path.pop() {
+            let mut btree_node = self.read_node(node_i).await?;
+            insert_child(&mut btree_node.children, key, child);
+            if btree_node.children.len() <= self.btree_fanout.node {
+                return self.write_node(node_i, btree_node).await;
             }
-        }
-        swap(&mut btree_node.children[index].1, &mut key);
-        btree_node.children.insert(index + 1, (child, key));
-        if btree_node.children.len() > BTREE_N + 1 {
-            let (next_node_i, mut next_node_b) = self.new_block();
-            let next_children = btree_node
-                .children
-                .split_off(btree_node.children.len().div_ceil(2));
-            let next_key = next_children.first().unwrap().1;
-            BTreeNode {
+            let (next_key, next_children) = split_children(&mut btree_node.children);
+            self.write_node(node_i, btree_node).await?;
+            key = next_key;
+            child = self.new_node(BTreeNode {
                 children: next_children,
-            }
-            .write(&mut next_node_b.cursor())?;
-            self.put_block(next_node_i, next_node_b);
-            self.mark_block_dirty(next_node_i);
-            self.insert_root(root, next_key, next_node_i).await?;
+            })?;
         }
-        self.write_node(node_i, btree_node).await?;
-        Ok(())
+        self.insert_root(root, key, child).await
     }
 
-    async fn seek_leaf(&mut self, root: BlockIndex, key: i64) -> Result<BlockIndex> {
-        let node_i = self.seek_node(root, key).await?;
-        let btree_node = self.read_node(node_i).await?;
-        let mut leaf_i = btree_node
-            .children
-            .last()
-            .ok_or_eyre("invalid btree index")?
-            .0;
-        for (child, criteria) in btree_node.children[..btree_node.children.len() - 1].iter() {
-            if key < *criteria {
-                leaf_i = *child;
-                break;
-            }
-        }
-        Ok(leaf_i)
+    /// Find the leftmost leaf that may contain the key.
+    pub(crate) async fn seek_leaf(&mut self, root: BlockIndex, key: i64) -> Result<BlockIndex> {
+        Ok(*self.seek_path(root, key, true).await?.last().unwrap())
     }
 
     async fn read_leaf(&mut self, leaf_i: BlockIndex) -> Result<BTreeLeaf> {
@@ -259,16 +379,27 @@ impl Aidb {
         Ok(btree_leaf)
     }
 
-    async fn insert_leaf(&mut self, root: BlockIndex, key: i64, record: DataPointer) -> Result<()> {
-        let leaf_i = self.seek_leaf(root, key).await?;
+    async fn insert_leaf(
+        &mut self,
+        root: BlockIndex,
+        key: i64,
+        record: DataPointer,
+        unique: bool,
+    ) -> Result<()> {
+        let mut path = self.seek_path(root, key, false).await?;
+        let leaf_i = path.pop().unwrap();
         let mut btree_leaf = self.read_leaf(leaf_i).await?;
         let index = btree_leaf
             .records
             .iter()
             .position(|(criteria, _)| *criteria > key)
             .unwrap_or(btree_leaf.records.len());
+        // a unique key never spans leaves, so the leaf it would be inserted into is enough
+        if unique && index > 0 && btree_leaf.records[index - 1].0 == key {
+            return Err(eyre!("unique key exists"));
+        }
         btree_leaf.records.insert(index, (key, record));
-        if btree_leaf.records.len() > BTREE_N + 1 {
+        if btree_leaf.records.len() > self.btree_fanout.leaf {
             let (next_leaf_i, mut next_leaf_b) = self.new_block();
             let next_records = btree_leaf
                 .records
@@ -276,109 +407,645 @@ impl Aidb {
             let next_key = next_records.first().unwrap().0;
             BTreeLeaf {
                 next: btree_leaf.next,
+                prev: leaf_i,
                 records: next_records,
             }
             .write(&mut next_leaf_b.cursor())?;
             self.put_block(next_leaf_i, next_leaf_b);
             self.mark_block_dirty(next_leaf_i);
+            if btree_leaf.next != 0 {
+                let mut after_leaf = self.read_leaf(btree_leaf.next).await?;
+                after_leaf.prev = next_leaf_i;
+                self.write_leaf(btree_leaf.next, after_leaf).await?;
+            }
             btree_leaf.next = next_leaf_i;
-            self.insert_node(root, next_key, next_leaf_i).await?;
+            self.insert_node(root, path, next_key, next_leaf_i).await?;
         }
         self.write_leaf(leaf_i, btree_leaf).await?;
         Ok(())
     }
 
+    /// Remove the record of a key pointing at a row, leaves are left in place even if emptied.
+    pub(crate) async fn remove_btree(
+        &mut self,
+        root: BlockIndex,
+        key: i64,
+        record: &DataPointer,
+    ) -> Result<()> {
+        let mut leaf_i = self.seek_leaf(root, key).await?;
+        while leaf_i != 0 {
+            let mut btree_leaf = self.read_leaf(leaf_i).await?;
+            if let Some(index) = btree_leaf
+                .records
+                .iter()
+                .position(|(criteria, r)| *criteria == key && r == record)
+            {
+                btree_leaf.records.remove(index);
+                self.write_leaf(leaf_i, btree_leaf).await?;
+                return Ok(());
+            }
+            if btree_leaf
+                .records
+                .last()
+                .is_some_and(|(criteria, _)| *criteria > key)
+            {
+                break;
+            }
+            leaf_i = btree_leaf.next;
+        }
+        Err(eyre!("key {key} not found in btree index"))
+    }
+
     pub(crate) async fn select_btree(
         &mut self,
         root: BlockIndex,
         key: i64,
         state: &mut BTreeExactState,
     ) -> Result<Option<DataPointer>> {
-        if root == 0 {
-            return Ok(None);
-        }
-        match state {
-            BTreeExactState::Initialized => {
-                let leaf_i = self.seek_leaf(root, key).await?;
-                let leaf = self.read_leaf(leaf_i).await?;
-                let record = leaf
-                    .records
-                    .into_iter()
-                    .find(|(criteria, _)| key == *criteria)
-                    .map(|(_, record)| record);
-                *state = BTreeExactState::Done;
-                Ok(record)
+        loop {
+            match state {
+                BTreeExactState::Initialized => {
+                    let leaf_i = self.seek_leaf(root, key).await?;
+                    let leaf = self.read_leaf(leaf_i).await?;
+                    *state = BTreeExactState::Running {
+                        next: leaf.next,
+                        stream: leaf.records.into_iter(),
+                    };
+                }
+                BTreeExactState::Running { next, stream } => {
+                    for (criteria, record) in stream.by_ref() {
+                        if criteria == key {
+                            return Ok(Some(record));
+                        } else if criteria > key {
+                            *state = BTreeExactState::Done;
+                            return Ok(None);
+                        }
+                    }
+                    if *next == 0 {
+                        *state = BTreeExactState::Done;
+                    } else {
+                        let leaf = self.read_leaf(*next).await?;
+                        *next = leaf.next;
+                        *stream = leaf.records.into_iter();
+                    }
+                }
+                BTreeExactState::Done => return Ok(None),
             }
-            BTreeExactState::Done => Ok(None),
         }
     }
 
+    /// Records with keys in the range one at a time, in order of keys, descending if reverse.
     pub(crate) async fn select_range_btree(
         &mut self,
         root: BlockIndex,
         range: (Bound<i64>, Bound<i64>),
+        reverse: bool,
         state: &mut BTreeRangeState,
     ) -> Result<Option<DataPointer>> {
-        if root == 0 {
+        let lower = match range.0 {
+            Bound::Included(key) => Some(key),
+            Bound::Excluded(key) => key.checked_add(1),
+            Bound::Unbounded => Some(i64::MIN),
+        };
+        let upper = match range.1 {
+            Bound::Included(key) => Some(key),
+            Bound::Excluded(key) => key.checked_sub(1),
+            Bound::Unbounded => Some(i64::MAX),
+        };
+        let (Some(lower), Some(upper)) = (lower, upper) else {
             return Ok(None);
-        }
-        let left_bound = match range.0 {
-            Bound::Included(v) => v,
-            Bound::Excluded(v) => {
-                if v == i64::MAX {
-                    return Ok(None);
-                } else {
-                    v + 1
-                }
-            }
-            Bound::Unbounded => i64::MIN,
         };
-        let right_bound = match range.0 {
-            Bound::Included(v) => v,
-            Bound::Excluded(v) => {
-                if v == i64::MIN {
-                    return Ok(None);
-                } else {
-                    v - 1
+        loop {
+            match state {
+                BTreeRangeState::Initialized => {
+                    let leaf_i = if reverse {
+                        // the rightmost leaf that may contain the upper bound, walking back from
+                        // there reaches duplicates of it in leaves before
+                        *self.seek_path(root, upper, false).await?.last().unwrap()
+                    } else {
+                        self.seek_leaf(root, lower).await?
+                    };
+                    let (next, stream) = self.read_leaf(leaf_i).await?.into_scan(reverse);
+                    *state = BTreeRangeState::Running { next, stream };
                 }
-            }
-            Bound::Unbounded => i64::MAX,
-        };
-        match state {
-            BTreeRangeState::Initialized => {
-                let leaf_i = self.seek_leaf(root, left_bound).await?;
-                let leaf = self.read_leaf(leaf_i).await?;
-                *state = BTreeRangeState::Running {
-                    next: leaf.next,
-                    stream: leaf.records.into_iter(),
-                };
-                Box::pin(self.select_range_btree(root, range, state)).await
-            }
-            BTreeRangeState::Running { next, stream } => {
-                let mut result = vec![];
-                'seek_block: loop {
-                    for (criteria, record) in stream.by_ref() {
-                        if criteria < left_bound {
-                            continue;
-                        } else if criteria > right_bound {
-                            break 'seek_block;
+                BTreeRangeState::Running { next, stream } => {
+                    for (key, record) in stream.by_ref() {
+                        let (past, within) = if reverse {
+                            (key < lower, key <= upper)
                         } else {
-                            result.push(record);
+                            (key > upper, key >= lower)
+                        };
+                        if past {
+                            *state = BTreeRangeState::Done;
+                            return Ok(None);
+                        } else if within {
+                            return Ok(Some(record));
                         }
                     }
                     if *next == 0 {
-                        break;
+                        *state = BTreeRangeState::Done;
                     } else {
-                        let next_leaf_i = *next;
-                        let mut next_leaf_b = self.get_block(next_leaf_i).await?;
-                        let leaf = BTreeLeaf::read(&mut next_leaf_b.cursor())?;
-                        *next = leaf.next;
-                        *stream = leaf.records.into_iter();
-                        self.put_block(next_leaf_i, next_leaf_b);
+                        (*next, *stream) = self.read_leaf(*next).await?.into_scan(reverse);
                     }
                 }
-                Ok(None)
+                BTreeRangeState::Done => return Ok(None),
+            }
+        }
+    }
+
+    /// Check that the leaf chain visits the leaves of the tree in order and terminates, that each
+    /// leaf points back at the one before, and that keys are sorted along it. Returns records of the leaf chain.
+    pub(crate) async fn check_btree(
+        &mut self,
+        root: BlockIndex,
+        visited: &mut HashSet<BlockIndex>,
+        context: &str,
+        problems: &mut Vec<String>,
+    ) -> Result<Vec<(i64, DataPointer)>> {
+        let btree_root = match self.read_root(root).await {
+            Ok(btree_root) => btree_root,
+            Err(e) => {
+                problems.push(format!("{context}: root {root} is unreadable: {e}"));
+                return Ok(vec![]);
+            }
+        };
+        let mut level = btree_root
+            .children
+            .iter()
+            .map(|(i, _)| *i)
+            .collect::<Vec<_>>();
+        for _ in 0..btree_root.height {
+            let mut next_level = vec![];
+            for node_i in level {
+                if !self.check_block(node_i, visited, context, problems) {
+                    continue;
+                }
+                match self.read_node(node_i).await {
+                    Ok(node) => next_level.extend(node.children.iter().map(|(i, _)| *i)),
+                    Err(e) => problems.push(format!("{context}: node {node_i} is unreadable: {e}")),
+                }
+            }
+            level = next_level;
+        }
+
+        let mut chain = vec![];
+        let mut records: Vec<(i64, DataPointer)> = vec![];
+        let mut leaf_i = level.first().copied().unwrap_or(0);
+        while leaf_i != 0 && self.check_block(leaf_i, visited, context, problems) {
+            let leaf = match self.read_leaf(leaf_i).await {
+                Ok(leaf) => leaf,
+                Err(e) => {
+                    problems.push(format!("{context}: leaf {leaf_i} is unreadable: {e}"));
+                    break;
+                }
+            };
+            if !leaf.records.is_sorted_by_key(|(key, _)| *key) {
+                problems.push(format!("{context}: keys of leaf {leaf_i} are not sorted"));
+            } else if let (Some((previous, _)), Some((first, _))) =
+                (records.last(), leaf.records.first())
+                && previous > first
+            {
+                problems.push(format!(
+                    "{context}: keys of leaf {leaf_i} are below those of the previous leaf"
+                ));
+            }
+            let prev = chain.last().copied().unwrap_or(0);
+            if leaf.prev != prev {
+                problems.push(format!(
+                    "{context}: leaf {leaf_i} points back at {} instead of {prev}",
+                    leaf.prev
+                ));
+            }
+            chain.push(leaf_i);
+            records.extend(leaf.records);
+            leaf_i = leaf.next;
+        }
+        if chain != level {
+            problems.push(format!(
+                "{context}: leaf chain {chain:?} differs from leaves of the tree {level:?}"
+            ));
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, ops::RangeBounds};
+
+    use super::*;
+
+    /// Database whose trees grow after a few keys.
+    async fn small_fanout() -> Aidb {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.btree_fanout = BTreeFanout { node: 8, leaf: 8 };
+        aidb
+    }
+
+    fn record(key: i64) -> DataPointer {
+        DataPointer {
+            block: key as BlockIndex,
+            offset: 0,
+        }
+    }
+
+    #[test]
+    fn test_btree_capacity() {
+        let mut buffer = Cursor::new(vec![]);
+        BTreeNode {
+            children: vec![(1, 1); BTREE_NODE_CAPACITY],
+        }
+        .write(&mut buffer)
+        .unwrap();
+        assert_eq!(
+            buffer.get_ref().len(),
+            BTREE_NODE_HEADER_SIZE + BTREE_NODE_CAPACITY * BTREE_NODE_ENTRY_SIZE
+        );
+        assert!(buffer.get_ref().len() <= BLOCK_SIZE);
+        assert!(buffer.get_ref().len() + BTREE_NODE_ENTRY_SIZE > BLOCK_SIZE);
+
+        let mut buffer = Cursor::new(vec![]);
+        BTreeLeaf {
+            next: 0,
+            prev: 0,
+            records: vec![(1, record(1)); BTREE_LEAF_CAPACITY],
+        }
+        .write(&mut buffer)
+        .unwrap();
+        assert_eq!(
+            buffer.get_ref().len(),
+            BTREE_LEAF_HEADER_SIZE + BTREE_LEAF_CAPACITY * BTREE_LEAF_ENTRY_SIZE
+        );
+        assert!(buffer.get_ref().len() <= BLOCK_SIZE);
+        assert!(buffer.get_ref().len() + BTREE_LEAF_ENTRY_SIZE > BLOCK_SIZE);
+
+        let mut block = Aidb::new_volatile_block();
+        // root carries an extra height on top of the node layout
+        BTreeRoot {
+            height: 1,
+            children: vec![(1, 1); BTREE_NODE_CAPACITY],
+        }
+        .write(&mut block.cursor())
+        .unwrap();
+        assert!(
+            BTreeNode {
+                children: vec![(1, 1); BTREE_NODE_CAPACITY + 1],
+            }
+            .write(&mut block.cursor())
+            .is_err()
+        );
+        assert!(
+            BTreeLeaf {
+                next: 0,
+                prev: 0,
+                records: vec![(1, record(1)); BTREE_LEAF_CAPACITY + 1],
+            }
+            .write(&mut block.cursor())
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_btree_leaf_split() {
+        for mut aidb in [Aidb::new_memory().await, small_fanout().await] {
+            let n = aidb.btree_fanout.leaf as i64;
+            let root = aidb.new_btree(0, record(0)).await.unwrap();
+            for key in 1..n {
+                aidb.insert_btree(root, key, record(key), true)
+                    .await
+                    .unwrap();
+            }
+            let path = aidb.seek_path(root, 0, false).await.unwrap();
+            let (node_i, leaf_i) = (path[0], path[1]);
+            assert_eq!(aidb.read_node(node_i).await.unwrap().children.len(), 1);
+            assert_eq!(
+                aidb.read_leaf(leaf_i).await.unwrap().records.len(),
+                n as usize
+            );
+
+            aidb.insert_btree(root, n, record(n), true).await.unwrap();
+            assert_eq!(aidb.read_node(node_i).await.unwrap().children.len(), 2);
+            for key in 0..=n {
+                let ptr = aidb
+                    .select_btree(root, key, &mut Default::default())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(ptr.block, key as BlockIndex);
+            }
+            aidb.submit().await.unwrap();
+            let mut problems = vec![];
+            aidb.check_btree(root, &mut HashSet::new(), "t", &mut problems)
+                .await
+                .unwrap();
+            assert_eq!(problems, Vec::<String>::new());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_btree_node_split() {
+        for mut aidb in [Aidb::new_memory().await, small_fanout().await] {
+            let n = aidb.btree_fanout.node as i64;
+            let root = aidb.new_btree(0, record(0)).await.unwrap();
+            let node_i = aidb.seek_path(root, 0, false).await.unwrap()[0];
+            // children are never read by seek_leaf, so fake leaf indices are enough
+            let fake_leaf = |i: i64| (1_000_000 + i) as BlockIndex;
+            aidb.write_node(
+                node_i,
+                BTreeNode {
+                    children: (0..n).map(|i| (fake_leaf(i), (i + 1) * 10)).collect(),
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(aidb.read_root(root).await.unwrap().children.len(), 1);
+
+            aidb.insert_node(root, vec![node_i], 5, fake_leaf(-1))
+                .await
+                .unwrap();
+            assert_eq!(aidb.read_root(root).await.unwrap().children.len(), 2);
+            let leaf =
+                async |aidb: &mut Aidb, key| aidb.seek_path(root, key, false).await.unwrap()[1];
+            assert_eq!(leaf(&mut aidb, 0).await, fake_leaf(0));
+            assert_eq!(leaf(&mut aidb, 5).await, fake_leaf(-1));
+            for i in 1..n {
+                assert_eq!(leaf(&mut aidb, i * 10).await, fake_leaf(i));
+                assert_eq!(leaf(&mut aidb, i * 10 + 9).await, fake_leaf(i));
+            }
+
+            // a full root grows a level, which fits in a block at full capacity
+            let mut btree_root = aidb.read_root(root).await.unwrap();
+            btree_root.children = (0..n).map(|i| (fake_leaf(i), (i + 1) * 10)).collect();
+            aidb.write_root(root, btree_root).await.unwrap();
+            aidb.insert_root(root, 5, fake_leaf(-1)).await.unwrap();
+            let btree_root = aidb.read_root(root).await.unwrap();
+            assert_eq!((btree_root.height, btree_root.children.len()), (2, 2));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_btree_root_split() {
+        let mut aidb = small_fanout().await;
+        let n = 5000;
+        // visit keys in a scattered order so that splits happen all over the tree
+        let keys = (0..n).map(|i| i * 7919 % n).collect::<Vec<_>>();
+        let root = aidb.new_btree(keys[0], record(keys[0])).await.unwrap();
+        for key in keys[1..].iter() {
+            aidb.insert_btree(root, *key, record(*key), true)
+                .await
+                .unwrap();
+        }
+        assert!(aidb.read_root(root).await.unwrap().height >= 3);
+        for key in 0..n {
+            let ptr = aidb
+                .select_btree(root, key, &mut Default::default())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(ptr.block, key as BlockIndex);
+        }
+        assert!(
+            aidb.select_btree(root, n, &mut Default::default())
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(aidb.insert_btree(root, 42, record(42), true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_btree_insert_single_descent() {
+        let mut aidb = small_fanout().await;
+        let root = aidb.new_btree(0, record(0)).await.unwrap();
+        for key in 1..100 {
+            aidb.insert_btree(root, key * 2, record(key * 2), true)
+                .await
+                .unwrap();
+        }
+        let height = aidb.read_root(root).await.unwrap().height as usize;
+
+        // root, nodes and leaf are looked up once, then the leaf again to write it back
+        aidb.reset_block_io_log();
+        aidb.insert_btree(root, 1, record(1), true).await.unwrap();
+        assert_eq!(aidb.get_block_io_log().lookups, height + 3);
+
+        aidb.reset_block_io_log();
+        assert!(aidb.insert_btree(root, 4, record(4), true).await.is_err());
+        assert_eq!(aidb.get_block_io_log().lookups, height + 2);
+    }
+
+    #[tokio::test]
+    async fn test_btree_non_unique() {
+        let mut aidb = small_fanout().await;
+        let root = aidb.new_btree(0, record(0)).await.unwrap();
+        // duplicates of 5 spread over several leaves
+        for key in (1..10).chain([5; 30]).chain(10..20) {
+            aidb.insert_btree(root, key, record(key), false)
+                .await
+                .unwrap();
+        }
+        let mut state = BTreeExactState::Initialized;
+        let mut count = 0;
+        while let Some(ptr) = aidb.select_btree(root, 5, &mut state).await.unwrap() {
+            assert_eq!(ptr.block, 5);
+            count += 1;
+        }
+        assert_eq!(count, 31);
+        for key in (0..5).chain(6..20) {
+            let mut state = BTreeExactState::Initialized;
+            assert!(
+                aidb.select_btree(root, key, &mut state)
+                    .await
+                    .unwrap()
+                    .is_some()
+            );
+            assert!(
+                aidb.select_btree(root, key, &mut state)
+                    .await
+                    .unwrap()
+                    .is_none()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_btree_range_state() {
+        let mut aidb = small_fanout().await;
+        let root = aidb.new_btree(0, record(0)).await.unwrap();
+        for key in 1..100 {
+            aidb.insert_btree(root, key, record(key), true)
+                .await
+                .unwrap();
+        }
+        // two scans over many leaves interleaved, each resuming from its own state
+        let range = (Bound::Included(10), Bound::Excluded(90));
+        let (mut first, mut second) = (BTreeRangeState::Initialized, BTreeRangeState::Initialized);
+        let (mut first_keys, mut second_keys) = (vec![], vec![]);
+        loop {
+            let a = aidb
+                .select_range_btree(root, range, false, &mut first)
+                .await
+                .unwrap();
+            let b = aidb
+                .select_range_btree(root, range, false, &mut second)
+                .await
+                .unwrap();
+            if a.is_none() && b.is_none() {
+                break;
+            }
+            first_keys.extend(a.map(|ptr| ptr.block));
+            second_keys.extend(b.map(|ptr| ptr.block));
+        }
+        assert_eq!(first_keys, (10..90).collect::<Vec<_>>());
+        assert_eq!(second_keys, first_keys);
+        assert!(matches!(first, BTreeRangeState::Done));
+        assert!(
+            aidb.select_range_btree(root, range, false, &mut first)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_btree_range_reverse() {
+        let range = async |aidb: &mut Aidb, root, range, reverse| {
+            let mut state = BTreeRangeState::Initialized;
+            let mut keys = vec![];
+            while let Some(ptr) = aidb
+                .select_range_btree(root, range, reverse, &mut state)
+                .await
+                .unwrap()
+            {
+                keys.push(ptr.block as i64);
+            }
+            keys
+        };
+        let mut aidb = small_fanout().await;
+        let n = 500;
+        // scattered inserts split leaves in the middle of the chain, duplicates of 42 and 43
+        // spread over several leaves
+        let mut keys = (0..n).map(|i| i * 7919 % n).collect::<Vec<_>>();
+        keys.extend([42; 20].into_iter().chain([43; 20]));
+        let incremental = aidb.new_btree(keys[0], record(keys[0])).await.unwrap();
+        for key in keys[1..].iter() {
+            aidb.insert_btree(incremental, *key, record(*key), false)
+                .await
+                .unwrap();
+        }
+        let records = keys.iter().map(|key| (*key, record(*key))).collect();
+        let bulk = aidb
+            .build_btree(records, false)
+            .await
+            .unwrap()
+            .get()
+            .unwrap();
+        keys.sort();
+
+        use Bound::*;
+        for root in [incremental, bulk] {
+            let mut problems = vec![];
+            aidb.check_btree(root, &mut HashSet::new(), "t", &mut problems)
+                .await
+                .unwrap();
+            assert_eq!(problems, Vec::<String>::new());
+            for bounds in [
+                (Unbounded, Unbounded),
+                (Included(42), Included(43)),
+                (Excluded(42), Excluded(100)),
+                (Included(10), Unbounded),
+                (Unbounded, Excluded(43)),
+                (Included(n), Unbounded),
+                (Excluded(5), Excluded(6)),
+            ] {
+                let forward = range(&mut aidb, root, bounds, false).await;
+                let expected = keys
+                    .iter()
+                    .copied()
+                    .filter(|key| bounds.contains(key))
+                    .collect::<Vec<_>>();
+                assert_eq!(forward, expected);
+                let mut backward = range(&mut aidb, root, bounds, true).await;
+                backward.reverse();
+                assert_eq!(backward, expected);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_btree_build() {
+        let n = 100000;
+        let keys = (0..n).map(|i| i * 7919 % n * 2).collect::<Vec<_>>();
+
+        let mut aidb = small_fanout().await;
+        let first_block = aidb.superblock.next_empty_block;
+        let incremental = aidb.new_btree(keys[0], record(keys[0])).await.unwrap();
+        for key in keys[1..].iter() {
+            aidb.insert_btree(incremental, *key, record(*key), true)
+                .await
+                .unwrap();
+        }
+        let incremental_blocks = aidb.superblock.next_empty_block - first_block;
+
+        let first_block = aidb.superblock.next_empty_block;
+        aidb.reset_block_io_log();
+        let records = keys.iter().map(|key| (*key, record(*key))).collect();
+        let bulk = aidb
+            .build_btree(records, true)
+            .await
+            .unwrap()
+            .get()
+            .unwrap();
+        let bulk_blocks = aidb.superblock.next_empty_block - first_block;
+        // nothing is looked up, 12500 leaves packed full, 1563 + 196 + 25 + 4 nodes and the root
+        assert_eq!(aidb.get_block_io_log().lookups, 0);
+        assert_eq!(bulk_blocks, 12500 + 1563 + 196 + 25 + 4 + 1);
+        assert!(bulk_blocks < incremental_blocks);
+
+        for key in -1..n * 2 + 1 {
+            let expected = aidb
+                .select_btree(incremental, key, &mut Default::default())
+                .await
+                .unwrap()
+                .map(|ptr| ptr.block);
+            let actual = aidb
+                .select_btree(bulk, key, &mut Default::default())
+                .await
+                .unwrap()
+                .map(|ptr| ptr.block);
+            assert_eq!(actual, expected);
+        }
+        // the built tree keeps growing with regular inserts
+        aidb.insert_btree(bulk, 1, record(1), true).await.unwrap();
+        assert!(aidb.insert_btree(bulk, 2, record(2), true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_btree_build_duplicates() {
+        let mut aidb = small_fanout().await;
+        let records = (0..100)
+            .map(|i| (i / 20, record(i / 20)))
+            .collect::<Vec<_>>();
+        assert!(aidb.build_btree(records.clone(), true).await.is_err());
+        assert_eq!(
+            aidb.build_btree(vec![], true).await.unwrap(),
+            BlockPtr::NONE
+        );
+
+        let root = aidb
+            .build_btree(records, false)
+            .await
+            .unwrap()
+            .get()
+            .unwrap();
+        for key in 0..5 {
+            let mut state = BTreeExactState::Initialized;
+            let mut count = 0;
+            while let Some(ptr) = aidb.select_btree(root, key, &mut state).await.unwrap() {
+                assert_eq!(ptr.block, key as BlockIndex);
+                count += 1;
             }
+            assert_eq!(count, 20);
         }
     }
 }
@@ -0,0 +1,477 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use eyre::Result;
+
+use crate::{
+    Aidb, Column, DataType, Response, Row, Value,
+    schema::{IndexInfo, IndexType, Schema},
+    storage::{BLOCK_SIZE, BlockIndex, BlockOffset, BlockType},
+};
+
+impl Aidb {
+    /// Walk the schema chain, data block chains and indices of all tables, returns a description
+    /// of each structural inconsistency found.
+    pub async fn check_integrity(&mut self) -> Result<Vec<String>> {
+        let mut problems = vec![];
+        for schema in self.check_schema_chain(&mut problems).await? {
+            self.check_schema(&schema, &mut problems).await?;
+        }
+        self.check_text_free_map(&mut HashSet::new(), &mut problems)
+            .await?;
+        Ok(problems)
+    }
+
+    /// Classify blocks reached from the superblock by walking the structures checked by
+    /// [`Aidb::check_integrity`], blocks that are not reached are left out.
+    pub async fn block_types(&mut self) -> Result<BTreeMap<BlockIndex, BlockType>> {
+        let mut types = BTreeMap::from([(0, BlockType::SuperBlock)]);
+        // problems are left to the integrity check
+        let mut problems = vec![];
+        let mut label = |visited: HashSet<BlockIndex>, block_type| {
+            types.extend(visited.into_iter().map(|index| (index, block_type)));
+        };
+        let schemas = self.check_schema_chain(&mut problems).await?;
+        label(
+            schemas.iter().map(|schema| schema.block_index).collect(),
+            BlockType::Schema,
+        );
+        let mut text = HashSet::new();
+        for schema in &schemas {
+            let mut visited = HashSet::new();
+            self.data_chain_blocks(schema, &mut visited, &mut text)
+                .await?;
+            label(visited, BlockType::Data);
+            for index in &schema.indices {
+                let (null_list, blocks) = self.index_blocks(schema, index).await?;
+                label(null_list, BlockType::NullList);
+                label(
+                    blocks,
+                    match index.type_ {
+                        IndexType::BTree => BlockType::BTree,
+                        IndexType::Hash => BlockType::Hash,
+                    },
+                );
+            }
+        }
+        // the text block being filled may not be pointed at yet
+        if let Some(index) = self.superblock.next_text_block.get() {
+            text.insert(index);
+        }
+        label(text, BlockType::Text);
+        let mut visited = HashSet::new();
+        self.check_text_free_map(&mut visited, &mut problems)
+            .await?;
+        label(visited, BlockType::TextFreeMap);
+        Ok(types)
+    }
+
+    /// Blocks of the NULL list and of the tree or hash of an index.
+    async fn index_blocks(
+        &mut self,
+        schema: &Schema,
+        index: &IndexInfo,
+    ) -> Result<(HashSet<BlockIndex>, HashSet<BlockIndex>)> {
+        // problems are left to the integrity check
+        let mut problems = vec![];
+        let null_list = schema.null_list(index.column_index);
+        let mut null_blocks = HashSet::new();
+        if let Some(null_list) = null_list.get()
+            && self.check_block(null_list, &mut null_blocks, "", &mut problems)
+        {
+            self.check_null(null_list, &mut null_blocks, "", &mut problems)
+                .await?;
+        }
+        let mut blocks = HashSet::new();
+        if let Some(root) = index.block.get()
+            && self.check_block(root, &mut blocks, "", &mut problems)
+        {
+            match index.type_ {
+                IndexType::BTree => {
+                    self.check_btree(root, &mut blocks, "", &mut problems)
+                        .await?;
+                }
+                IndexType::Hash => {
+                    self.check_hash(root, &mut blocks, "", &mut problems)
+                        .await?;
+                }
+            }
+        }
+        Ok((null_blocks, blocks))
+    }
+
+    /// Count the blocks and bytes used by a table by walking its data chain and indices.
+    pub async fn table_size(&mut self, table: &str) -> Result<TableSize> {
+        let schema = self.get_schema(table).await?;
+        let r = self.schema_size(&schema).await;
+        self.put_schema(table.to_owned(), schema);
+        r
+    }
+
+    async fn schema_size(&mut self, schema: &Schema) -> Result<TableSize> {
+        let (mut data, mut text) = (HashSet::new(), HashSet::new());
+        let (rows, text_bytes) = self.data_chain_blocks(schema, &mut data, &mut text).await?;
+        let mut index_blocks = 0;
+        for index in &schema.indices {
+            let (null_list, blocks) = self.index_blocks(schema, index).await?;
+            index_blocks += null_list.len() + blocks.len();
+        }
+        Ok(TableSize {
+            rows,
+            data_blocks: data.len(),
+            index_blocks,
+            text_blocks: text.len(),
+            text_bytes,
+        })
+    }
+
+    /// Answer `SHOW TABLE STATUS` with the size of each table, lengths are in bytes.
+    pub(crate) async fn show_table_status(&mut self) -> Result<Response> {
+        let columns = [
+            ("Name", DataType::Text),
+            ("Rows", DataType::Integer),
+            ("Data_blocks", DataType::Integer),
+            ("Index_blocks", DataType::Integer),
+            ("Text_blocks", DataType::Integer),
+            ("Data_length", DataType::Integer),
+            ("Index_length", DataType::Integer),
+            ("Text_length", DataType::Integer),
+            ("Comment", DataType::Text),
+        ];
+        let mut rows = vec![];
+        for table in self.tables().await? {
+            let size = self.table_size(&table.name).await?;
+            let blocks = |count: usize| Value::Integer((count * BLOCK_SIZE) as i64);
+            rows.push(vec![
+                Value::Text(table.name),
+                Value::Integer(size.rows as i64),
+                Value::Integer(size.data_blocks as i64),
+                Value::Integer(size.index_blocks as i64),
+                Value::Integer(size.text_blocks as i64),
+                blocks(size.data_blocks),
+                blocks(size.index_blocks),
+                Value::Integer(size.text_bytes as i64),
+                Value::Text(table.comments.table),
+            ]);
+        }
+        Ok(Response::Rows {
+            columns: columns
+                .into_iter()
+                .map(|(name, datatype)| Column {
+                    name: name.to_owned(),
+                    datatype,
+                })
+                .collect(),
+            rows,
+            truncated: false,
+        })
+    }
+
+    /// Check a single table, answering `CHECK TABLE` with one row per problem.
+    pub(crate) async fn check_table(&mut self, table: String) -> Result<Response> {
+        let schema = self.get_schema(&table).await?;
+        let mut problems = vec![];
+        let r = self.check_schema(&schema, &mut problems).await;
+        self.put_schema(table.clone(), schema);
+        r?;
+        let text_column = |name: &str| Column {
+            name: name.to_owned(),
+            datatype: DataType::Text,
+        };
+        let rows = if problems.is_empty() {
+            vec![("status", "OK".to_owned())]
+        } else {
+            problems
+                .into_iter()
+                .map(|problem| ("error", problem))
+                .collect()
+        };
+        Ok(Response::Rows {
+            columns: vec![
+                text_column("Table"),
+                text_column("Op"),
+                text_column("Msg_type"),
+                text_column("Msg_text"),
+            ],
+            rows: rows
+                .into_iter()
+                .map(|(msg_type, msg_text)| {
+                    vec![
+                        Value::Text(table.clone()),
+                        Value::Text("check".to_owned()),
+                        Value::Text(msg_type.to_owned()),
+                        Value::Text(msg_text),
+                    ]
+                })
+                .collect(),
+            truncated: false,
+        })
+    }
+
+    async fn check_schema(&mut self, schema: &Schema, problems: &mut Vec<String>) -> Result<()> {
+        let table = schema.name();
+        let rows = self.check_data_chain(schema, problems).await?;
+        for index in &schema.indices {
+            let column = &schema.columns[index.column_index as usize].name;
+            let context = format!("table {table}: index on {column}");
+            let mut visited = HashSet::new();
+            if let Some(null_list) = schema.null_list(index.column_index).get()
+                && self.check_block(null_list, &mut visited, &context, problems)
+            {
+                for ptr in self
+                    .check_null(null_list, &mut visited, &context, problems)
+                    .await?
+                {
+                    match rows.get(&(ptr.block, ptr.offset)) {
+                        Some(row) if row[index.column_index as usize] == Value::Null => {}
+                        Some(row) => problems.push(format!(
+                            "{context}: NULL list points at {ptr} holding {}",
+                            row[index.column_index as usize]
+                        )),
+                        None => problems.push(format!(
+                            "{context}: NULL list points at {ptr} which is not a live row"
+                        )),
+                    }
+                }
+            }
+            // indices created on an empty table have no block until the first insert
+            let Some(root) = index.block.get() else {
+                continue;
+            };
+            if !self.check_block(root, &mut visited, &context, problems) {
+                continue;
+            }
+            let records = match index.type_ {
+                IndexType::BTree => {
+                    self.check_btree(root, &mut visited, &context, problems)
+                        .await?
+                }
+                IndexType::Hash => {
+                    self.check_hash(root, &mut visited, &context, problems)
+                        .await?
+                }
+            };
+            for (key, ptr) in records {
+                match rows.get(&(ptr.block, ptr.offset)) {
+                    None => problems.push(format!(
+                        "{context}: key {key} points at {ptr} which is not a live row"
+                    )),
+                    Some(row) if row[index.column_index as usize] != Value::Integer(key) => {
+                        problems.push(format!(
+                            "{context}: key {key} points at {ptr} holding {}",
+                            row[index.column_index as usize]
+                        ))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a block of a chain or tree may be followed, blocks that are out of range or
+    /// reached twice are reported instead.
+    pub(crate) fn check_block(
+        &self,
+        index: BlockIndex,
+        visited: &mut HashSet<BlockIndex>,
+        context: &str,
+        problems: &mut Vec<String>,
+    ) -> bool {
+        if index == 0 || index >= self.superblock.next_empty_block {
+            problems.push(format!(
+                "{context}: block {index} is beyond next empty block {}",
+                self.superblock.next_empty_block
+            ));
+            false
+        } else if !visited.insert(index) {
+            problems.push(format!("{context}: block {index} is reached twice"));
+            false
+        } else {
+            true
+        }
+    }
+}
+
+/// Storage used by a table, see [`Aidb::table_size`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TableSize {
+    pub rows: usize,
+    pub data_blocks: usize,
+    /// blocks of all indices including their NULL lists
+    pub index_blocks: usize,
+    /// text blocks holding texts and blobs of the table, which may be shared with other tables
+    pub text_blocks: usize,
+    pub text_bytes: u64,
+}
+
+/// Live rows of a table by location.
+pub(crate) type LiveRows = HashMap<(BlockIndex, BlockOffset), Row>;
+
+#[cfg(test)]
+mod test {
+    use binrw::{BinRead, BinWrite};
+
+    use super::*;
+    use crate::{data::DataHeader, storage::DataPointer};
+
+    #[tokio::test]
+    async fn test_check_integrity() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER UNIQUE, x INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("CREATE TABLE u (id INTEGER, x INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("CREATE INDEX ux ON u (x) USING HASH;")
+            .await
+            .unwrap();
+        for i in 0..50 {
+            aidb.query(format!("INSERT INTO t VALUES ({i}, {i});"))
+                .await
+                .unwrap();
+            aidb.query(format!("INSERT INTO u VALUES ({i}, {i});"))
+                .await
+                .unwrap();
+        }
+        assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
+        let Response::Rows { rows, .. } = aidb.query("CHECK TABLE t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows[0][2], Value::Text("status".to_owned()));
+
+        // point the first leaf of the btree on t back at itself
+        let schema = aidb.get_schema("t").await.unwrap();
+        let root = schema.indices[0].block.get().unwrap();
+        aidb.put_schema("t".to_owned(), schema);
+        let leaf = aidb.seek_leaf(root, i64::MIN).await.unwrap();
+        let mut block = aidb.get_block(leaf).await.unwrap();
+        leaf.write_le(&mut block.cursor()).unwrap();
+        aidb.put_block(leaf, block);
+        let problems = aidb.check_integrity().await.unwrap();
+        assert!(
+            problems
+                .iter()
+                .any(|p| p == &format!("table t: index on id: block {leaf} is reached twice")),
+            "{problems:?}"
+        );
+
+        // add an entry for a row that doesn't exist into the hash index on u
+        let schema = aidb.get_schema("u").await.unwrap();
+        let dir = schema.indices[0].block.get().unwrap();
+        let data_block = schema.data_block.get().unwrap();
+        aidb.put_schema("u".to_owned(), schema);
+        let ptr = DataPointer {
+            block: data_block,
+            offset: 60000,
+        };
+        aidb.insert_hash(dir, 100, ptr.clone(), false)
+            .await
+            .unwrap();
+        let Response::Rows { rows, .. } = aidb.query("CHECK TABLE u").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(
+            rows,
+            vec![vec![
+                Value::Text("u".to_owned()),
+                Value::Text("check".to_owned()),
+                Value::Text("error".to_owned()),
+                Value::Text(format!(
+                    "table u: index on x: key 100 points at {ptr} which is not a live row"
+                )),
+            ]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block_types() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, s TEXT, x INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("CREATE INDEX tx ON t (x) USING HASH;")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 'a', NULL), (2, 'b', 2);")
+            .await
+            .unwrap();
+        let schema = aidb.get_schema("t").await.unwrap();
+        let (schema_block, data_block) = (schema.block_index, schema.data_block.get().unwrap());
+        let (btree, hash) = (
+            schema.indices[0].block.get().unwrap(),
+            schema.indices[1].block.get().unwrap(),
+        );
+        aidb.put_schema("t".to_owned(), schema);
+
+        let types = aidb.block_types().await.unwrap();
+        assert_eq!(types[&0], BlockType::SuperBlock);
+        assert_eq!(types[&schema_block], BlockType::Schema);
+        assert_eq!(types[&data_block], BlockType::Data);
+        assert_eq!(types[&btree], BlockType::BTree);
+        assert_eq!(types[&hash], BlockType::Hash);
+        assert!(types.values().any(|t| *t == BlockType::Text));
+        assert!(types.values().any(|t| *t == BlockType::NullList));
+        assert_eq!(types.len() as u64, aidb.superblock.next_empty_block);
+    }
+
+    #[tokio::test]
+    async fn test_table_size() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("CREATE TABLE u (id INTEGER);").await.unwrap();
+        for i in 0..10000 {
+            aidb.query(format!("INSERT INTO t VALUES ({i}, 'row {i}');"))
+                .await
+                .unwrap();
+        }
+        let size = aidb.table_size("t").await.unwrap();
+
+        let schema = aidb.get_schema("t").await.unwrap();
+        let mut next = schema.data_block;
+        aidb.put_schema("t".to_owned(), schema);
+        let mut data_blocks = 0;
+        while let Some(index) = next.get() {
+            let mut block = aidb.get_block(index).await.unwrap();
+            next = DataHeader::read(&mut block.cursor())
+                .unwrap()
+                .next_data_block;
+            aidb.put_block(index, block);
+            data_blocks += 1;
+        }
+        assert!(data_blocks > 1);
+        assert_eq!(size.data_blocks, data_blocks);
+        assert_eq!(size.rows, 10000);
+        assert!(size.index_blocks > 1);
+        assert!(size.text_blocks > 0);
+        assert_eq!(
+            size.text_bytes,
+            (0..10000)
+                .map(|i| format!("row {i}").len() as u64)
+                .sum::<u64>()
+        );
+
+        let Response::Rows { rows, .. } = aidb.query("SHOW TABLE STATUS;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0][0], Value::Text("t".to_owned()));
+        assert_eq!(rows[0][2], Value::Integer(data_blocks as i64));
+        assert_eq!(
+            rows[0][5],
+            Value::Integer((data_blocks * BLOCK_SIZE) as i64)
+        );
+        assert_eq!(
+            rows[1][1..5],
+            [
+                Value::Integer(0),
+                Value::Integer(0),
+                Value::Integer(0),
+                Value::Integer(0)
+            ]
+        );
+        assert!(aidb.table_size("missing").await.is_err());
+    }
+}
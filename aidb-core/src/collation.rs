@@ -0,0 +1,212 @@
+use std::{borrow::Cow, cmp::Ordering, fmt::Display, str::FromStr};
+
+use eyre::{Report, eyre};
+
+use crate::{Aidb, Value};
+
+/// How text is compared by `=`, `<=`, IN and LIKE, chosen with `SET collation_connection`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Collation {
+    /// by code point, e.g. `utf8mb4_bin`
+    #[default]
+    Binary,
+    /// ignoring case, e.g. `utf8mb4_0900_as_ci`
+    CaseInsensitive,
+    /// ignoring case and accents of Latin letters, e.g. `utf8mb4_general_ci`
+    CaseAccentInsensitive,
+}
+
+impl FromStr for Collation {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_lowercase();
+        if name == "binary" || name.ends_with("_bin") {
+            Ok(Collation::Binary)
+        } else if name.ends_with("_as_ci") {
+            Ok(Collation::CaseInsensitive)
+        } else if name.ends_with("_ci") {
+            Ok(Collation::CaseAccentInsensitive)
+        } else {
+            Err(eyre!("unknown collation {s}"))
+        }
+    }
+}
+
+impl Display for Collation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Collation::Binary => write!(f, "utf8mb4_bin"),
+            Collation::CaseInsensitive => write!(f, "utf8mb4_0900_as_ci"),
+            Collation::CaseAccentInsensitive => write!(f, "utf8mb4_general_ci"),
+        }
+    }
+}
+
+/// Base letters of U+00C0 to U+017F, letters without one are kept.
+const LATIN_BASE: &str = "AAAAAAÆCEEEEIIIIÐNOOOOO×OUUUUYÞßaaaaaaæceeeeiiiiðnooooo÷ouuuuyþyAaAaAaCcCcCcCcDdDdEeEeEeEeEeGgGgGgGgHhHhIiIiIiIiIiĲĳJjKkĸLlLlLlLlLlNnNnNnŉŊŋOoOoOoŒœRrRrRrSsSsSsSsTtTtTtUuUuUuUuUuUuWwYyYZzZzZzſ";
+
+fn strip_accent(c: char) -> char {
+    match c as u32 {
+        i @ 0xC0..=0x17F => LATIN_BASE.chars().nth((i - 0xC0) as usize).unwrap(),
+        _ => c,
+    }
+}
+
+/// Piece of a LIKE pattern, `\` escapes the following character.
+#[derive(Debug, PartialEq)]
+enum Token {
+    /// `%`
+    Any,
+    /// `_`
+    One,
+    Char(char),
+}
+
+fn tokenize(pattern: &str, escape: char) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            c if c == escape => Token::Char(chars.next().unwrap_or(escape)),
+            '%' => Token::Any,
+            '_' => Token::One,
+            c => Token::Char(c),
+        });
+    }
+    tokens
+}
+
+impl Collation {
+    /// Text in the form compared under this collation.
+    pub(crate) fn normalize<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        match self {
+            Collation::Binary => Cow::Borrowed(s),
+            Collation::CaseInsensitive => Cow::Owned(s.to_lowercase()),
+            Collation::CaseAccentInsensitive => {
+                Cow::Owned(s.to_lowercase().chars().map(strip_accent).collect())
+            }
+        }
+    }
+
+    /// Compare like [`Value::compare`] with text normalized first.
+    pub(crate) fn compare(&self, lhs: &Value, rhs: &Value) -> Option<Ordering> {
+        match (self, lhs, rhs) {
+            (Collation::Binary, _, _) => lhs.compare(rhs),
+            (_, Value::Text(lhs), Value::Text(rhs)) => {
+                Some(self.normalize(lhs).cmp(&self.normalize(rhs)))
+            }
+            _ => lhs.compare(rhs),
+        }
+    }
+
+    /// Match `s` against a LIKE pattern where `%` is any sequence and `_` is one character, that
+    /// is one `char` and not one byte of multibyte text. `escape` makes the next character of the
+    /// pattern literal.
+    pub(crate) fn like(&self, pattern: &str, escape: char, s: &str) -> bool {
+        let escape = self
+            .normalize(&escape.to_string())
+            .chars()
+            .next()
+            .unwrap_or(escape);
+        let pattern = tokenize(&self.normalize(pattern), escape);
+        let s = self.normalize(s).chars().collect::<Vec<_>>();
+        // the last `%` and the position in `s` it is retried from on mismatch
+        let mut backtrack = None;
+        let (mut p, mut i) = (0, 0);
+        while i < s.len() {
+            match pattern.get(p) {
+                Some(Token::Any) => {
+                    p += 1;
+                    backtrack = Some((p, i));
+                }
+                Some(Token::One) => (p, i) = (p + 1, i + 1),
+                Some(Token::Char(c)) if *c == s[i] => (p, i) = (p + 1, i + 1),
+                _ => match backtrack {
+                    Some((after_any, from)) => {
+                        (p, i) = (after_any, from + 1);
+                        backtrack = Some((after_any, from + 1));
+                    }
+                    None => return false,
+                },
+            }
+        }
+        pattern[p..].iter().all(|token| *token == Token::Any)
+    }
+}
+
+impl Aidb {
+    /// Collation of `collation_connection`, binary if it is unknown.
+    pub(crate) fn collation(&self) -> Collation {
+        match self.variable("collation_connection") {
+            Value::Text(name) => name.parse().unwrap_or_default(),
+            _ => Collation::Binary,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_like() {
+        let binary = Collation::Binary;
+        assert!(binary.like("abc%", '\\', "abcdef"));
+        assert!(binary.like("%c%", '\\', "abcdef"));
+        assert!(binary.like("a_c", '\\', "abc"));
+        assert!(binary.like("%", '\\', ""));
+        assert!(binary.like("a%b%c", '\\', "aXbYbZc"));
+        assert!(!binary.like("a%b%c", '\\', "aXbYbZ"));
+        assert!(!binary.like("a_c", '\\', "ac"));
+        assert!(!binary.like("abc", '\\', "abcd"));
+        assert!(binary.like("100\\%", '\\', "100%"));
+        assert!(!binary.like("100\\%", '\\', "1000"));
+        assert!(binary.like("a\\_c", '\\', "a_c"));
+        assert!(!binary.like("a\\_c", '\\', "abc"));
+        assert!(binary.like("50!%", '!', "50%"));
+        assert!(!binary.like("50!%", '!', "500"));
+        assert!(binary.like("a\\%", '!', "a\\bc"));
+        assert!(binary.like("100!!", '!', "100!"));
+        // the escape is normalized like the pattern
+        assert!(Collation::CaseInsensitive.like("50X%", 'x', "50%"));
+    }
+
+    #[test]
+    fn test_like_multibyte() {
+        let binary = Collation::Binary;
+        // `_` is one character however many bytes it takes
+        assert!(binary.like("张_", '\\', "张三"));
+        assert!(!binary.like("张_", '\\', "张三丰"));
+        assert!(!binary.like("张__", '\\', "张三"));
+        assert!(binary.like("a_c", '\\', "a张c"));
+        assert!(binary.like("_三%", '\\', "张三丰"));
+        assert!(binary.like("%丰", '\\', "张三丰"));
+        assert!(binary.like("😀_", '\\', "😀é"));
+        assert!(Collation::CaseAccentInsensitive.like("张_", '\\', "张三"));
+    }
+
+    #[test]
+    fn test_collation() {
+        assert!(!Collation::Binary.like("abc%", '\\', "ABC"));
+        for collation in ["utf8mb4_0900_as_ci", "utf8_general_ci"] {
+            let collation: Collation = collation.parse().unwrap();
+            assert!(collation.like("abc%", '\\', "ABC"));
+            assert!(collation.like("ABC%", '\\', "abcdef"));
+            assert_eq!(
+                collation.compare(&Value::Text("ABC".into()), &Value::Text("abc".into())),
+                Some(Ordering::Equal)
+            );
+        }
+        let as_ci = Collation::CaseInsensitive;
+        let ai_ci = Collation::CaseAccentInsensitive;
+        assert!(!as_ci.like("cafe", '\\', "Café"));
+        assert!(ai_ci.like("cafe", '\\', "Café"));
+        assert!(ai_ci.like("ŁÓDŹ", '\\', "lodz"));
+        assert_eq!(
+            "utf8mb4_bin".parse::<Collation>().unwrap(),
+            Collation::Binary
+        );
+        assert!("latin1_swedish".parse::<Collation>().is_err());
+    }
+}
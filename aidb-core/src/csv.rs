@@ -0,0 +1,256 @@
+use std::io::Read;
+
+use eyre::{Result, eyre};
+
+use crate::{Aidb, Column, DataType, Row, Value};
+
+/// Outcome of [`Aidb::load_csv`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CsvLoad {
+    pub inserted: usize,
+    /// one message per skipped row, prefixed by the line it starts on
+    pub errors: Vec<String>,
+}
+
+/// A field as written in the file, quoted fields are never NULL.
+#[derive(Debug, Default, PartialEq)]
+struct Field {
+    text: String,
+    quoted: bool,
+}
+
+/// Split RFC 4180 CSV into records along with the 1-based line each starts on. Quoted fields
+/// may contain separators, line breaks and `""` for a quote.
+fn parse_csv(s: &str) -> Result<Vec<(usize, Vec<Field>)>> {
+    let mut records = vec![];
+    let mut chars = s.chars().peekable();
+    let mut line = 1;
+    while chars.peek().is_some() {
+        let start = line;
+        let mut fields = vec![];
+        loop {
+            let mut field = Field::default();
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                field.quoted = true;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.text.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            field.text.push(c);
+                        }
+                        None => return Err(eyre!("line {start}: unterminated quoted field")),
+                    }
+                }
+            }
+            while let Some(&c) = chars.peek() {
+                if matches!(c, ',' | '\n' | '\r') {
+                    break;
+                }
+                if field.quoted {
+                    return Err(eyre!("line {line}: unexpected {c:?} after quoted field"));
+                }
+                field.text.push(c);
+                chars.next();
+            }
+            fields.push(field);
+            match chars.next() {
+                Some(',') => continue,
+                Some('\r') if chars.peek() == Some(&'\n') => {
+                    chars.next();
+                }
+                _ => {}
+            }
+            line += 1;
+            break;
+        }
+        // blank lines carry no record
+        if fields.len() == 1 && fields[0] == Field::default() {
+            continue;
+        }
+        records.push((start, fields));
+    }
+    Ok(records)
+}
+
+/// Convert a field into a value of the column, empty unquoted fields and `\N` are NULL.
+fn field_to_value(field: Field, column: &Column) -> Result<Value> {
+    if !field.quoted && (field.text.is_empty() || field.text == "\\N") {
+        return Ok(Value::Null);
+    }
+    let text = field.text;
+    Ok(match column.datatype {
+        DataType::Integer => Value::Integer(
+            text.trim()
+                .parse()
+                .map_err(|_| eyre!("column {}: {text:?} is not a valid INTEGER", column.name))?,
+        ),
+        DataType::Real => Value::Real(
+            text.trim()
+                .parse()
+                .map_err(|_| eyre!("column {}: {text:?} is not a valid REAL", column.name))?,
+        ),
+        DataType::Text => Value::Text(text),
+        DataType::Blob => Value::Blob(text.into_bytes()),
+    })
+}
+
+fn record_to_row(fields: Vec<Field>, columns: &[Column]) -> Result<Row> {
+    if fields.len() != columns.len() {
+        return Err(eyre!(
+            "expected {} fields, found {}",
+            columns.len(),
+            fields.len()
+        ));
+    }
+    fields
+        .into_iter()
+        .zip(columns)
+        .map(|(field, column)| field_to_value(field, column))
+        .collect()
+}
+
+impl Aidb {
+    /// Insert CSV rows with fields in the order of table definition, returns the number of
+    /// inserted rows and the skipped ones.
+    ///
+    /// Fields are converted into the datatype of their column. All rows are inserted with a single
+    /// statement, so any bad row fails the whole load unless `skip_bad_rows` is set, in which case
+    /// bad rows are reported and the others inserted one by one if they can't go in together.
+    pub async fn load_csv<R: Read>(
+        &mut self,
+        table: &str,
+        mut reader: R,
+        has_header: bool,
+        skip_bad_rows: bool,
+    ) -> Result<CsvLoad> {
+        let mut s = String::new();
+        reader.read_to_string(&mut s)?;
+        let mut records = parse_csv(&s)?;
+        if has_header && !records.is_empty() {
+            records.remove(0);
+        }
+        let schema = self.get_schema(table).await?;
+        let columns = schema.columns.clone();
+        self.put_schema(table.to_owned(), schema);
+
+        let mut r = CsvLoad::default();
+        let mut rows = vec![];
+        for (line, fields) in records {
+            match record_to_row(fields, &columns) {
+                Ok(row) => rows.push((line, row)),
+                Err(e) if skip_bad_rows => r.errors.push(format!("line {line}: {e}")),
+                Err(e) => return Err(eyre!("line {line}: {e}")),
+            }
+        }
+        let bulk = rows.iter().map(|(_, row)| row.clone()).collect();
+        match self.insert(table, bulk).await {
+            Ok(n) => r.inserted = n,
+            Err(e) if !skip_bad_rows => return Err(e),
+            Err(_) => {
+                for (line, row) in rows {
+                    match self.insert(table, vec![row]).await {
+                        Ok(n) => r.inserted += n,
+                        Err(e) => r.errors.push(format!("line {line}: {e}")),
+                    }
+                }
+            }
+        }
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Response;
+
+    async fn setup() -> Aidb {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, x REAL, s TEXT)")
+            .await
+            .unwrap();
+        aidb
+    }
+
+    async fn count(aidb: &mut Aidb) -> usize {
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t").await.unwrap() else {
+            panic!()
+        };
+        rows.len()
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let records = parse_csv("a,\"b,\"\"c\"\"\"\r\n\n\"x\ny\",\n").unwrap();
+        assert_eq!(
+            records
+                .iter()
+                .map(|(line, fields)| (*line, fields.iter().map(|f| f.text.as_str()).collect()))
+                .collect::<Vec<(usize, Vec<&str>)>>(),
+            [(1, vec!["a", "b,\"c\""]), (3, vec!["x\ny", ""])]
+        );
+        assert!(parse_csv("\"a").is_err());
+        assert!(parse_csv("\"a\"b").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_csv() {
+        let mut aidb = setup().await;
+        let csv = "1,1.5,a\n2,,\"\"\n3,\\N,\"x, y\"\n";
+        let r = aidb.load_csv("t", csv.as_bytes(), false, false).await;
+        assert_eq!(r.unwrap().inserted, 3);
+        let r = aidb.query("SELECT * FROM t").await.unwrap();
+        let Response::Rows { rows, .. } = r else {
+            panic!()
+        };
+        assert_eq!(
+            rows,
+            [
+                vec![Value::Integer(1), Value::Real(1.5), Value::Text("a".into())],
+                vec![Value::Integer(2), Value::Null, Value::Text("".into())],
+                vec![Value::Integer(3), Value::Null, Value::Text("x, y".into())],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_csv_header() {
+        let mut aidb = setup().await;
+        let csv = "id,x,s\r\n1,0.5,a\r\n2,1,b\r\n";
+        let r = aidb.load_csv("t", csv.as_bytes(), true, false).await;
+        assert_eq!(r.unwrap().inserted, 2);
+        let r = aidb.query("SELECT s FROM t WHERE id = 2").await.unwrap();
+        let Response::Rows { rows, .. } = r else {
+            panic!()
+        };
+        assert_eq!(rows, [vec![Value::Text("b".into())]]);
+    }
+
+    #[tokio::test]
+    async fn test_load_csv_type_mismatch() {
+        let mut aidb = setup().await;
+        let csv = "1,0.5,a\n2,half,b\n3,1.5\n4,2.5,d\n1,0,e\n";
+        let e = aidb.load_csv("t", csv.as_bytes(), false, false).await;
+        assert_eq!(
+            e.unwrap_err().to_string(),
+            "line 2: column x: \"half\" is not a valid REAL"
+        );
+        assert_eq!(count(&mut aidb).await, 0);
+
+        let r = aidb.load_csv("t", csv.as_bytes(), false, true).await;
+        let r = r.unwrap();
+        assert_eq!(r.inserted, 2);
+        assert_eq!(r.errors.len(), 3);
+        assert_eq!(r.errors[1], "line 3: expected 3 fields, found 2");
+        assert!(r.errors[2].starts_with("line 5: "));
+        assert_eq!(count(&mut aidb).await, 2);
+    }
+}
@@ -1,18 +1,21 @@
 use std::{
+    cmp::Ordering,
+    collections::HashSet,
     fmt::{Display, Formatter},
     io::{Cursor, Read, Write},
 };
 
 use binrw::{BinRead, BinWrite, binrw};
 use eyre::{OptionExt, Result, eyre};
-use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use tracing::debug;
 
 use crate::{
-    Aidb, Column, Response,
-    schema::{IndexInfo, IndexType},
-    storage::{BLOCK_SIZE, BlockIndex, BlockOffset, DataPointer},
+    Aidb, Column, Response, Row,
+    check::LiveRows,
+    schema::{IndexInfo, IndexType, Schema},
+    sql::SqlCol,
+    storage::{BLOCK_SIZE, BlockIndex, BlockOffset, BlockPtr, DataPointer},
 };
 
 #[binrw]
@@ -22,6 +25,8 @@ pub enum DataType {
     Integer = 1,
     Real = 2,
     Text = 3,
+    /// bytes stored like text but without UTF-8 validation
+    Blob = 4,
 }
 
 impl DataType {
@@ -30,6 +35,7 @@ impl DataType {
             DataType::Integer => Value::Integer(0),
             DataType::Real => Value::Real(0f64),
             DataType::Text => Value::Text("".to_owned()),
+            DataType::Blob => Value::Blob(vec![]),
         }
     }
 
@@ -37,7 +43,7 @@ impl DataType {
         match self {
             DataType::Integer => size_of::<u64>(),
             DataType::Real => size_of::<f64>(),
-            DataType::Text => size_of::<u64>() + size_of::<u64>(),
+            DataType::Text | DataType::Blob => size_of::<u64>() + size_of::<u64>(),
         }
     }
 }
@@ -48,6 +54,7 @@ impl Display for DataType {
             DataType::Integer => write!(f, "INTEGER"),
             DataType::Real => write!(f, "REAL"),
             DataType::Text => write!(f, "TEXT"),
+            DataType::Blob => write!(f, "BLOB"),
         }
     }
 }
@@ -58,15 +65,60 @@ pub enum Value {
     Integer(i64),
     Real(f64),
     Text(String),
+    Blob(Vec<u8>),
+    /// `?` in prepared statements, numbered from 0 in order of appearance
+    Placeholder(usize),
 }
 
 impl Value {
     pub fn datatype(&self) -> Option<DataType> {
         match self {
-            Value::Null => None,
+            Value::Null | Value::Placeholder(_) => None,
             Value::Integer(_) => Some(DataType::Integer),
             Value::Real(_) => Some(DataType::Real),
             Value::Text(_) => Some(DataType::Text),
+            Value::Blob(_) => Some(DataType::Blob),
+        }
+    }
+
+    /// Compare two values of the same datatype or two numbers by value, NULL is not comparable
+    /// with anything.
+    pub(crate) fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Integer(lhs), Value::Integer(rhs)) => lhs.partial_cmp(rhs),
+            (Value::Real(lhs), Value::Real(rhs)) => lhs.partial_cmp(rhs),
+            (Value::Integer(lhs), Value::Real(rhs)) => (*lhs as f64).partial_cmp(rhs),
+            (Value::Real(lhs), Value::Integer(rhs)) => lhs.partial_cmp(&(*rhs as f64)),
+            (Value::Text(lhs), Value::Text(rhs)) => lhs.partial_cmp(rhs),
+            (Value::Blob(lhs), Value::Blob(rhs)) => lhs.partial_cmp(rhs),
+            _ => None,
+        }
+    }
+
+    /// Render as a literal that parses back into the same value, unlike [`Display`] which
+    /// escapes text the Rust way. REAL is rendered with a fraction or exponent to stay REAL,
+    /// NaN and infinities have no literal and are rendered as by [`Display`].
+    pub fn to_sql_literal(&self) -> String {
+        match self {
+            Value::Real(v) if v.is_finite() => format!("{v:?}"),
+            Value::Text(s) => {
+                let mut literal = String::with_capacity(s.len() + 2);
+                literal.push('\'');
+                for c in s.chars() {
+                    match c {
+                        '\'' => literal.push_str("\\'"),
+                        '\\' => literal.push_str("\\\\"),
+                        '\n' => literal.push_str("\\n"),
+                        '\r' => literal.push_str("\\r"),
+                        '\t' => literal.push_str("\\t"),
+                        c if c.is_control() => literal.push_str(&format!("\\{{{:x}}}", c as u32)),
+                        c => literal.push(c),
+                    }
+                }
+                literal.push('\'');
+                literal
+            }
+            value => value.to_string(),
         }
     }
 }
@@ -76,17 +128,39 @@ impl Display for Value {
         match self {
             Value::Null => write!(f, "NULL"),
             Value::Integer(v) => write!(f, "{v}"),
+            Value::Real(v) if v.is_nan() => write!(f, "NaN"),
+            Value::Real(v) if v.is_infinite() => {
+                write!(f, "{}Infinity", if *v < 0.0 { "-" } else { "" })
+            }
             Value::Real(v) => write!(f, "{v}"),
             Value::Text(v) => write!(f, "'{}'", v.escape_debug()),
+            Value::Blob(v) => write!(f, "x'{}'", to_hex(v)),
+            Value::Placeholder(_) => write!(f, "?"),
         }
     }
 }
 
+/// Upper case hex digits of a BLOB.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02X}")).collect()
+}
+
+/// Bytes of a BLOB from hex digits in either case.
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
 #[binrw]
 #[brw(little)]
 #[derive(Debug, Clone)]
 pub(crate) struct DataHeader {
-    pub(crate) next_data_block: BlockIndex,
+    pub(crate) next_data_block: BlockPtr,
     #[br(map = |v: u8| v != 0u8)]
     #[bw(map = |v: &bool| if *v {1u8} else {0u8})]
     pub(crate) is_full: bool,
@@ -108,6 +182,10 @@ pub(crate) enum ValueRepr {
     Text { len: u16, ptr: DataPointer },
     #[brw(magic = 6u8)]
     TextNull(#[brw(pad_size_to = 12)] ()),
+    #[brw(magic = 7u8)]
+    Blob { len: u16, ptr: DataPointer },
+    #[brw(magic = 8u8)]
+    BlobNull(#[brw(pad_size_to = 12)] ()),
 }
 
 impl ValueRepr {
@@ -119,6 +197,8 @@ impl ValueRepr {
             ValueRepr::RealNull(()) => DataType::Real,
             ValueRepr::Text { .. } => DataType::Text,
             ValueRepr::TextNull(()) => DataType::Text,
+            ValueRepr::Blob { .. } => DataType::Blob,
+            ValueRepr::BlobNull(()) => DataType::Blob,
         }
     }
 }
@@ -133,42 +213,78 @@ pub(crate) struct RowRepr {
     values: Vec<ValueRepr>,
 }
 
+/// Place values given for the listed columns into a row, other columns are NULL.
+fn full_row(columns_count: usize, column_indices: &[usize], row: Row) -> Result<Row> {
+    if row.len() != column_indices.len() {
+        return Err(eyre!(
+            "expected {} values, found {}",
+            column_indices.len(),
+            row.len()
+        ));
+    }
+    let mut full_row = vec![Value::Null; columns_count];
+    for (i, value) in column_indices.iter().zip(row) {
+        full_row[*i] = value;
+    }
+    Ok(full_row)
+}
+
 impl Aidb {
     pub(crate) async fn insert_into(
         &mut self,
         table: String,
         columns: Vec<String>,
         values: Vec<Vec<Value>>,
+        on_duplicate: Vec<(SqlCol, Value)>,
     ) -> Result<Response> {
         let mut schema = self.get_schema(&table).await?;
-        let affected_rows = values.len();
-        let (mut index, mut block) = if schema.data_block == 0 {
-            let (index, block) = self.new_block();
-            schema.data_block = index;
-            self.mark_schema_dirty(table.clone());
-            (index, block)
-        } else {
-            (schema.data_block, self.get_block(schema.data_block).await?)
-        };
-        let column_indices: Vec<usize> = if columns.is_empty() {
-            (0..schema.columns.len()).collect()
-        } else {
-            let column_indices = columns
-                .into_iter()
-                .map(|name| {
-                    schema
-                        .columns
-                        .iter()
-                        .position(|column| column.name == name)
-                        .ok_or_eyre("column not found")
-                })
-                .collect::<Result<Vec<_>>>()?;
-            if !column_indices.iter().all_unique() {
-                return Err(eyre!("column specified multiple times"));
+        // like MySQL, an inserted row counts as 1 and an updated row as 2
+        let mut affected_rows = 0;
+        let (mut index, mut block) = match schema.data_block.get() {
+            None => {
+                let (index, block) = self.new_block();
+                schema.data_block = BlockPtr::to(index);
+                self.mark_schema_dirty(table.clone());
+                (index, block)
+            }
+            Some(data_block) => {
+                // blocks before the last one inserted into are full unless rows were deleted
+                let index = match schema.insert_block.get() {
+                    Some(insert_block) if !self.reuse_free_slots => insert_block,
+                    _ => data_block,
+                };
+                (index, self.get_block(index).await?)
             }
-            column_indices
         };
+        let column_indices = schema.column_indices(columns)?;
+        let on_duplicate = on_duplicate
+            .into_iter()
+            .map(|(column, value)| {
+                let column = match column {
+                    SqlCol::Short(column) => column,
+                    SqlCol::Full { table: t, column } if t == table => column,
+                    SqlCol::Full { .. } => return Err(eyre!("table not specified")),
+                };
+                let index = schema
+                    .columns
+                    .iter()
+                    .position(|c| c.name == column)
+                    .ok_or_eyre("column not found")?;
+                if schema
+                    .indices
+                    .iter()
+                    .any(|info| info.column_index as usize == index)
+                {
+                    return Err(eyre!("update indexed column is not implemented"));
+                }
+                Ok((index, value))
+            })
+            .collect::<Result<Vec<_>>>()?;
         let schema_columns_count = schema.columns.len();
+        let auto_increment_column = schema.auto_increment_column();
+        let mut first_generated_id = None;
+        // rows holding NULL in indexed columns, added to the NULL lists once indices are released
+        let mut null_rows = vec![];
         let schema_row_size = schema.row_size() as isize;
         let indices = &mut schema.indices;
 
@@ -190,21 +306,47 @@ impl Aidb {
                         self.put_block(index, block);
                         break 'seek_block;
                     };
-                    let mut full_row = vec![Value::Null; schema_columns_count];
-                    for item in column_indices.iter().zip_longest(row) {
-                        match item {
-                            itertools::EitherOrBoth::Both(i, value) => full_row[*i] = value,
-                            itertools::EitherOrBoth::Left(_) => {
-                                return Err(eyre!("missing values"));
+                    let mut full_row = full_row(schema_columns_count, &column_indices, row)?;
+                    if let Some(i) = auto_increment_column {
+                        match full_row[i] {
+                            Value::Null => {
+                                schema.auto_increment += 1;
+                                full_row[i] = Value::Integer(schema.auto_increment);
+                                first_generated_id.get_or_insert(schema.auto_increment);
                             }
-                            itertools::EitherOrBoth::Right(_) => {
-                                return Err(eyre!("too much values"));
+                            // explicit values move the counter past them
+                            Value::Integer(v) if v > schema.auto_increment => {
+                                schema.auto_increment = v;
                             }
+                            _ => {}
+                        }
+                        self.mark_schema_dirty(table.clone());
+                    }
+                    if !on_duplicate.is_empty()
+                        && let Some(ptr) = self.find_duplicate(indices, &full_row).await?
+                    {
+                        if ptr.block == index {
+                            cursor.set_position(ptr.offset as u64);
+                            self.update_row(&mut cursor, on_duplicate.clone()).await?;
+                            cursor.set_position(position);
+                        } else {
+                            let mut duplicate_block = self.get_block(ptr.block).await?;
+                            self.update_row(
+                                &mut duplicate_block.cursor_at(ptr.offset),
+                                on_duplicate.clone(),
+                            )
+                            .await?;
+                            self.put_block(ptr.block, duplicate_block);
+                            self.mark_block_dirty(ptr.block);
                         }
+                        affected_rows += 2;
+                        continue;
                     }
+                    affected_rows += 1;
                     for IndexInfo {
                         column_index,
                         type_,
+                        unique,
                         block,
                     } in indices.iter_mut()
                     {
@@ -216,16 +358,49 @@ impl Aidb {
                                         block: index,
                                         offset: cursor.position() as u16,
                                     };
-                                    if *block == 0 {
-                                        *block = self.new_btree(v, record).await?;
-                                        self.mark_schema_dirty(table.clone());
-                                    } else {
-                                        self.insert_btree(*block, v, record).await?;
+                                    match block.get() {
+                                        None => {
+                                            *block = BlockPtr::to(self.new_btree(v, record).await?);
+                                            self.mark_schema_dirty(table.clone());
+                                        }
+                                        Some(root) => {
+                                            self.insert_btree(root, v, record, *unique).await?
+                                        }
                                     }
                                 }
-                                Value::Null => {
-                                    return Err(eyre!("indexed column must not be NULL"));
+                                Value::Null => null_rows.push((
+                                    *column_index,
+                                    DataPointer {
+                                        block: index,
+                                        offset: cursor.position() as u16,
+                                    },
+                                )),
+                                _ => return Err(eyre!("invalid value")),
+                            },
+                            IndexType::Hash => match full_row[*column_index as usize] {
+                                Value::Integer(v) => {
+                                    debug!(key = v, "insert hash");
+                                    let record = DataPointer {
+                                        block: index,
+                                        offset: cursor.position() as u16,
+                                    };
+                                    match block.get() {
+                                        None => {
+                                            *block = BlockPtr::to(self.new_hash(v, record).await?);
+                                            self.mark_schema_dirty(table.clone());
+                                        }
+                                        Some(root) => {
+                                            self.insert_hash(root, v, record, *unique).await?
+                                        }
+                                    }
                                 }
+                                Value::Null => null_rows.push((
+                                    *column_index,
+                                    DataPointer {
+                                        block: index,
+                                        offset: cursor.position() as u16,
+                                    },
+                                )),
                                 _ => return Err(eyre!("invalid value")),
                             },
                         }
@@ -236,16 +411,14 @@ impl Aidb {
                 dirty = true;
                 header.is_full = true;
             }
-            let (next_index, next_block) = if header.next_data_block == 0 {
-                let (next_index, next_block) = self.new_block();
-                header.next_data_block = next_index;
-                dirty = true;
-                (next_index, next_block)
-            } else {
-                (
-                    header.next_data_block,
-                    self.get_block(header.next_data_block).await?,
-                )
+            let (next_index, next_block) = match header.next_data_block.get() {
+                None => {
+                    let (next_index, next_block) = self.new_block();
+                    header.next_data_block = BlockPtr::to(next_index);
+                    dirty = true;
+                    (next_index, next_block)
+                }
+                Some(next_index) => (next_index, self.get_block(next_index).await?),
             };
             cursor.set_position(0);
             header.write(&mut cursor)?;
@@ -255,13 +428,144 @@ impl Aidb {
             }
             (index, block) = (next_index, next_block);
         }
+        if schema.insert_block != BlockPtr::to(index) {
+            schema.insert_block = BlockPtr::to(index);
+            self.mark_schema_dirty(table.clone());
+        }
+        for (column_index, record) in null_rows {
+            let head = schema.null_list(column_index);
+            let new_head = self.insert_null(head, record).await?;
+            if new_head != head {
+                schema.set_null_list(column_index, new_head);
+                self.mark_schema_dirty(table.clone());
+            }
+        }
         self.put_schema(table, schema);
+        if let Some(id) = first_generated_id {
+            self.last_insert_id = *self.insert_id.get_or_insert(id);
+        }
+        Ok(Response::Meta { affected_rows })
+    }
+
+    /// Look for slots freed by deleted rows from the first data block on when inserting, instead
+    /// of appending after the last block inserted into.
+    pub fn set_reuse_free_slots(&mut self, reuse_free_slots: bool) {
+        self.reuse_free_slots = reuse_free_slots;
+    }
+
+    /// First AUTO_INCREMENT value generated by the latest INSERT that generated one, as returned
+    /// by `LAST_INSERT_ID()`.
+    pub fn last_insert_id(&self) -> i64 {
+        self.last_insert_id
+    }
+
+    pub fn set_last_insert_id(&mut self, last_insert_id: i64) {
+        self.last_insert_id = last_insert_id;
+    }
+
+    /// First AUTO_INCREMENT value generated by the latest statement.
+    pub fn insert_id(&self) -> Option<i64> {
+        self.insert_id
+    }
+
+    /// Insert rows, deleting rows holding the same key in any unique index first. Returns the
+    /// number of inserted and deleted rows like MySQL.
+    pub(crate) async fn replace_into(
+        &mut self,
+        table: String,
+        columns: Vec<String>,
+        values: Vec<Vec<Value>>,
+    ) -> Result<Response> {
+        let mut affected_rows = 0;
+        for row in values {
+            let schema = self.get_schema(&table).await?;
+            let column_indices = schema.column_indices(columns.clone())?;
+            let full_row = full_row(schema.columns.len(), &column_indices, row)?;
+            while let Some(ptr) = self.find_duplicate(&schema.indices, &full_row).await? {
+                self.delete_indexed_row(&schema, ptr).await?;
+                affected_rows += 1;
+            }
+            self.put_schema(table.clone(), schema);
+            self.insert_into(table.clone(), vec![], vec![full_row], vec![])
+                .await?;
+            affected_rows += 1;
+        }
         Ok(Response::Meta { affected_rows })
     }
 
-    async fn read_text(self: &mut Aidb, len: u16, ptr: DataPointer) -> Result<String> {
+    /// Delete a row along with its entries in all indices.
+    async fn delete_indexed_row(&mut self, schema: &Schema, ptr: DataPointer) -> Result<()> {
+        let mut block = self.get_block(ptr.block).await?;
+        let mut cursor = block.cursor_at(ptr.offset);
+        let row = self
+            .read_row(&mut cursor)
+            .await?
+            .ok_or_eyre("index points at a deleted row")?;
+        cursor.set_position(ptr.offset as u64);
+        self.delete_row(&mut cursor).await?;
+        // the freed slot may be reused by inserts looking for free slots
+        cursor.set_position(0);
+        let mut header = DataHeader::read(&mut cursor)?;
+        if header.is_full {
+            header.is_full = false;
+            cursor.set_position(0);
+            header.write(&mut cursor)?;
+        }
+        self.put_block(ptr.block, block);
+        self.mark_block_dirty(ptr.block);
+        for info in schema.indices.iter() {
+            // an indexed key implies the index has a root
+            let root = || info.block.get().ok_or_else(|| eyre!("index has no root"));
+            match (&row[info.column_index as usize], info.type_) {
+                (Value::Null, _) => {
+                    self.remove_null(schema.null_list(info.column_index), &ptr)
+                        .await?
+                }
+                (Value::Integer(key), IndexType::BTree) => {
+                    self.remove_btree(root()?, *key, &ptr).await?
+                }
+                (Value::Integer(key), IndexType::Hash) => {
+                    self.remove_hash(root()?, *key, &ptr).await?
+                }
+                _ => return Err(eyre!("invalid value")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Location of a row holding the same key as `row` in any unique index.
+    async fn find_duplicate(
+        &mut self,
+        indices: &[IndexInfo],
+        row: &[Value],
+    ) -> Result<Option<DataPointer>> {
+        for info in indices.iter().filter(|info| info.unique) {
+            let (Some(root), Value::Integer(key)) =
+                (info.block.get(), &row[info.column_index as usize])
+            else {
+                continue;
+            };
+            let ptr = match info.type_ {
+                IndexType::BTree => {
+                    self.select_btree(root, *key, &mut Default::default())
+                        .await?
+                }
+                IndexType::Hash => {
+                    self.select_hash(root, *key, &mut Default::default())
+                        .await?
+                }
+            };
+            if ptr.is_some() {
+                return Ok(ptr);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read bytes of a text or blob from the text blocks.
+    async fn read_text(self: &mut Aidb, len: u16, ptr: DataPointer) -> Result<Vec<u8>> {
         if len == 0 {
-            return Ok("".to_owned());
+            return Ok(vec![]);
         }
         if len as usize > BLOCK_SIZE {
             return Err(eyre!("text too long"));
@@ -271,10 +575,12 @@ impl Aidb {
         let mut buf = vec![0u8; len as usize];
         cursor.read_exact(&mut buf)?;
         self.put_block(ptr.block, block);
-        Ok(String::from_utf8(buf)?)
+        Ok(buf)
     }
 
-    async fn insert_text(self: &mut Aidb, s: String) -> Result<DataPointer> {
+    /// Put bytes of a text or blob into the text blocks.
+    async fn insert_text(self: &mut Aidb, s: impl AsRef<[u8]>) -> Result<DataPointer> {
+        let s = s.as_ref();
         if s.is_empty() {
             return Ok(DataPointer {
                 block: 0,
@@ -284,23 +590,34 @@ impl Aidb {
         if s.len() > BLOCK_SIZE {
             return Err(eyre!("text too long"));
         }
-        let ((index, mut block), offset) = if self.superblock.next_text_block == 0
-            || (BLOCK_SIZE - self.superblock.next_text_offset as usize) < s.len()
-        {
-            (self.new_block(), 0)
-        } else {
-            let index = self.superblock.next_text_block;
-            (
+        if let Some(ptr) = self.alloc_free_text(s.len()).await? {
+            let mut block = self.get_block(ptr.block).await?;
+            block.cursor_at(ptr.offset).write_all(s)?;
+            self.put_block(ptr.block, block);
+            self.mark_block_dirty(ptr.block);
+            return Ok(ptr);
+        }
+        let ((index, mut block), offset) = match self.superblock.next_text_block.get() {
+            Some(index) if (BLOCK_SIZE - self.superblock.next_text_offset as usize) >= s.len() => (
                 (index, self.get_block(index).await?),
                 self.superblock.next_text_offset,
-            )
+            ),
+            previous => {
+                if let Some(previous) = previous {
+                    // the tail of the previous block stays available to shorter text
+                    let offset = self.superblock.next_text_offset;
+                    self.free_text(previous, offset, BLOCK_SIZE - offset as usize)
+                        .await?;
+                }
+                (self.new_block(), 0)
+            }
         };
         let mut cursor = block.cursor_at(offset);
-        cursor.write_all(s.as_bytes())?;
+        cursor.write_all(s)?;
         let next_offset = cursor.position() as BlockOffset;
         self.put_block(index, block);
         self.mark_block_dirty(index);
-        self.superblock.next_text_block = index;
+        self.superblock.next_text_block = BlockPtr::to(index);
         self.superblock.next_text_offset = next_offset;
         self.mark_superblock_dirty();
         Ok(DataPointer {
@@ -328,12 +645,16 @@ impl Aidb {
         let mut values = vec![];
         for value in row.values {
             values.push(match value {
-                ValueRepr::IntegerNull(()) | ValueRepr::RealNull(()) | ValueRepr::TextNull(()) => {
-                    Value::Null
-                }
+                ValueRepr::IntegerNull(())
+                | ValueRepr::RealNull(())
+                | ValueRepr::TextNull(())
+                | ValueRepr::BlobNull(()) => Value::Null,
                 ValueRepr::Integer(v) => Value::Integer(v),
                 ValueRepr::Real(v) => Value::Real(v),
-                ValueRepr::Text { len, ptr } => Value::Text(self.read_text(len, ptr).await?),
+                ValueRepr::Text { len, ptr } => {
+                    Value::Text(String::from_utf8(self.read_text(len, ptr).await?)?)
+                }
+                ValueRepr::Blob { len, ptr } => Value::Blob(self.read_text(len, ptr).await?),
             });
         }
         Ok(Some(values))
@@ -355,12 +676,21 @@ impl Aidb {
                 (DataType::Integer, Value::Null) => ValueRepr::IntegerNull(()),
                 (DataType::Real, Value::Null) => ValueRepr::RealNull(()),
                 (DataType::Text, Value::Null) => ValueRepr::TextNull(()),
+                (DataType::Blob, Value::Null) => ValueRepr::BlobNull(()),
                 (DataType::Integer, Value::Integer(v)) => ValueRepr::Integer(v),
+                // NaN has no place in an ordering and infinities no SQL literal to dump them as
+                (DataType::Real, Value::Real(v)) if !v.is_finite() => {
+                    return Err(eyre!("{} is not a valid REAL", Value::Real(v)));
+                }
                 (DataType::Real, Value::Real(v)) => ValueRepr::Real(v),
                 (DataType::Text, Value::Text(s)) => ValueRepr::Text {
                     len: s.len() as u16,
                     ptr: self.insert_text(s).await?,
                 },
+                (DataType::Blob, Value::Blob(b)) => ValueRepr::Blob {
+                    len: b.len() as u16,
+                    ptr: self.insert_text(b).await?,
+                },
                 _ => return Err(eyre!("invalid value")),
             });
         }
@@ -384,16 +714,26 @@ impl Aidb {
         debug!(pos, "update_row");
         let mut values = RowRepr::read(cursor)?.values;
         for (index, value) in set {
+            self.free_value(&values[index]).await?;
             values[index] = match (values[index].datatype(), value) {
                 (DataType::Integer, Value::Null) => ValueRepr::IntegerNull(()),
                 (DataType::Real, Value::Null) => ValueRepr::RealNull(()),
                 (DataType::Text, Value::Null) => ValueRepr::TextNull(()),
+                (DataType::Blob, Value::Null) => ValueRepr::BlobNull(()),
                 (DataType::Integer, Value::Integer(v)) => ValueRepr::Integer(v),
+                // NaN has no place in an ordering and infinities no SQL literal to dump them as
+                (DataType::Real, Value::Real(v)) if !v.is_finite() => {
+                    return Err(eyre!("{} is not a valid REAL", Value::Real(v)));
+                }
                 (DataType::Real, Value::Real(v)) => ValueRepr::Real(v),
                 (DataType::Text, Value::Text(s)) => ValueRepr::Text {
                     len: s.len() as u16,
                     ptr: self.insert_text(s).await?,
                 },
+                (DataType::Blob, Value::Blob(b)) => ValueRepr::Blob {
+                    len: b.len() as u16,
+                    ptr: self.insert_text(b).await?,
+                },
                 _ => return Err(eyre!("invalid value")),
             };
         }
@@ -414,7 +754,105 @@ impl Aidb {
         debug!(pos, "delete_row");
         let len = i8::read_le(cursor)?;
         cursor.set_position(pos);
+        if len > 0 {
+            for value in RowRepr::read(cursor)?.values {
+                self.free_value(&value).await?;
+            }
+            cursor.set_position(pos);
+        }
         (-len.abs()).write_le(cursor)?;
         Ok(())
     }
+
+    /// Give the text of a value that is about to be overwritten back to the free extents.
+    async fn free_value(&mut self, value: &ValueRepr) -> Result<()> {
+        match value {
+            ValueRepr::Text { len, ptr } | ValueRepr::Blob { len, ptr } if *len > 0 => {
+                self.free_text(ptr.block, ptr.offset, *len as usize).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Blocks of the data chain of a table along with the text blocks its live rows point at,
+    /// the text itself is not read. Returns the number of live rows and bytes of text they hold.
+    pub(crate) async fn data_chain_blocks(
+        &mut self,
+        schema: &Schema,
+        visited: &mut HashSet<BlockIndex>,
+        text: &mut HashSet<BlockIndex>,
+    ) -> Result<(usize, u64)> {
+        let row_size = schema.row_size() as u64;
+        let (mut rows, mut text_bytes) = (0, 0);
+        let mut next = schema.data_block;
+        while let Some(index) = next.get()
+            && index < self.superblock.next_empty_block
+            && visited.insert(index)
+        {
+            let mut block = self.get_block(index).await?;
+            let mut cursor = block.cursor();
+            let header = DataHeader::read(&mut cursor)?;
+            while (BLOCK_SIZE as u64 - cursor.position()) > row_size {
+                let position = cursor.position();
+                if Aidb::is_row_valid(&mut cursor)? {
+                    cursor.set_position(position);
+                    rows += 1;
+                    for value in RowRepr::read(&mut cursor)?.values {
+                        if let ValueRepr::Text { len, ptr } | ValueRepr::Blob { len, ptr } = value
+                            && len > 0
+                        {
+                            text.insert(ptr.block);
+                            text_bytes += len as u64;
+                        }
+                    }
+                }
+                cursor.set_position(position + row_size);
+            }
+            self.put_block(index, block);
+            next = header.next_data_block;
+        }
+        Ok((rows, text_bytes))
+    }
+
+    /// Check that the data block chain of a table terminates, returns its live rows.
+    pub(crate) async fn check_data_chain(
+        &mut self,
+        schema: &Schema,
+        problems: &mut Vec<String>,
+    ) -> Result<LiveRows> {
+        let context = format!("table {}: data", schema.name());
+        let row_size = schema.row_size() as u64;
+        let mut visited = HashSet::new();
+        let mut rows = LiveRows::new();
+        let mut next = schema.data_block;
+        while let Some(index) = next.get()
+            && self.check_block(index, &mut visited, &context, problems)
+        {
+            let mut block = match self.get_block(index).await {
+                Ok(block) => block,
+                Err(e) => {
+                    problems.push(format!("{context}: block {index} is unreadable: {e}"));
+                    break;
+                }
+            };
+            let mut cursor = block.cursor();
+            let header = DataHeader::read(&mut cursor)?;
+            while (BLOCK_SIZE as u64 - cursor.position()) > row_size {
+                let position = cursor.position();
+                match self.read_row(&mut cursor).await {
+                    Ok(Some(row)) => {
+                        rows.insert((index, position as BlockOffset), row);
+                    }
+                    Ok(None) => {}
+                    Err(e) => problems.push(format!(
+                        "{context}: row @{index}:{position} is unreadable: {e}"
+                    )),
+                }
+                cursor.set_position(position + row_size);
+            }
+            self.put_block(index, block);
+            next = header.next_data_block;
+        }
+        Ok(rows)
+    }
 }
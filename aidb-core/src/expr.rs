@@ -0,0 +1,381 @@
+use std::{
+    cmp::Ordering,
+    fmt::{Display, Formatter},
+};
+
+use eyre::{Result, eyre};
+
+use crate::{
+    Aidb, DataType, Row, Value,
+    sql::{SqlBinaryOp, SqlCmpOp, SqlExpr},
+};
+
+impl SqlExpr {
+    pub(crate) fn has_column(&self) -> bool {
+        match self {
+            SqlExpr::Column(_) => true,
+            SqlExpr::Const(_) => false,
+            SqlExpr::Binary { lhs, rhs, .. } => lhs.has_column() || rhs.has_column(),
+            SqlExpr::Call { args, .. } => args.iter().any(|arg| arg.has_column()),
+        }
+    }
+
+    pub(crate) fn has_aggregate(&self) -> bool {
+        match self {
+            SqlExpr::Column(_) | SqlExpr::Const(_) => false,
+            SqlExpr::Binary { lhs, rhs, .. } => lhs.has_aggregate() || rhs.has_aggregate(),
+            SqlExpr::Call { function, args } => {
+                AggregateFn::from_name(function).is_some()
+                    || args.iter().any(|arg| arg.has_aggregate())
+            }
+        }
+    }
+
+    /// Replace calls reading session state, e.g. `LAST_INSERT_ID()`, with their current value.
+    pub(crate) fn bind_session(&mut self, aidb: &Aidb) {
+        match self {
+            SqlExpr::Column(_) | SqlExpr::Const(_) => {}
+            SqlExpr::Binary { lhs, rhs, .. } => {
+                lhs.bind_session(aidb);
+                rhs.bind_session(aidb);
+            }
+            SqlExpr::Call { function, args } if function == "LAST_INSERT_ID" && args.is_empty() => {
+                *self = SqlExpr::Const(Value::Integer(aidb.last_insert_id()));
+            }
+            SqlExpr::Call { args, .. } => {
+                for arg in args {
+                    arg.bind_session(aidb);
+                }
+            }
+        }
+    }
+
+    /// Fold an expression without column references into a value.
+    pub(crate) fn eval_const(&self) -> Result<Value> {
+        match self {
+            SqlExpr::Column(_) => Err(eyre!("expression is not constant")),
+            SqlExpr::Const(value) => Ok(value.clone()),
+            SqlExpr::Binary { op, lhs, rhs } => binary(*op, lhs.eval_const()?, rhs.eval_const()?),
+            SqlExpr::Call { function, args } => call(
+                function,
+                args.iter()
+                    .map(|arg| arg.eval_const())
+                    .collect::<Result<_>>()?,
+            ),
+        }
+    }
+}
+
+fn binary(op: SqlBinaryOp, lhs: Value, rhs: Value) -> Result<Value> {
+    use SqlBinaryOp::*;
+    match (lhs, rhs) {
+        (Value::Null, _) | (_, Value::Null) => Ok(Value::Null),
+        (Value::Integer(lhs), Value::Integer(rhs)) => match op {
+            Add => lhs.checked_add(rhs),
+            Sub => lhs.checked_sub(rhs),
+            Mul => lhs.checked_mul(rhs),
+            Div if rhs == 0 => return Err(eyre!("division by zero")),
+            Div => lhs.checked_div(rhs),
+        }
+        .map(Value::Integer)
+        .ok_or_else(|| eyre!("integer overflow")),
+        (
+            lhs @ (Value::Integer(_) | Value::Real(_)),
+            rhs @ (Value::Integer(_) | Value::Real(_)),
+        ) => {
+            let (lhs, rhs) = (as_real(&lhs), as_real(&rhs));
+            let result = match op {
+                Add => lhs + rhs,
+                Sub => lhs - rhs,
+                Mul => lhs * rhs,
+                Div if rhs == 0.0 => return Err(eyre!("division by zero")),
+                Div => lhs / rhs,
+            };
+            if !result.is_finite() {
+                return Err(eyre!("real overflow"));
+            }
+            Ok(Value::Real(result))
+        }
+        _ => Err(eyre!("datatype mismatch")),
+    }
+}
+
+/// Compare values, with integers and reals compared numerically.
+fn compare(lhs: &Value, rhs: &Value) -> Option<Ordering> {
+    match (lhs, rhs) {
+        (Value::Integer(_), Value::Real(_)) | (Value::Real(_), Value::Integer(_)) => {
+            as_real(lhs).partial_cmp(&as_real(rhs))
+        }
+        _ => lhs.compare(rhs),
+    }
+}
+
+fn as_real(value: &Value) -> f64 {
+    match value {
+        Value::Integer(v) => *v as f64,
+        Value::Real(v) => *v,
+        _ => unreachable!(),
+    }
+}
+
+fn call(function: &str, args: Vec<Value>) -> Result<Value> {
+    if !matches!(function, "VERSION" | "UPPER" | "LOWER" | "LENGTH" | "ABS") {
+        return Err(eyre!("unknown function {function}"));
+    }
+    if function == "VERSION" {
+        return match args.len() {
+            0 => Ok(Value::Text(Aidb::version())),
+            n => Err(eyre!("{function} expects 0 arguments, found {n}")),
+        };
+    }
+    let [arg] = <[Value; 1]>::try_from(args)
+        .map_err(|args| eyre!("{function} expects 1 argument, found {}", args.len()))?;
+    match (function, arg) {
+        (_, Value::Null) => Ok(Value::Null),
+        ("UPPER", Value::Text(s)) => Ok(Value::Text(s.to_uppercase())),
+        ("LOWER", Value::Text(s)) => Ok(Value::Text(s.to_lowercase())),
+        ("LENGTH", Value::Text(s)) => Ok(Value::Integer(s.chars().count() as i64)),
+        ("LENGTH", Value::Blob(b)) => Ok(Value::Integer(b.len() as i64)),
+        ("ABS", Value::Integer(v)) => v
+            .checked_abs()
+            .map(Value::Integer)
+            .ok_or_else(|| eyre!("integer overflow")),
+        ("ABS", Value::Real(v)) => Ok(Value::Real(v.abs())),
+        _ => Err(eyre!("datatype mismatch")),
+    }
+}
+
+fn call_datatype(function: &str, args: &[DataType]) -> DataType {
+    match (function, args) {
+        ("LENGTH", _) => DataType::Integer,
+        ("ABS", [datatype]) => *datatype,
+        _ => DataType::Text,
+    }
+}
+
+/// Expression over positions of a row, e.g. the output of grouping.
+#[derive(Debug, Clone)]
+pub(crate) enum RowExpr {
+    Column(usize),
+    Const(Value),
+    Binary {
+        op: SqlBinaryOp,
+        lhs: Box<RowExpr>,
+        rhs: Box<RowExpr>,
+    },
+    Call {
+        function: String,
+        args: Vec<RowExpr>,
+    },
+}
+
+impl RowExpr {
+    pub(crate) fn eval(&self, row: &Row) -> Result<Value> {
+        match self {
+            RowExpr::Column(index) => Ok(row[*index].clone()),
+            RowExpr::Const(value) => Ok(value.clone()),
+            RowExpr::Binary { op, lhs, rhs } => binary(*op, lhs.eval(row)?, rhs.eval(row)?),
+            RowExpr::Call { function, args } => call(
+                function,
+                args.iter()
+                    .map(|arg| arg.eval(row))
+                    .collect::<Result<_>>()?,
+            ),
+        }
+    }
+
+    /// Datatype of the result given datatypes of the row.
+    pub(crate) fn datatype(&self, columns: &[DataType]) -> DataType {
+        match self {
+            RowExpr::Column(index) => columns[*index],
+            RowExpr::Const(value) => value.datatype().unwrap_or(DataType::Text),
+            RowExpr::Binary { lhs, rhs, .. } => {
+                match (lhs.datatype(columns), rhs.datatype(columns)) {
+                    (DataType::Integer, DataType::Integer) => DataType::Integer,
+                    _ => DataType::Real,
+                }
+            }
+            RowExpr::Call { function, args } => call_datatype(
+                function,
+                &args
+                    .iter()
+                    .map(|arg| arg.datatype(columns))
+                    .collect::<Vec<_>>(),
+            ),
+        }
+    }
+}
+
+impl Display for RowExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RowExpr::Column(index) => write!(f, "${index}"),
+            RowExpr::Const(value) => write!(f, "{value}"),
+            RowExpr::Binary { op, lhs, rhs } => write!(f, "({lhs} {op} {rhs})"),
+            RowExpr::Call { function, args } => write!(
+                f,
+                "{function}({})",
+                args.iter()
+                    .map(|arg| arg.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// Condition over positions of a row, NULLs and incomparable values make it unknown.
+#[derive(Debug, Clone)]
+pub(crate) enum RowCondition {
+    Cmp {
+        op: SqlCmpOp,
+        lhs: RowExpr,
+        rhs: RowExpr,
+    },
+    And(Box<RowCondition>, Box<RowCondition>),
+    Or(Box<RowCondition>, Box<RowCondition>),
+    Not(Box<RowCondition>),
+}
+
+impl RowCondition {
+    /// Whether the condition is known to be true.
+    pub(crate) fn check(&self, row: &Row) -> Result<bool> {
+        Ok(self.eval(row)? == Some(true))
+    }
+
+    fn eval(&self, row: &Row) -> Result<Option<bool>> {
+        use Ordering::*;
+        match self {
+            RowCondition::Cmp { op, lhs, rhs } => {
+                let ordering = compare(&lhs.eval(row)?, &rhs.eval(row)?);
+                Ok(ordering.map(|ordering| match op {
+                    SqlCmpOp::Eq => ordering == Equal,
+                    SqlCmpOp::Ne => ordering != Equal,
+                    SqlCmpOp::Lt => ordering == Less,
+                    SqlCmpOp::Le => ordering != Greater,
+                    SqlCmpOp::Gt => ordering == Greater,
+                    SqlCmpOp::Ge => ordering != Less,
+                }))
+            }
+            RowCondition::And(lhs, rhs) => Ok(match (lhs.eval(row)?, rhs.eval(row)?) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            }),
+            RowCondition::Or(lhs, rhs) => Ok(match (lhs.eval(row)?, rhs.eval(row)?) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            }),
+            RowCondition::Not(condition) => Ok(condition.eval(row)?.map(|b| !b)),
+        }
+    }
+}
+
+impl Display for RowCondition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RowCondition::Cmp { op, lhs, rhs } => write!(f, "{lhs} {op} {rhs}"),
+            RowCondition::And(lhs, rhs) => write!(f, "({lhs} ∧ {rhs})"),
+            RowCondition::Or(lhs, rhs) => write!(f, "({lhs} ∨ {rhs})"),
+            RowCondition::Not(condition) => write!(f, "¬({condition})"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AggregateFn {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl AggregateFn {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "COUNT" => Some(AggregateFn::Count),
+            "SUM" => Some(AggregateFn::Sum),
+            "AVG" => Some(AggregateFn::Avg),
+            "MIN" => Some(AggregateFn::Min),
+            "MAX" => Some(AggregateFn::Max),
+            _ => None,
+        }
+    }
+
+    /// Datatype of the result, `input` is `None` for `COUNT(*)`.
+    pub(crate) fn datatype(&self, input: Option<DataType>) -> DataType {
+        match (self, input) {
+            (AggregateFn::Count, _) => DataType::Integer,
+            (AggregateFn::Avg, _) => DataType::Real,
+            (_, Some(datatype)) => datatype,
+            (_, None) => DataType::Integer,
+        }
+    }
+}
+
+impl Display for AggregateFn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AggregateFn::Count => write!(f, "COUNT"),
+            AggregateFn::Sum => write!(f, "SUM"),
+            AggregateFn::Avg => write!(f, "AVG"),
+            AggregateFn::Min => write!(f, "MIN"),
+            AggregateFn::Max => write!(f, "MAX"),
+        }
+    }
+}
+
+/// Running state of an aggregate over one group, NULL inputs are skipped.
+#[derive(Debug, Clone)]
+pub(crate) struct Accumulator {
+    count: usize,
+    value: Value,
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            value: Value::Null,
+        }
+    }
+}
+
+impl Accumulator {
+    /// Feed a value, `None` for `COUNT(*)`.
+    pub(crate) fn update(&mut self, function: AggregateFn, input: Option<&Value>) -> Result<()> {
+        let Some(input) = input else {
+            self.count += 1;
+            return Ok(());
+        };
+        if *input == Value::Null {
+            return Ok(());
+        }
+        self.count += 1;
+        let replace = match (function, &self.value) {
+            (AggregateFn::Count, _) => false,
+            (_, Value::Null) => true,
+            (AggregateFn::Sum | AggregateFn::Avg, value) => {
+                self.value = binary(SqlBinaryOp::Add, value.clone(), input.clone())?;
+                false
+            }
+            (AggregateFn::Min, value) => compare(input, value) == Some(Ordering::Less),
+            (AggregateFn::Max, value) => compare(input, value) == Some(Ordering::Greater),
+        };
+        if replace {
+            self.value = input.clone();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(&self, function: AggregateFn) -> Value {
+        match function {
+            AggregateFn::Count => Value::Integer(self.count as i64),
+            AggregateFn::Avg if self.count == 0 => Value::Null,
+            AggregateFn::Avg => Value::Real(as_real(&self.value) / self.count as f64),
+            _ => self.value.clone(),
+        }
+    }
+}
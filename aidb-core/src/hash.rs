@@ -0,0 +1,311 @@
+use std::collections::HashSet;
+
+use binrw::{BinRead, BinWrite, binrw};
+use eyre::{Result, eyre};
+
+use crate::{
+    Aidb,
+    storage::{BLOCK_SIZE, BlockIndex, DataPointer},
+};
+
+const HASH_BITS: u32 = 10;
+const HASH_BUCKETS: usize = 1 << HASH_BITS;
+const HASH_N: usize = (BLOCK_SIZE - 10) / 18;
+
+#[binrw]
+#[brw(little)]
+#[derive(Debug)]
+struct HashDirectory {
+    #[br(count = HASH_BUCKETS)]
+    #[bw(assert(buckets.len() == HASH_BUCKETS))]
+    buckets: Vec<BlockIndex>,
+}
+
+#[binrw]
+#[brw(little)]
+#[derive(Debug)]
+struct HashBucket {
+    next: BlockIndex,
+    #[br(temp)]
+    #[bw(calc = records.len() as u16)]
+    len: u16,
+    /// empty only after removals
+    #[br(count = len)]
+    #[bw(assert(records.len() <= HASH_N))]
+    records: Vec<(i64, DataPointer)>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) enum HashLookupState {
+    #[default]
+    Initialized,
+    Running {
+        next: BlockIndex,
+        stream: std::vec::IntoIter<(i64, DataPointer)>,
+    },
+    Done,
+}
+
+/// Fibonacci hashing, must stay stable since bucket positions are persisted.
+fn hash_bucket(key: i64) -> usize {
+    ((key as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (u64::BITS - HASH_BITS)) as usize
+}
+
+impl Aidb {
+    pub(crate) async fn new_hash(&mut self, key: i64, record: DataPointer) -> Result<BlockIndex> {
+        let (dir_i, mut dir_b) = self.new_block();
+        HashDirectory {
+            buckets: vec![0; HASH_BUCKETS],
+        }
+        .write(&mut dir_b.cursor())?;
+        self.put_block(dir_i, dir_b);
+        self.mark_block_dirty(dir_i);
+        self.insert_hash(dir_i, key, record, true).await?;
+        Ok(dir_i)
+    }
+
+    async fn read_directory(&mut self, dir_i: BlockIndex) -> Result<HashDirectory> {
+        let mut dir_b = self.get_block(dir_i).await?;
+        let directory = HashDirectory::read(&mut dir_b.cursor())?;
+        self.put_block(dir_i, dir_b);
+        Ok(directory)
+    }
+
+    async fn write_directory(&mut self, dir_i: BlockIndex, directory: HashDirectory) -> Result<()> {
+        let mut dir_b = self.get_block(dir_i).await?;
+        directory.write(&mut dir_b.cursor())?;
+        self.put_block(dir_i, dir_b);
+        self.mark_block_dirty(dir_i);
+        Ok(())
+    }
+
+    async fn read_bucket(&mut self, bucket_i: BlockIndex) -> Result<HashBucket> {
+        let mut bucket_b = self.get_block(bucket_i).await?;
+        let bucket = HashBucket::read(&mut bucket_b.cursor())?;
+        self.put_block(bucket_i, bucket_b);
+        Ok(bucket)
+    }
+
+    async fn write_bucket(&mut self, bucket_i: BlockIndex, bucket: HashBucket) -> Result<()> {
+        let mut bucket_b = self.get_block(bucket_i).await?;
+        bucket.write(&mut bucket_b.cursor())?;
+        self.put_block(bucket_i, bucket_b);
+        self.mark_block_dirty(bucket_i);
+        Ok(())
+    }
+
+    fn new_bucket(&mut self, key: i64, record: DataPointer) -> Result<BlockIndex> {
+        let (bucket_i, mut bucket_b) = self.new_block();
+        HashBucket {
+            next: 0,
+            records: vec![(key, record)],
+        }
+        .write(&mut bucket_b.cursor())?;
+        self.put_block(bucket_i, bucket_b);
+        self.mark_block_dirty(bucket_i);
+        Ok(bucket_i)
+    }
+
+    pub(crate) async fn insert_hash(
+        &mut self,
+        dir_i: BlockIndex,
+        key: i64,
+        record: DataPointer,
+        unique: bool,
+    ) -> Result<()> {
+        let mut directory = self.read_directory(dir_i).await?;
+        let h = hash_bucket(key);
+        if directory.buckets[h] == 0 {
+            directory.buckets[h] = self.new_bucket(key, record)?;
+            self.write_directory(dir_i, directory).await?;
+            return Ok(());
+        }
+
+        // walk the whole chain to enforce uniqueness, remember the first bucket with free space
+        let mut bucket_i = directory.buckets[h];
+        let mut vacant = None;
+        loop {
+            let bucket = self.read_bucket(bucket_i).await?;
+            if unique && bucket.records.iter().any(|(criteria, _)| *criteria == key) {
+                return Err(eyre!("unique key exists"));
+            }
+            if vacant.is_none() && bucket.records.len() < HASH_N {
+                vacant = Some(bucket_i);
+            }
+            if bucket.next == 0 {
+                break;
+            }
+            bucket_i = bucket.next;
+        }
+
+        match vacant {
+            Some(vacant_i) => {
+                let mut bucket = self.read_bucket(vacant_i).await?;
+                bucket.records.push((key, record));
+                self.write_bucket(vacant_i, bucket).await?;
+            }
+            None => {
+                let next_bucket_i = self.new_bucket(key, record)?;
+                let mut bucket = self.read_bucket(bucket_i).await?;
+                bucket.next = next_bucket_i;
+                self.write_bucket(bucket_i, bucket).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the record of a key pointing at a row, buckets are left in place even if emptied.
+    pub(crate) async fn remove_hash(
+        &mut self,
+        dir_i: BlockIndex,
+        key: i64,
+        record: &DataPointer,
+    ) -> Result<()> {
+        let directory = self.read_directory(dir_i).await?;
+        let mut bucket_i = directory.buckets[hash_bucket(key)];
+        while bucket_i != 0 {
+            let mut bucket = self.read_bucket(bucket_i).await?;
+            if let Some(index) = bucket
+                .records
+                .iter()
+                .position(|(criteria, r)| *criteria == key && r == record)
+            {
+                bucket.records.remove(index);
+                return self.write_bucket(bucket_i, bucket).await;
+            }
+            bucket_i = bucket.next;
+        }
+        Err(eyre!("key {key} not found in hash index"))
+    }
+
+    pub(crate) async fn select_hash(
+        &mut self,
+        dir_i: BlockIndex,
+        key: i64,
+        state: &mut HashLookupState,
+    ) -> Result<Option<DataPointer>> {
+        loop {
+            match state {
+                HashLookupState::Initialized => {
+                    let directory = self.read_directory(dir_i).await?;
+                    *state = HashLookupState::Running {
+                        next: directory.buckets[hash_bucket(key)],
+                        stream: vec![].into_iter(),
+                    };
+                }
+                HashLookupState::Running { next, stream } => {
+                    if let Some((_, record)) = stream.find(|(criteria, _)| key == *criteria) {
+                        return Ok(Some(record));
+                    }
+                    if *next == 0 {
+                        *state = HashLookupState::Done;
+                    } else {
+                        let bucket = self.read_bucket(*next).await?;
+                        *next = bucket.next;
+                        *stream = bucket.records.into_iter();
+                    }
+                }
+                HashLookupState::Done => return Ok(None),
+            }
+        }
+    }
+
+    /// Check that bucket chains terminate and hold keys hashed to them, returns all records.
+    pub(crate) async fn check_hash(
+        &mut self,
+        dir_i: BlockIndex,
+        visited: &mut HashSet<BlockIndex>,
+        context: &str,
+        problems: &mut Vec<String>,
+    ) -> Result<Vec<(i64, DataPointer)>> {
+        let directory = match self.read_directory(dir_i).await {
+            Ok(directory) => directory,
+            Err(e) => {
+                problems.push(format!("{context}: directory {dir_i} is unreadable: {e}"));
+                return Ok(vec![]);
+            }
+        };
+        let mut records = vec![];
+        for (h, mut bucket_i) in directory.buckets.into_iter().enumerate() {
+            while bucket_i != 0 && self.check_block(bucket_i, visited, context, problems) {
+                let bucket = match self.read_bucket(bucket_i).await {
+                    Ok(bucket) => bucket,
+                    Err(e) => {
+                        problems.push(format!("{context}: bucket {bucket_i} is unreadable: {e}"));
+                        break;
+                    }
+                };
+                for (key, record) in bucket.records {
+                    if hash_bucket(key) != h {
+                        problems.push(format!("{context}: key {key} is in the wrong bucket"));
+                    }
+                    records.push((key, record));
+                }
+                bucket_i = bucket.next;
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(key: i64) -> DataPointer {
+        DataPointer {
+            block: key as BlockIndex,
+            offset: (key % 1000) as u16,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hash_lookup() {
+        let mut aidb = Aidb::new_memory().await;
+        let dir = aidb.new_hash(0, record(0)).await.unwrap();
+        for key in 1..10000 {
+            aidb.insert_hash(dir, key * 7, record(key * 7), true)
+                .await
+                .unwrap();
+        }
+        for key in 0..10000 {
+            let ptr = aidb
+                .select_hash(dir, key * 7, &mut Default::default())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(ptr.block, record(key * 7).block);
+            assert_eq!(ptr.offset, record(key * 7).offset);
+        }
+        assert!(
+            aidb.select_hash(dir, 1, &mut Default::default())
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(aidb.insert_hash(dir, 7, record(7), true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hash_bucket_overflow() {
+        let mut aidb = Aidb::new_memory().await;
+        // keys sharing a bucket force the chain to grow past a single block
+        let keys = (0..)
+            .filter(|key| hash_bucket(*key) == hash_bucket(0))
+            .take(HASH_N + 10)
+            .collect::<Vec<_>>();
+        assert_eq!(keys.len(), HASH_N + 10);
+        let dir = aidb.new_hash(keys[0], record(0)).await.unwrap();
+        for key in keys[1..].iter() {
+            aidb.insert_hash(dir, *key, record(0), true).await.unwrap();
+        }
+        for key in keys.iter() {
+            assert!(
+                aidb.select_hash(dir, *key, &mut Default::default())
+                    .await
+                    .unwrap()
+                    .is_some()
+            );
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use eyre::{Result, eyre};
+use serde_json::{Map, Number, Value as Json};
+
+use crate::{
+    Aidb, Column, DataType, Row, Value,
+    data::{from_hex, to_hex},
+};
+
+/// Outcome of [`Aidb::import_table_json`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct JsonImport {
+    pub inserted: usize,
+    /// one message per rejected row, prefixed by its position in the array
+    pub errors: Vec<String>,
+}
+
+fn value_to_json(value: Value) -> Result<Json> {
+    Ok(match value {
+        Value::Null => Json::Null,
+        Value::Integer(i) => Json::from(i),
+        Value::Real(f) => Json::Number(
+            Number::from_f64(f).ok_or_else(|| eyre!("{f} is not representable in JSON"))?,
+        ),
+        Value::Text(s) => Json::String(s),
+        // hex digits as in the literal, bytes aren't necessarily UTF-8
+        Value::Blob(b) => Json::String(to_hex(&b)),
+        Value::Placeholder(_) => return Err(eyre!("invalid value")),
+    })
+}
+
+fn json_to_value(json: Json, datatype: DataType) -> Result<Value> {
+    let value = match (json, datatype) {
+        (Json::Null, _) => Value::Null,
+        (Json::Bool(b), DataType::Integer) => Value::Integer(b as i64),
+        (Json::Bool(b), DataType::Real) => Value::Real(b as i64 as f64),
+        (Json::Bool(b), DataType::Text) => Value::Text(b.to_string()),
+        (Json::Number(n), DataType::Integer) => {
+            if let Some(i) = n.as_i64() {
+                Value::Integer(i)
+            } else {
+                match n.as_f64() {
+                    Some(f) if f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64 => {
+                        Value::Integer(f as i64)
+                    }
+                    _ => return Err(eyre!("{n} is not a valid INTEGER")),
+                }
+            }
+        }
+        (Json::Number(n), DataType::Real) => {
+            Value::Real(n.as_f64().ok_or_else(|| eyre!("{n} is not a valid REAL"))?)
+        }
+        (Json::Number(n), DataType::Text) => Value::Text(n.to_string()),
+        (Json::String(s), DataType::Integer) => Value::Integer(
+            s.trim()
+                .parse()
+                .map_err(|_| eyre!("{s:?} is not a valid INTEGER"))?,
+        ),
+        (Json::String(s), DataType::Real) => Value::Real(
+            s.trim()
+                .parse()
+                .map_err(|_| eyre!("{s:?} is not a valid REAL"))?,
+        ),
+        (Json::String(s), DataType::Text) => Value::Text(s),
+        (Json::String(s), DataType::Blob) => {
+            Value::Blob(from_hex(&s).ok_or_else(|| eyre!("{s:?} is not a valid BLOB"))?)
+        }
+        (json @ (Json::Array(_) | Json::Object(_)), _) => {
+            return Err(eyre!("{json} is not a scalar"));
+        }
+        (json, DataType::Blob) => return Err(eyre!("{json} is not a valid BLOB")),
+    };
+    Ok(value)
+}
+
+/// Convert an object into a row in the order of `columns`, missing keys become NULL.
+fn object_to_row(json: Json, columns: &[Column]) -> Result<Row> {
+    let Json::Object(mut object) = json else {
+        return Err(eyre!("expected an object, found {json}"));
+    };
+    let row = columns
+        .iter()
+        .map(|column| match object.shift_remove(&column.name) {
+            Some(json) => json_to_value(json, column.datatype)
+                .map_err(|e| eyre!("column {}: {e}", column.name)),
+            None => Ok(Value::Null),
+        })
+        .collect::<Result<Row>>()?;
+    if let Some(key) = object.keys().next() {
+        return Err(eyre!("unknown column {key}"));
+    }
+    Ok(row)
+}
+
+impl Aidb {
+    async fn table_definition(&mut self, table: &str) -> Result<Vec<Column>> {
+        let schema = self.get_schema(table).await?;
+        let columns = schema.columns.clone();
+        self.put_schema(table.to_owned(), schema);
+        Ok(columns)
+    }
+
+    /// Export all rows of a table as a JSON array of objects mapping column names to values.
+    pub async fn export_table_json(&mut self, table: &str) -> Result<String> {
+        let columns = self.table_definition(table).await?;
+        let rows = self
+            .select_with_ptr(table.to_owned())
+            .await?
+            .into_iter()
+            .map(|(row, _)| {
+                columns
+                    .iter()
+                    .zip(row)
+                    .map(|(column, value)| Ok((column.name.clone(), value_to_json(value)?)))
+                    .collect::<Result<Map<_, _>>>()
+                    .map(Json::Object)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(serde_json::to_string(&rows)?)
+    }
+
+    /// Insert rows from a JSON array of objects as made by [`Aidb::export_table_json`].
+    ///
+    /// Values are coerced into the datatype of their column where possible. Each row is inserted
+    /// on its own, rows which fail to convert or insert are skipped and reported. Inside a
+    /// transaction a failed insertion rolls back the whole transaction as any other statement.
+    pub async fn import_table_json(&mut self, table: &str, json: &str) -> Result<JsonImport> {
+        let Json::Array(objects) = serde_json::from_str(json)? else {
+            return Err(eyre!("expected an array of objects"));
+        };
+        let columns = self.table_definition(table).await?;
+        let mut r = JsonImport::default();
+        for (i, object) in objects.into_iter().enumerate() {
+            let inserted = match object_to_row(object, &columns) {
+                Ok(row) => self.insert(table, vec![row]).await,
+                Err(e) => Err(e),
+            };
+            match inserted {
+                Ok(n) => r.inserted += n,
+                Err(e) => r.errors.push(format!("row {i}: {e}")),
+            }
+        }
+        Ok(r)
+    }
+
+    /// Plan a query like [`Aidb::explain_tree`] does, as JSON with the fields of
+    /// [`crate::PlanNode`], for tools to check which plan is chosen.
+    pub async fn plan_json(&mut self, sql: impl AsRef<str>) -> Result<Json> {
+        Ok(serde_json::to_value(self.explain_tree(sql).await?)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_json_round_trip() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, x REAL, s TEXT)")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 1.5, 'a'), (2, NULL, 'b\"c'), (3, 2.0, NULL)")
+            .await
+            .unwrap();
+        let json = aidb.export_table_json("t").await.unwrap();
+        assert_eq!(
+            json,
+            r#"[{"id":1,"x":1.5,"s":"a"},{"id":2,"x":null,"s":"b\"c"},{"id":3,"x":2.0,"s":null}]"#
+        );
+
+        let mut fresh = Aidb::new_memory().await;
+        fresh
+            .query("CREATE TABLE t (id INTEGER PRIMARY KEY, x REAL, s TEXT)")
+            .await
+            .unwrap();
+        let r = fresh.import_table_json("t", &json).await.unwrap();
+        assert_eq!(
+            r,
+            JsonImport {
+                inserted: 3,
+                errors: vec![]
+            }
+        );
+        assert_eq!(fresh.export_table_json("t").await.unwrap(), json);
+    }
+
+    #[tokio::test]
+    async fn test_json_import_coercion() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, x REAL, s TEXT)")
+            .await
+            .unwrap();
+        let json = r#"[
+            {"id": "1", "x": 2, "s": 3},
+            {"id": 2.0, "x": "0.5", "s": true},
+            {"id": 3},
+            {"id": 1.5},
+            {"id": "four"},
+            {"id": 5, "y": 0},
+            {"id": 1},
+            [6]
+        ]"#;
+        let r = aidb.import_table_json("t", json).await.unwrap();
+        assert_eq!(r.inserted, 3);
+        assert_eq!(
+            r.errors.iter().map(|e| &e[..6]).collect::<Vec<_>>(),
+            ["row 3:", "row 4:", "row 5:", "row 6:", "row 7:"]
+        );
+        assert_eq!(r.errors[0], "row 3: column id: 1.5 is not a valid INTEGER");
+        assert_eq!(r.errors[2], "row 5: unknown column y");
+        assert_eq!(
+            aidb.export_table_json("t").await.unwrap(),
+            r#"[{"id":1,"x":2.0,"s":"3"},{"id":2,"x":0.5,"s":"true"},{"id":3,"x":null,"s":null}]"#
+        );
+        assert!(aidb.import_table_json("t", "{}").await.is_err());
+        assert!(aidb.import_table_json("u", "[]").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_plan_json() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, s TEXT)")
+            .await
+            .unwrap();
+        let plan = aidb
+            .plan_json("SELECT s FROM t WHERE id = 1")
+            .await
+            .unwrap();
+        assert_eq!(plan["kind"], "Projection");
+        let lookup = &plan["children"][0];
+        assert_eq!(lookup["kind"], "BTreeExact");
+        assert_eq!(lookup["children"], Json::Array(vec![]));
+        assert_eq!(
+            serde_json::from_value::<crate::PlanNode>(plan).unwrap(),
+            aidb.explain_tree("SELECT s FROM t WHERE id = 1")
+                .await
+                .unwrap()
+        );
+        assert!(
+            aidb.plan_json("INSERT INTO t VALUES (1, 'a')")
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,28 +1,54 @@
 mod btree;
+mod check;
+mod collation;
+mod csv;
 mod data;
+mod expr;
+mod hash;
+#[cfg(feature = "json")]
+mod json;
+mod metrics;
+mod null;
 mod query;
 mod schema;
 mod select;
+mod sort;
 mod sql;
 mod storage;
 mod superblock;
+mod text;
+mod variable;
 
 use std::{
     collections::{HashMap, HashSet},
     io::{Read, Write},
 };
 
+pub use check::TableSize;
+pub use collation::Collation;
+pub use csv::CsvLoad;
 pub use data::{DataType, Value};
-pub use query::{Response, Row};
-pub use schema::Column;
-pub use storage::BlockIoLog;
+#[cfg(feature = "json")]
+pub use json::JsonImport;
+pub use query::{CancelToken, Cursor, Response, Row};
+pub use schema::{Column, IndexType, TableComments, TableIndex, TableInfo};
+pub use select::PlanNode;
+pub use sql::{Prepared, SyntaxError, Unsupported, split_statements};
+pub use storage::{BlockIoLog, BlockType, Layout, with_retry};
 
-use archive::{load, save};
-use schema::Schema;
+use archive::{load, save_objects};
+use btree::BTreeFanout;
+use metrics::QueryMetrics;
+use query::{Savepoint, StatementUndo};
+use schema::{Schema, SchemaMap};
+use sort::Spill;
+use sql::{SqlStmt, StmtCache};
 use storage::{Block, BlockIndex};
 use superblock::SuperBlock;
 
 pub use eyre::Result;
+use eyre::eyre;
+use futures::TryStreamExt;
 use opendal::Operator;
 
 #[cfg(feature = "memory")]
@@ -38,11 +64,55 @@ pub struct Aidb {
     pub(crate) superblock_dirty: bool,
     pub(crate) schemas: HashMap<String, Box<Schema>>,
     pub(crate) schemas_dirty: HashSet<String>,
+    pub(crate) schema_map: Option<SchemaMap>,
     pub(crate) transaction_in_progress: bool,
     pub(crate) superblock_backup: Option<SuperBlock>,
+    /// client the transaction in progress belongs to, set by whoever serves several clients
+    pub(crate) transaction_owner: Option<u32>,
+    pub(crate) savepoints: Vec<Savepoint>,
+    /// blocks changed by the statement in progress within a transaction
+    pub(crate) statement_undo: Option<StatementUndo>,
+    pub(crate) stmt_cache: StmtCache,
+    pub(crate) read_only: bool,
+    pub(crate) cancel_token: Option<CancelToken>,
+    pub(crate) variables: HashMap<String, Value>,
+    pub(crate) recovery: bool,
+    pub(crate) reuse_free_slots: bool,
+    pub(crate) last_insert_id: i64,
+    pub(crate) insert_id: Option<i64>,
+    pub(crate) max_rows: Option<usize>,
+    pub(crate) sort_buffer_rows: usize,
+    pub(crate) btree_fanout: BTreeFanout,
+    /// storage of temporary objects under [`TMP_DIR`], the operator of the database unless
+    /// read-only, `None` keeps everything in memory
+    pub(crate) scratch: Option<Operator>,
+    /// temporary objects of sorts stopped before they were merged, removed by the next sort
+    /// that spills
+    pub(crate) spilled_sorts: Vec<Spill>,
+    /// blocks written since the last archive saved by this instance, `None` until it saves one
+    pub(crate) archived: Option<HashSet<BlockIndex>>,
 }
 
+/// Directory of temporary objects on the scratch storage, see [`Aidb::set_scratch`].
+pub const TMP_DIR: &str = "tmp/";
+
 impl Aidb {
+    pub const DEFAULT_MAX_ROWS: usize = 10_000;
+    pub const DEFAULT_SORT_BUFFER_ROWS: usize = 100_000;
+    /// Version of the on-disk format stored in the superblock, bumped by every change to the
+    /// layout of any block. Databases in another format are refused when opened.
+    pub const FORMAT_VERSION: u32 = 1;
+
+    /// Version reported to clients, the crate version followed by the build identifier given
+    /// in `AIDB_BUILD` at compile time, `dev` without it.
+    pub fn version() -> String {
+        const BUILD: &str = match option_env!("AIDB_BUILD") {
+            Some(build) => build,
+            None => "dev",
+        };
+        format!("{}-{BUILD}", env!("CARGO_PKG_VERSION"))
+    }
+
     /// Create a new database with data stored in memory.
     #[cfg(feature = "memory")]
     pub async fn new_memory() -> Self {
@@ -59,14 +129,117 @@ impl Aidb {
             superblock_dirty: true,
             schemas: HashMap::new(),
             schemas_dirty: HashSet::new(),
+            schema_map: None,
             transaction_in_progress: false,
             superblock_backup: None,
+            transaction_owner: None,
+            savepoints: vec![],
+            statement_undo: None,
+            stmt_cache: StmtCache::new(StmtCache::DEFAULT_CAPACITY),
+            read_only: false,
+            cancel_token: None,
+            variables: HashMap::new(),
+            recovery: false,
+            reuse_free_slots: false,
+            last_insert_id: 0,
+            insert_id: None,
+            max_rows: Some(Self::DEFAULT_MAX_ROWS),
+            sort_buffer_rows: Self::DEFAULT_SORT_BUFFER_ROWS,
+            btree_fanout: BTreeFanout::default(),
+            scratch: None,
+            spilled_sorts: vec![],
+            archived: None,
         };
+        this.scratch = Some(this.op.clone());
         this.submit().await.unwrap();
         this
     }
 
+    /// Create a new database in memory restored from an archive made by [`Aidb::save_archive`].
+    #[cfg(feature = "memory")]
+    pub async fn new_memory_from_archive<R: Read>(r: R) -> Result<Self> {
+        Self::new_memory_from_archives([r]).await
+    }
+
+    /// Create a new database in memory restored from an archive made by [`Aidb::save_archive`]
+    /// followed by the archives made by [`Aidb::save_incremental_archive`] after it, in order.
+    #[cfg(feature = "memory")]
+    pub async fn new_memory_from_archives<R: Read>(
+        archives: impl IntoIterator<Item = R>,
+    ) -> Result<Self> {
+        let op = Operator::from_config(MemoryConfig::default())?
+            .layer(LoggingLayer::default())
+            .finish();
+        let mut epoch = None;
+        for r in archives {
+            load(&op, r).await?;
+            let archive_epoch = Self::from_op_read_only(op.clone())
+                .await?
+                .superblock
+                .archive_epoch;
+            if let Some(epoch) = epoch
+                && archive_epoch != epoch + 1
+            {
+                return Err(eyre!(
+                    "archive of epoch {archive_epoch} does not follow the one of epoch {epoch}"
+                ));
+            }
+            epoch = Some(archive_epoch);
+        }
+        Self::from_op(op).await
+    }
+
     pub async fn from_op(op: Operator) -> Result<Self> {
+        Self::open(op, false, false, Layout::default()).await
+    }
+
+    /// Open a database, creating it with blocks stored in `layout` if there is none. An existing
+    /// database keeps the layout it was created with.
+    pub async fn from_op_with_layout(op: Operator, layout: Layout) -> Result<Self> {
+        Self::open(op, false, false, layout).await
+    }
+
+    /// Open a database rejecting statements that modify it, nothing is ever written to `op`.
+    pub async fn from_op_read_only(op: Operator) -> Result<Self> {
+        Self::open(op, true, false, Layout::default()).await
+    }
+
+    /// Open a database in [recovery mode](Aidb::set_recovery) from the start, so that a damaged
+    /// superblock is salvaged as well. Readers opened from it are in recovery mode too.
+    pub async fn from_op_recovery(op: Operator, read_only: bool) -> Result<Self> {
+        Self::open(op, read_only, true, Layout::default()).await
+    }
+
+    /// Open a read-only instance on the same storage, e.g. to run statements from
+    /// [`Aidb::is_read`] while this one is busy. It sees what this one commits once
+    /// [refreshed](Aidb::refresh), never uncommitted changes of a transaction.
+    pub async fn open_reader(&self) -> Result<Self> {
+        let mut reader =
+            Self::open(self.op.clone(), true, self.recovery, self.superblock.layout).await?;
+        reader.max_rows = self.max_rows;
+        reader.sort_buffer_rows = self.sort_buffer_rows;
+        reader.scratch = self.scratch.clone();
+        Ok(reader)
+    }
+
+    /// Read the superblock again, so that a read-only instance sees what was committed to its
+    /// storage by another instance since it was opened. Cached blocks and schemas are only
+    /// forgotten if anything was committed since the last refresh.
+    pub async fn refresh(&mut self) -> Result<()> {
+        if !self.read_only {
+            return Err(eyre!("only a read-only database can be refreshed"));
+        }
+        let sequence = self.superblock.sequence;
+        self.load_superblock().await?;
+        if self.superblock.sequence != sequence {
+            self.blocks.clear();
+            self.schemas.clear();
+            self.schema_map = None;
+        }
+        Ok(())
+    }
+
+    async fn open(op: Operator, read_only: bool, recovery: bool, layout: Layout) -> Result<Self> {
         let mut this = Self {
             op,
             log: BlockIoLog::default(),
@@ -76,26 +249,148 @@ impl Aidb {
             superblock_dirty: false,
             schemas: HashMap::new(),
             schemas_dirty: HashSet::new(),
+            schema_map: None,
             transaction_in_progress: false,
             superblock_backup: None,
+            transaction_owner: None,
+            savepoints: vec![],
+            statement_undo: None,
+            stmt_cache: StmtCache::new(StmtCache::DEFAULT_CAPACITY),
+            read_only,
+            cancel_token: None,
+            variables: HashMap::new(),
+            recovery,
+            reuse_free_slots: false,
+            last_insert_id: 0,
+            insert_id: None,
+            max_rows: Some(Self::DEFAULT_MAX_ROWS),
+            sort_buffer_rows: Self::DEFAULT_SORT_BUFFER_ROWS,
+            btree_fanout: BTreeFanout::default(),
+            scratch: None,
+            spilled_sorts: vec![],
+            archived: None,
         };
+        this.superblock.layout = layout;
+        if !read_only {
+            // left behind by sorts of an instance that didn't exit cleanly
+            this.op.remove_all(TMP_DIR).await?;
+            this.scratch = Some(this.op.clone());
+        }
         this.load_superblock().await?;
-        this.submit().await?;
+        // only a new database or one opened from the superblock mirror needs it written
+        if this.superblock_dirty && !read_only {
+            this.submit().await?;
+        }
         Ok(this)
     }
 
     pub async fn query(&mut self, sql: impl AsRef<str>) -> Result<Response> {
-        self.superblock_backup = Some(self.superblock.clone());
-        let r = self.dispatch(Self::parse(sql)?).await;
+        let stmt = self.stmt_cache.parse(sql.as_ref())?;
+        self.query_stmt(stmt).await
+    }
+
+    /// Set how many rows SELECT returns at most, [`Aidb::DEFAULT_MAX_ROWS`] by default, `None` for
+    /// no limit. Rows beyond it are dropped and the response is marked truncated, unlike `LIMIT`
+    /// this guards against results too large to hold or display.
+    pub fn set_max_rows(&mut self, max_rows: Option<usize>) {
+        self.max_rows = max_rows;
+    }
+
+    /// Set how many rows ORDER BY sorts in memory, [`Aidb::DEFAULT_SORT_BUFFER_ROWS`] by default.
+    /// More rows are sorted in runs of this size written to the [scratch](Aidb::set_scratch)
+    /// storage and merged, or all sorted in memory without one.
+    pub fn set_sort_buffer_rows(&mut self, rows: usize) {
+        self.sort_buffer_rows = rows.max(1);
+    }
+
+    /// Set where temporary objects are written under [`TMP_DIR`], `None` keeps them in memory.
+    /// It is the operator of the database unless opened read-only, which never writes to it,
+    /// and readers share the one of the instance they were opened from. Temporary objects are
+    /// never archived, and removed when the database is opened for writing.
+    pub fn set_scratch(&mut self, scratch: Option<Operator>) {
+        self.scratch = scratch;
+    }
+
+    /// Set how many parsed statements are kept for repeated queries, 0 disables the cache.
+    pub fn set_stmt_cache_capacity(&mut self, capacity: usize) {
+        self.stmt_cache.set_capacity(capacity);
+    }
+
+    /// Bind parameters to placeholders of a prepared statement and run it.
+    pub async fn query_params(
+        &mut self,
+        prepared: &Prepared,
+        params: Vec<Value>,
+    ) -> Result<Response> {
+        if params.len() != prepared.params {
+            return Err(eyre!(
+                "expected {} parameters, found {}",
+                prepared.params,
+                params.len()
+            ));
+        }
+        if params.iter().any(|v| matches!(v, Value::Placeholder(_))) {
+            return Err(eyre!("invalid parameter"));
+        }
+        let mut stmt = prepared.stmt.clone();
+        for value in stmt.values_mut() {
+            if let Value::Placeholder(i) = value {
+                *value = params[*i].clone();
+            }
+        }
+        self.query_stmt(stmt).await
+    }
+
+    pub(crate) async fn query_stmt(&mut self, stmt: SqlStmt) -> Result<Response> {
+        let metrics = QueryMetrics::start(self, stmt.kind());
+        let r = self.run_stmt(stmt).await;
+        metrics.finish(self, &r);
+        r
+    }
+
+    async fn run_stmt(&mut self, stmt: SqlStmt) -> Result<Response> {
+        // a transaction rolls back to where it started, a failed statement within it only undoes
+        // itself, of which reads have nothing
+        let in_transaction = self.transaction_in_progress;
+        if !in_transaction {
+            self.superblock_backup = Some(self.superblock.clone());
+        } else if !stmt.is_read() {
+            self.statement_undo = Some(StatementUndo::new(self.superblock.clone()));
+        }
+        self.insert_id = None;
+        let r = self.dispatch(stmt).await;
+        let undo = self.statement_undo.take();
         if r.is_ok() {
-            self.submit().await?;
+            // a read-only instance has nothing to submit and keeps what it cached
+            if !self.read_only {
+                self.submit().await?;
+            }
+        } else if in_transaction && self.transaction_in_progress {
+            if let Some(undo) = undo {
+                self.undo_statement(undo);
+            }
         } else {
             self.transaction_in_progress = true;
-            self.dispatch(sql::SqlStmt::Rollback).await.unwrap();
+            self.dispatch(SqlStmt::Rollback).await.unwrap();
         }
         r
     }
 
+    /// Insert rows with values in the order of table definition without going through SQL,
+    /// returns the number of affected rows.
+    pub async fn insert(&mut self, table: &str, rows: Vec<Row>) -> Result<usize> {
+        let stmt = SqlStmt::InsertInto {
+            table: table.to_owned(),
+            columns: vec![],
+            values: rows,
+            on_duplicate: vec![],
+        };
+        let Response::Meta { affected_rows } = self.query_stmt(stmt).await? else {
+            unreachable!()
+        };
+        Ok(affected_rows)
+    }
+
     pub async fn query_log_blocks(
         &mut self,
         sql: impl AsRef<str>,
@@ -105,11 +400,1033 @@ impl Aidb {
         result.map(|r| (r, self.get_block_io_log()))
     }
 
+    /// Save all the data in storage to an archive. Blocks written after it are tracked, so that
+    /// [`Aidb::save_incremental_archive`] stores only those.
     pub async fn save_archive<W: Write>(&mut self, w: W) -> Result<W> {
-        save(&self.op, w).await
+        // nothing a read-only instance or a transaction in progress writes is ever tracked
+        if !self.read_only && !self.transaction_in_progress {
+            self.checkpoint().await?;
+        }
+        let mut paths = vec![];
+        let mut lister = self.op.lister_with("/").recursive(true).await?;
+        while let Some(entry) = lister.try_next().await? {
+            let path = entry.path();
+            if !path.ends_with('/') && !path.starts_with(TMP_DIR) {
+                paths.push(path.to_owned());
+            }
+        }
+        paths.sort();
+        save_objects(&self.op, paths, w).await
+    }
+
+    /// Save the blocks written since the last archive saved by this instance, either full or
+    /// incremental, which is much smaller than a full archive after small changes.
+    pub async fn save_incremental_archive<W: Write>(&mut self, w: W) -> Result<W> {
+        if self.read_only {
+            return Err(eyre!("database is read-only"));
+        }
+        if self.transaction_in_progress {
+            return Err(eyre!("cannot archive during a transaction"));
+        }
+        if self.archived.is_none() {
+            return Err(eyre!("no archive was saved to increment on"));
+        }
+        let mut written = self.checkpoint().await?.into_iter().collect::<Vec<_>>();
+        written.sort();
+        let paths = written
+            .into_iter()
+            .map(|index| self.superblock.layout.path(index));
+        save_objects(&self.op, paths, w).await
+    }
+
+    /// Start a new archive epoch, returns the blocks written since the previous one.
+    async fn checkpoint(&mut self) -> Result<HashSet<BlockIndex>> {
+        self.superblock.archive_epoch += 1;
+        self.mark_superblock_dirty();
+        self.submit().await?;
+        Ok(self.archived.replace(HashSet::new()).unwrap_or_default())
     }
 
     pub async fn load_archive<R: Read>(&mut self, r: R) -> Result<R> {
         load(&self.op, r).await
     }
 }
+
+#[cfg(test)]
+mod test {
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::storage::BLOCK_SIZE;
+
+    /// Create table `t` of 120 INTEGER columns, returns how many of its rows fit in a data block.
+    pub(crate) async fn create_wide_table(aidb: &mut Aidb) -> usize {
+        let columns = (0..120).map(|i| format!("c{i} INTEGER")).join(", ");
+        aidb.query(format!("CREATE TABLE t ({columns});"))
+            .await
+            .unwrap();
+        let row_size = aidb.get_schema("t").await.unwrap().row_size();
+        // rows follow the 9 bytes header while more than a row is left
+        (BLOCK_SIZE - 10) / row_size
+    }
+
+    #[tokio::test]
+    async fn test_insert() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER UNIQUE, x REAL, s TEXT);")
+            .await
+            .unwrap();
+        let rows = vec![
+            vec![
+                Value::Integer(1),
+                Value::Real(0.5),
+                Value::Text("it's 'quoted'".to_owned()),
+            ],
+            vec![Value::Integer(2), Value::Null, Value::Null],
+        ];
+        assert_eq!(aidb.insert("t", rows.clone()).await.unwrap(), 2);
+        let Response::Rows { rows: selected, .. } = aidb.query("SELECT * FROM t;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(selected, rows);
+
+        // failed inserts are rolled back like failed queries
+        assert!(
+            aidb.insert("t", vec![vec![Value::Integer(1), Value::Null, Value::Null]])
+                .await
+                .is_err()
+        );
+        assert!(
+            aidb.insert("t", vec![vec![Value::Integer(3)]])
+                .await
+                .is_err()
+        );
+        assert!(aidb.insert("missing", vec![]).await.is_err());
+        let Response::Rows { rows: selected, .. } = aidb.query("SELECT * FROM t;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(selected, rows);
+    }
+
+    #[tokio::test]
+    async fn test_blob() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, b BLOB);")
+            .await
+            .unwrap();
+        // not valid UTF-8
+        let bytes = vec![0xDE, 0xAD, 0xBE, 0xEF, 0x00, 0xFF];
+        aidb.query("INSERT INTO t VALUES (1, x'DEADbeef00ff'), (2, x''), (3, NULL);")
+            .await
+            .unwrap();
+        aidb.insert(
+            "t",
+            vec![vec![Value::Integer(4), Value::Blob(bytes.clone())]],
+        )
+        .await
+        .unwrap();
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::Integer(1), Value::Blob(bytes.clone())],
+                vec![Value::Integer(2), Value::Blob(vec![])],
+                vec![Value::Integer(3), Value::Null],
+                vec![Value::Integer(4), Value::Blob(bytes.clone())],
+            ]
+        );
+        assert_eq!(rows[0][1].to_string(), "x'DEADBEEF00FF'");
+
+        let Response::Rows { rows, .. } = aidb
+            .query("SELECT id FROM t WHERE b = x'deadbeef00ff';")
+            .await
+            .unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, vec![vec![Value::Integer(1)], vec![Value::Integer(4)]]);
+        let Response::Rows { rows, .. } = aidb.query("SELECT LENGTH(x'00FF');").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, vec![vec![Value::Integer(2)]]);
+        assert!(aidb.query("SELECT * FROM t WHERE b = 'a';").await.is_err());
+        assert!(
+            aidb.query("INSERT INTO t VALUES (5, x'ABC');")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_special_reals() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, x REAL);")
+            .await
+            .unwrap();
+        for x in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let e = aidb
+                .insert("t", vec![vec![Value::Integer(1), Value::Real(x)]])
+                .await
+                .unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                format!("{} is not a valid REAL", Value::Real(x))
+            );
+        }
+        assert_eq!(Value::Real(f64::NAN).to_string(), "NaN");
+        assert_eq!(Value::Real(f64::NEG_INFINITY).to_string(), "-Infinity");
+        assert!(
+            aidb.query("INSERT INTO t VALUES (1, 1e999);")
+                .await
+                .is_err()
+        );
+        let e = aidb.query("SELECT 1e308 * 10;").await.unwrap_err();
+        assert_eq!(e.to_string(), "real overflow");
+        let e = aidb.query("SELECT 1.5 / 0;").await.unwrap_err();
+        assert_eq!(e.to_string(), "division by zero");
+
+        // negative zero is kept but equals zero
+        aidb.insert("t", vec![vec![Value::Integer(2), Value::Real(-0.0)]])
+            .await
+            .unwrap();
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t WHERE x = 0;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, vec![vec![Value::Integer(2), Value::Real(-0.0)]]);
+        let Value::Real(x) = rows[0][1] else {
+            unreachable!()
+        };
+        assert!(x.is_sign_negative());
+    }
+
+    #[tokio::test]
+    async fn test_insert_column_list() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (a INTEGER, b INTEGER, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t (b, a) VALUES (2, 1);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t (a) VALUES (3), (4);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t (s, b) VALUES ('x', 5);")
+            .await
+            .unwrap();
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::Integer(1), Value::Integer(2), Value::Null],
+                vec![Value::Integer(3), Value::Null, Value::Null],
+                vec![Value::Integer(4), Value::Null, Value::Null],
+                vec![Value::Null, Value::Integer(5), Value::Text("x".to_owned())],
+            ]
+        );
+
+        // values must still match the column list
+        let e = aidb
+            .query("INSERT INTO t VALUES (1, 2);")
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "expected 3 values, found 2");
+        for sql in [
+            "INSERT INTO t (a, b) VALUES (1);",
+            "INSERT INTO t (a) VALUES (1, 2);",
+            "INSERT INTO t (a, a) VALUES (1, 2);",
+            "INSERT INTO t (c) VALUES (1);",
+        ] {
+            assert!(aidb.query(sql).await.is_err(), "{sql}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insert_on_duplicate() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER UNIQUE, n INTEGER, s TEXT);")
+            .await
+            .unwrap();
+        let upsert = async |aidb: &mut Aidb, sql: &str| {
+            let Response::Meta { affected_rows } = aidb.query(sql).await.unwrap() else {
+                panic!("meta expected");
+            };
+            affected_rows
+        };
+        assert_eq!(
+            upsert(
+                &mut aidb,
+                "INSERT INTO t VALUES (1, 1, 'a'), (2, 2, 'b') ON DUPLICATE KEY UPDATE n = 0;"
+            )
+            .await,
+            2
+        );
+        assert_eq!(
+            upsert(
+                &mut aidb,
+                "INSERT INTO t (id, n) VALUES (1, 5) ON DUPLICATE KEY UPDATE n = 5, s = 'x';"
+            )
+            .await,
+            2
+        );
+        assert_eq!(
+            upsert(
+                &mut aidb,
+                "INSERT INTO t (id, n) VALUES (3, 3), (2, 9) ON DUPLICATE KEY UPDATE t.n = 9;"
+            )
+            .await,
+            3
+        );
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(
+            rows,
+            vec![
+                vec![
+                    Value::Integer(1),
+                    Value::Integer(5),
+                    Value::Text("x".to_owned())
+                ],
+                vec![
+                    Value::Integer(2),
+                    Value::Integer(9),
+                    Value::Text("b".to_owned())
+                ],
+                vec![Value::Integer(3), Value::Integer(3), Value::Null],
+            ]
+        );
+
+        assert!(
+            aidb.query("INSERT INTO t VALUES (1, 0, 'a');")
+                .await
+                .is_err()
+        );
+        assert!(
+            aidb.query("INSERT INTO t VALUES (1, 0, 'a') ON DUPLICATE KEY UPDATE id = 4;")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replace_into() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER UNIQUE, n INTEGER, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("CREATE INDEX tn ON t (n) USING HASH;")
+            .await
+            .unwrap();
+        let replace = async |aidb: &mut Aidb, sql: &str| {
+            let Response::Meta { affected_rows } = aidb.query(sql).await.unwrap() else {
+                panic!("meta expected");
+            };
+            affected_rows
+        };
+        assert_eq!(
+            replace(&mut aidb, "REPLACE INTO t VALUES (1, 1, 'a'), (2, 2, 'b');").await,
+            2
+        );
+        // unspecified columns of the replaced row become NULL
+        assert_eq!(
+            replace(&mut aidb, "REPLACE INTO t (id, n) VALUES (1, 9);").await,
+            2
+        );
+        let Response::Rows { rows, .. } =
+            aidb.query("SELECT * FROM t WHERE id = 1;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(
+            rows,
+            vec![vec![Value::Integer(1), Value::Integer(9), Value::Null]]
+        );
+        // the old entry of the non-unique index is gone along with the row
+        let Response::Rows { rows, .. } =
+            aidb.query("SELECT id FROM t WHERE n = 1;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert!(rows.is_empty());
+        let Response::Rows { rows, .. } =
+            aidb.query("SELECT id FROM t WHERE n = 9;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, vec![vec![Value::Integer(1)]]);
+        assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
+
+        let Response::Rows { rows, .. } = aidb.query("SELECT id FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_auto_increment() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY AUTO_INCREMENT, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t (s) VALUES ('a');").await.unwrap();
+        assert_eq!(aidb.insert_id(), Some(1));
+        aidb.query("INSERT INTO t (s) VALUES ('b'), ('c');")
+            .await
+            .unwrap();
+        assert_eq!(aidb.insert_id(), Some(2));
+        // explicit values move the counter past them
+        aidb.query("INSERT INTO t VALUES (10, 'd');").await.unwrap();
+        assert_eq!(aidb.insert_id(), None);
+        aidb.query("INSERT INTO t VALUES (NULL, 'e');")
+            .await
+            .unwrap();
+        assert_eq!(aidb.insert_id(), Some(11));
+        let Response::Rows { rows, .. } = aidb.query("SELECT id FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(
+            rows,
+            [1, 2, 3, 10, 11]
+                .map(|id| vec![Value::Integer(id)])
+                .to_vec()
+        );
+        let Response::Rows { rows, .. } = aidb.query("SELECT LAST_INSERT_ID();").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, vec![vec![Value::Integer(11)]]);
+
+        assert!(
+            aidb.query("CREATE TABLE u (s TEXT AUTO_INCREMENT);")
+                .await
+                .is_err()
+        );
+        assert!(
+            aidb.query("CREATE TABLE u (a INTEGER AUTO_INCREMENT, b INTEGER AUTO_INCREMENT);")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tables() {
+        let mut aidb = Aidb::new_memory().await;
+        assert!(aidb.tables().await.unwrap().is_empty());
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY AUTO_INCREMENT, s TEXT, x REAL);")
+            .await
+            .unwrap();
+        aidb.query("CREATE TABLE u (id INTEGER, t_id INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("CREATE INDEX ut ON u (t_id) USING HASH;")
+            .await
+            .unwrap();
+
+        let tables = aidb.tables().await.unwrap();
+        assert_eq!(
+            tables.iter().map(|table| table.name.as_str()).collect_vec(),
+            ["t", "u"]
+        );
+        assert_eq!(
+            tables[0]
+                .columns
+                .iter()
+                .map(|column| (column.name.as_str(), column.datatype))
+                .collect_vec(),
+            [
+                ("id", DataType::Integer),
+                ("s", DataType::Text),
+                ("x", DataType::Real)
+            ]
+        );
+        assert_eq!(
+            tables[0].indices,
+            [TableIndex {
+                column: "id".to_owned(),
+                type_: IndexType::BTree,
+                unique: true,
+            }]
+        );
+        assert_eq!(tables[0].auto_increment.as_deref(), Some("id"));
+        assert_eq!(
+            tables[1].indices,
+            [TableIndex {
+                column: "t_id".to_owned(),
+                type_: IndexType::Hash,
+                unique: false,
+            }]
+        );
+        assert_eq!(tables[1].auto_increment, None);
+    }
+
+    #[tokio::test]
+    async fn test_insert_append() {
+        let mut aidb = Aidb::new_memory().await;
+        let rows_per_block = create_wide_table(&mut aidb).await;
+        let row = vec![Value::Integer(1); 120];
+        aidb.insert("t", vec![row.clone(); rows_per_block * 10])
+            .await
+            .unwrap();
+
+        aidb.reset_block_io_log();
+        aidb.insert("t", vec![row.clone()]).await.unwrap();
+        assert!(aidb.get_block_io_log().lookups <= 3);
+
+        aidb.set_reuse_free_slots(true);
+        aidb.reset_block_io_log();
+        aidb.insert("t", vec![row.clone()]).await.unwrap();
+        assert!(aidb.get_block_io_log().lookups >= 10);
+
+        let Response::Rows { rows, .. } = aidb.query("SELECT c0 FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows.len(), rows_per_block * 10 + 2);
+    }
+
+    #[tokio::test]
+    async fn test_query_params() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, s TEXT);")
+            .await
+            .unwrap();
+        let insert = Aidb::prepare("INSERT INTO t VALUES (?, ?), (3, ?);").unwrap();
+        assert_eq!(insert.params(), 3);
+        aidb.query_params(
+            &insert,
+            vec![
+                Value::Integer(1),
+                Value::Text("'; DROP TABLE t; --".to_owned()),
+                Value::Null,
+            ],
+        )
+        .await
+        .unwrap();
+
+        let select = Aidb::prepare("SELECT s FROM t WHERE ? = id AND s = ?;").unwrap();
+        assert_eq!(select.params(), 2);
+        let Response::Rows { rows, .. } = aidb
+            .query_params(
+                &select,
+                vec![
+                    Value::Integer(1),
+                    Value::Text("'; DROP TABLE t; --".to_owned()),
+                ],
+            )
+            .await
+            .unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(
+            rows,
+            vec![vec![Value::Text("'; DROP TABLE t; --".to_owned())]]
+        );
+
+        assert!(
+            aidb.query_params(&select, vec![Value::Integer(1)])
+                .await
+                .is_err()
+        );
+        assert!(
+            aidb.query_params(&insert, vec![Value::Integer(1); 4])
+                .await
+                .is_err()
+        );
+        assert!(aidb.query("SELECT s FROM t WHERE id = ?;").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reopen_from_archive() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 'a'), (2, 'b');")
+            .await
+            .unwrap();
+        let archive = aidb.save_archive(vec![]).await.unwrap();
+
+        let mut aidb = Aidb::new_memory_from_archive(&archive[..]).await.unwrap();
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::Integer(1), Value::Text("a".to_owned())],
+                vec![Value::Integer(2), Value::Text("b".to_owned())],
+            ]
+        );
+        assert!(
+            Aidb::new_memory_from_archive(&b"garbage"[..])
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_incremental_archive() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.set_max_rows(None);
+        assert!(aidb.save_incremental_archive(vec![]).await.is_err());
+        aidb.query("CREATE TABLE t (id INTEGER, s TEXT);")
+            .await
+            .unwrap();
+        let rows = (0..50000)
+            .map(|i: i64| {
+                let s = format!("{:x}", (i as u64).wrapping_mul(0x9e3779b97f4a7c15));
+                vec![Value::Integer(i), Value::Text(s.repeat(4))]
+            })
+            .collect();
+        aidb.insert("t", rows).await.unwrap();
+        let base = aidb.save_archive(vec![]).await.unwrap();
+
+        aidb.query("UPDATE t SET s = 'changed' WHERE id = 42;")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (50000, 'new');")
+            .await
+            .unwrap();
+        let first = aidb.save_incremental_archive(vec![]).await.unwrap();
+        assert!(
+            first.len() * 10 < base.len(),
+            "{} {}",
+            first.len(),
+            base.len()
+        );
+        aidb.query("DELETE FROM t WHERE id = 7;").await.unwrap();
+        aidb.query("START TRANSACTION;").await.unwrap();
+        assert!(aidb.save_incremental_archive(vec![]).await.is_err());
+        aidb.query("ROLLBACK;").await.unwrap();
+        let second = aidb.save_incremental_archive(vec![]).await.unwrap();
+
+        let select = async |aidb: &mut Aidb| {
+            let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap() else {
+                panic!("rows expected");
+            };
+            rows
+        };
+        let expected = select(&mut aidb).await;
+        assert_eq!(expected.len(), 50000);
+        let mut restored = Aidb::new_memory_from_archives([&base[..], &first[..], &second[..]])
+            .await
+            .unwrap();
+        restored.set_max_rows(None);
+        assert_eq!(select(&mut restored).await, expected);
+        assert_eq!(
+            restored.check_integrity().await.unwrap(),
+            Vec::<String>::new()
+        );
+
+        // increments apply in order over their base only
+        assert!(
+            Aidb::new_memory_from_archives([&base[..], &second[..]])
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_open_existing() {
+        let op = Operator::from_config(MemoryConfig::default())
+            .unwrap()
+            .finish();
+        let mut aidb = Aidb::from_op(op.clone()).await.unwrap();
+        assert!(aidb.get_block_io_log().written.contains(&0));
+        aidb.query("CREATE TABLE t (id INTEGER);").await.unwrap();
+
+        let mut aidb = Aidb::from_op(op.clone()).await.unwrap();
+        assert!(aidb.get_block_io_log().written.is_empty());
+        aidb.query("INSERT INTO t VALUES (1);").await.unwrap();
+        assert!(!aidb.get_block_io_log().written.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rollback_text_heap() {
+        let op = Operator::from_config(MemoryConfig::default())
+            .unwrap()
+            .finish();
+        let mut aidb = Aidb::from_op(op.clone()).await.unwrap();
+        aidb.query("CREATE TABLE t (id INTEGER, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (0, 'short text kept in the row');")
+            .await
+            .unwrap();
+        let before = format!("{:?}", aidb.superblock);
+
+        aidb.query("START TRANSACTION;").await.unwrap();
+        let s = "x".repeat(30000);
+        for i in 1..10 {
+            aidb.query(format!("INSERT INTO t VALUES ({i}, '{s}');"))
+                .await
+                .unwrap();
+        }
+        aidb.query("CREATE TABLE u (id INTEGER);").await.unwrap();
+        aidb.query("ROLLBACK;").await.unwrap();
+        assert_eq!(format!("{:?}", aidb.superblock), before);
+
+        // nothing allocated by the transaction is referenced from storage
+        let mut aidb = Aidb::from_op(op.clone()).await.unwrap();
+        assert_eq!(format!("{:?}", aidb.superblock), before);
+        assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
+        assert!(aidb.query("SELECT * FROM u;").await.is_err());
+        aidb.query(format!("INSERT INTO t VALUES (1, '{s}');"))
+            .await
+            .unwrap();
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1][1], Value::Text(s));
+    }
+
+    #[tokio::test]
+    async fn test_rollback_ddl() {
+        let op = Operator::from_config(MemoryConfig::default())
+            .unwrap()
+            .finish();
+        let mut aidb = Aidb::from_op(op.clone()).await.unwrap();
+        aidb.query("CREATE TABLE t (id INTEGER, x INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("CREATE TABLE v (id INTEGER);").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 1), (2, 2);")
+            .await
+            .unwrap();
+
+        aidb.query("START TRANSACTION;").await.unwrap();
+        aidb.query("CREATE TABLE u (id INTEGER);").await.unwrap();
+        aidb.query("INSERT INTO u VALUES (1);").await.unwrap();
+        aidb.query("CREATE INDEX tx ON t (x);").await.unwrap();
+        aidb.query("DROP TABLE v;").await.unwrap();
+        aidb.query("DROP TABLE t;").await.unwrap();
+        aidb.query("ROLLBACK;").await.unwrap();
+
+        for mut aidb in [aidb, Aidb::from_op(op).await.unwrap()] {
+            let Response::Rows { rows, .. } = aidb.query("SHOW TABLES;").await.unwrap() else {
+                panic!("rows expected");
+            };
+            assert_eq!(
+                rows,
+                vec![
+                    vec![Value::Text("t".to_owned())],
+                    vec![Value::Text("v".to_owned())]
+                ]
+            );
+            // the index is gone as well
+            let tree = aidb
+                .explain_tree("SELECT id FROM t WHERE x = 1;")
+                .await
+                .unwrap();
+            assert_eq!(tree.children[0].children[0].kind, "Scan");
+            let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap() else {
+                panic!("rows expected");
+            };
+            assert_eq!(rows.len(), 2);
+            assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_statement_in_transaction() {
+        let op = Operator::from_config(MemoryConfig::default())
+            .unwrap()
+            .finish();
+        let mut aidb = Aidb::from_op(op.clone()).await.unwrap();
+        aidb.query("CREATE TABLE t (id INTEGER UNIQUE);")
+            .await
+            .unwrap();
+        aidb.query("START TRANSACTION;").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (1);").await.unwrap();
+        aidb.query("CREATE TABLE u (id INTEGER);").await.unwrap();
+        aidb.query("SAVEPOINT s1;").await.unwrap();
+        // only the failed statement is undone, along with (2) if it was inserted before (1)
+        assert!(aidb.query("INSERT INTO t VALUES (2), (1);").await.is_err());
+        assert!(aidb.query("CREATE TABLE u (id INTEGER);").await.is_err());
+        assert!(aidb.query("SELECT * FROM v;").await.is_err());
+        assert!(aidb.in_transaction());
+        aidb.query("INSERT INTO u VALUES (3);").await.unwrap();
+        aidb.query("ROLLBACK TO s1;").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (2);").await.unwrap();
+        aidb.query("COMMIT;").await.unwrap();
+
+        let mut aidb = Aidb::from_op(op).await.unwrap();
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, vec![vec![Value::Integer(1)], vec![Value::Integer(2)]]);
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM u;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert!(rows.is_empty());
+        assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_statement_undo() {
+        let mut aidb = Aidb::new_memory().await;
+        for i in 0..20 {
+            aidb.query(format!("CREATE TABLE t{i} (id INTEGER UNIQUE);"))
+                .await
+                .unwrap();
+        }
+        aidb.query("CREATE TABLE u (id INTEGER UNIQUE);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO u VALUES (1);").await.unwrap();
+        aidb.query("START TRANSACTION;").await.unwrap();
+        for i in 0..20 {
+            aidb.query(format!("INSERT INTO t{i} VALUES (1);"))
+                .await
+                .unwrap();
+        }
+        aidb.query("INSERT INTO u VALUES (2);").await.unwrap();
+        let dirty = aidb.blocks_dirty.len();
+        assert!(dirty > 40);
+
+        // a statement only copies the blocks it may change
+        aidb.statement_undo = Some(StatementUndo::new(aidb.superblock.clone()));
+        let stmt = Aidb::parse("INSERT INTO u VALUES (3), (1);").unwrap();
+        assert!(aidb.dispatch(stmt).await.is_err());
+        let undo = aidb.statement_undo.take().unwrap();
+        assert!(undo.blocks.len() < 10);
+        aidb.undo_statement(undo);
+        assert_eq!(aidb.blocks_dirty.len(), dirty);
+
+        aidb.query("COMMIT;").await.unwrap();
+        let Response::Rows { rows, .. } = aidb.query("SELECT id FROM u;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, vec![vec![Value::Integer(1)], vec![Value::Integer(2)]]);
+        assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_savepoint() {
+        async fn ids(aidb: &mut Aidb) -> Vec<i64> {
+            let Response::Rows { rows, .. } = aidb.query("SELECT id FROM t;").await.unwrap() else {
+                panic!("rows expected");
+            };
+            rows.into_iter()
+                .map(|row| match row[0] {
+                    Value::Integer(id) => id,
+                    _ => panic!("integer expected"),
+                })
+                .collect()
+        }
+
+        let op = Operator::from_config(MemoryConfig::default())
+            .unwrap()
+            .finish();
+        let mut aidb = Aidb::from_op(op.clone()).await.unwrap();
+        aidb.query("CREATE TABLE t (id INTEGER UNIQUE, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("START TRANSACTION;").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 'a');").await.unwrap();
+        aidb.query("SAVEPOINT s1;").await.unwrap();
+        aidb.query(format!("INSERT INTO t VALUES (2, '{}');", "b".repeat(1000)))
+            .await
+            .unwrap();
+        aidb.query("SAVEPOINT s2;").await.unwrap();
+        for i in 3..100 {
+            aidb.query(format!("INSERT INTO t VALUES ({i}, 'c');"))
+                .await
+                .unwrap();
+        }
+        aidb.query("CREATE TABLE u (id INTEGER);").await.unwrap();
+        aidb.query("ROLLBACK TO SAVEPOINT s2;").await.unwrap();
+        assert_eq!(ids(&mut aidb).await, [1, 2]);
+        let Response::Rows { rows, .. } = aidb.query("SHOW TABLES;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, vec![vec![Value::Text("t".to_owned())]]);
+
+        // s2 is kept after rolling back to it
+        aidb.query("INSERT INTO t VALUES (3, 'c');").await.unwrap();
+        aidb.query("ROLLBACK TO s2;").await.unwrap();
+        assert_eq!(ids(&mut aidb).await, [1, 2]);
+        aidb.query("ROLLBACK TO s1;").await.unwrap();
+        assert_eq!(ids(&mut aidb).await, [1]);
+        // s2 is discarded by rolling back to an earlier savepoint, naming it leaves the
+        // transaction and its remaining savepoints as they are
+        aidb.query("INSERT INTO t VALUES (3, 'c');").await.unwrap();
+        let e = aidb.query("ROLLBACK TO s2;").await.unwrap_err();
+        assert_eq!(e.to_string(), "SAVEPOINT s2 does not exist");
+        assert!(aidb.query("RELEASE SAVEPOINT s2;").await.is_err());
+        assert!(aidb.in_transaction());
+        assert_eq!(ids(&mut aidb).await, [1, 3]);
+        aidb.query("ROLLBACK TO s1;").await.unwrap();
+        assert_eq!(ids(&mut aidb).await, [1]);
+        aidb.query("ROLLBACK;").await.unwrap();
+
+        aidb.query("START TRANSACTION;").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (4, 'd');").await.unwrap();
+        aidb.query("SAVEPOINT s1;").await.unwrap();
+        aidb.query("RELEASE SAVEPOINT s1;").await.unwrap();
+        assert!(aidb.query("ROLLBACK TO s1;").await.is_err());
+        assert!(aidb.in_transaction());
+        assert_eq!(ids(&mut aidb).await, [4]);
+        aidb.query("ROLLBACK;").await.unwrap();
+
+        aidb.query("START TRANSACTION;").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (5, 'e');").await.unwrap();
+        aidb.query("SAVEPOINT s1;").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (6, 'f');").await.unwrap();
+        aidb.query("ROLLBACK TO s1;").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (7, 'g');").await.unwrap();
+        aidb.query("COMMIT;").await.unwrap();
+
+        let mut aidb = Aidb::from_op(op).await.unwrap();
+        assert_eq!(ids(&mut aidb).await, [5, 7]);
+        assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_open_reader() {
+        async fn count(aidb: &mut Aidb) -> usize {
+            let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap() else {
+                panic!("rows expected");
+            };
+            rows.len()
+        }
+
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER);").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (1);").await.unwrap();
+        let mut reader = aidb.open_reader().await.unwrap();
+        assert_eq!(count(&mut reader).await, 1);
+        assert!(reader.query("INSERT INTO t VALUES (2);").await.is_err());
+
+        aidb.query("INSERT INTO t VALUES (2);").await.unwrap();
+        reader.refresh().await.unwrap();
+        assert_eq!(count(&mut reader).await, 2);
+
+        // uncommitted changes stay invisible
+        aidb.query("START TRANSACTION;").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (3);").await.unwrap();
+        aidb.query("CREATE TABLE u (id INTEGER);").await.unwrap();
+        reader.refresh().await.unwrap();
+        assert_eq!(count(&mut reader).await, 2);
+        assert!(reader.query("SELECT * FROM u;").await.is_err());
+        aidb.query("COMMIT;").await.unwrap();
+        reader.refresh().await.unwrap();
+        assert_eq!(count(&mut reader).await, 3);
+        assert_eq!(count(&mut aidb).await, 3);
+
+        // the cache is kept until something is committed, even if only rows change in place
+        reader.refresh().await.unwrap();
+        assert!(!reader.blocks.is_empty());
+        aidb.query("UPDATE t SET id = 4 WHERE id = 3;")
+            .await
+            .unwrap();
+        reader.refresh().await.unwrap();
+        assert!(reader.blocks.is_empty());
+        let Response::Rows { rows, .. } =
+            reader.query("SELECT * FROM t WHERE id = 4;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows.len(), 1);
+
+        assert!(aidb.refresh().await.is_err());
+        assert!(Aidb::is_read("SELECT * FROM t WHERE id = 1"));
+        assert!(Aidb::is_read("EXPLAIN SELECT * FROM t"));
+        assert!(Aidb::is_read("SHOW TABLES"));
+        assert!(!Aidb::is_read("INSERT INTO t VALUES (4)"));
+        assert!(!Aidb::is_read("COMMIT"));
+        assert!(!Aidb::is_read("SELECT * FROM t WHERE id = ?"));
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        async fn snapshot(op: &Operator) -> Vec<(String, Vec<u8>)> {
+            let mut files = vec![];
+            for entry in op.list("/").await.unwrap() {
+                let data = op.read(entry.path()).await.unwrap().to_vec();
+                files.push((entry.path().to_owned(), data));
+            }
+            files.sort();
+            files
+        }
+
+        let op = Operator::from_config(MemoryConfig::default())
+            .unwrap()
+            .finish();
+        let mut aidb = Aidb::from_op(op.clone()).await.unwrap();
+        aidb.query("CREATE TABLE t (id INTEGER);").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (1);").await.unwrap();
+        let before = snapshot(&op).await;
+
+        let mut aidb = Aidb::from_op_read_only(op.clone()).await.unwrap();
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, vec![vec![Value::Integer(1)]]);
+        assert!(!aidb.blocks.is_empty());
+        aidb.query("SHOW TABLES;").await.unwrap();
+        aidb.query("DESCRIBE t;").await.unwrap();
+        aidb.query("EXPLAIN SELECT * FROM t;").await.unwrap();
+        for sql in [
+            "INSERT INTO t VALUES (2);",
+            "UPDATE t SET id = 2;",
+            "DELETE FROM t;",
+            "CREATE TABLE u (id INTEGER);",
+            "CREATE INDEX i ON t (id);",
+            "DROP TABLE t;",
+        ] {
+            let e = aidb.query(sql).await.unwrap_err();
+            assert_eq!(e.to_string(), "database is read-only");
+        }
+        assert!(
+            aidb.insert("t", vec![vec![Value::Integer(2)]])
+                .await
+                .is_err()
+        );
+        assert!(aidb.get_block_io_log().written.is_empty());
+        assert_eq!(snapshot(&op).await, before);
+    }
+
+    #[tokio::test]
+    async fn test_stmt_cache() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER);").await.unwrap();
+        assert_eq!(aidb.stmt_cache.parses, 1);
+        for _ in 0..3 {
+            aidb.query("SELECT * FROM t;").await.unwrap();
+        }
+        assert_eq!(aidb.stmt_cache.parses, 2);
+
+        // entries survive schema changes since planning happens after the cache
+        assert!(aidb.query("SELECT * FROM u;").await.is_err());
+        aidb.query("CREATE TABLE u (id INTEGER);").await.unwrap();
+        aidb.query("SELECT * FROM u;").await.unwrap();
+        assert_eq!(aidb.stmt_cache.parses, 4);
+
+        // syntax errors are not cached
+        assert!(aidb.query("SELEC 1;").await.is_err());
+        assert!(aidb.query("SELEC 1;").await.is_err());
+        assert_eq!(aidb.stmt_cache.parses, 6);
+
+        aidb.set_stmt_cache_capacity(1);
+        aidb.query("SELECT 1;").await.unwrap();
+        aidb.query("SELECT * FROM t;").await.unwrap();
+        aidb.query("SELECT 1;").await.unwrap();
+        assert_eq!(aidb.stmt_cache.parses, 9);
+
+        aidb.set_stmt_cache_capacity(0);
+        aidb.query("SELECT 1;").await.unwrap();
+        aidb.query("SELECT 1;").await.unwrap();
+        assert_eq!(aidb.stmt_cache.parses, 11);
+    }
+}
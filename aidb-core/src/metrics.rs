@@ -0,0 +1,137 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+use tracing::info;
+
+use crate::{Aidb, Response, Result};
+
+/// Counters of [`crate::BlockIoLog`] at the start of a statement, reported as the
+/// `aidb_core::metrics` tracing event once it finishes.
+pub(crate) struct QueryMetrics {
+    statement: &'static str,
+    lookups: usize,
+    reads: usize,
+    writes: usize,
+    /// there is no clock on wasm, where the embedder measures time itself
+    #[cfg(not(target_arch = "wasm32"))]
+    start: Instant,
+}
+
+impl QueryMetrics {
+    pub(crate) fn start(aidb: &Aidb, statement: &'static str) -> Self {
+        Self {
+            statement,
+            lookups: aidb.log.lookups,
+            reads: aidb.log.reads,
+            writes: aidb.log.writes,
+            #[cfg(not(target_arch = "wasm32"))]
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn finish(self, aidb: &Aidb, r: &Result<Response>) {
+        let rows = match r {
+            Ok(Response::Rows { rows, .. }) => rows.len(),
+            Ok(Response::Meta { affected_rows }) => *affected_rows,
+            Err(_) => 0,
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        let elapsed_us = self.start.elapsed().as_micros() as u64;
+        #[cfg(target_arch = "wasm32")]
+        let elapsed_us = tracing::field::Empty;
+        info!(
+            target: "aidb_core::metrics",
+            statement = self.statement,
+            ok = r.is_ok(),
+            rows,
+            lookups = aidb.log.lookups - self.lookups,
+            reads = aidb.log.reads - self.reads,
+            writes = aidb.log.writes - self.writes,
+            elapsed_us,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::Value;
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Capture {
+        /// Value of `field` in the last metrics event.
+        fn field(&self, field: &str) -> String {
+            let output = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+            let line = output
+                .lines()
+                .rfind(|line| line.contains("aidb_core::metrics"))
+                .unwrap()
+                .to_owned();
+            let start = line.find(&format!(" {field}=")).unwrap() + field.len() + 2;
+            line[start..].split(' ').next().unwrap().to_owned()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_metrics() {
+        let capture = Capture::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let capture = capture.clone();
+                move || capture.clone()
+            })
+            .with_ansi(false)
+            .with_max_level(tracing::Level::INFO)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, x INTEGER);")
+            .await
+            .unwrap();
+        let rows = (0..2000)
+            .map(|i| vec![Value::Integer(i), Value::Integer(i)])
+            .collect();
+        aidb.insert("t", rows).await.unwrap();
+        assert_eq!(capture.field("statement"), "\"INSERT\"");
+        assert_eq!(capture.field("rows"), "2000");
+        assert_ne!(capture.field("writes"), "0");
+        aidb.query("FLUSH TABLES;").await.unwrap();
+
+        aidb.query("SELECT x FROM t WHERE id = 1234;")
+            .await
+            .unwrap();
+        assert_eq!(capture.field("statement"), "\"SELECT\"");
+        assert_eq!(capture.field("ok"), "true");
+        assert_eq!(capture.field("rows"), "1");
+        assert_eq!(capture.field("writes"), "0");
+        // schema, then the b+ tree from root to leaf, then the data block
+        let reads: usize = capture.field("reads").parse().unwrap();
+        assert!(reads <= 10, "{reads} reads");
+        aidb.query("SELECT x FROM t WHERE id = 1234;")
+            .await
+            .unwrap();
+        assert_eq!(capture.field("reads"), "0");
+        assert_ne!(capture.field("lookups"), "0");
+
+        assert!(aidb.query("SELECT * FROM u;").await.is_err());
+        assert_eq!(capture.field("ok"), "false");
+    }
+}
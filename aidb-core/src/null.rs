@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+
+use binrw::{BinRead, BinWrite, binrw};
+use eyre::{Result, eyre};
+
+use crate::{
+    Aidb,
+    storage::{BLOCK_SIZE, BlockIndex, BlockPtr, DataPointer},
+};
+
+const NULL_N: usize = (BLOCK_SIZE - 10) / 10;
+
+/// Block of rows holding NULL in an indexed column, which are kept out of the index since NULL
+/// has no place in the order of keys.
+#[binrw]
+#[brw(little)]
+#[derive(Debug)]
+struct NullList {
+    next: BlockPtr,
+    #[br(temp)]
+    #[bw(calc = records.len() as u16)]
+    len: u16,
+    /// empty only after removals
+    #[br(count = len)]
+    #[bw(assert(records.len() <= NULL_N))]
+    records: Vec<DataPointer>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) enum NullListState {
+    #[default]
+    Initialized,
+    Running {
+        next: BlockPtr,
+        stream: std::vec::IntoIter<DataPointer>,
+    },
+    Done,
+}
+
+impl Aidb {
+    async fn read_null_list(&mut self, index: BlockIndex) -> Result<NullList> {
+        let mut block = self.get_block(index).await?;
+        let list = NullList::read(&mut block.cursor())?;
+        self.put_block(index, block);
+        Ok(list)
+    }
+
+    async fn write_null_list(&mut self, index: BlockIndex, list: NullList) -> Result<()> {
+        let mut block = self.get_block(index).await?;
+        list.write(&mut block.cursor())?;
+        self.put_block(index, block);
+        self.mark_block_dirty(index);
+        Ok(())
+    }
+
+    fn new_null_list(&mut self, record: DataPointer) -> Result<BlockPtr> {
+        let (index, mut block) = self.new_block();
+        NullList {
+            next: BlockPtr::NONE,
+            records: vec![record],
+        }
+        .write(&mut block.cursor())?;
+        self.put_block(index, block);
+        self.mark_block_dirty(index);
+        Ok(BlockPtr::to(index))
+    }
+
+    /// Add a row to the list starting at `head`, returns the head which is new if `head` is none.
+    pub(crate) async fn insert_null(
+        &mut self,
+        head: BlockPtr,
+        record: DataPointer,
+    ) -> Result<BlockPtr> {
+        let Some(mut index) = head.get() else {
+            return self.new_null_list(record);
+        };
+        loop {
+            let mut list = self.read_null_list(index).await?;
+            if list.records.len() < NULL_N {
+                list.records.push(record);
+                self.write_null_list(index, list).await?;
+                return Ok(head);
+            }
+            let Some(next) = list.next.get() else {
+                list.next = self.new_null_list(record)?;
+                self.write_null_list(index, list).await?;
+                return Ok(head);
+            };
+            index = next;
+        }
+    }
+
+    /// Remove a row from the list, blocks are left in place even if emptied.
+    pub(crate) async fn remove_null(&mut self, head: BlockPtr, record: &DataPointer) -> Result<()> {
+        let mut next = head;
+        while let Some(index) = next.get() {
+            let mut list = self.read_null_list(index).await?;
+            if let Some(position) = list.records.iter().position(|r| r == record) {
+                list.records.remove(position);
+                return self.write_null_list(index, list).await;
+            }
+            next = list.next;
+        }
+        Err(eyre!("{record} not found in NULL list"))
+    }
+
+    pub(crate) async fn select_null(
+        &mut self,
+        head: BlockPtr,
+        state: &mut NullListState,
+    ) -> Result<Option<DataPointer>> {
+        loop {
+            match state {
+                NullListState::Initialized => {
+                    *state = NullListState::Running {
+                        next: head,
+                        stream: vec![].into_iter(),
+                    };
+                }
+                NullListState::Running { next, stream } => {
+                    if let Some(record) = stream.next() {
+                        return Ok(Some(record));
+                    }
+                    match next.get() {
+                        None => *state = NullListState::Done,
+                        Some(index) => {
+                            let list = self.read_null_list(index).await?;
+                            *next = list.next;
+                            *stream = list.records.into_iter();
+                        }
+                    }
+                }
+                NullListState::Done => return Ok(None),
+            }
+        }
+    }
+
+    /// Check that the list terminates, returns all records. `head` is checked by the caller.
+    pub(crate) async fn check_null(
+        &mut self,
+        head: BlockIndex,
+        visited: &mut HashSet<BlockIndex>,
+        context: &str,
+        problems: &mut Vec<String>,
+    ) -> Result<Vec<DataPointer>> {
+        let mut records = vec![];
+        let mut index = head;
+        loop {
+            let list = match self.read_null_list(index).await {
+                Ok(list) => list,
+                Err(e) => {
+                    problems.push(format!("{context}: NULL list {index} is unreadable: {e}"));
+                    break;
+                }
+            };
+            records.extend(list.records);
+            match list.next.get() {
+                Some(next) if self.check_block(next, visited, context, problems) => index = next,
+                _ => break,
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(i: usize) -> DataPointer {
+        DataPointer {
+            block: i as BlockIndex,
+            offset: (i % 1000) as u16,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_null_list_overflow() {
+        let mut aidb = Aidb::new_memory().await;
+        let mut head = BlockPtr::NONE;
+        assert!(aidb.remove_null(head, &record(0)).await.is_err());
+        for i in 0..NULL_N + 10 {
+            head = aidb.insert_null(head, record(i)).await.unwrap();
+        }
+        aidb.remove_null(head, &record(3)).await.unwrap();
+        assert!(aidb.remove_null(head, &record(3)).await.is_err());
+        let mut state = Default::default();
+        let mut records = vec![];
+        while let Some(ptr) = aidb.select_null(head, &mut state).await.unwrap() {
+            records.push(ptr);
+        }
+        assert_eq!(
+            records,
+            (0..NULL_N + 10)
+                .filter(|i| *i != 3)
+                .map(record)
+                .collect::<Vec<_>>()
+        );
+    }
+}
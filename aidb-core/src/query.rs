@@ -1,7 +1,23 @@
-use eyre::Result;
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use eyre::{Result, eyre};
 use serde::{Deserialize, Serialize};
 
-use crate::{Aidb, data::Value, schema::Column, sql::SqlStmt};
+use crate::{
+    Aidb,
+    data::Value,
+    schema::Column,
+    select::CursorPlan,
+    sql::SqlStmt,
+    storage::{Block, BlockIndex},
+    superblock::SuperBlock,
+};
 
 pub type Row = Vec<Value>;
 
@@ -10,38 +26,295 @@ pub enum Response {
     Rows {
         columns: Vec<Column>,
         rows: Vec<Row>,
+        /// rows beyond [`crate::Aidb::set_max_rows`] were dropped
+        #[serde(default)]
+        truncated: bool,
     },
     Meta {
         affected_rows: usize,
     },
 }
 
+/// Rows of a statement that only reads, fetched one at a time with [`Cursor::next`], see
+/// [`Aidb::open_cursor`]. SELECT and UNION run their plan as rows are fetched, dropping the
+/// cursor stops the plan where it is and returns the blocks it holds to the cache.
+pub struct Cursor<'a> {
+    db: &'a mut Aidb,
+    columns: Vec<Column>,
+    source: CursorSource,
+}
+
+enum CursorSource {
+    Plan(CursorPlan),
+    /// rows of other statements, collected when the cursor is opened
+    Rows(std::vec::IntoIter<Row>),
+    Done,
+}
+
+impl Cursor<'_> {
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    /// The next row, `None` once all rows are fetched. The cursor is done after an error.
+    pub async fn next(&mut self) -> Result<Option<Row>> {
+        match &mut self.source {
+            CursorSource::Plan(plan) => {
+                let row = self.db.execute_cursor_plan(plan).await;
+                if !matches!(row, Ok(Some(_))) {
+                    plan.reset(self.db);
+                    self.source = CursorSource::Done;
+                }
+                row
+            }
+            CursorSource::Rows(rows) => Ok(rows.next()),
+            CursorSource::Done => Ok(None),
+        }
+    }
+}
+
+impl Drop for Cursor<'_> {
+    fn drop(&mut self) {
+        if let CursorSource::Plan(plan) = &mut self.source {
+            plan.reset(self.db);
+        }
+    }
+}
+
+/// State of a transaction at a savepoint. Dirty blocks are copied since later statements modify
+/// them in place.
+#[derive(Debug)]
+pub(crate) struct Savepoint {
+    name: String,
+    superblock: SuperBlock,
+    blocks_dirty: HashMap<BlockIndex, Block>,
+}
+
+/// State of the transaction before the statement in progress. Blocks are copied the first time
+/// the statement may change them, blocks it dirties first need no copy as they revert to their
+/// stored content.
+#[derive(Debug)]
+pub(crate) struct StatementUndo {
+    superblock: SuperBlock,
+    /// content before the statement, `None` for blocks that weren't dirty
+    pub(crate) blocks: HashMap<BlockIndex, Option<Block>>,
+}
+
+impl StatementUndo {
+    pub(crate) fn new(superblock: SuperBlock) -> Self {
+        Self {
+            superblock,
+            blocks: HashMap::new(),
+        }
+    }
+
+    /// Keep the content of a block dirtied by earlier statements unless it's already kept.
+    pub(crate) fn keep(&mut self, index: BlockIndex, block: impl FnOnce() -> Block) {
+        self.blocks.entry(index).or_insert_with(|| Some(block()));
+    }
+
+    /// Remember a block first dirtied by the statement.
+    pub(crate) fn dirtied(&mut self, index: BlockIndex) {
+        self.blocks.entry(index).or_insert(None);
+    }
+}
+
+/// Aborts queries between rows once cancelled or once its check returns true.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    check: Option<Arc<dyn Fn() -> bool + Send + Sync>>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel when `check` returns true, e.g. when a deadline has passed.
+    pub fn with_check(check: impl Fn() -> bool + Send + Sync + 'static) -> Self {
+        Self {
+            cancelled: Default::default(),
+            check: Some(Arc::new(check)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.check.as_ref().is_some_and(|check| check())
+    }
+}
+
+impl std::fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CancelToken")
+            .field("cancelled", &self.cancelled)
+            .finish_non_exhaustive()
+    }
+}
+
 impl Aidb {
+    /// Set the token checked by subsequent queries, `None` lets them run to completion.
+    pub fn set_cancel_token(&mut self, token: Option<CancelToken>) {
+        self.cancel_token = token;
+    }
+
+    /// Open a cursor over the rows of a statement that only reads, see [`Aidb::is_read`]. Unlike
+    /// [`Aidb::query`] rows are not collected, nor capped by [`Aidb::set_max_rows`].
+    pub async fn open_cursor(&mut self, sql: impl AsRef<str>) -> Result<Cursor<'_>> {
+        let stmt = self.stmt_cache.parse(sql.as_ref())?;
+        if !stmt.is_read() {
+            return Err(eyre!("only statements that read can be run with a cursor"));
+        }
+        let (columns, source) = if let SqlStmt::Select { .. } | SqlStmt::Union { .. } = stmt {
+            let (columns, plan) = self.build_cursor_plan(stmt).await?;
+            (columns, CursorSource::Plan(plan))
+        } else {
+            let Response::Rows { columns, rows, .. } = self.query_stmt(stmt).await? else {
+                unreachable!()
+            };
+            (columns, CursorSource::Rows(rows.into_iter()))
+        };
+        Ok(Cursor {
+            db: self,
+            columns,
+            source,
+        })
+    }
+
+    /// Whether statements are accumulating in a transaction until COMMIT or ROLLBACK.
+    pub fn in_transaction(&self) -> bool {
+        self.transaction_in_progress
+    }
+
+    /// Client the transaction in progress belongs to, `None` outside a transaction or if no
+    /// client claimed it.
+    pub fn transaction_owner(&self) -> Option<u32> {
+        self.transaction_owner
+            .filter(|_| self.transaction_in_progress)
+    }
+
+    /// Claim the transaction in progress for a client, it is forgotten once the transaction ends.
+    pub fn set_transaction_owner(&mut self, owner: u32) {
+        if self.transaction_in_progress {
+            self.transaction_owner = Some(owner);
+        }
+    }
+
+    /// Savepoint of the transaction as it is now. Schemas must have been saved into their blocks,
+    /// as they are once a statement is submitted.
+    pub(crate) fn savepoint(&self, name: String) -> Savepoint {
+        let blocks_dirty = self
+            .blocks_dirty
+            .iter()
+            .map(|index| (*index, self.blocks.get(index).unwrap().clone()))
+            .collect();
+        Savepoint {
+            name,
+            superblock: self.superblock.clone(),
+            blocks_dirty,
+        }
+    }
+
+    /// Undo everything the transaction did after the savepoint, later savepoints are left to the
+    /// caller.
+    pub(crate) fn restore_savepoint(&mut self, savepoint: &Savepoint) {
+        // blocks first modified after the savepoint revert to their stored content
+        for index in self.blocks_dirty.drain() {
+            self.blocks.remove(&index);
+        }
+        for (index, block) in savepoint.blocks_dirty.iter() {
+            self.blocks.insert(*index, block.clone());
+            self.blocks_dirty.insert(*index);
+        }
+        self.superblock = savepoint.superblock.clone();
+        self.superblock_dirty = true;
+        self.schemas.clear();
+        self.schemas_dirty.clear();
+        self.forget_modified_schema_map();
+    }
+
+    /// Undo a failed statement within a transaction, leaving what earlier statements did.
+    pub(crate) fn undo_statement(&mut self, undo: StatementUndo) {
+        for (index, block) in undo.blocks {
+            match block {
+                Some(block) => {
+                    self.put_block(index, block);
+                    self.blocks_dirty.insert(index);
+                }
+                None => {
+                    self.blocks.remove(&index);
+                    self.blocks_dirty.remove(&index);
+                }
+            }
+        }
+        self.superblock = undo.superblock;
+        self.superblock_dirty = true;
+        self.schemas.clear();
+        self.schemas_dirty.clear();
+        self.forget_modified_schema_map();
+    }
+
+    /// Position of the latest savepoint with the name.
+    fn find_savepoint(&self, name: &str) -> Result<usize> {
+        self.savepoints
+            .iter()
+            .rposition(|savepoint| savepoint.name == name)
+            .ok_or_else(|| eyre!("SAVEPOINT {name} does not exist"))
+    }
+
+    pub(crate) fn check_cancelled(&self) -> Result<()> {
+        if self.cancel_token.as_ref().is_some_and(|t| t.is_cancelled()) {
+            return Err(eyre!("query cancelled"));
+        }
+        Ok(())
+    }
+
     pub async fn dispatch(self: &mut Aidb, stmt: SqlStmt) -> Result<Response> {
+        if self.read_only && stmt.is_mutating() {
+            return Err(eyre!("database is read-only"));
+        }
         match stmt {
             SqlStmt::ShowTables => self.show_tables().await,
+            SqlStmt::ShowTableStatus => self.show_table_status().await,
             SqlStmt::Describe { table } => self.describe(table).await,
-            SqlStmt::CreateTable { table, columns } => self.create_table(table, columns).await,
-            SqlStmt::DropTable { table } => self.drop_table(table).await,
+            SqlStmt::CheckTable { table } => self.check_table(table).await,
+            SqlStmt::CreateTable {
+                table,
+                columns,
+                auto_increment,
+                if_not_exists,
+                comments,
+            } => {
+                self.create_table(table, columns, auto_increment, if_not_exists, comments)
+                    .await
+            }
+            SqlStmt::DropTable { table, if_exists } => self.drop_table(table, if_exists).await,
+            SqlStmt::CreateIndex {
+                table,
+                column,
+                type_,
+                unique,
+            } => self.create_index(table, column, type_, unique).await,
+            SqlStmt::Reindex { table } => self.reindex(table).await,
             SqlStmt::InsertInto {
                 table,
                 columns,
                 values,
-            } => self.insert_into(table, columns, values).await,
-            SqlStmt::Select {
-                columns,
+                on_duplicate,
+            } => self.insert_into(table, columns, values, on_duplicate).await,
+            SqlStmt::ReplaceInto {
                 table,
-                join_on,
-                where_,
-                limit,
-            } => self.select(columns, table, join_on, where_, limit).await,
-            SqlStmt::Explain {
                 columns,
-                table,
-                join_on,
-                where_,
-                limit,
-            } => self.explain(columns, table, join_on, where_, limit).await,
+                values,
+            } => self.replace_into(table, columns, values).await,
+            stmt @ SqlStmt::Select { .. } => self.select(stmt).await,
+            SqlStmt::Union { left, right, all } => self.union(*left, *right, all).await,
+            stmt @ SqlStmt::Explain { analyze: false, .. } => self.explain(stmt).await,
+            stmt @ SqlStmt::Explain { analyze: true, .. } => self.explain_analyze(stmt).await,
             SqlStmt::Update { table, set, where_ } => self.update(table, set, where_).await,
             SqlStmt::DeleteFrom { table, where_ } => self.delete_from(table, where_).await,
             SqlStmt::FlushTables => {
@@ -64,21 +337,112 @@ impl Aidb {
                     return Ok(Response::Meta { affected_rows: 0 });
                 }
                 self.transaction_in_progress = false;
+                self.transaction_owner = None;
+                self.savepoints.clear();
+                // flush everything accumulated by the transaction at once
+                self.submit().await?;
                 Ok(Response::Meta { affected_rows: 0 })
             }
             SqlStmt::Rollback => {
                 if !self.transaction_in_progress {
                     return Ok(Response::Meta { affected_rows: 0 });
                 }
+                // DDL is transactional as well, schema blocks and the schema chain only change in
+                // cache and the superblock from the start of the transaction is restored
                 self.schemas.clear();
                 self.schemas_dirty.clear();
+                self.forget_modified_schema_map();
                 self.blocks.clear();
                 self.blocks_dirty.clear();
                 self.superblock = self.superblock_backup.take().unwrap();
                 self.superblock_dirty = false;
                 self.transaction_in_progress = false;
+                self.transaction_owner = None;
+                self.savepoints.clear();
+                Ok(Response::Meta { affected_rows: 0 })
+            }
+            SqlStmt::Savepoint { name } => {
+                if !self.transaction_in_progress {
+                    return Ok(Response::Meta { affected_rows: 0 });
+                }
+                self.savepoints.retain(|savepoint| savepoint.name != name);
+                let savepoint = self.savepoint(name);
+                self.savepoints.push(savepoint);
+                Ok(Response::Meta { affected_rows: 0 })
+            }
+            SqlStmt::RollbackTo { name } => {
+                let position = self.find_savepoint(&name)?;
+                self.savepoints.truncate(position + 1);
+                let savepoint = self.savepoints.pop().unwrap();
+                self.restore_savepoint(&savepoint);
+                self.savepoints.push(savepoint);
                 Ok(Response::Meta { affected_rows: 0 })
             }
+            SqlStmt::Release { name } => {
+                let position = self.find_savepoint(&name)?;
+                self.savepoints.truncate(position);
+                Ok(Response::Meta { affected_rows: 0 })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use itertools::Itertools;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cursor() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, s TEXT);")
+            .await
+            .unwrap();
+        // enough rows to span several data blocks
+        let values = (0..5000)
+            .map(|i| format!("({i}, 'text number {i}')"))
+            .join(", ");
+        aidb.query(format!("INSERT INTO t VALUES {values};"))
+            .await
+            .unwrap();
+        let first_block = aidb
+            .get_schema("t")
+            .await
+            .unwrap()
+            .data_block
+            .get()
+            .unwrap();
+
+        let mut cursor = aidb.open_cursor("SELECT id FROM t;").await.unwrap();
+        assert_eq!(cursor.columns().len(), 1);
+        for i in 0..10 {
+            assert_eq!(cursor.next().await.unwrap(), Some(vec![Value::Integer(i)]));
         }
+        // the block being scanned is checked out of the cache
+        assert!(!cursor.db.blocks.contains_key(&first_block));
+        drop(cursor);
+        assert!(aidb.blocks.contains_key(&first_block));
+
+        let mut cursor = aidb.open_cursor("SELECT id FROM t;").await.unwrap();
+        let mut count = 0;
+        while let Some(row) = cursor.next().await.unwrap() {
+            assert_eq!(row, vec![Value::Integer(count)]);
+            count += 1;
+        }
+        assert_eq!(count, 5000);
+        assert_eq!(cursor.next().await.unwrap(), None);
+        drop(cursor);
+        assert!(aidb.blocks.contains_key(&first_block));
+
+        let mut cursor = aidb.open_cursor("DESCRIBE t;").await.unwrap();
+        assert!(cursor.next().await.unwrap().is_some());
+        drop(cursor);
+        assert!(aidb.open_cursor("DELETE FROM t;").await.is_err());
+        let Response::Rows { rows, .. } = aidb.query("SELECT COUNT(*) FROM t;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, vec![vec![Value::Integer(5000)]]);
     }
 }
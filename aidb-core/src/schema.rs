@@ -1,14 +1,21 @@
+use std::{
+    collections::{HashMap, HashSet},
+    iter,
+};
+
 use binrw::{BinRead, BinWrite, binrw};
-use eyre::{Result, eyre};
+use eyre::{OptionExt, Result, eyre};
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
-use crate::{Aidb, BlockIndex, DataType, Response, Value};
+use crate::{Aidb, BlockIndex, DataType, Response, Value, storage::BlockPtr};
 
 #[binrw]
 #[brw(little, repr = u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum IndexType {
     BTree = 1,
+    Hash = 2,
 }
 
 #[binrw]
@@ -17,7 +24,11 @@ pub enum IndexType {
 pub struct IndexInfo {
     pub column_index: u8,
     pub type_: IndexType,
-    pub block: BlockIndex,
+    #[br(map = |v: u8| v != 0u8)]
+    #[bw(map = |v: &bool| if *v {1u8} else {0u8})]
+    pub unique: bool,
+    /// root of the tree or directory of the hash, none until the first insert
+    pub block: BlockPtr,
 }
 
 #[binrw]
@@ -25,8 +36,8 @@ pub struct IndexInfo {
 #[derive(Debug, Clone)]
 pub struct Schema {
     #[brw(ignore)]
-    block_index: BlockIndex,
-    next_schema_block: BlockIndex,
+    pub(crate) block_index: BlockIndex,
+    next_schema_block: BlockPtr,
     #[br(temp)]
     #[bw(calc = name.len() as u8)]
     name_len: u8,
@@ -43,23 +54,161 @@ pub struct Schema {
     indices_len: u8,
     #[br(count = indices_len)]
     pub(crate) indices: Vec<IndexInfo>,
-    pub(crate) data_block: BlockIndex,
+    pub(crate) data_block: BlockPtr,
+    /// data block inserts append to, none for the first one
+    pub(crate) insert_block: BlockPtr,
+    /// 1 + position of the AUTO_INCREMENT column, 0 for none
+    auto_increment_column: u8,
+    /// last value generated for the AUTO_INCREMENT column
+    pub(crate) auto_increment: i64,
+    #[br(temp)]
+    #[bw(calc = null_lists.len() as u8)]
+    null_lists_len: u8,
+    /// rows holding NULL in indexed columns
+    #[br(count = null_lists_len)]
+    null_lists: Vec<NullListInfo>,
+    /// layout version of the fields below, 0 for schemas written before they were added as the
+    /// rest of a block is zeroed
+    #[br(temp)]
+    #[bw(calc = SCHEMA_VERSION)]
+    version: u8,
+    #[br(if(version >= 1))]
+    comment: ShortString,
+    #[br(if(version >= 1, vec![ShortString::default(); columns.len()]), count = columns.len())]
+    column_comments: Vec<ShortString>,
+    /// size of a row in data blocks, computed from `columns` when the schema is created or read
+    /// instead of for every plan, whatever changes the columns has to compute it again
+    #[br(calc = row_size(&columns))]
+    #[bw(ignore)]
+    row_size: usize,
+}
+
+/// Version of the layout of [`Schema`] written.
+const SCHEMA_VERSION: u8 = 1;
+
+/// Most columns of a table, as their count is a byte in [`Schema`]. Indexes and NULL lists, also
+/// counted in a byte, stay within it as there is at most one of each per column.
+const MAX_COLUMNS: usize = u8::MAX as usize;
+
+/// Longest table name in bytes, as its length is a byte in [`Schema`].
+const MAX_TABLE_NAME_LEN: usize = u8::MAX as usize;
+
+/// UTF-8 prefixed by its length in 2 bytes.
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone, Default)]
+struct ShortString {
+    #[br(temp)]
+    #[bw(calc = s.len() as u16)]
+    len: u16,
+    #[br(count = len, try_map = |s: Vec<u8>| String::from_utf8(s))]
+    #[bw(map = |s: &String| s.as_bytes())]
+    s: String,
+}
+
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+struct NullListInfo {
+    column_index: u8,
+    block: BlockPtr,
 }
 
 impl Schema {
-    pub(crate) fn row_size(&self) -> usize {
-        1 + self
-            .columns
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// First block of the list of rows holding NULL in an indexed column.
+    pub(crate) fn null_list(&self, column_index: u8) -> BlockPtr {
+        self.null_lists
             .iter()
-            .map(|column| match column.datatype {
-                DataType::Integer => 9,
-                DataType::Real => 9,
-                DataType::Text => 13,
+            .find(|list| list.column_index == column_index)
+            .map_or(BlockPtr::NONE, |list| list.block)
+    }
+
+    pub(crate) fn set_null_list(&mut self, column_index: u8, block: BlockPtr) {
+        match self
+            .null_lists
+            .iter_mut()
+            .find(|list| list.column_index == column_index)
+        {
+            Some(list) => list.block = block,
+            None => self.null_lists.push(NullListInfo {
+                column_index,
+                block,
+            }),
+        }
+    }
+
+    pub(crate) fn info(&self) -> TableInfo {
+        TableInfo {
+            name: self.name.clone(),
+            columns: self.columns.clone(),
+            indices: self
+                .indices
+                .iter()
+                .map(|index| TableIndex {
+                    column: self.columns[index.column_index as usize].name.clone(),
+                    type_: index.type_,
+                    unique: index.unique,
+                })
+                .collect(),
+            auto_increment: self
+                .auto_increment_column()
+                .map(|i| self.columns[i].name.clone()),
+            comments: self.comments(),
+        }
+    }
+
+    pub(crate) fn comments(&self) -> TableComments {
+        TableComments {
+            table: self.comment.s.clone(),
+            columns: self.column_comments.iter().map(|c| c.s.clone()).collect(),
+        }
+    }
+
+    /// Position of the AUTO_INCREMENT column.
+    pub(crate) fn auto_increment_column(&self) -> Option<usize> {
+        (self.auto_increment_column as usize).checked_sub(1)
+    }
+
+    /// Positions of the listed columns, all columns if none is listed.
+    pub(crate) fn column_indices(&self, columns: Vec<String>) -> Result<Vec<usize>> {
+        if columns.is_empty() {
+            return Ok((0..self.columns.len()).collect());
+        }
+        let column_indices = columns
+            .into_iter()
+            .map(|name| {
+                self.columns
+                    .iter()
+                    .position(|column| column.name == name)
+                    .ok_or_eyre("column not found")
             })
-            .sum::<usize>()
+            .collect::<Result<Vec<_>>>()?;
+        if !column_indices.iter().all_unique() {
+            return Err(eyre!("column specified multiple times"));
+        }
+        Ok(column_indices)
+    }
+
+    pub(crate) fn row_size(&self) -> usize {
+        self.row_size
     }
 }
 
+fn row_size(columns: &[Column]) -> usize {
+    1 + columns
+        .iter()
+        .map(|column| match column.datatype {
+            DataType::Integer => 9,
+            DataType::Real => 9,
+            DataType::Text | DataType::Blob => 13,
+        })
+        .sum::<usize>()
+}
+
 #[binrw]
 #[brw(little)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -73,29 +222,126 @@ pub struct Column {
     pub datatype: DataType,
 }
 
+/// Position of each schema in the schema chain, built by walking the chain once so that looking
+/// up a table reads its schema block only.
+#[derive(Debug, Default)]
+pub(crate) struct SchemaMap {
+    entries: HashMap<String, SchemaEntry>,
+    /// table at the end of the chain
+    last: Option<String>,
+    /// whether the chain changed since it was last submitted
+    pub(crate) modified: bool,
+}
+
+#[derive(Debug)]
+struct SchemaEntry {
+    block: BlockIndex,
+    previous: Option<String>,
+    next: Option<String>,
+}
+
+impl SchemaMap {
+    fn push(&mut self, table: String, block: BlockIndex) {
+        if let Some(last) = &self.last {
+            self.entries.get_mut(last).unwrap().next = Some(table.clone());
+        }
+        let entry = SchemaEntry {
+            block,
+            previous: self.last.replace(table.clone()),
+            next: None,
+        };
+        self.entries.insert(table, entry);
+    }
+
+    fn remove(&mut self, table: &str) -> Option<SchemaEntry> {
+        let entry = self.entries.remove(table)?;
+        if let Some(previous) = &entry.previous {
+            self.entries.get_mut(previous).unwrap().next = entry.next.clone();
+        }
+        match &entry.next {
+            Some(next) => self.entries.get_mut(next).unwrap().previous = entry.previous.clone(),
+            None => self.last = entry.previous.clone(),
+        }
+        Some(entry)
+    }
+
+    fn block(&self, table: &str) -> Option<BlockIndex> {
+        self.entries.get(table).map(|entry| entry.block)
+    }
+}
+
+/// Table metadata returned by [`Aidb::tables`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableInfo {
+    pub name: String,
+    pub columns: Vec<Column>,
+    pub indices: Vec<TableIndex>,
+    /// name of the AUTO_INCREMENT column
+    pub auto_increment: Option<String>,
+    pub comments: TableComments,
+}
+
+/// `COMMENT` of a table and each of its columns, empty if not given.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableComments {
+    pub table: String,
+    pub columns: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableIndex {
+    pub column: String,
+    pub type_: IndexType,
+    pub unique: bool,
+}
+
 impl Aidb {
     pub async fn show_tables(self: &mut Aidb) -> Result<Response> {
-        let mut schema_block_index = self.superblock.first_schema_block;
-        let mut tables = vec![];
-        while schema_block_index > 0 {
-            let mut block = self.get_block(schema_block_index).await?;
-            let mut schema = Schema::read(&mut block.cursor())?;
-            schema.block_index = schema_block_index;
-            tables.push(schema.name.clone());
-            self.put_block(schema_block_index, block);
-            let next_schema_block_index = schema.next_schema_block;
-            self.put_schema(schema.name.clone(), Box::new(schema));
-            schema_block_index = next_schema_block_index;
-        }
+        let tables = self.table_columns().await?;
         Ok(Response::Rows {
             columns: vec![Column {
                 name: "table_name".to_owned(),
                 datatype: DataType::Text,
             }],
-            rows: tables.into_iter().map(|s| vec![Value::Text(s)]).collect(),
+            rows: tables
+                .into_iter()
+                .map(|(table, _)| vec![Value::Text(table)])
+                .collect(),
+            truncated: false,
         })
     }
 
+    /// Names of all tables and their columns.
+    pub(crate) async fn table_columns(self: &mut Aidb) -> Result<Vec<(String, Vec<String>)>> {
+        Ok(self
+            .tables()
+            .await?
+            .into_iter()
+            .map(|table| {
+                (
+                    table.name,
+                    table.columns.into_iter().map(|c| c.name).collect(),
+                )
+            })
+            .collect())
+    }
+
+    /// Metadata of all tables in order of creation.
+    pub async fn tables(self: &mut Aidb) -> Result<Vec<TableInfo>> {
+        let mut next = self.superblock.first_schema_block;
+        let mut tables = vec![];
+        while let Some(schema_block_index) = next.get() {
+            let mut block = self.get_block(schema_block_index).await?;
+            let mut schema = Schema::read(&mut block.cursor())?;
+            schema.block_index = schema_block_index;
+            tables.push(schema.info());
+            self.put_block(schema_block_index, block);
+            // only the block is cached, listing every table shouldn't fill the schema cache
+            next = schema.next_schema_block;
+        }
+        Ok(tables)
+    }
+
     pub async fn describe(self: &mut Aidb, table: String) -> Result<Response> {
         let schema = self.get_schema(&table).await?;
         let r = Response::Rows {
@@ -108,17 +354,24 @@ impl Aidb {
                     name: "column_datatype".to_owned(),
                     datatype: DataType::Text,
                 },
+                Column {
+                    name: "column_comment".to_owned(),
+                    datatype: DataType::Text,
+                },
             ],
             rows: schema
                 .columns
                 .iter()
-                .map(|column| {
+                .zip(&schema.column_comments)
+                .map(|(column, comment)| {
                     vec![
                         Value::Text(column.name.clone()),
                         Value::Text(column.datatype.to_string()),
+                        Value::Text(comment.s.clone()),
                     ]
                 })
                 .collect(),
+            truncated: false,
         };
         self.put_schema(table, schema);
         Ok(r)
@@ -129,15 +382,28 @@ impl Aidb {
         table: String,
         columns: Vec<Column>,
         indices: Vec<IndexInfo>,
+        auto_increment_column: u8,
+        comments: TableComments,
     ) -> Result<BlockIndex> {
         let (index, mut block) = self.new_block();
         let schema = Schema {
             block_index: index,
-            next_schema_block: 0,
+            next_schema_block: BlockPtr::NONE,
             name: table.clone(),
+            row_size: row_size(&columns),
+            comment: ShortString { s: comments.table },
+            column_comments: comments
+                .columns
+                .into_iter()
+                .map(|s| ShortString { s })
+                .collect(),
             columns,
             indices,
-            data_block: 0,
+            data_block: BlockPtr::NONE,
+            insert_block: BlockPtr::NONE,
+            auto_increment_column,
+            auto_increment: 0,
+            null_lists: vec![],
         };
         schema.write(&mut block.cursor())?;
         self.put_schema(table.clone(), Box::new(schema));
@@ -151,7 +417,42 @@ impl Aidb {
         self: &mut Aidb,
         table: String,
         columns: Vec<(Column, Option<IndexType>)>,
+        auto_increment: Option<String>,
+        if_not_exists: bool,
+        mut comments: TableComments,
     ) -> Result<Response> {
+        if table.len() > MAX_TABLE_NAME_LEN {
+            return Err(eyre!(
+                "table name too long, at most {MAX_TABLE_NAME_LEN} bytes"
+            ));
+        }
+        if columns.len() > MAX_COLUMNS {
+            return Err(eyre!("too many columns, at most {MAX_COLUMNS}"));
+        }
+        if comments.columns.len() > columns.len() {
+            return Err(eyre!("more comments than columns"));
+        }
+        comments.columns.resize(columns.len(), String::new());
+        if iter::once(&comments.table)
+            .chain(&comments.columns)
+            .any(|comment| comment.len() > u16::MAX as usize)
+        {
+            return Err(eyre!("comment too long"));
+        }
+        let auto_increment_column = match auto_increment {
+            Some(name) => {
+                let Some(i) = columns.iter().position(|(column, _)| column.name == name) else {
+                    return Err(eyre!("Unknown column '{name}'"));
+                };
+                if columns[i].0.datatype != DataType::Integer {
+                    return Err(eyre!(
+                        "AUTO_INCREMENT is implemented on integer column only"
+                    ));
+                }
+                i as u8 + 1
+            }
+            None => 0,
+        };
         let mut schema_columns = vec![];
         let mut schema_indices = vec![];
         for (i, (column, index)) in columns.into_iter().enumerate() {
@@ -162,72 +463,194 @@ impl Aidb {
                 schema_indices.push(IndexInfo {
                     column_index: i as u8,
                     type_,
-                    block: 0,
+                    unique: true,
+                    block: BlockPtr::NONE,
                 });
             }
             schema_columns.push(column);
         }
 
-        let mut schema_block_index = self.superblock.first_schema_block;
-        if schema_block_index == 0 {
-            let index = self
-                .new_schema_block(table, schema_columns, schema_indices)
-                .await?;
-            self.superblock.first_schema_block = index;
-            self.mark_superblock_dirty();
-            return Ok(Response::Meta { affected_rows: 0 });
+        let mut map = self.get_schema_map().await?;
+        if map.block(&table).is_some() {
+            self.put_schema_map(map);
+            if if_not_exists {
+                return Ok(Response::Meta { affected_rows: 0 });
+            }
+            return Err(eyre!("Table exists"));
         }
-        loop {
-            let mut block = self.get_block(schema_block_index).await?;
-            let mut schema = Schema::read(&mut block.cursor())?;
-            schema.block_index = schema_block_index;
-            if schema.name == table {
-                self.put_block(schema_block_index, block);
-                return Err(eyre!("Table exists"));
+        let index = self
+            .new_schema_block(
+                table.clone(),
+                schema_columns,
+                schema_indices,
+                auto_increment_column,
+                comments,
+            )
+            .await?;
+        let last = map.last.clone();
+        map.push(table, index);
+        map.modified = true;
+        self.put_schema_map(map);
+        match last {
+            Some(last) => {
+                let mut schema = self.get_schema(&last).await?;
+                schema.next_schema_block = BlockPtr::to(index);
+                self.put_schema(last.clone(), schema);
+                self.mark_schema_dirty(last);
+            }
+            None => {
+                self.superblock.first_schema_block = BlockPtr::to(index);
+                self.mark_superblock_dirty();
             }
-            if schema.next_schema_block == 0 {
-                let index = self
-                    .new_schema_block(table, schema_columns, schema_indices)
-                    .await?;
-                schema.next_schema_block = index;
-                self.mark_schema_dirty(schema.name.clone());
-                self.put_schema(schema.name.clone(), Box::new(schema));
+        }
+        Ok(Response::Meta { affected_rows: 0 })
+    }
+
+    pub async fn drop_table(self: &mut Aidb, table: String, if_exists: bool) -> Result<Response> {
+        let mut map = self.get_schema_map().await?;
+        let Some(entry) = map.remove(&table) else {
+            self.put_schema_map(map);
+            if if_exists {
                 return Ok(Response::Meta { affected_rows: 0 });
             }
-            self.put_block(schema_block_index, block);
-            let next_schema_block_index = schema.next_schema_block;
-            self.put_schema(schema.name.clone(), Box::new(schema));
-            schema_block_index = next_schema_block_index;
+            return Err(eyre!("table not found"));
+        };
+        let next = entry.next.as_ref().map_or(BlockPtr::NONE, |next| {
+            BlockPtr::to(map.block(next).unwrap())
+        });
+        map.modified = true;
+        self.put_schema_map(map);
+        self.schemas.remove(&table);
+        self.schemas_dirty.remove(&table);
+        match entry.previous {
+            Some(previous) => {
+                let mut schema = self.get_schema(&previous).await?;
+                schema.next_schema_block = next;
+                self.put_schema(previous.clone(), schema);
+                self.mark_schema_dirty(previous);
+            }
+            None => {
+                // this schema is the first
+                self.superblock.first_schema_block = next;
+                self.mark_superblock_dirty();
+            }
         }
+        Ok(Response::Meta { affected_rows: 0 })
     }
 
-    pub async fn drop_table(self: &mut Aidb, table: String) -> Result<Response> {
-        let mut previous_table = "".to_owned();
-        let mut schema_block_index = self.superblock.first_schema_block;
-        while schema_block_index != 0 {
-            let mut block = self.get_block(schema_block_index).await?;
-            let mut schema = Schema::read(&mut block.cursor())?;
-            schema.block_index = schema_block_index;
-            if schema.name == table {
-                if previous_table.is_empty() {
-                    // this schema is the first
-                    self.superblock.first_schema_block = schema.next_schema_block;
-                    self.superblock_dirty = true;
-                } else {
-                    let mut previous_schema = self.get_schema(&previous_table).await.unwrap();
-                    previous_schema.next_schema_block = schema.next_schema_block;
-                    self.put_schema(previous_table.clone(), previous_schema);
-                    self.mark_schema_dirty(previous_table);
+    pub async fn create_index(
+        self: &mut Aidb,
+        table: String,
+        column: String,
+        type_: IndexType,
+        unique: bool,
+    ) -> Result<Response> {
+        let schema = self.get_schema(&table).await?;
+        let column_index = schema
+            .columns
+            .iter()
+            .position(|c| c.name == column)
+            .ok_or_eyre("column not found")?;
+        if schema.columns[column_index].datatype != DataType::Integer {
+            return Err(eyre!("index is implemented on integer column only"));
+        }
+        if schema
+            .indices
+            .iter()
+            .any(|index| index.column_index as usize == column_index)
+        {
+            return Err(eyre!("column is already indexed"));
+        }
+        self.put_schema(table.clone(), schema);
+
+        let (block, null_list) = self
+            .build_indexes(&table, &[(column_index as u8, type_, unique)])
+            .await?
+            .pop()
+            .unwrap();
+
+        let mut schema = self.get_schema(&table).await?;
+        schema.indices.push(IndexInfo {
+            column_index: column_index as u8,
+            type_,
+            unique,
+            block,
+        });
+        if null_list.is_some() {
+            schema.set_null_list(column_index as u8, null_list);
+        }
+        self.put_schema(table.clone(), schema);
+        self.mark_schema_dirty(table);
+        Ok(Response::Meta { affected_rows: 0 })
+    }
+
+    /// Rebuild every index of the table and its NULL list from the rows where they are now, e.g.
+    /// after a suspected corruption. Blocks of the old indexes are left unreferenced, as those of
+    /// dropped tables are.
+    pub async fn reindex(self: &mut Aidb, table: String) -> Result<Response> {
+        let schema = self.get_schema(&table).await?;
+        let indices = schema
+            .indices
+            .iter()
+            .map(|index| (index.column_index, index.type_, index.unique))
+            .collect::<Vec<_>>();
+        self.put_schema(table.clone(), schema);
+
+        let rebuilt = self.build_indexes(&table, &indices).await?;
+
+        let mut schema = self.get_schema(&table).await?;
+        for (i, (block, null_list)) in rebuilt.into_iter().enumerate() {
+            let column_index = schema.indices[i].column_index;
+            schema.indices[i].block = block;
+            if null_list.is_some() || schema.null_list(column_index).is_some() {
+                schema.set_null_list(column_index, null_list);
+            }
+        }
+        self.put_schema(table.clone(), schema);
+        self.mark_schema_dirty(table);
+        Ok(Response::Meta { affected_rows: 0 })
+    }
+
+    /// Build indexes on columns of the table from a single scan of its rows, B-trees through the
+    /// bulk path. Returns the index block and the head of the NULL list of each column.
+    async fn build_indexes(
+        self: &mut Aidb,
+        table: &str,
+        columns: &[(u8, IndexType, bool)],
+    ) -> Result<Vec<(BlockPtr, BlockPtr)>> {
+        let mut records = vec![vec![]; columns.len()];
+        let mut null_lists = vec![BlockPtr::NONE; columns.len()];
+        for (row, record) in self.select_with_ptr(table.to_owned()).await? {
+            for (i, (column_index, _, _)) in columns.iter().enumerate() {
+                match row[*column_index as usize] {
+                    Value::Integer(v) => records[i].push((v, record.clone())),
+                    Value::Null => {
+                        null_lists[i] = self.insert_null(null_lists[i], record.clone()).await?
+                    }
+                    _ => return Err(eyre!("invalid value")),
                 }
-                return Ok(Response::Meta { affected_rows: 0 });
             }
-            self.put_block(schema_block_index, block);
-            let next_schema_block_index = schema.next_schema_block;
-            previous_table = schema.name.clone();
-            self.put_schema(schema.name.clone(), Box::new(schema));
-            schema_block_index = next_schema_block_index;
         }
-        Err(eyre!("table not found"))
+        let mut indexes = vec![];
+        for (((_, type_, unique), records), null_list) in
+            columns.iter().zip(records).zip(null_lists)
+        {
+            let block = match type_ {
+                IndexType::BTree => self.build_btree(records, *unique).await?,
+                IndexType::Hash => {
+                    let mut block = BlockPtr::NONE;
+                    for (key, record) in records {
+                        match block.get() {
+                            None => block = BlockPtr::to(self.new_hash(key, record).await?),
+                            Some(root) => self.insert_hash(root, key, record, *unique).await?,
+                        }
+                    }
+                    block
+                }
+            };
+            indexes.push((block, null_list));
+        }
+        Ok(indexes)
     }
 
     pub(crate) async fn get_schema(self: &mut Aidb, table: &str) -> Result<Box<Schema>> {
@@ -237,6 +660,26 @@ impl Aidb {
         self.load_schema(table).await
     }
 
+    /// Make sure schemas of the tables are cached, so that they can be borrowed together with
+    /// [`Aidb::cached_schema`].
+    pub(crate) async fn cache_schemas(self: &mut Aidb, tables: &[String]) -> Result<()> {
+        for table in tables {
+            if !self.schemas.contains_key(table) {
+                let schema = self.load_schema(table).await?;
+                self.put_schema(table.clone(), schema);
+            }
+        }
+        Ok(())
+    }
+
+    /// Borrow a schema cached by [`Aidb::cache_schemas`] without taking it out of the cache.
+    pub(crate) fn cached_schema(self: &Aidb, table: &str) -> Result<&Schema> {
+        self.schemas
+            .get(table)
+            .map(|schema| schema.as_ref())
+            .ok_or_eyre("table not found")
+    }
+
     pub(crate) fn put_schema(self: &mut Aidb, table: String, schema: Box<Schema>) {
         self.schemas.insert(table, schema);
     }
@@ -254,19 +697,392 @@ impl Aidb {
     }
 
     pub async fn load_schema(&mut self, table: &str) -> Result<Box<Schema>> {
-        let mut schema_block_index = self.superblock.first_schema_block;
-        while schema_block_index > 0 {
+        let map = self.get_schema_map().await?;
+        let schema_block_index = map.block(table);
+        self.put_schema_map(map);
+        let schema_block_index = schema_block_index.ok_or_eyre("table not found")?;
+        let mut block = self.get_block(schema_block_index).await?;
+        let mut schema = Schema::read(&mut block.cursor())?;
+        schema.block_index = schema_block_index;
+        self.put_block(schema_block_index, block);
+        Ok(Box::new(schema))
+    }
+
+    /// Take the schema map out, walking the schema chain if it isn't built yet.
+    async fn get_schema_map(&mut self) -> Result<SchemaMap> {
+        if let Some(map) = self.schema_map.take() {
+            return Ok(map);
+        }
+        let mut map = SchemaMap::default();
+        let mut next = self.superblock.first_schema_block;
+        while let Some(schema_block_index) = next.get() {
             let mut block = self.get_block(schema_block_index).await?;
-            let mut schema = Schema::read(&mut block.cursor())?;
-            schema.block_index = schema_block_index;
+            let schema = Schema::read(&mut block.cursor())?;
             self.put_block(schema_block_index, block);
-            if schema.name == table {
-                return Ok(Box::new(schema));
-            }
-            let next_schema_block_index = schema.next_schema_block;
-            self.put_schema(schema.name.clone(), Box::new(schema));
-            schema_block_index = next_schema_block_index;
+            map.push(schema.name, schema_block_index);
+            next = schema.next_schema_block;
+        }
+        // the chain may hold tables created by the transaction, which a rollback removes
+        map.modified = self.transaction_in_progress;
+        Ok(map)
+    }
+
+    fn put_schema_map(&mut self, map: SchemaMap) {
+        self.schema_map = Some(map);
+    }
+
+    /// Drop the schema map if the schema chain changed since it was last submitted, so that it is
+    /// built again from the chain being restored.
+    pub(crate) fn forget_modified_schema_map(&mut self) {
+        if self.schema_map.as_ref().is_some_and(|map| map.modified) {
+            self.schema_map = None;
+        }
+    }
+
+    /// Check that the schema chain terminates, returns schemas along it.
+    pub(crate) async fn check_schema_chain(
+        &mut self,
+        problems: &mut Vec<String>,
+    ) -> Result<Vec<Schema>> {
+        let mut visited = HashSet::new();
+        let mut schemas = vec![];
+        let mut next = self.superblock.first_schema_block;
+        while let Some(schema_block_index) = next.get()
+            && self.check_block(schema_block_index, &mut visited, "schemas", problems)
+        {
+            let schema = match self.get_block(schema_block_index).await {
+                Ok(mut block) => {
+                    let schema = Schema::read(&mut block.cursor()).map_err(Into::into);
+                    self.put_block(schema_block_index, block);
+                    schema
+                }
+                Err(e) => Err(e),
+            };
+            let mut schema = match schema {
+                Ok(schema) => schema,
+                Err(e) => {
+                    problems.push(format!(
+                        "schemas: block {schema_block_index} is unreadable: {e}"
+                    ));
+                    break;
+                }
+            };
+            schema.block_index = schema_block_index;
+            next = schema.next_schema_block;
+            schemas.push(schema);
+        }
+        Ok(schemas)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Value;
+
+    async fn table_names(aidb: &mut Aidb) -> Vec<String> {
+        aidb.tables()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|table| table.name)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_schema_lookup_reads_one_block() {
+        let mut aidb = Aidb::new_memory().await;
+        for i in 0..100 {
+            aidb.query(format!("CREATE TABLE t{i} (id INTEGER);"))
+                .await
+                .unwrap();
+        }
+        let block = aidb.schema_map.as_ref().unwrap().block("t77").unwrap();
+        aidb.query("FLUSH TABLES;").await.unwrap();
+        let (_, log) = aidb.query_log_blocks("DESCRIBE t77;").await.unwrap();
+        assert_eq!(log.read, HashSet::from([block]));
+    }
+
+    #[tokio::test]
+    async fn test_schema_lookup_caches_one_schema() {
+        let mut aidb = Aidb::new_memory().await;
+        for i in 0..100 {
+            aidb.query(format!("CREATE TABLE t{i} (id INTEGER);"))
+                .await
+                .unwrap();
+        }
+        aidb.query("FLUSH TABLES;").await.unwrap();
+        aidb.schema_map = None;
+        // walking the chain to build the schema map doesn't cache the schemas either
+        aidb.query("SELECT * FROM t99;").await.unwrap();
+        assert_eq!(aidb.schemas.keys().collect::<Vec<_>>(), ["t99"]);
+
+        aidb.query("FLUSH TABLES;").await.unwrap();
+        aidb.query("SHOW TABLES;").await.unwrap();
+        assert_eq!(aidb.table_columns().await.unwrap().len(), 100);
+        assert!(aidb.schemas.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_row_size_memoized() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, x REAL, s TEXT);")
+            .await
+            .unwrap();
+        let schema = aidb.get_schema("t").await.unwrap();
+        assert_eq!(schema.row_size(), 1 + 9 + 9 + 13);
+        aidb.put_schema("t".to_owned(), schema);
+        aidb.query("FLUSH TABLES;").await.unwrap();
+        aidb.schemas.clear();
+
+        // read back from the schema block, then kept with the cached schema
+        let mut schema = aidb.get_schema("t").await.unwrap();
+        assert_eq!(schema.row_size(), 1 + 9 + 9 + 13);
+        schema.columns.pop();
+        assert_eq!(schema.row_size(), 1 + 9 + 9 + 13);
+    }
+
+    #[tokio::test]
+    async fn test_comments() {
+        use std::io::{Cursor, Write};
+
+        use crate::storage::BLOCK_SIZE;
+
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query(
+            "CREATE TABLE t (id INTEGER PRIMARY KEY COMMENT 'primary key', s TEXT, \
+             x REAL COMMENT \"it's x\") COMMENT = 'users';",
+        )
+        .await
+        .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 'a', 0.5);")
+            .await
+            .unwrap();
+        aidb.query("FLUSH TABLES;").await.unwrap();
+        aidb.schemas.clear();
+
+        let describe = |rows: Vec<Vec<Value>>| {
+            rows.into_iter()
+                .map(|row| match &row[2] {
+                    Value::Text(comment) => comment.clone(),
+                    _ => panic!("text expected"),
+                })
+                .collect::<Vec<_>>()
+        };
+        let Response::Rows { columns, rows, .. } = aidb.query("DESCRIBE t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(columns[2].name, "column_comment");
+        assert_eq!(describe(rows), ["primary key", "", "it's x"]);
+        let comments = aidb.tables().await.unwrap().remove(0).comments;
+        assert_eq!(comments.table, "users");
+        assert_eq!(comments.columns, ["primary key", "", "it's x"]);
+
+        // schemas written before comments end with zeroes where the version is
+        let schema = aidb.get_schema("t").await.unwrap();
+        let mut bytes = Cursor::new(vec![]);
+        schema.write(&mut bytes).unwrap();
+        let comments_len = 2 + "users".len() + (2 + "primary key".len()) + 2 + (2 + "it's x".len());
+        let version = bytes.into_inner().len() - comments_len - 1;
+        let block_index = schema.block_index;
+        aidb.put_schema("t".to_owned(), schema);
+        let mut block = aidb.get_block(block_index).await.unwrap();
+        block
+            .cursor_at(version as u16)
+            .write_all(&vec![0; BLOCK_SIZE - version])
+            .unwrap();
+        aidb.put_block(block_index, block);
+        aidb.schemas.clear();
+        let Response::Rows { rows, .. } = aidb.query("DESCRIBE t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(describe(rows), ["", "", ""]);
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_create_if_not_exists() {
+        let mut aidb = Aidb::new_memory().await;
+        let create = "CREATE TABLE IF NOT EXISTS t (id INTEGER);";
+        aidb.query(create).await.unwrap();
+        aidb.query("INSERT INTO t VALUES (1);").await.unwrap();
+        // present, the table is left as it is
+        aidb.query(create).await.unwrap();
+        aidb.query("create table if not exists t (s TEXT);")
+            .await
+            .unwrap();
+        assert!(aidb.query("CREATE TABLE t (id INTEGER);").await.is_err());
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, [vec![Value::Integer(1)]]);
+        assert_eq!(table_names(&mut aidb).await, ["t"]);
+    }
+
+    #[tokio::test]
+    async fn test_schema_limits() {
+        let mut aidb = Aidb::new_memory().await;
+        let columns = |n: usize| {
+            (0..n)
+                .map(|i| format!("c{i} INTEGER UNIQUE"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let e = aidb
+            .query(format!("CREATE TABLE t ({});", columns(MAX_COLUMNS + 1)))
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "too many columns, at most 255");
+        let name = "t".repeat(MAX_TABLE_NAME_LEN + 1);
+        let e = aidb
+            .query(format!("CREATE TABLE {name} (id INTEGER);"))
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "table name too long, at most 255 bytes");
+        assert_eq!(table_names(&mut aidb).await, Vec::<String>::new());
+
+        // an index on each of the most columns and the longest name survive reopening
+        let name = "t".repeat(MAX_TABLE_NAME_LEN);
+        aidb.query(format!("CREATE TABLE {name} ({});", columns(MAX_COLUMNS)))
+            .await
+            .unwrap();
+        let mut reopened = Aidb::from_op(aidb.op.clone()).await.unwrap();
+        let schema = reopened.get_schema(&name).await.unwrap();
+        assert_eq!(
+            (schema.columns.len(), schema.indices.len()),
+            (MAX_COLUMNS, MAX_COLUMNS)
+        );
+        assert_eq!(schema.indices[MAX_COLUMNS - 1].column_index, u8::MAX - 1);
+    }
+
+    #[tokio::test]
+    async fn test_drop_if_exists() {
+        let mut aidb = Aidb::new_memory().await;
+        // absent
+        aidb.query("DROP TABLE IF EXISTS t;").await.unwrap();
+        assert!(aidb.query("DROP TABLE t;").await.is_err());
+        // present
+        aidb.query("CREATE TABLE t (id INTEGER);").await.unwrap();
+        aidb.query("drop table if exists t;").await.unwrap();
+        assert_eq!(table_names(&mut aidb).await, Vec::<String>::new());
+        aidb.query("DROP TABLE IF EXISTS t;").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_schema_map_drop_and_rollback() {
+        let mut aidb = Aidb::new_memory().await;
+        for table in ["a", "b", "c", "d"] {
+            aidb.query(format!("CREATE TABLE {table} (id INTEGER);"))
+                .await
+                .unwrap();
         }
-        Err(eyre!("table not found"))
+        // the middle, the first and the last of the chain
+        for table in ["b", "a", "d"] {
+            aidb.query(format!("DROP TABLE {table};")).await.unwrap();
+        }
+        aidb.query("CREATE TABLE e (id INTEGER);").await.unwrap();
+        assert!(aidb.query("CREATE TABLE c (id INTEGER);").await.is_err());
+        assert!(aidb.query("DROP TABLE a;").await.is_err());
+
+        aidb.query("START TRANSACTION;").await.unwrap();
+        aidb.query("DROP TABLE c;").await.unwrap();
+        aidb.query("CREATE TABLE f (id INTEGER);").await.unwrap();
+        assert_eq!(table_names(&mut aidb).await, ["e", "f"]);
+        aidb.query("ROLLBACK;").await.unwrap();
+        assert_eq!(table_names(&mut aidb).await, ["c", "e"]);
+        assert!(aidb.query("SELECT * FROM f;").await.is_err());
+        aidb.query("SELECT * FROM c;").await.unwrap();
+
+        let mut reopened = Aidb::from_op(aidb.op.clone()).await.unwrap();
+        assert_eq!(table_names(&mut reopened).await, ["c", "e"]);
+        assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_reindex() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, x INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("CREATE INDEX tx ON t (x) USING HASH;")
+            .await
+            .unwrap();
+        let values = (0..200)
+            .map(|i| match i % 10 {
+                0 => format!("({i}, NULL)"),
+                _ => format!("({i}, {})", i * 2),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        aidb.query(format!("INSERT INTO t VALUES {values};"))
+            .await
+            .unwrap();
+        let rows = async |aidb: &mut Aidb, sql: &str| {
+            let Response::Rows { rows, .. } = aidb.query(sql).await.unwrap() else {
+                panic!("rows expected");
+            };
+            rows
+        };
+
+        // as if rows had moved, point the index on id at the row after each and lose the NULL
+        // list of x
+        let records = aidb.select_with_ptr("t".to_owned()).await.unwrap();
+        let moved = (0..records.len())
+            .map(|i| (i as i64, records[(i + 1) % records.len()].1.clone()))
+            .collect();
+        let moved = aidb.build_btree(moved, true).await.unwrap();
+        let mut schema = aidb.get_schema("t").await.unwrap();
+        schema.indices[0].block = moved;
+        schema.set_null_list(1, BlockPtr::NONE);
+        aidb.put_schema("t".to_owned(), schema);
+        assert_eq!(
+            rows(&mut aidb, "SELECT x FROM t WHERE id = 5;").await,
+            [[Value::Integer(12)]]
+        );
+        assert!(
+            rows(&mut aidb, "SELECT id FROM t WHERE x IS NULL;")
+                .await
+                .is_empty()
+        );
+
+        aidb.query("REINDEX t;").await.unwrap();
+        assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
+        for (sql, expected) in [
+            ("SELECT x FROM t WHERE id = 5;", vec![Value::Integer(10)]),
+            ("SELECT x FROM t WHERE id = 10;", vec![Value::Null]),
+            ("SELECT id FROM t WHERE x = 398;", vec![Value::Integer(199)]),
+        ] {
+            assert_eq!(rows(&mut aidb, sql).await, [expected], "{sql}");
+        }
+        assert_eq!(
+            rows(&mut aidb, "SELECT id FROM t WHERE x IS NULL;")
+                .await
+                .len(),
+            20
+        );
+        assert_eq!(
+            rows(&mut aidb, "SELECT id FROM t WHERE id >= 100 AND id < 150;")
+                .await
+                .len(),
+            50
+        );
+
+        // indexes keep working after more writes and the other spelling
+        aidb.query("INSERT INTO t VALUES (200, NULL);")
+            .await
+            .unwrap();
+        aidb.query("alter table t rebuild index").await.unwrap();
+        assert_eq!(
+            rows(&mut aidb, "SELECT id FROM t WHERE x IS NULL;")
+                .await
+                .len(),
+            21
+        );
+        assert!(aidb.query("INSERT INTO t VALUES (5, 1);").await.is_err());
+        assert!(aidb.query("REINDEX missing;").await.is_err());
+        assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
     }
 }
@@ -1,29 +1,44 @@
 use std::{
-    collections::HashMap,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
     fmt::{Display, Formatter},
     iter::repeat,
-    mem::swap,
+    mem::{replace, swap, take},
     ops::Bound,
 };
 
 use crate::{
-    Aidb, Column, DataType, Response, Row, Value,
+    Aidb, Collation, Column, DataType, Response, Row, Value,
     btree::{BTreeExactState, BTreeRangeState},
     data::DataHeader,
+    expr::{Accumulator, AggregateFn, RowCondition, RowExpr},
+    hash::HashLookupState,
+    null::NullListState,
     schema::{IndexInfo, IndexType},
-    sql::{SqlCol, SqlColOrExpr, SqlOn, SqlRel, SqlSelectTarget, SqlWhere},
-    storage::{BLOCK_SIZE, Block, BlockIndex, BlockOffset, DataPointer},
+    sort::{SortKeys, SortState},
+    sql::{
+        SqlCol, SqlColOrExpr, SqlCondition, SqlExpr, SqlGroupBy, SqlIn, SqlOrderBy, SqlRel,
+        SqlSelectTarget, SqlStmt, SqlWhere, Unsupported,
+    },
+    storage::{BLOCK_SIZE, Block, BlockIndex, BlockOffset, BlockPtr, DataPointer},
 };
 
-use binrw::BinRead;
+use binrw::{BinRead, BinWrite};
 use eyre::{OptionExt, Result, eyre};
+use futures::{Stream, future::Either, stream};
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use tracing::debug;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum QueryColumn {
-    Column { table: String, column: String },
+    Column {
+        table: String,
+        column: String,
+    },
     Const(Value),
+    /// over the output of grouping
+    Expr(RowExpr),
 }
 
 #[derive(Debug)]
@@ -39,6 +54,82 @@ enum QueryConstraint {
         column: String,
         value: Value,
     },
+    /// `<=>` of two columns, also true when both are NULL
+    NullSafeEqColumn {
+        table_lhs: String,
+        column_lhs: String,
+        table_rhs: String,
+        column_rhs: String,
+    },
+    NeColumn {
+        table_lhs: String,
+        column_lhs: String,
+        table_rhs: String,
+        column_rhs: String,
+    },
+    NeConst {
+        table: String,
+        column: String,
+        value: Value,
+    },
+    LeColumn {
+        table_lhs: String,
+        column_lhs: String,
+        table_rhs: String,
+        column_rhs: String,
+    },
+    LeConst {
+        table: String,
+        column: String,
+        value: Value,
+    },
+    GeConst {
+        table: String,
+        column: String,
+        value: Value,
+    },
+    LtColumn {
+        table_lhs: String,
+        column_lhs: String,
+        table_rhs: String,
+        column_rhs: String,
+    },
+    LtConst {
+        table: String,
+        column: String,
+        value: Value,
+    },
+    GtConst {
+        table: String,
+        column: String,
+        value: Value,
+    },
+    InConst {
+        table: String,
+        column: String,
+        values: Vec<Value>,
+    },
+    IsNull {
+        table: String,
+        column: String,
+        negated: bool,
+    },
+    Like {
+        table: String,
+        column: String,
+        pattern: String,
+        escape: char,
+    },
+    /// comparison of constants folded to false
+    False,
+}
+
+/// Grouping whose output rows are the group by columns followed by the aggregates.
+#[derive(Debug)]
+struct LogicalAggregate {
+    group_by: Vec<(String, String)>,
+    aggregates: Vec<(AggregateFn, Option<(String, String)>)>,
+    having: Option<RowCondition>,
 }
 
 #[derive(Debug)]
@@ -46,21 +137,127 @@ struct LogicalQueryPlan {
     tables: Vec<String>,
     columns: Vec<QueryColumn>,
     constraints: Vec<QueryConstraint>,
+    aggregate: Option<LogicalAggregate>,
+    /// sort keys, descending if true
+    order_by: Vec<(QueryColumn, bool)>,
     limit: Option<usize>,
 }
 
 type ColumnIndex = usize;
 
+/// bounds of keys of a B-tree range scan
+type KeyRange = (Bound<i64>, Bound<i64>);
+
 #[derive(Debug)]
 enum ProjectionColumn {
     Column(ColumnIndex),
     Const(Value),
+    Expr(RowExpr),
 }
 
 #[derive(Debug)]
 enum SelectionConstraint {
     EqColumn(ColumnIndex, ColumnIndex),
     EqConst(ColumnIndex, Value),
+    NullSafeEqColumn(ColumnIndex, ColumnIndex),
+    NeColumn(ColumnIndex, ColumnIndex),
+    NeConst(ColumnIndex, Value),
+    LeColumn(ColumnIndex, ColumnIndex),
+    LeConst(ColumnIndex, Value),
+    GeConst(ColumnIndex, Value),
+    LtColumn(ColumnIndex, ColumnIndex),
+    LtConst(ColumnIndex, Value),
+    GtConst(ColumnIndex, Value),
+    InConst(ColumnIndex, Vec<Value>),
+    IsNull(ColumnIndex, bool),
+    Like(ColumnIndex, String, char),
+}
+
+impl SelectionConstraint {
+    /// Comparisons with NULL are unknown and never match, so NULLs don't join either. Text is
+    /// compared under `collation`.
+    fn check(&self, row: &Row, collation: Collation) -> bool {
+        use Ordering::*;
+        let compare = |lhs, rhs| collation.compare(lhs, rhs);
+        match self {
+            SelectionConstraint::EqColumn(lhs, rhs) => {
+                compare(&row[*lhs], &row[*rhs]) == Some(Equal)
+            }
+            SelectionConstraint::EqConst(index, value) => {
+                compare(&row[*index], value) == Some(Equal)
+            }
+            SelectionConstraint::NullSafeEqColumn(lhs, rhs) => match (&row[*lhs], &row[*rhs]) {
+                (Value::Null, Value::Null) => true,
+                (lhs, rhs) => compare(lhs, rhs) == Some(Equal),
+            },
+            SelectionConstraint::NeColumn(lhs, rhs) => {
+                matches!(compare(&row[*lhs], &row[*rhs]), Some(Less | Greater))
+            }
+            SelectionConstraint::NeConst(index, value) => {
+                matches!(compare(&row[*index], value), Some(Less | Greater))
+            }
+            SelectionConstraint::LeColumn(lhs, rhs) => {
+                matches!(compare(&row[*lhs], &row[*rhs]), Some(Less | Equal))
+            }
+            SelectionConstraint::LeConst(index, value) => {
+                matches!(compare(&row[*index], value), Some(Less | Equal))
+            }
+            SelectionConstraint::GeConst(index, value) => {
+                matches!(compare(&row[*index], value), Some(Greater | Equal))
+            }
+            SelectionConstraint::LtColumn(lhs, rhs) => {
+                compare(&row[*lhs], &row[*rhs]) == Some(Less)
+            }
+            SelectionConstraint::LtConst(index, value) => {
+                compare(&row[*index], value) == Some(Less)
+            }
+            SelectionConstraint::GtConst(index, value) => {
+                compare(&row[*index], value) == Some(Greater)
+            }
+            SelectionConstraint::InConst(index, values) => values
+                .iter()
+                .any(|value| compare(&row[*index], value) == Some(Equal)),
+            SelectionConstraint::IsNull(index, negated) => (row[*index] == Value::Null) != *negated,
+            SelectionConstraint::Like(index, pattern, escape) => match &row[*index] {
+                Value::Text(s) => collation.like(pattern, *escape, s),
+                _ => false,
+            },
+        }
+    }
+}
+
+impl Display for SelectionConstraint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelectionConstraint::EqColumn(lhs, rhs) => write!(f, "${lhs} = ${rhs}"),
+            SelectionConstraint::EqConst(index, value) => write!(f, "${index} = {value}"),
+            SelectionConstraint::NullSafeEqColumn(lhs, rhs) => write!(f, "${lhs} ≡ ${rhs}"),
+            SelectionConstraint::NeColumn(lhs, rhs) => write!(f, "${lhs} ≠ ${rhs}"),
+            SelectionConstraint::NeConst(index, value) => write!(f, "${index} ≠ {value}"),
+            SelectionConstraint::LeColumn(lhs, rhs) => write!(f, "${lhs} ≤ ${rhs}"),
+            SelectionConstraint::LeConst(index, value) => write!(f, "${index} ≤ {value}"),
+            SelectionConstraint::GeConst(index, value) => write!(f, "${index} ≥ {value}"),
+            SelectionConstraint::LtColumn(lhs, rhs) => write!(f, "${lhs} < ${rhs}"),
+            SelectionConstraint::LtConst(index, value) => write!(f, "${index} < {value}"),
+            SelectionConstraint::GtConst(index, value) => write!(f, "${index} > {value}"),
+            SelectionConstraint::InConst(index, values) => write!(
+                f,
+                "${index} ∈ {{{}}}",
+                values.iter().map(|value| value.to_string()).join(", ")
+            ),
+            SelectionConstraint::IsNull(index, false) => write!(f, "${index} = NULL"),
+            SelectionConstraint::IsNull(index, true) => write!(f, "${index} ≠ NULL"),
+            SelectionConstraint::Like(index, pattern, '\\') => {
+                write!(f, "${index} LIKE {}", Value::Text(pattern.clone()))
+            }
+            SelectionConstraint::Like(index, pattern, escape) => write!(
+                f,
+                "${index} LIKE {} ESCAPE {}",
+                Value::Text(pattern.clone()),
+                Value::Text(escape.to_string())
+            ),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -68,7 +265,7 @@ enum ScanState {
     Initialized,
     Running {
         block_index: BlockIndex,
-        next_block_index: BlockIndex,
+        next_block_index: BlockPtr,
         block: Block,
         offset: BlockOffset,
     },
@@ -80,9 +277,32 @@ impl Default for ScanState {
     }
 }
 
+/// Plan borrowed by a stream, reset on drop so that blocks held by the plan return to cache even if
+/// the stream is not polled to the end.
+struct PlanGuard<'a> {
+    db: &'a mut Aidb,
+    plan: PhysicalPlan,
+}
+
+impl Drop for PlanGuard<'_> {
+    fn drop(&mut self) {
+        self.plan.reset(self.db);
+    }
+}
+
+/// Plan run by a [`crate::Cursor`], reset by its owner when it is dropped.
+pub(crate) struct CursorPlan(PhysicalPlan);
+
+impl CursorPlan {
+    pub(crate) fn reset(&mut self, db: &mut Aidb) {
+        self.0.reset(db);
+    }
+}
+
 #[derive(Debug)]
 struct CartesianProductState {
     first_run: bool,
+    exhausted: bool,
     previous_row: Vec<Row>,
 }
 
@@ -90,28 +310,86 @@ impl Default for CartesianProductState {
     fn default() -> Self {
         Self {
             first_run: true,
+            exhausted: false,
             previous_row: vec![],
         }
     }
 }
 
+#[derive(Debug, Default)]
+struct AggregateState {
+    output: Option<std::vec::IntoIter<Row>>,
+}
+
+/// Value of a row kept in a hash set, reals by their bits with -0.0 as 0.0 so that equal values
+/// have equal keys.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ValueKey {
+    Null,
+    Integer(i64),
+    Real(u64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl From<&Value> for ValueKey {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => ValueKey::Null,
+            Value::Integer(v) => ValueKey::Integer(*v),
+            Value::Real(v) => ValueKey::Real((v + 0.0).to_bits()),
+            Value::Text(s) => ValueKey::Text(s.clone()),
+            Value::Blob(bytes) => ValueKey::Blob(bytes.clone()),
+            Value::Placeholder(_) => unreachable!(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct UnionState {
+    right: bool,
+    seen: HashSet<Vec<ValueKey>>,
+}
+
+/// Counters of an operator for `EXPLAIN ANALYZE`, including those of its inner plans.
+#[derive(Debug, Default)]
+struct OperatorStats {
+    rows: usize,
+    lookups: usize,
+    reads: usize,
+}
+
 #[derive(Debug)]
 enum PhysicalPlan {
     Scan {
         row_size: usize,
-        first_block: BlockIndex,
+        first_block: BlockPtr,
         state: ScanState,
     },
     BTreeExact {
-        root: BlockIndex,
+        root: BlockPtr,
         key: i64,
         state: BTreeExactState,
     },
+    /// records with keys in the range, in descending order of keys if reverse
     BTreeRange {
-        root: BlockIndex,
-        range: (Bound<i64>, Bound<i64>),
+        root: BlockPtr,
+        range: KeyRange,
+        reverse: bool,
         state: BTreeRangeState,
     },
+    HashLookup {
+        root: BlockPtr,
+        key: i64,
+        state: HashLookupState,
+    },
+    /// rows holding NULL in an indexed column
+    NullList {
+        head: BlockPtr,
+        state: NullListState,
+    },
+    /// no rows, in place of tables when the WHERE clause is always false
+    Empty,
     Projection {
         columns: Vec<ProjectionColumn>,
         inner: Box<PhysicalPlan>,
@@ -122,6 +400,7 @@ enum PhysicalPlan {
     },
     Selection {
         constraints: Vec<SelectionConstraint>,
+        collation: Collation,
         inner: Box<PhysicalPlan>,
     },
     Limit {
@@ -129,6 +408,33 @@ enum PhysicalPlan {
         inner: Box<PhysicalPlan>,
         state: usize,
     },
+    Aggregate {
+        group_by: Vec<ColumnIndex>,
+        aggregates: Vec<(AggregateFn, Option<ColumnIndex>)>,
+        inner: Box<PhysicalPlan>,
+        state: AggregateState,
+    },
+    /// filter on the output of grouping
+    Having {
+        condition: RowCondition,
+        inner: Box<PhysicalPlan>,
+    },
+    Sort {
+        keys: SortKeys,
+        inner: Box<PhysicalPlan>,
+        state: SortState,
+    },
+    Union {
+        left: Box<PhysicalPlan>,
+        right: Box<PhysicalPlan>,
+        all: bool,
+        state: UnionState,
+    },
+    /// counts rows and block I/O of the inner operator for `EXPLAIN ANALYZE`
+    Analyze {
+        inner: Box<PhysicalPlan>,
+        stats: OperatorStats,
+    },
 }
 
 impl PhysicalPlan {
@@ -147,6 +453,9 @@ impl PhysicalPlan {
             }
             PhysicalPlan::BTreeExact { state, .. } => *state = BTreeExactState::Initialized,
             PhysicalPlan::BTreeRange { state, .. } => *state = BTreeRangeState::Initialized,
+            PhysicalPlan::HashLookup { state, .. } => *state = HashLookupState::Initialized,
+            PhysicalPlan::NullList { state, .. } => *state = NullListState::Initialized,
+            PhysicalPlan::Empty => {}
             PhysicalPlan::Projection { inner, .. } => inner.reset(db),
             PhysicalPlan::CartesianProduct { inner, state } => {
                 for plan in inner {
@@ -159,95 +468,481 @@ impl PhysicalPlan {
                 inner.reset(db);
                 *state = 0;
             }
+            PhysicalPlan::Aggregate { inner, state, .. } => {
+                inner.reset(db);
+                *state = Default::default();
+            }
+            PhysicalPlan::Having { inner, .. } => inner.reset(db),
+            PhysicalPlan::Sort { inner, state, .. } => {
+                inner.reset(db);
+                state.reset(db);
+            }
+            PhysicalPlan::Union {
+                left, right, state, ..
+            } => {
+                left.reset(db);
+                right.reset(db);
+                *state = Default::default();
+            }
+            // counters add up over restarts of the inner plan
+            PhysicalPlan::Analyze { inner, .. } => inner.reset(db),
+        }
+    }
+
+    /// Wrap every operator to count what it does.
+    fn analyze(mut self) -> Self {
+        for inner in self.children_mut() {
+            *inner = replace(inner, PhysicalPlan::Empty).analyze();
+        }
+        PhysicalPlan::Analyze {
+            inner: Box::new(self),
+            stats: Default::default(),
+        }
+    }
+
+    /// One row for each operator of an analyzed plan, indented by depth.
+    fn analyzed_rows(&self, depth: usize, rows: &mut Vec<Row>) {
+        let PhysicalPlan::Analyze { inner, stats } = self else {
+            unreachable!()
+        };
+        rows.push(vec![
+            Value::Text(format!(
+                "{}{} {}",
+                "  ".repeat(depth),
+                inner.kind(),
+                inner.detail()
+            )),
+            Value::Integer(stats.rows as i64),
+            Value::Integer(stats.lookups as i64),
+            Value::Integer(stats.reads as i64),
+        ]);
+        for plan in inner.children() {
+            plan.analyzed_rows(depth + 1, rows);
         }
     }
 }
 
-impl Display for PhysicalPlan {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+/// Node of a physical plan for display, see [`Aidb::explain_tree`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlanNode {
+    /// operator, e.g. `Scan` or `Selection`
+    pub kind: String,
+    /// operator arguments in relational algebra notation
+    pub detail: String,
+    /// relative cost from plan shape alone, as tables have no statistics
+    pub estimated_cost: u64,
+    pub children: Vec<PlanNode>,
+}
+
+impl PhysicalPlan {
+    fn kind(&self) -> &'static str {
         match self {
-            PhysicalPlan::Scan { first_block, .. } => write!(f, "@{first_block}"),
-            PhysicalPlan::BTreeExact { root, key, .. } => write!(f, "btree@{root} = {key}"),
-            PhysicalPlan::BTreeRange { root, range, .. } => write!(f, "btree@{root} {range:?}"),
-            PhysicalPlan::Projection { columns, inner } => write!(
-                f,
-                "Π{{{}}} ({inner})",
+            PhysicalPlan::Scan { .. } => "Scan",
+            PhysicalPlan::BTreeExact { .. } => "BTreeExact",
+            PhysicalPlan::BTreeRange { .. } => "BTreeRange",
+            PhysicalPlan::HashLookup { .. } => "HashLookup",
+            PhysicalPlan::NullList { .. } => "NullList",
+            PhysicalPlan::Empty => "Empty",
+            PhysicalPlan::Projection { .. } => "Projection",
+            PhysicalPlan::CartesianProduct { .. } => "CartesianProduct",
+            PhysicalPlan::Selection { .. } => "Selection",
+            PhysicalPlan::Limit { .. } => "Limit",
+            PhysicalPlan::Aggregate { .. } => "Aggregate",
+            PhysicalPlan::Having { .. } => "Having",
+            PhysicalPlan::Sort { .. } => "Sort",
+            PhysicalPlan::Union { .. } => "Union",
+            PhysicalPlan::Analyze { inner, .. } => inner.kind(),
+        }
+    }
+
+    /// Operator with its arguments but without inner plans.
+    fn detail(&self) -> String {
+        match self {
+            PhysicalPlan::Scan { first_block, .. } => format!("@{first_block}"),
+            PhysicalPlan::BTreeExact { root, key, .. } => format!("btree@{root} = {key}"),
+            PhysicalPlan::BTreeRange {
+                root,
+                range,
+                reverse,
+                ..
+            } => format!(
+                "btree@{root} {range:?}{}",
+                if *reverse { " DESC" } else { "" }
+            ),
+            PhysicalPlan::HashLookup { root, key, .. } => format!("hash@{root} = {key}"),
+            PhysicalPlan::NullList { head, .. } => format!("nulls@{head}"),
+            PhysicalPlan::Empty => "false".to_owned(),
+            PhysicalPlan::Projection { columns, .. } => format!(
+                "Π{{{}}}",
                 columns
                     .iter()
                     .map(|column| match column {
                         ProjectionColumn::Column(index) => format!("${index}"),
                         ProjectionColumn::Const(value) => format!("{value}"),
+                        ProjectionColumn::Expr(expr) => format!("{expr}"),
                     })
                     .collect_vec()
                     .join(", ")
             ),
             PhysicalPlan::CartesianProduct { inner, .. } => {
-                if inner.is_empty() {
-                    write!(f, "∅")
-                } else {
-                    write!(
-                        f,
-                        "{}",
-                        inner
-                            .iter()
-                            .map(|plan| format!("({plan})"))
-                            .collect_vec()
-                            .join(" × ")
-                    )
-                }
+                if inner.is_empty() { "∅" } else { "×" }.to_owned()
             }
-            PhysicalPlan::Selection { constraints, inner } => write!(
-                f,
-                "σ{{{}}} ({inner})",
+            PhysicalPlan::Selection { constraints, .. } => format!(
+                "σ{{{}}}",
                 constraints
                     .iter()
-                    .map(|constraint| match constraint {
-                        SelectionConstraint::EqColumn(lhs, rhs) => format!("${lhs} = ${rhs}"),
-                        SelectionConstraint::EqConst(index, value) => format!("${index} = {value}"),
-                    })
+                    .map(|constraint| constraint.to_string())
                     .collect_vec()
                     .join(" ∧ ")
             ),
-            PhysicalPlan::Limit { limit, inner, .. } => write!(f, "limit{{{limit}}} ({inner})"),
+            PhysicalPlan::Limit { limit, .. } => format!("limit{{{limit}}}"),
+            PhysicalPlan::Aggregate {
+                group_by,
+                aggregates,
+                ..
+            } => format!(
+                "γ{{{}}}",
+                group_by
+                    .iter()
+                    .map(|index| format!("${index}"))
+                    .chain(aggregates.iter().map(|(function, index)| match index {
+                        Some(index) => format!("{function}(${index})"),
+                        None => format!("{function}(*)"),
+                    }))
+                    .join(", ")
+            ),
+            PhysicalPlan::Having { condition, .. } => format!("σ{{{condition}}}"),
+            PhysicalPlan::Sort { keys, .. } => format!("τ{{{keys}}}"),
+            PhysicalPlan::Union { all, .. } => if *all { "⊎" } else { "∪" }.to_owned(),
+            PhysicalPlan::Analyze { inner, .. } => inner.detail(),
+        }
+    }
+
+    fn children(&self) -> Vec<&PhysicalPlan> {
+        match self {
+            PhysicalPlan::Scan { .. }
+            | PhysicalPlan::BTreeExact { .. }
+            | PhysicalPlan::BTreeRange { .. }
+            | PhysicalPlan::HashLookup { .. }
+            | PhysicalPlan::NullList { .. }
+            | PhysicalPlan::Empty => vec![],
+            PhysicalPlan::CartesianProduct { inner, .. } => inner.iter().collect(),
+            PhysicalPlan::Projection { inner, .. }
+            | PhysicalPlan::Selection { inner, .. }
+            | PhysicalPlan::Limit { inner, .. }
+            | PhysicalPlan::Aggregate { inner, .. }
+            | PhysicalPlan::Having { inner, .. }
+            | PhysicalPlan::Sort { inner, .. } => vec![inner],
+            PhysicalPlan::Union { left, right, .. } => vec![left, right],
+            PhysicalPlan::Analyze { inner, .. } => inner.children(),
+        }
+    }
+
+    fn children_mut(&mut self) -> Vec<&mut PhysicalPlan> {
+        match self {
+            PhysicalPlan::Scan { .. }
+            | PhysicalPlan::BTreeExact { .. }
+            | PhysicalPlan::BTreeRange { .. }
+            | PhysicalPlan::HashLookup { .. }
+            | PhysicalPlan::NullList { .. }
+            | PhysicalPlan::Empty => vec![],
+            PhysicalPlan::CartesianProduct { inner, .. } => inner.iter_mut().collect(),
+            PhysicalPlan::Projection { inner, .. }
+            | PhysicalPlan::Selection { inner, .. }
+            | PhysicalPlan::Limit { inner, .. }
+            | PhysicalPlan::Aggregate { inner, .. }
+            | PhysicalPlan::Having { inner, .. }
+            | PhysicalPlan::Sort { inner, .. }
+            | PhysicalPlan::Analyze { inner, .. } => vec![inner],
+            PhysicalPlan::Union { left, right, .. } => vec![left, right],
+        }
+    }
+
+    fn to_node(&self) -> PlanNode {
+        if let PhysicalPlan::Analyze { inner, .. } = self {
+            return inner.to_node();
+        }
+        let children = self.children().into_iter().map(Self::to_node).collect_vec();
+        let estimated_cost = match self {
+            PhysicalPlan::Scan { .. } => 100,
+            PhysicalPlan::BTreeExact { .. } => 3,
+            PhysicalPlan::BTreeRange { .. } => 30,
+            PhysicalPlan::HashLookup { .. } => 1,
+            PhysicalPlan::NullList { .. } => 3,
+            PhysicalPlan::CartesianProduct { .. } => children
+                .iter()
+                .map(|node| node.estimated_cost)
+                .reduce(|lhs, rhs| lhs.saturating_mul(rhs))
+                .unwrap_or(1),
+            _ => children.iter().map(|node| node.estimated_cost).sum(),
+        };
+        PlanNode {
+            kind: self.kind().to_owned(),
+            detail: self.detail(),
+            estimated_cost,
+            children,
+        }
+    }
+}
+
+impl Display for PhysicalPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PhysicalPlan::CartesianProduct { inner, .. } if !inner.is_empty() => write!(
+                f,
+                "{}",
+                inner
+                    .iter()
+                    .map(|plan| format!("({plan})"))
+                    .collect_vec()
+                    .join(" × ")
+            ),
+            PhysicalPlan::Union { left, right, .. } => {
+                write!(f, "({left}) {} ({right})", self.detail())
+            }
+            PhysicalPlan::Analyze { inner, .. } => write!(f, "{inner}"),
+            _ => match &self.children()[..] {
+                [] => write!(f, "{}", self.detail()),
+                [inner] => write!(f, "{} ({inner})", self.detail()),
+                _ => unreachable!(),
+            },
+        }
+    }
+}
+
+/// The query to plan for SELECT, UNION or EXPLAIN.
+fn explained(stmt: SqlStmt) -> Option<SqlStmt> {
+    match stmt {
+        SqlStmt::Explain {
+            columns,
+            table,
+            join_on,
+            where_,
+            group_by,
+            order_by,
+            limit,
+            ..
+        } => Some(SqlStmt::Select {
+            columns,
+            table,
+            join_on,
+            where_,
+            group_by,
+            order_by,
+            limit,
+        }),
+        stmt @ (SqlStmt::Select { .. } | SqlStmt::Union { .. }) => Some(stmt),
+        _ => None,
+    }
+}
+
+/// SELECT of every column of a table, as UPDATE and DELETE look for rows.
+fn scan_stmt(table: String, where_: Option<SqlWhere>) -> SqlStmt {
+    SqlStmt::Select {
+        columns: vec![],
+        table: Some(table),
+        join_on: vec![],
+        where_,
+        group_by: None,
+        order_by: vec![],
+        limit: None,
+    }
+}
+
+/// Keep one of the equality constraints on a column with the same constant, and replace those
+/// with different numbers by [`QueryConstraint::False`]. Different texts may still be equal under
+/// a case-insensitive collation, they are kept.
+fn merge_eq_consts(constraints: &mut Vec<QueryConstraint>) {
+    let mut seen: Vec<(String, String, Value)> = vec![];
+    let mut contradicted = false;
+    constraints.retain(|constraint| {
+        let QueryConstraint::EqConst {
+            table,
+            column,
+            value,
+        } = constraint
+        else {
+            return true;
+        };
+        let mut same_column = seen.iter().filter(|(t, c, _)| t == table && c == column);
+        if same_column
+            .clone()
+            .any(|(_, _, v)| v.compare(value) == Some(Ordering::Equal))
+        {
+            return false;
+        }
+        if same_column.any(|(_, _, v)| !matches!(v, Value::Text(_))) {
+            contradicted = true;
+        }
+        seen.push((table.clone(), column.clone(), value.clone()));
+        true
+    });
+    if contradicted {
+        constraints.push(QueryConstraint::False);
+    }
+}
+
+impl QueryConstraint {
+    /// Table, column and bound of a comparison with an integer, and whether it is a lower bound.
+    fn integer_bound(&self) -> Option<(&str, &str, Bound<i64>, bool)> {
+        match self {
+            QueryConstraint::LeConst {
+                table,
+                column,
+                value: Value::Integer(value),
+            } => Some((table, column, Bound::Included(*value), false)),
+            QueryConstraint::LtConst {
+                table,
+                column,
+                value: Value::Integer(value),
+            } => Some((table, column, Bound::Excluded(*value), false)),
+            QueryConstraint::GeConst {
+                table,
+                column,
+                value: Value::Integer(value),
+            } => Some((table, column, Bound::Included(*value), true)),
+            QueryConstraint::GtConst {
+                table,
+                column,
+                value: Value::Integer(value),
+            } => Some((table, column, Bound::Excluded(*value), true)),
+            _ => None,
+        }
+    }
+}
+
+/// The tighter of two lower bounds, or of two upper bounds if not `lower`.
+fn tighter(lhs: Bound<i64>, rhs: Bound<i64>, lower: bool) -> Bound<i64> {
+    use Bound::*;
+    let pick = |a: i64, b: i64| if lower { a.max(b) } else { a.min(b) };
+    match (lhs, rhs) {
+        (Unbounded, bound) | (bound, Unbounded) => bound,
+        (Included(a), Included(b)) => Included(pick(a, b)),
+        (Excluded(a), Excluded(b)) => Excluded(pick(a, b)),
+        (Included(a), Excluded(b)) | (Excluded(b), Included(a)) => {
+            if pick(a, b) == b {
+                Excluded(b)
+            } else {
+                Included(a)
+            }
+        }
+    }
+}
+
+/// Take the integer bounds on the first column of `table` with a B-tree index, given the root by
+/// `btree_root`, merged into one range of keys to scan. Bounds on other columns and comparisons
+/// with fractions or NULL are left to selection.
+fn take_btree_range(
+    constraints: &mut Vec<QueryConstraint>,
+    table: &str,
+    btree_root: impl Fn(&str) -> Option<BlockPtr>,
+) -> Option<(BlockPtr, KeyRange)> {
+    let (column, root) = constraints.iter().find_map(|constraint| {
+        let (t, column, _, _) = constraint.integer_bound()?;
+        let root = btree_root(column).filter(|_| t == table)?;
+        Some((column.to_owned(), root))
+    })?;
+    let mut range = (Bound::Unbounded, Bound::Unbounded);
+    constraints.retain(|constraint| match constraint.integer_bound() {
+        Some((t, c, bound, lower)) if t == table && c == column => {
+            if lower {
+                range.0 = tighter(range.0, bound, true);
+            } else {
+                range.1 = tighter(range.1, bound, false);
+            }
+            false
+        }
+        _ => true,
+    });
+    Some((root, range))
+}
+
+/// INTEGER and REAL columns are compared by value, TEXT only with TEXT.
+fn check_comparable(lhs: DataType, rhs: DataType) -> Result<()> {
+    let numeric = |datatype| matches!(datatype, DataType::Integer | DataType::Real);
+    if lhs == rhs || (numeric(lhs) && numeric(rhs)) {
+        Ok(())
+    } else {
+        Err(eyre!("cannot compare {lhs} with {rhs}"))
+    }
+}
+
+/// Convert a constant compared with a column. Numeric text is parsed for INTEGER and REAL columns,
+/// integral REAL becomes INTEGER for INTEGER columns so indices apply and INTEGER becomes REAL for
+/// REAL columns, other numbers are compared by value. Text and numbers can't be compared otherwise.
+fn coerce_const(value: Value, datatype: DataType) -> Result<Value> {
+    match (datatype, value) {
+        (_, value @ (Value::Null | Value::Placeholder(_))) => Ok(value),
+        (DataType::Integer, Value::Real(f))
+            if f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64 =>
+        {
+            Ok(Value::Integer(f as i64))
         }
+        (DataType::Real, Value::Integer(i)) => Ok(Value::Real(i as f64)),
+        (DataType::Integer | DataType::Real, value @ (Value::Integer(_) | Value::Real(_))) => {
+            Ok(value)
+        }
+        (DataType::Integer | DataType::Real, Value::Text(s)) => {
+            let number = match s.trim().parse::<i64>() {
+                Ok(i) => Value::Integer(i),
+                Err(_) => match s.trim().parse::<f64>() {
+                    Ok(f) if f.is_finite() => Value::Real(f),
+                    _ => return Err(eyre!("cannot compare {datatype} with TEXT '{s}'")),
+                },
+            };
+            coerce_const(number, datatype)
+        }
+        (DataType::Text, value @ Value::Text(_)) => Ok(value),
+        (DataType::Blob, value @ Value::Blob(_)) => Ok(value),
+        (_, value) => Err(eyre!(
+            "cannot compare {datatype} with {}",
+            value.datatype().unwrap()
+        )),
     }
 }
 
 impl Aidb {
-    pub(crate) async fn select(
-        &mut self,
-        columns: Vec<SqlSelectTarget>,
-        table: Option<String>,
-        join_on: Vec<(String, SqlOn)>,
-        where_: Option<SqlWhere>,
-        limit: Option<usize>,
-    ) -> Result<Response> {
-        let (columns, plan) = self
-            .build_logical_plan(columns, table, join_on, where_, limit)
-            .await?;
+    pub(crate) async fn select(&mut self, stmt: SqlStmt) -> Result<Response> {
+        let (columns, plan) = self.build_logical_plan(stmt).await?;
         debug!(logical = ?plan);
         let mut plan = self.build_physical_plan(plan).await?;
         debug!(physical = plan.to_string());
-        let mut rows = vec![];
-        while let Some(row) = self.execute_select(&mut plan).await? {
-            debug!(?row);
-            rows.push(row);
-        }
+        let r = self.collect_rows(&mut plan).await;
         plan.reset(self);
-        Ok(Response::Rows { columns, rows })
+        let (rows, truncated) = r?;
+        Ok(Response::Rows {
+            columns,
+            rows,
+            truncated,
+        })
     }
 
-    pub(crate) async fn explain(
+    pub(crate) async fn union(
         &mut self,
-        columns: Vec<SqlSelectTarget>,
-        table: Option<String>,
-        join_on: Vec<(String, SqlOn)>,
-        where_: Option<SqlWhere>,
-        limit: Option<usize>,
+        left: SqlStmt,
+        right: SqlStmt,
+        all: bool,
     ) -> Result<Response> {
-        let (_, plan) = self
-            .build_logical_plan(columns, table, join_on, where_, limit)
+        let (columns, mut plan) = self
+            .build_union_plan(SqlStmt::Union {
+                left: Box::new(left),
+                right: Box::new(right),
+                all,
+            })
             .await?;
+        debug!(physical = plan.to_string());
+        let r = self.collect_rows(&mut plan).await;
+        plan.reset(self);
+        let (rows, truncated) = r?;
+        Ok(Response::Rows {
+            columns,
+            rows,
+            truncated,
+        })
+    }
+
+    pub(crate) async fn explain(&mut self, stmt: SqlStmt) -> Result<Response> {
+        let (_, plan) = self.build_logical_plan(explained(stmt).unwrap()).await?;
         debug!(logical = ?plan);
         let plan = self.build_physical_plan(plan).await?;
         debug!(physical = plan.to_string());
@@ -257,27 +952,177 @@ impl Aidb {
                 datatype: DataType::Text,
             }],
             rows: vec![vec![Value::Text(plan.to_string())]],
+            truncated: false,
         })
     }
 
-    async fn select_for_ptr(
-        &mut self,
-        table: String,
-        where_: Option<SqlWhere>,
-    ) -> Result<Vec<DataPointer>> {
-        let (_, plan) = self
-            .build_logical_plan(vec![], Some(table), vec![], where_, None)
-            .await?;
+    /// Run a plan to the end, discarding its rows, and report what each operator did: rows it
+    /// produced, block lookups and physical block reads, each including its inner operators.
+    pub(crate) async fn explain_analyze(&mut self, stmt: SqlStmt) -> Result<Response> {
+        let (_, plan) = self.build_logical_plan(explained(stmt).unwrap()).await?;
         debug!(logical = ?plan);
-        let mut plan = self.build_physical_plan(plan).await?;
+        let mut plan = self.build_physical_plan(plan).await?.analyze();
         debug!(physical = plan.to_string());
-        let mut rows = vec![];
-        while let Some((row, ptr)) = self.execute_for_ptr(&mut plan).await? {
-            debug!(?row, ptr = ptr.to_string());
-            rows.push(ptr);
+        let mut r = self.execute_select(&mut plan).await;
+        while let Ok(Some(_)) = r {
+            r = self.execute_select(&mut plan).await;
         }
         plan.reset(self);
-        Ok(rows)
+        r?;
+        let mut rows = vec![];
+        plan.analyzed_rows(0, &mut rows);
+        let column = |name: &str, datatype| Column {
+            name: name.to_owned(),
+            datatype,
+        };
+        Ok(Response::Rows {
+            columns: vec![
+                column("operator", DataType::Text),
+                column("rows", DataType::Integer),
+                column("lookups", DataType::Integer),
+                column("reads", DataType::Integer),
+            ],
+            rows,
+            truncated: false,
+        })
+    }
+
+    /// Run a plan to the end, keeping at most [`Aidb::set_max_rows`] rows. Returns whether there
+    /// were more.
+    async fn collect_rows(&mut self, plan: &mut PhysicalPlan) -> Result<(Vec<Row>, bool)> {
+        let mut rows = vec![];
+        while let Some(row) = self.execute_select(plan).await? {
+            if self.max_rows.is_some_and(|max_rows| rows.len() >= max_rows) {
+                return Ok((rows, true));
+            }
+            debug!(?row);
+            rows.push(row);
+        }
+        Ok((rows, false))
+    }
+
+    /// Plan a query like `EXPLAIN` does, as a tree instead of text.
+    pub async fn explain_tree(&mut self, sql: impl AsRef<str>) -> Result<PlanNode> {
+        let stmt = explained(Self::parse(sql)?).ok_or_eyre("only SELECT can be explained")?;
+        let (_, plan) = self.build_union_plan(stmt).await?;
+        debug!(physical = plan.to_string());
+        Ok(plan.to_node())
+    }
+
+    /// Check a statement without executing it, with the syntax error if it doesn't parse. SELECT
+    /// and EXPLAIN are also planned to resolve tables and columns, returning the physical plan
+    /// or the first semantic error. Only schemas are read, never data.
+    pub async fn validate(&mut self, sql: impl AsRef<str>) -> Result<Option<String>> {
+        let Some(stmt) = explained(Self::parse(sql)?) else {
+            return Ok(None);
+        };
+        let (_, plan) = self.build_union_plan(stmt).await?;
+        Ok(Some(plan.to_string()))
+    }
+
+    /// Scan all rows of a table without going through SQL, along with the column headers.
+    pub async fn scan_table(
+        &mut self,
+        table: &str,
+    ) -> Result<(Vec<Column>, impl Stream<Item = Result<Row>> + '_)> {
+        let schema = self.get_schema(table).await?;
+        let columns = schema.columns.clone();
+        let plan = PhysicalPlan::Scan {
+            row_size: schema.row_size(),
+            first_block: schema.data_block,
+            state: Default::default(),
+        };
+        self.put_schema(table.to_owned(), schema);
+        Ok((columns, self.stream_plan(plan)))
+    }
+
+    /// Run a statement that only reads, see [`Aidb::is_read`], returning its column headers and
+    /// rows. SELECT and UNION produce rows as the plan does instead of collecting them first,
+    /// dropping the stream stops the plan where it is. Other statements run as in
+    /// [`Aidb::query`].
+    pub async fn query_stream(
+        &mut self,
+        sql: impl AsRef<str>,
+    ) -> Result<(Vec<Column>, impl Stream<Item = Result<Row>> + '_)> {
+        let stmt = self.stmt_cache.parse(sql.as_ref())?;
+        if !stmt.is_read() {
+            return Err(eyre!("only statements that read can be streamed"));
+        }
+        if let SqlStmt::Select { .. } | SqlStmt::Union { .. } = stmt {
+            let (columns, plan) = self.build_union_plan(stmt).await?;
+            debug!(physical = plan.to_string());
+            return Ok((columns, Either::Left(self.stream_plan(plan))));
+        }
+        let Response::Rows { columns, rows, .. } = self.query_stmt(stmt).await? else {
+            unreachable!()
+        };
+        Ok((
+            columns,
+            Either::Right(stream::iter(rows.into_iter().map(Ok))),
+        ))
+    }
+
+    /// Plan of SELECT or UNION to be run a row at a time by a cursor.
+    pub(crate) async fn build_cursor_plan(
+        &mut self,
+        stmt: SqlStmt,
+    ) -> Result<(Vec<Column>, CursorPlan)> {
+        let (columns, plan) = self.build_union_plan(stmt).await?;
+        debug!(physical = plan.to_string());
+        Ok((columns, CursorPlan(plan)))
+    }
+
+    pub(crate) async fn execute_cursor_plan(
+        &mut self,
+        plan: &mut CursorPlan,
+    ) -> Result<Option<Row>> {
+        self.execute_select(&mut plan.0).await
+    }
+
+    fn stream_plan(&mut self, plan: PhysicalPlan) -> impl Stream<Item = Result<Row>> + '_ {
+        let guard = PlanGuard { db: self, plan };
+        stream::unfold(Some(guard), async |guard| {
+            let mut guard = guard?;
+            let PlanGuard { db, plan } = &mut guard;
+            match db.execute_select(plan).await {
+                Ok(Some(row)) => Some((Ok(row), Some(guard))),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+
+    /// Scan all rows of a table along with their location.
+    pub(crate) async fn select_with_ptr(
+        &mut self,
+        table: String,
+    ) -> Result<Vec<(Row, DataPointer)>> {
+        let (_, plan) = self.build_logical_plan(scan_stmt(table, None)).await?;
+        let mut plan = self.build_physical_plan(plan).await?;
+        let mut rows = vec![];
+        while let Some(row) = self.execute_for_ptr(&mut plan).await? {
+            rows.push(row);
+        }
+        plan.reset(self);
+        Ok(rows)
+    }
+
+    async fn select_for_ptr(
+        &mut self,
+        table: String,
+        where_: Option<SqlWhere>,
+    ) -> Result<Vec<DataPointer>> {
+        let (_, plan) = self.build_logical_plan(scan_stmt(table, where_)).await?;
+        debug!(logical = ?plan);
+        let mut plan = self.build_physical_plan(plan).await?;
+        debug!(physical = plan.to_string());
+        let mut rows = vec![];
+        while let Some((row, ptr)) = self.execute_for_ptr(&mut plan).await? {
+            debug!(?row, ptr = ptr.to_string());
+            rows.push(ptr);
+        }
+        plan.reset(self);
+        Ok(rows)
     }
 
     pub(crate) async fn update(
@@ -337,6 +1182,14 @@ impl Aidb {
         for ptr in rows {
             let mut block = self.get_block(ptr.block).await?;
             self.delete_row(&mut block.cursor_at(ptr.offset)).await?;
+            // the freed slot may be reused by inserts looking for free slots
+            let mut cursor = block.cursor();
+            let mut header = DataHeader::read(&mut cursor)?;
+            if header.is_full {
+                header.is_full = false;
+                cursor.set_position(0);
+                header.write(&mut cursor)?;
+            }
             self.put_block(ptr.block, block);
             self.mark_block_dirty(ptr.block);
         }
@@ -345,12 +1198,27 @@ impl Aidb {
 
     async fn build_logical_plan(
         &mut self,
-        columns: Vec<SqlSelectTarget>,
-        table: Option<String>,
-        join_on: Vec<(String, SqlOn)>,
-        where_: Option<SqlWhere>,
-        limit: Option<usize>,
+        stmt: SqlStmt,
     ) -> Result<(Vec<Column>, LogicalQueryPlan)> {
+        let SqlStmt::Select {
+            mut columns,
+            table,
+            join_on,
+            where_,
+            group_by,
+            mut order_by,
+            limit,
+        } = stmt
+        else {
+            unreachable!()
+        };
+        let where_ = match where_ {
+            Some(mut where_) => {
+                self.materialize_subqueries(&mut where_).await?;
+                Some(where_)
+            }
+            None => None,
+        };
         let from_table = table;
         let mut headers = vec![];
         let mut tables = vec![];
@@ -360,15 +1228,17 @@ impl Aidb {
         tables.extend(join_on.iter().map(|(table, _on)| table.clone()));
         let mut query_columns = vec![];
         let mut constraints = vec![];
+        let mut sort_keys = vec![];
 
-        let mut schemas = HashMap::new();
-        for table in tables.iter() {
-            if schemas.contains_key(table) {
-                Err(eyre!("duplicate table"))?;
-            }
-            let schema = self.get_schema(table).await?;
-            schemas.insert(table.clone(), schema);
+        // columns are identified by table name, so a table can't appear twice
+        if !tables.iter().all_unique() {
+            Err(eyre!("duplicate table"))?;
         }
+        self.cache_schemas(&tables).await?;
+        let schemas = tables
+            .iter()
+            .map(|table| Ok((table.clone(), self.cached_schema(table)?)))
+            .collect::<Result<HashMap<_, _>>>()?;
 
         let reify_column = |column| -> Result<(String, String, DataType)> {
             match column {
@@ -379,7 +1249,7 @@ impl Aidb {
                     let Some(Column { datatype, .. }) =
                         schema.columns.iter().find(|c| column == c.name)
                     else {
-                        return Err(eyre!("column not found"));
+                        return Err(eyre!("column {table}.{column} not found"));
                     };
                     Ok((table, column, *datatype))
                 }
@@ -391,9 +1261,9 @@ impl Aidb {
                         .map(|(t, c)| (t.clone(), c.clone()))
                         .collect_vec();
                     if matched_columns.is_empty() {
-                        Err(eyre!("column not found"))?
+                        Err(eyre!("column {column} not found"))?
                     } else if matched_columns.len() > 1 {
-                        Err(eyre!("ambiguous column"))?;
+                        Err(eyre!("ambiguous column {column}"))?;
                     }
                     let (table, Column { datatype, .. }) =
                         matched_columns.into_iter().next().unwrap();
@@ -402,10 +1272,176 @@ impl Aidb {
             }
         };
 
+        type BoundAggregate = (AggregateFn, Option<(String, String, DataType)>);
+
+        fn bind_grouped(
+            reify_column: &impl Fn(SqlCol) -> Result<(String, String, DataType)>,
+            group_by: &[(String, String, DataType)],
+            aggregates: &mut Vec<BoundAggregate>,
+            expr: SqlExpr,
+        ) -> Result<RowExpr> {
+            match expr {
+                SqlExpr::Column(column) => {
+                    let (table, column, _) = reify_column(column)?;
+                    let index = group_by
+                        .iter()
+                        .position(|(t, c, _)| *t == table && *c == column)
+                        .ok_or_eyre("column must appear in GROUP BY or an aggregate")?;
+                    Ok(RowExpr::Column(index))
+                }
+                SqlExpr::Const(value) => Ok(RowExpr::Const(value)),
+                SqlExpr::Binary { op, lhs, rhs } => Ok(RowExpr::Binary {
+                    op,
+                    lhs: Box::new(bind_grouped(reify_column, group_by, aggregates, *lhs)?),
+                    rhs: Box::new(bind_grouped(reify_column, group_by, aggregates, *rhs)?),
+                }),
+                SqlExpr::Call { function, args } => {
+                    let Some(aggregate) = AggregateFn::from_name(&function) else {
+                        return Ok(RowExpr::Call {
+                            function,
+                            args: args
+                                .into_iter()
+                                .map(|arg| bind_grouped(reify_column, group_by, aggregates, arg))
+                                .collect::<Result<_>>()?,
+                        });
+                    };
+                    let input = match &args[..] {
+                        [] if aggregate == AggregateFn::Count => None,
+                        [SqlExpr::Column(column)] => {
+                            let input = reify_column(column.clone())?;
+                            if matches!(aggregate, AggregateFn::Sum | AggregateFn::Avg)
+                                && input.2 == DataType::Text
+                            {
+                                Err(eyre!("datatype mismatch"))?;
+                            }
+                            Some(input)
+                        }
+                        _ => Err(eyre!("{function} expects a column"))?,
+                    };
+                    let aggregate = (aggregate, input);
+                    let index = match aggregates.iter().position(|a| *a == aggregate) {
+                        Some(index) => index,
+                        None => {
+                            aggregates.push(aggregate);
+                            aggregates.len() - 1
+                        }
+                    };
+                    Ok(RowExpr::Column(group_by.len() + index))
+                }
+            }
+        }
+
+        fn bind_having(
+            reify_column: &impl Fn(SqlCol) -> Result<(String, String, DataType)>,
+            group_by: &[(String, String, DataType)],
+            aggregates: &mut Vec<BoundAggregate>,
+            condition: SqlCondition,
+        ) -> Result<RowCondition> {
+            let mut bind = |condition| bind_having(reify_column, group_by, aggregates, condition);
+            Ok(match condition {
+                SqlCondition::Cmp { op, lhs, rhs } => RowCondition::Cmp {
+                    op,
+                    lhs: bind_grouped(reify_column, group_by, aggregates, lhs)?,
+                    rhs: bind_grouped(reify_column, group_by, aggregates, rhs)?,
+                },
+                SqlCondition::And(lhs, rhs) => {
+                    RowCondition::And(Box::new(bind(*lhs)?), Box::new(bind(*rhs)?))
+                }
+                SqlCondition::Or(lhs, rhs) => {
+                    RowCondition::Or(Box::new(bind(*lhs)?), Box::new(bind(*rhs)?))
+                }
+                SqlCondition::Not(condition) => RowCondition::Not(Box::new(bind(*condition)?)),
+            })
+        }
+
+        let grouped = group_by.is_some()
+            || columns.iter().any(
+                |column| matches!(column, SqlSelectTarget::Expr(expr) if expr.has_aggregate()),
+            );
+        let aggregate = if grouped {
+            let SqlGroupBy {
+                columns: group_columns,
+                having,
+            } = group_by.unwrap_or(SqlGroupBy {
+                columns: vec![],
+                having: None,
+            });
+            let group_by = group_columns
+                .into_iter()
+                .map(reify_column)
+                .collect::<Result<Vec<_>>>()?;
+            let mut aggregates = vec![];
+            let mut targets = vec![];
+            for column in take(&mut columns) {
+                let name = column.to_string();
+                let expr = match column {
+                    SqlSelectTarget::Column(column) => SqlExpr::Column(column),
+                    SqlSelectTarget::Const(value) => SqlExpr::Const(value),
+                    SqlSelectTarget::Expr(expr) => expr,
+                    SqlSelectTarget::Wildcard | SqlSelectTarget::Variable(_) => {
+                        Err(eyre!("{name} is not allowed with GROUP BY"))?
+                    }
+                };
+                targets.push((
+                    name,
+                    bind_grouped(&reify_column, &group_by, &mut aggregates, expr)?,
+                ));
+            }
+            let having = having
+                .map(|having| bind_having(&reify_column, &group_by, &mut aggregates, having))
+                .transpose()?;
+            for SqlOrderBy { expr, desc } in take(&mut order_by) {
+                let key = match expr {
+                    SqlExpr::Const(Value::Integer(position)) => position
+                        .checked_sub(1)
+                        .and_then(|index| targets.get(index as usize))
+                        .ok_or_else(|| eyre!("ORDER BY position {position} is out of range"))?
+                        .1
+                        .clone(),
+                    // sorting by a constant keeps the order
+                    SqlExpr::Const(_) => continue,
+                    expr => bind_grouped(&reify_column, &group_by, &mut aggregates, expr)?,
+                };
+                sort_keys.push((QueryColumn::Expr(key), desc));
+            }
+            let output_datatypes = group_by
+                .iter()
+                .map(|(_, _, datatype)| *datatype)
+                .chain(aggregates.iter().map(|(function, input)| {
+                    function.datatype(input.as_ref().map(|(_, _, datatype)| *datatype))
+                }))
+                .collect_vec();
+            for (name, expr) in targets {
+                headers.push(Column {
+                    name,
+                    datatype: expr.datatype(&output_datatypes),
+                });
+                query_columns.push(QueryColumn::Expr(expr));
+            }
+            Some(LogicalAggregate {
+                group_by: group_by
+                    .into_iter()
+                    .map(|(table, column, _)| (table, column))
+                    .collect(),
+                aggregates: aggregates
+                    .into_iter()
+                    .map(|(function, input)| {
+                        (function, input.map(|(table, column, _)| (table, column)))
+                    })
+                    .collect(),
+                having,
+            })
+        } else {
+            None
+        };
+
         for column in columns {
             let name = column.to_string();
             match column {
                 SqlSelectTarget::Column(column) => {
+                    if tables.is_empty() {
+                        Err(eyre!("table required"))?;
+                    }
                     let (table, column, datatype) = reify_column(column)?;
                     headers.push(Column { name, datatype });
                     query_columns.push(QueryColumn::Column { table, column });
@@ -426,25 +1462,60 @@ impl Aidb {
                     });
                     query_columns.push(QueryColumn::Const(v));
                 }
+                SqlSelectTarget::Expr(mut expr) => {
+                    expr.bind_session(self);
+                    if expr.has_column() {
+                        if tables.is_empty() {
+                            Err(eyre!("table required"))?;
+                        }
+                        Err(eyre!("expressions on columns are not supported"))?;
+                    }
+                    let v = expr.eval_const()?;
+                    headers.push(Column {
+                        name,
+                        datatype: v.datatype().unwrap_or(DataType::Text),
+                    });
+                    query_columns.push(QueryColumn::Const(v));
+                }
                 SqlSelectTarget::Variable(v) => {
+                    let v = self.variable(&v);
                     headers.push(Column {
                         name,
-                        datatype: DataType::Text,
+                        datatype: v.datatype().unwrap_or(DataType::Text),
                     });
-                    query_columns.push(QueryColumn::Const(match v.as_str() {
-                        "@@version_comment" => Value::Text("aidb".to_owned()),
-                        _ => Value::Null,
-                    }));
+                    query_columns.push(QueryColumn::Const(v));
                 }
             }
         }
 
+        for SqlOrderBy { expr, desc } in order_by {
+            let key = match expr {
+                SqlExpr::Column(column) => {
+                    let (table, column, _) = reify_column(column)?;
+                    QueryColumn::Column { table, column }
+                }
+                SqlExpr::Const(Value::Integer(position)) => {
+                    match position
+                        .checked_sub(1)
+                        .and_then(|index| query_columns.get(index as usize))
+                        .ok_or_else(|| eyre!("ORDER BY position {position} is out of range"))?
+                    {
+                        QueryColumn::Const(_) => continue,
+                        column => column.clone(),
+                    }
+                }
+                expr if expr.has_column() => {
+                    Err(eyre!("expressions on columns are not supported"))?
+                }
+                _ => continue,
+            };
+            sort_keys.push((key, desc));
+        }
+
         for (_, on) in join_on {
             let (table_lhs, column_lhs, datatype_lhs) = reify_column(on.lhs)?;
             let (table_rhs, column_rhs, datatype_rhs) = reify_column(on.rhs)?;
-            if datatype_lhs != datatype_rhs {
-                Err(eyre!("datatype mismatch"))?;
-            }
+            check_comparable(datatype_lhs, datatype_rhs)?;
             constraints.push(QueryConstraint::EqColumn {
                 table_lhs,
                 column_lhs,
@@ -464,9 +1535,7 @@ impl Aidb {
                 }) => {
                     let (table_lhs, column_lhs, datatype_lhs) = reify_column(lhs)?;
                     let (table_rhs, column_rhs, datatype_rhs) = reify_column(rhs)?;
-                    if datatype_lhs != datatype_rhs {
-                        Err(eyre!("datatype mismatch"))?;
-                    }
+                    check_comparable(datatype_lhs, datatype_rhs)?;
                     Ok(vec![QueryConstraint::EqColumn {
                         table_lhs,
                         column_lhs,
@@ -483,11 +1552,7 @@ impl Aidb {
                     rhs: SqlColOrExpr::Const(value),
                 }) => {
                     let (table, column, datatype) = reify_column(column)?;
-                    if let Some(value_datatype) = value.datatype()
-                        && datatype != value_datatype
-                    {
-                        Err(eyre!("datatype mismatch"))?;
-                    }
+                    let value = coerce_const(value, datatype)?;
                     Ok(vec![QueryConstraint::EqConst {
                         table,
                         column,
@@ -498,142 +1563,506 @@ impl Aidb {
                     lhs: SqlColOrExpr::Const(lhs),
                     rhs: SqlColOrExpr::Const(rhs),
                 }) => {
-                    if lhs == rhs {
+                    // NULL is not equal to anything, not even NULL
+                    if lhs.compare(&rhs) == Some(Ordering::Equal) {
                         Ok(vec![])
                     } else {
-                        Err(eyre!("where clause is always false"))
+                        Ok(vec![QueryConstraint::False])
                     }
                 }
-                SqlWhere::Rel(SqlRel::Le { .. }) => todo!(),
-                SqlWhere::Rel(SqlRel::Like { .. }) => todo!(),
-                SqlWhere::And(lhs, rhs) => {
-                    let mut constraints = reify_where(reify_column, *lhs)?;
-                    constraints.append(&mut reify_where(reify_column, *rhs)?);
-                    Ok(constraints)
+                SqlWhere::Rel(SqlRel::NullSafeEq {
+                    lhs: SqlColOrExpr::Column(lhs),
+                    rhs: SqlColOrExpr::Column(rhs),
+                }) => {
+                    let (table_lhs, column_lhs, datatype_lhs) = reify_column(lhs)?;
+                    let (table_rhs, column_rhs, datatype_rhs) = reify_column(rhs)?;
+                    check_comparable(datatype_lhs, datatype_rhs)?;
+                    Ok(vec![QueryConstraint::NullSafeEqColumn {
+                        table_lhs,
+                        column_lhs,
+                        table_rhs,
+                        column_rhs,
+                    }])
                 }
-                SqlWhere::Or(_lhs, _rhs) => todo!(),
-                SqlWhere::Not(_clause) => todo!(),
-            }
-        }
-
-        if let Some(where_) = where_ {
-            constraints.append(&mut reify_where(&reify_column, where_)?);
-        }
-
-        let plan = LogicalQueryPlan {
-            tables,
-            columns: query_columns,
-            constraints,
-            limit,
-        };
-        for (table, schema) in schemas {
-            self.put_schema(table, schema);
-        }
-        Ok((headers, plan))
-    }
-
-    async fn build_physical_plan(&mut self, mut logical: LogicalQueryPlan) -> Result<PhysicalPlan> {
-        let mut columns = vec![];
-        let mut row_sizes = HashMap::new();
-        let mut first_blocks = HashMap::new();
-        for table in logical.tables.iter() {
-            let schema = self.get_schema(table).await?;
-            row_sizes.insert(table.clone(), schema.row_size());
-            first_blocks.insert(table.clone(), schema.data_block);
-            for (i, column) in schema.columns.iter().enumerate() {
-                columns.push((
-                    table.clone(),
-                    column.name.clone(),
-                    schema
-                        .indices
-                        .iter()
-                        .find(|IndexInfo { column_index, .. }| i == *column_index as usize)
-                        .map(|IndexInfo { type_, block, .. }| (*type_, *block)),
-                ));
-            }
-            self.put_schema(table.clone(), schema);
-        }
-        let find_column_index = |table: &str, column: &str| -> ColumnIndex {
-            columns
-                .iter()
-                .position(|(t, c, _)| t == table && c == column)
-                .unwrap()
-        };
-        let find_column_index_info =
-            |table: &str, column: &str| -> Option<(IndexType, BlockIndex)> {
-                columns
-                    .iter()
-                    .enumerate()
-                    .find(|(_, (t, c, _))| t == table && c == column)
-                    .unwrap()
-                    .1
-                    .2
-            };
-
-        let mut plans = vec![];
-        for table in logical.tables.iter() {
-            let mut indexed = false;
-            let mut constraints_remaining = vec![];
-            for constraint in logical.constraints.into_iter() {
-                if let QueryConstraint::EqConst {
-                    table,
-                    column,
-                    value,
-                } = &constraint
-                    && let Some((type_, block)) = find_column_index_info(table, column)
-                {
-                    match type_ {
-                        IndexType::BTree => {
-                            let key = match value.clone() {
-                                Value::Integer(key) => key,
-                                Value::Null => {
-                                    return Err(eyre!("indexed column must not be NULL"));
-                                }
-                                _ => return Err(eyre!("datatype mismatch")),
-                            };
-                            plans.push(PhysicalPlan::BTreeExact {
-                                root: block,
-                                key,
-                                state: Default::default(),
-                            });
-                            indexed = true;
-                            continue;
-                        }
-                    }
+                // NULL-safe against a constant is IS NULL or plain equality
+                SqlWhere::Rel(SqlRel::NullSafeEq {
+                    lhs: SqlColOrExpr::Const(Value::Null),
+                    rhs: SqlColOrExpr::Column(column),
+                })
+                | SqlWhere::Rel(SqlRel::NullSafeEq {
+                    lhs: SqlColOrExpr::Column(column),
+                    rhs: SqlColOrExpr::Const(Value::Null),
+                }) => reify_where(
+                    reify_column,
+                    SqlWhere::Rel(SqlRel::IsNull {
+                        lhs: column,
+                        negated: false,
+                    }),
+                ),
+                SqlWhere::Rel(SqlRel::NullSafeEq {
+                    lhs: SqlColOrExpr::Const(Value::Null),
+                    rhs: SqlColOrExpr::Const(Value::Null),
+                }) => Ok(vec![]),
+                SqlWhere::Rel(SqlRel::NullSafeEq { lhs, rhs }) => {
+                    reify_where(reify_column, SqlWhere::Rel(SqlRel::Eq { lhs, rhs }))
                 }
-                constraints_remaining.push(constraint);
-            }
-            logical.constraints = constraints_remaining;
-            if !indexed {
-                plans.push(PhysicalPlan::Scan {
-                    row_size: *row_sizes.get(table).unwrap(),
-                    first_block: *first_blocks.get(table).unwrap(),
-                    state: Default::default(),
+                SqlWhere::Rel(SqlRel::Ne {
+                    lhs: SqlColOrExpr::Column(lhs),
+                    rhs: SqlColOrExpr::Column(rhs),
+                }) => {
+                    let (table_lhs, column_lhs, datatype_lhs) = reify_column(lhs)?;
+                    let (table_rhs, column_rhs, datatype_rhs) = reify_column(rhs)?;
+                    check_comparable(datatype_lhs, datatype_rhs)?;
+                    Ok(vec![QueryConstraint::NeColumn {
+                        table_lhs,
+                        column_lhs,
+                        table_rhs,
+                        column_rhs,
+                    }])
+                }
+                SqlWhere::Rel(SqlRel::Ne {
+                    lhs: SqlColOrExpr::Const(value),
+                    rhs: SqlColOrExpr::Column(column),
                 })
-            }
-        }
-
-        let plan = if plans.len() == 1 {
-            plans.pop().unwrap()
-        } else {
-            PhysicalPlan::CartesianProduct {
-                inner: plans,
-                state: Default::default(),
-            }
-        };
-
-        let plan = if logical.constraints.is_empty() {
-            plan
-        } else {
-            PhysicalPlan::Selection {
-                constraints: logical
-                    .constraints
-                    .into_iter()
-                    .map(|constraint| match constraint {
-                        QueryConstraint::EqColumn {
-                            table_lhs,
-                            column_lhs,
-                            table_rhs,
+                | SqlWhere::Rel(SqlRel::Ne {
+                    lhs: SqlColOrExpr::Column(column),
+                    rhs: SqlColOrExpr::Const(value),
+                }) => {
+                    let (table, column, datatype) = reify_column(column)?;
+                    let value = coerce_const(value, datatype)?;
+                    // nothing is unequal to NULL either
+                    if value == Value::Null {
+                        return Ok(vec![QueryConstraint::False]);
+                    }
+                    Ok(vec![QueryConstraint::NeConst {
+                        table,
+                        column,
+                        value,
+                    }])
+                }
+                SqlWhere::Rel(SqlRel::Ne {
+                    lhs: SqlColOrExpr::Const(lhs),
+                    rhs: SqlColOrExpr::Const(rhs),
+                }) => {
+                    if matches!(lhs.compare(&rhs), Some(Ordering::Less | Ordering::Greater)) {
+                        Ok(vec![])
+                    } else {
+                        Ok(vec![QueryConstraint::False])
+                    }
+                }
+                SqlWhere::Rel(SqlRel::Le {
+                    lhs: SqlColOrExpr::Column(lhs),
+                    rhs: SqlColOrExpr::Column(rhs),
+                }) => {
+                    let (table_lhs, column_lhs, datatype_lhs) = reify_column(lhs)?;
+                    let (table_rhs, column_rhs, datatype_rhs) = reify_column(rhs)?;
+                    check_comparable(datatype_lhs, datatype_rhs)?;
+                    Ok(vec![QueryConstraint::LeColumn {
+                        table_lhs,
+                        column_lhs,
+                        table_rhs,
+                        column_rhs,
+                    }])
+                }
+                SqlWhere::Rel(SqlRel::Le {
+                    lhs: SqlColOrExpr::Column(column),
+                    rhs: SqlColOrExpr::Const(value),
+                }) => {
+                    let (table, column, datatype) = reify_column(column)?;
+                    let value = coerce_const(value, datatype)?;
+                    Ok(vec![QueryConstraint::LeConst {
+                        table,
+                        column,
+                        value,
+                    }])
+                }
+                SqlWhere::Rel(SqlRel::Le {
+                    lhs: SqlColOrExpr::Const(value),
+                    rhs: SqlColOrExpr::Column(column),
+                }) => {
+                    let (table, column, datatype) = reify_column(column)?;
+                    let value = coerce_const(value, datatype)?;
+                    Ok(vec![QueryConstraint::GeConst {
+                        table,
+                        column,
+                        value,
+                    }])
+                }
+                SqlWhere::Rel(SqlRel::Le {
+                    lhs: SqlColOrExpr::Const(lhs),
+                    rhs: SqlColOrExpr::Const(rhs),
+                }) => {
+                    if matches!(lhs.compare(&rhs), Some(Ordering::Less | Ordering::Equal)) {
+                        Ok(vec![])
+                    } else {
+                        Ok(vec![QueryConstraint::False])
+                    }
+                }
+                SqlWhere::Rel(SqlRel::Lt {
+                    lhs: SqlColOrExpr::Column(lhs),
+                    rhs: SqlColOrExpr::Column(rhs),
+                }) => {
+                    let (table_lhs, column_lhs, datatype_lhs) = reify_column(lhs)?;
+                    let (table_rhs, column_rhs, datatype_rhs) = reify_column(rhs)?;
+                    check_comparable(datatype_lhs, datatype_rhs)?;
+                    Ok(vec![QueryConstraint::LtColumn {
+                        table_lhs,
+                        column_lhs,
+                        table_rhs,
+                        column_rhs,
+                    }])
+                }
+                SqlWhere::Rel(SqlRel::Lt {
+                    lhs: SqlColOrExpr::Column(column),
+                    rhs: SqlColOrExpr::Const(value),
+                }) => {
+                    let (table, column, datatype) = reify_column(column)?;
+                    let value = coerce_const(value, datatype)?;
+                    Ok(vec![QueryConstraint::LtConst {
+                        table,
+                        column,
+                        value,
+                    }])
+                }
+                SqlWhere::Rel(SqlRel::Lt {
+                    lhs: SqlColOrExpr::Const(value),
+                    rhs: SqlColOrExpr::Column(column),
+                }) => {
+                    let (table, column, datatype) = reify_column(column)?;
+                    let value = coerce_const(value, datatype)?;
+                    Ok(vec![QueryConstraint::GtConst {
+                        table,
+                        column,
+                        value,
+                    }])
+                }
+                SqlWhere::Rel(SqlRel::Lt {
+                    lhs: SqlColOrExpr::Const(lhs),
+                    rhs: SqlColOrExpr::Const(rhs),
+                }) => {
+                    if lhs.compare(&rhs) == Some(Ordering::Less) {
+                        Ok(vec![])
+                    } else {
+                        Ok(vec![QueryConstraint::False])
+                    }
+                }
+                SqlWhere::Rel(SqlRel::Like { lhs, rhs, escape }) => {
+                    let (table, column, datatype) = reify_column(lhs)?;
+                    if datatype != DataType::Text {
+                        Err(eyre!("datatype mismatch"))?;
+                    }
+                    Ok(vec![QueryConstraint::Like {
+                        table,
+                        column,
+                        pattern: rhs,
+                        escape,
+                    }])
+                }
+                SqlWhere::Rel(SqlRel::In {
+                    lhs,
+                    rhs: SqlIn::List(values),
+                }) => {
+                    let (table, column, datatype) = reify_column(lhs)?;
+                    let values = values
+                        .into_iter()
+                        .map(|value| coerce_const(value, datatype))
+                        .collect::<Result<_>>()?;
+                    Ok(vec![QueryConstraint::InConst {
+                        table,
+                        column,
+                        values,
+                    }])
+                }
+                SqlWhere::Rel(SqlRel::In {
+                    rhs: SqlIn::Select(_),
+                    ..
+                }) => unreachable!(),
+                SqlWhere::Rel(SqlRel::IsNull { lhs, negated }) => {
+                    let (table, column, _) = reify_column(lhs)?;
+                    Ok(vec![QueryConstraint::IsNull {
+                        table,
+                        column,
+                        negated,
+                    }])
+                }
+                SqlWhere::And(lhs, rhs) => {
+                    let mut constraints = reify_where(reify_column, *lhs)?;
+                    constraints.append(&mut reify_where(reify_column, *rhs)?);
+                    Ok(constraints)
+                }
+                SqlWhere::Or(..) => Err(Unsupported {
+                    feature: "OR".to_owned(),
+                })?,
+                SqlWhere::Not(..) => Err(Unsupported {
+                    feature: "NOT".to_owned(),
+                })?,
+            }
+        }
+
+        if let Some(where_) = where_ {
+            constraints.append(&mut reify_where(&reify_column, where_)?);
+        }
+
+        let plan = LogicalQueryPlan {
+            tables,
+            columns: query_columns,
+            constraints,
+            aggregate,
+            order_by: sort_keys,
+            limit,
+        };
+        Ok((headers, plan))
+    }
+
+    /// Plan a select or union of selects, the header of which comes from the leftmost select.
+    async fn build_union_plan(&mut self, stmt: SqlStmt) -> Result<(Vec<Column>, PhysicalPlan)> {
+        match stmt {
+            stmt @ SqlStmt::Select { .. } => {
+                let (columns, plan) = self.build_logical_plan(stmt).await?;
+                debug!(logical = ?plan);
+                Ok((columns, self.build_physical_plan(plan).await?))
+            }
+            SqlStmt::Union { left, right, all } => {
+                let (columns, left) = Box::pin(self.build_union_plan(*left)).await?;
+                let (right_columns, right) = Box::pin(self.build_union_plan(*right)).await?;
+                if columns.len() != right_columns.len() {
+                    Err(eyre!("column count mismatch"))?;
+                }
+                if columns
+                    .iter()
+                    .zip(&right_columns)
+                    .any(|(lhs, rhs)| lhs.datatype != rhs.datatype)
+                {
+                    Err(eyre!("datatype mismatch"))?;
+                }
+                Ok((
+                    columns,
+                    PhysicalPlan::Union {
+                        left: Box::new(left),
+                        right: Box::new(right),
+                        all,
+                        state: Default::default(),
+                    },
+                ))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Run subqueries in IN predicates and replace them with their results.
+    async fn materialize_subqueries(&mut self, where_: &mut SqlWhere) -> Result<()> {
+        match where_ {
+            SqlWhere::Rel(SqlRel::In { rhs, .. }) => {
+                let SqlIn::Select(stmt) = rhs else {
+                    return Ok(());
+                };
+                let (columns, mut plan) = Box::pin(self.build_union_plan(*stmt.clone())).await?;
+                if columns.len() != 1 {
+                    Err(eyre!("subquery must return exactly one column"))?;
+                }
+                let mut values = vec![];
+                while let Some(mut row) = self.execute_select(&mut plan).await? {
+                    values.push(row.pop().unwrap());
+                }
+                plan.reset(self);
+                *rhs = SqlIn::List(values);
+                Ok(())
+            }
+            SqlWhere::Rel(_) => Ok(()),
+            SqlWhere::And(lhs, rhs) | SqlWhere::Or(lhs, rhs) => {
+                Box::pin(self.materialize_subqueries(lhs)).await?;
+                Box::pin(self.materialize_subqueries(rhs)).await
+            }
+            SqlWhere::Not(clause) => Box::pin(self.materialize_subqueries(clause)).await,
+        }
+    }
+
+    async fn build_physical_plan(&mut self, mut logical: LogicalQueryPlan) -> Result<PhysicalPlan> {
+        let mut columns = vec![];
+        let mut row_sizes = HashMap::new();
+        let mut first_blocks = HashMap::new();
+        self.cache_schemas(&logical.tables).await?;
+        for table in logical.tables.iter() {
+            let schema = self.cached_schema(table)?;
+            row_sizes.insert(table.clone(), schema.row_size());
+            first_blocks.insert(table.clone(), schema.data_block);
+            for (i, column) in schema.columns.iter().enumerate() {
+                columns.push((
+                    table.clone(),
+                    column.name.clone(),
+                    schema
+                        .indices
+                        .iter()
+                        .find(|IndexInfo { column_index, .. }| i == *column_index as usize)
+                        .map(
+                            |IndexInfo {
+                                 column_index,
+                                 type_,
+                                 block,
+                                 ..
+                             }| {
+                                (*type_, *block, schema.null_list(*column_index))
+                            },
+                        ),
+                ));
+            }
+        }
+        let find_column_index = |table: &str, column: &str| -> ColumnIndex {
+            columns
+                .iter()
+                .position(|(t, c, _)| t == table && c == column)
+                .unwrap()
+        };
+        // index type, index block and first block of the NULL list
+        let find_column_index_info =
+            |table: &str, column: &str| -> Option<(IndexType, BlockPtr, BlockPtr)> {
+                columns
+                    .iter()
+                    .enumerate()
+                    .find(|(_, (t, c, _))| t == table && c == column)
+                    .unwrap()
+                    .1
+                    .2
+            };
+
+        merge_eq_consts(&mut logical.constraints);
+        let always_false = logical
+            .constraints
+            .iter()
+            .any(|constraint| matches!(constraint, QueryConstraint::False));
+        let mut plans = vec![];
+        if always_false {
+            logical.constraints.clear();
+            plans.push(PhysicalPlan::Empty);
+        }
+        for current in logical.tables.iter().filter(|_| !always_false) {
+            let mut indexed = false;
+            let mut constraints_remaining = vec![];
+            for constraint in logical.constraints.into_iter() {
+                if let QueryConstraint::EqConst {
+                    table,
+                    column,
+                    value,
+                } = &constraint
+                    // one lookup per table, further constraints are left to selection
+                    && !indexed
+                    && table == current
+                    // nothing equals NULL or a fraction, which are left to selection
+                    && let Value::Integer(key) = *value
+                    && let Some((type_, block, _)) = find_column_index_info(table, column)
+                {
+                    plans.push(match type_ {
+                        IndexType::BTree => PhysicalPlan::BTreeExact {
+                            root: block,
+                            key,
+                            state: Default::default(),
+                        },
+                        IndexType::Hash => PhysicalPlan::HashLookup {
+                            root: block,
+                            key,
+                            state: Default::default(),
+                        },
+                    });
+                    indexed = true;
+                    continue;
+                }
+                if let QueryConstraint::IsNull {
+                    table,
+                    column,
+                    negated: false,
+                } = &constraint
+                    && !indexed
+                    && table == current
+                    && let Some((_, _, head)) = find_column_index_info(table, column)
+                {
+                    plans.push(PhysicalPlan::NullList {
+                        head,
+                        state: Default::default(),
+                    });
+                    indexed = true;
+                    continue;
+                }
+                constraints_remaining.push(constraint);
+            }
+            if !indexed
+                && let Some((root, range)) =
+                    take_btree_range(&mut constraints_remaining, current, |column| {
+                        match find_column_index_info(current, column) {
+                            Some((IndexType::BTree, root, _)) => Some(root),
+                            _ => None,
+                        }
+                    })
+            {
+                plans.push(PhysicalPlan::BTreeRange {
+                    root,
+                    range,
+                    reverse: false,
+                    state: Default::default(),
+                });
+                indexed = true;
+            }
+            logical.constraints = constraints_remaining;
+            if !indexed {
+                plans.push(PhysicalPlan::Scan {
+                    row_size: *row_sizes.get(current).unwrap(),
+                    first_block: *first_blocks.get(current).unwrap(),
+                    state: Default::default(),
+                })
+            }
+        }
+
+        // ORDER BY a column of the only table with a B-tree index is served by scanning the index
+        // in order, also in place of a full scan if no row holds NULL in it as the index skips them
+        if let ([table], [(QueryColumn::Column { table: t, column }, desc)], None, [plan]) = (
+            &logical.tables[..],
+            &logical.order_by[..],
+            &logical.aggregate,
+            &mut plans[..],
+        ) && t == table
+            && let Some((IndexType::BTree, index_root, nulls)) = find_column_index_info(t, column)
+        {
+            let ordered = match plan {
+                PhysicalPlan::BTreeRange { root, reverse, .. } if *root == index_root => {
+                    *reverse = *desc;
+                    true
+                }
+                PhysicalPlan::Scan { .. } if !nulls.is_some() => {
+                    *plan = PhysicalPlan::BTreeRange {
+                        root: index_root,
+                        range: (Bound::Unbounded, Bound::Unbounded),
+                        reverse: *desc,
+                        state: Default::default(),
+                    };
+                    true
+                }
+                _ => false,
+            };
+            if ordered {
+                logical.order_by.clear();
+            }
+        }
+
+        let plan = if plans.len() == 1 {
+            plans.pop().unwrap()
+        } else {
+            PhysicalPlan::CartesianProduct {
+                inner: plans,
+                state: Default::default(),
+            }
+        };
+
+        let plan = if logical.constraints.is_empty() {
+            plan
+        } else {
+            PhysicalPlan::Selection {
+                constraints: logical
+                    .constraints
+                    .into_iter()
+                    .map(|constraint| match constraint {
+                        QueryConstraint::EqColumn {
+                            table_lhs,
+                            column_lhs,
+                            table_rhs,
                             column_rhs,
                         } => SelectionConstraint::EqColumn(
                             find_column_index(&table_lhs, &column_lhs),
@@ -646,12 +2075,171 @@ impl Aidb {
                         } => {
                             SelectionConstraint::EqConst(find_column_index(&table, &column), value)
                         }
+                        QueryConstraint::NullSafeEqColumn {
+                            table_lhs,
+                            column_lhs,
+                            table_rhs,
+                            column_rhs,
+                        } => SelectionConstraint::NullSafeEqColumn(
+                            find_column_index(&table_lhs, &column_lhs),
+                            find_column_index(&table_rhs, &column_rhs),
+                        ),
+                        QueryConstraint::NeColumn {
+                            table_lhs,
+                            column_lhs,
+                            table_rhs,
+                            column_rhs,
+                        } => SelectionConstraint::NeColumn(
+                            find_column_index(&table_lhs, &column_lhs),
+                            find_column_index(&table_rhs, &column_rhs),
+                        ),
+                        QueryConstraint::NeConst {
+                            table,
+                            column,
+                            value,
+                        } => {
+                            SelectionConstraint::NeConst(find_column_index(&table, &column), value)
+                        }
+                        QueryConstraint::LeColumn {
+                            table_lhs,
+                            column_lhs,
+                            table_rhs,
+                            column_rhs,
+                        } => SelectionConstraint::LeColumn(
+                            find_column_index(&table_lhs, &column_lhs),
+                            find_column_index(&table_rhs, &column_rhs),
+                        ),
+                        QueryConstraint::LeConst {
+                            table,
+                            column,
+                            value,
+                        } => {
+                            SelectionConstraint::LeConst(find_column_index(&table, &column), value)
+                        }
+                        QueryConstraint::GeConst {
+                            table,
+                            column,
+                            value,
+                        } => {
+                            SelectionConstraint::GeConst(find_column_index(&table, &column), value)
+                        }
+                        QueryConstraint::LtColumn {
+                            table_lhs,
+                            column_lhs,
+                            table_rhs,
+                            column_rhs,
+                        } => SelectionConstraint::LtColumn(
+                            find_column_index(&table_lhs, &column_lhs),
+                            find_column_index(&table_rhs, &column_rhs),
+                        ),
+                        QueryConstraint::LtConst {
+                            table,
+                            column,
+                            value,
+                        } => {
+                            SelectionConstraint::LtConst(find_column_index(&table, &column), value)
+                        }
+                        QueryConstraint::GtConst {
+                            table,
+                            column,
+                            value,
+                        } => {
+                            SelectionConstraint::GtConst(find_column_index(&table, &column), value)
+                        }
+                        QueryConstraint::InConst {
+                            table,
+                            column,
+                            values,
+                        } => {
+                            SelectionConstraint::InConst(find_column_index(&table, &column), values)
+                        }
+                        QueryConstraint::IsNull {
+                            table,
+                            column,
+                            negated,
+                        } => {
+                            SelectionConstraint::IsNull(find_column_index(&table, &column), negated)
+                        }
+                        QueryConstraint::Like {
+                            table,
+                            column,
+                            pattern,
+                            escape,
+                        } => SelectionConstraint::Like(
+                            find_column_index(&table, &column),
+                            pattern,
+                            escape,
+                        ),
+                        QueryConstraint::False => unreachable!(),
                     })
                     .collect(),
+                collation: self.collation(),
                 inner: Box::new(plan),
             }
         };
 
+        let plan = match logical.aggregate {
+            Some(LogicalAggregate {
+                group_by,
+                aggregates,
+                having,
+            }) => {
+                let plan = PhysicalPlan::Aggregate {
+                    group_by: group_by
+                        .iter()
+                        .map(|(table, column)| find_column_index(table, column))
+                        .collect(),
+                    aggregates: aggregates
+                        .iter()
+                        .map(|(function, input)| {
+                            (
+                                *function,
+                                input
+                                    .as_ref()
+                                    .map(|(table, column)| find_column_index(table, column)),
+                            )
+                        })
+                        .collect(),
+                    inner: Box::new(plan),
+                    state: Default::default(),
+                };
+                match having {
+                    Some(condition) => PhysicalPlan::Having {
+                        condition,
+                        inner: Box::new(plan),
+                    },
+                    None => plan,
+                }
+            }
+            None => plan,
+        };
+
+        let plan = if logical.order_by.is_empty() {
+            plan
+        } else {
+            PhysicalPlan::Sort {
+                keys: SortKeys {
+                    keys: logical
+                        .order_by
+                        .into_iter()
+                        .map(|(column, desc)| {
+                            let expr = match column {
+                                QueryColumn::Column { table, column } => {
+                                    RowExpr::Column(find_column_index(&table, &column))
+                                }
+                                QueryColumn::Expr(expr) => expr,
+                                QueryColumn::Const(_) => unreachable!(),
+                            };
+                            (expr, desc)
+                        })
+                        .collect(),
+                    collation: self.collation(),
+                },
+                inner: Box::new(plan),
+                state: Default::default(),
+            }
+        };
+
         let plan = if logical.columns.is_empty() {
             plan
         } else {
@@ -664,6 +2252,7 @@ impl Aidb {
                             ProjectionColumn::Column(find_column_index(&table, &column))
                         }
                         QueryColumn::Const(value) => ProjectionColumn::Const(value),
+                        QueryColumn::Expr(expr) => ProjectionColumn::Expr(expr),
                     })
                     .collect(),
                 inner: Box::new(plan),
@@ -684,6 +2273,7 @@ impl Aidb {
 
     async fn execute_select(&mut self, plan: &mut PhysicalPlan) -> Result<Option<Row>> {
         debug!(plan = plan.to_string(), "execute_select");
+        self.check_cancelled()?;
         match plan {
             PhysicalPlan::Scan {
                 row_size,
@@ -691,21 +2281,22 @@ impl Aidb {
                 state,
             } => match state {
                 ScanState::Initialized => {
-                    debug!(first_block);
-                    if *first_block == 0 {
-                        Ok(None)
-                    } else {
-                        let mut block = self.get_block(*first_block).await?;
-                        let mut cursor = block.cursor();
-                        let header = DataHeader::read(&mut cursor)?;
-                        let offset = cursor.position() as BlockOffset;
-                        *state = ScanState::Running {
-                            block_index: *first_block,
-                            next_block_index: header.next_data_block,
-                            block,
-                            offset,
-                        };
-                        Box::pin(self.execute_select(plan)).await
+                    debug!(%first_block);
+                    match first_block.get() {
+                        None => Ok(None),
+                        Some(first_block) => {
+                            let mut block = self.get_block(first_block).await?;
+                            let mut cursor = block.cursor();
+                            let header = DataHeader::read(&mut cursor)?;
+                            let offset = cursor.position() as BlockOffset;
+                            *state = ScanState::Running {
+                                block_index: first_block,
+                                next_block_index: header.next_data_block,
+                                block,
+                                offset,
+                            };
+                            Box::pin(self.execute_select(plan)).await
+                        }
                     }
                 }
                 ScanState::Running {
@@ -714,7 +2305,7 @@ impl Aidb {
                     offset,
                     ..
                 } => {
-                    debug!(next_block_index);
+                    debug!(%next_block_index);
                     let mut cursor = block.cursor_at(*offset);
                     while (BLOCK_SIZE as isize - cursor.position() as isize) > *row_size as isize {
                         let position = cursor.position();
@@ -724,24 +2315,13 @@ impl Aidb {
                         };
                         cursor.set_position(position + *row_size as u64);
                     }
-                    if *next_block_index == 0 {
-                        let mut new_state = ScanState::Initialized;
-                        swap(state, &mut new_state);
-                        let ScanState::Running {
-                            block_index, block, ..
-                        } = new_state
-                        else {
-                            unreachable!()
-                        };
-                        self.put_block(block_index, block);
-                        Ok(None)
-                    } else {
-                        let mut block = self.get_block(*next_block_index).await?;
+                    if let Some(next_block_index) = next_block_index.get() {
+                        let mut block = self.get_block(next_block_index).await?;
                         let mut cursor = block.cursor();
                         let header = DataHeader::read(&mut cursor)?;
                         let offset = cursor.position() as BlockOffset;
                         let mut new_state = ScanState::Running {
-                            block_index: *next_block_index,
+                            block_index: next_block_index,
                             next_block_index: header.next_data_block,
                             block,
                             offset,
@@ -755,11 +2335,59 @@ impl Aidb {
                         };
                         self.put_block(block_index, block);
                         Box::pin(self.execute_select(plan)).await
+                    } else {
+                        let mut new_state = ScanState::Initialized;
+                        swap(state, &mut new_state);
+                        let ScanState::Running {
+                            block_index, block, ..
+                        } = new_state
+                        else {
+                            unreachable!()
+                        };
+                        self.put_block(block_index, block);
+                        Ok(None)
                     }
                 }
             },
             PhysicalPlan::BTreeExact { root, key, state } => {
-                let Some(ptr) = self.select_btree(*root, *key, state).await? else {
+                let Some(root) = root.get() else {
+                    return Ok(None);
+                };
+                let Some(ptr) = self.select_btree(root, *key, state).await? else {
+                    return Ok(None);
+                };
+                let mut block = self.get_block(ptr.block).await?;
+                let mut cursor = block.cursor_at(ptr.offset);
+                let row = self.read_row(&mut cursor).await?;
+                self.put_block(ptr.block, block);
+                Ok(row)
+            }
+            PhysicalPlan::BTreeRange {
+                root,
+                range,
+                reverse,
+                state,
+            } => {
+                let Some(root) = root.get() else {
+                    return Ok(None);
+                };
+                let Some(ptr) = self
+                    .select_range_btree(root, *range, *reverse, state)
+                    .await?
+                else {
+                    return Ok(None);
+                };
+                let mut block = self.get_block(ptr.block).await?;
+                let mut cursor = block.cursor_at(ptr.offset);
+                let row = self.read_row(&mut cursor).await?;
+                self.put_block(ptr.block, block);
+                Ok(row)
+            }
+            PhysicalPlan::HashLookup { root, key, state } => {
+                let Some(root) = root.get() else {
+                    return Ok(None);
+                };
+                let Some(ptr) = self.select_hash(root, *key, state).await? else {
                     return Ok(None);
                 };
                 let mut block = self.get_block(ptr.block).await?;
@@ -768,8 +2396,8 @@ impl Aidb {
                 self.put_block(ptr.block, block);
                 Ok(row)
             }
-            PhysicalPlan::BTreeRange { root, range, state } => {
-                let Some(ptr) = self.select_range_btree(*root, *range, state).await? else {
+            PhysicalPlan::NullList { head, state } => {
+                let Some(ptr) = self.select_null(*head, state).await? else {
                     return Ok(None);
                 };
                 let mut block = self.get_block(ptr.block).await?;
@@ -778,6 +2406,7 @@ impl Aidb {
                 self.put_block(ptr.block, block);
                 Ok(row)
             }
+            PhysicalPlan::Empty => Ok(None),
             PhysicalPlan::Projection { columns, inner } => {
                 let Some(row) = Box::pin(self.execute_select(inner)).await? else {
                     return Ok(None);
@@ -785,10 +2414,11 @@ impl Aidb {
                 let row = columns
                     .iter()
                     .map(|column| match column {
-                        ProjectionColumn::Column(index) => row[*index].clone(),
-                        ProjectionColumn::Const(value) => value.clone(),
+                        ProjectionColumn::Column(index) => Ok(row[*index].clone()),
+                        ProjectionColumn::Const(value) => Ok(value.clone()),
+                        ProjectionColumn::Expr(expr) => expr.eval(&row),
                     })
-                    .collect();
+                    .collect::<Result<_>>()?;
                 Ok(Some(row))
             }
             PhysicalPlan::CartesianProduct { inner, state } => {
@@ -800,6 +2430,9 @@ impl Aidb {
                         return Ok(None);
                     }
                 }
+                if state.exhausted {
+                    return Ok(None);
+                }
                 if state.first_run {
                     state.first_run = false;
                     for plan in inner.iter_mut() {
@@ -808,54 +2441,166 @@ impl Aidb {
                                 state.previous_row.push(row);
                             }
                             None => {
+                                state.exhausted = true;
                                 return Ok(None);
                             }
                         }
                     }
                     Ok(Some(state.previous_row.iter().flatten().cloned().collect()))
                 } else {
+                    // odometer with the first plan turning fastest
                     let mut index = 0;
                     loop {
                         match Box::pin(self.execute_select(&mut inner[index])).await? {
                             Some(row) => {
                                 state.previous_row[index] = row;
-                                return Ok(Some(
-                                    state.previous_row.iter().flatten().cloned().collect(),
-                                ));
+                                break;
                             }
                             None => {
-                                inner[index].reset(self);
-                                index += 1;
-                                if index >= inner.len() {
-                                    return Ok(None);
-                                }
+                                inner[index].reset(self);
+                                index += 1;
+                                if index >= inner.len() {
+                                    state.exhausted = true;
+                                    return Ok(None);
+                                }
+                            }
+                        }
+                    }
+                    // plans wrapped around start over from their first row
+                    for (plan, previous_row) in
+                        inner.iter_mut().zip(&mut state.previous_row).take(index)
+                    {
+                        match Box::pin(self.execute_select(plan)).await? {
+                            Some(row) => *previous_row = row,
+                            None => {
+                                state.exhausted = true;
+                                return Ok(None);
+                            }
+                        }
+                    }
+                    Ok(Some(state.previous_row.iter().flatten().cloned().collect()))
+                }
+            }
+            PhysicalPlan::Selection {
+                constraints,
+                collation,
+                inner,
+            } => {
+                while let Some(row) = Box::pin(self.execute_select(inner)).await? {
+                    if constraints
+                        .iter()
+                        .all(|constraint| constraint.check(&row, *collation))
+                    {
+                        return Ok(Some(row));
+                    }
+                }
+                Ok(None)
+            }
+            PhysicalPlan::Limit {
+                limit,
+                inner,
+                state,
+            } => {
+                if state < limit {
+                    *state += 1;
+                    Box::pin(self.execute_select(inner)).await
+                } else {
+                    Ok(None)
+                }
+            }
+            PhysicalPlan::Aggregate {
+                group_by,
+                aggregates,
+                inner,
+                state,
+            } => {
+                if state.output.is_none() {
+                    let mut groups: Vec<(Row, Vec<Accumulator>)> = vec![];
+                    while let Some(row) = Box::pin(self.execute_select(inner)).await? {
+                        let key: Row = group_by.iter().map(|index| row[*index].clone()).collect();
+                        let accumulators = match groups.iter().position(|(k, _)| *k == key) {
+                            Some(index) => &mut groups[index].1,
+                            None => {
+                                groups.push((key, vec![Default::default(); aggregates.len()]));
+                                &mut groups.last_mut().unwrap().1
                             }
+                        };
+                        for ((function, input), accumulator) in
+                            aggregates.iter().zip(accumulators.iter_mut())
+                        {
+                            accumulator.update(*function, input.map(|index| &row[index]))?;
                         }
                     }
+                    // aggregates without GROUP BY always produce one row
+                    if groups.is_empty() && group_by.is_empty() {
+                        groups.push((vec![], vec![Default::default(); aggregates.len()]));
+                    }
+                    state.output = Some(
+                        groups
+                            .into_iter()
+                            .map(|(mut row, accumulators)| {
+                                row.extend(
+                                    aggregates
+                                        .iter()
+                                        .zip(accumulators)
+                                        .map(|((function, _), a)| a.finish(*function)),
+                                );
+                                row
+                            })
+                            .collect_vec()
+                            .into_iter(),
+                    );
                 }
+                Ok(state.output.as_mut().unwrap().next())
             }
-            PhysicalPlan::Selection { constraints, inner } => {
+            PhysicalPlan::Having { condition, inner } => {
                 while let Some(row) = Box::pin(self.execute_select(inner)).await? {
-                    if constraints.iter().all(|constraint| match constraint {
-                        SelectionConstraint::EqColumn(lhs, rhs) => row[*lhs] == row[*rhs],
-                        SelectionConstraint::EqConst(index, value) => row[*index] == *value,
-                    }) {
+                    if condition.check(&row)? {
                         return Ok(Some(row));
                     }
                 }
                 Ok(None)
             }
-            PhysicalPlan::Limit {
-                limit,
-                inner,
+            PhysicalPlan::Sort { keys, inner, state } => {
+                while state.is_buffering() {
+                    match Box::pin(self.execute_select(inner)).await? {
+                        Some(row) => self.sort_push(keys, state, row).await?,
+                        None => self.sort_finish(keys, state).await?,
+                    }
+                }
+                self.sort_next(keys, state).await
+            }
+            PhysicalPlan::Union {
+                left,
+                right,
+                all,
                 state,
-            } => {
-                if state < limit {
-                    *state += 1;
-                    Box::pin(self.execute_select(inner)).await
+            } => loop {
+                let inner = if state.right {
+                    right.as_mut()
                 } else {
-                    Ok(None)
+                    left.as_mut()
+                };
+                let Some(row) = Box::pin(self.execute_select(inner)).await? else {
+                    if state.right {
+                        return Ok(None);
+                    }
+                    state.right = true;
+                    continue;
+                };
+                if !*all && !state.seen.insert(row.iter().map(ValueKey::from).collect()) {
+                    continue;
                 }
+                return Ok(Some(row));
+            },
+            PhysicalPlan::Analyze { inner, stats } => {
+                let (lookups, reads) = (self.log.lookups, self.log.reads);
+                let row = Box::pin(self.execute_select(inner)).await;
+                stats.lookups += self.log.lookups - lookups;
+                stats.reads += self.log.reads - reads;
+                let row = row?;
+                stats.rows += row.is_some() as usize;
+                Ok(row)
             }
         }
     }
@@ -865,6 +2610,7 @@ impl Aidb {
         plan: &mut PhysicalPlan,
     ) -> Result<Option<(Row, DataPointer)>> {
         debug!(plan = plan.to_string(), "execute_update_delete_from");
+        self.check_cancelled()?;
         match plan {
             PhysicalPlan::Scan {
                 row_size,
@@ -872,21 +2618,22 @@ impl Aidb {
                 state,
             } => match state {
                 ScanState::Initialized => {
-                    debug!(first_block);
-                    if *first_block == 0 {
-                        Ok(None)
-                    } else {
-                        let mut block = self.get_block(*first_block).await?;
-                        let mut cursor = block.cursor();
-                        let header = DataHeader::read(&mut cursor)?;
-                        let offset = cursor.position() as BlockOffset;
-                        *state = ScanState::Running {
-                            block_index: *first_block,
-                            next_block_index: header.next_data_block,
-                            block,
-                            offset,
-                        };
-                        Box::pin(self.execute_for_ptr(plan)).await
+                    debug!(%first_block);
+                    match first_block.get() {
+                        None => Ok(None),
+                        Some(first_block) => {
+                            let mut block = self.get_block(first_block).await?;
+                            let mut cursor = block.cursor();
+                            let header = DataHeader::read(&mut cursor)?;
+                            let offset = cursor.position() as BlockOffset;
+                            *state = ScanState::Running {
+                                block_index: first_block,
+                                next_block_index: header.next_data_block,
+                                block,
+                                offset,
+                            };
+                            Box::pin(self.execute_for_ptr(plan)).await
+                        }
                     }
                 }
                 ScanState::Running {
@@ -895,7 +2642,7 @@ impl Aidb {
                     block,
                     offset,
                 } => {
-                    debug!(next_block_index);
+                    debug!(%next_block_index);
                     let mut cursor = block.cursor_at(*offset);
                     while (BLOCK_SIZE as isize - cursor.position() as isize) > *row_size as isize {
                         let position = cursor.position();
@@ -911,24 +2658,13 @@ impl Aidb {
                         };
                         cursor.set_position(position + *row_size as u64);
                     }
-                    if *next_block_index == 0 {
-                        let mut new_state = ScanState::Initialized;
-                        swap(state, &mut new_state);
-                        let ScanState::Running {
-                            block_index, block, ..
-                        } = new_state
-                        else {
-                            unreachable!()
-                        };
-                        self.put_block(block_index, block);
-                        Ok(None)
-                    } else {
-                        let mut block = self.get_block(*next_block_index).await?;
+                    if let Some(next_block_index) = next_block_index.get() {
+                        let mut block = self.get_block(next_block_index).await?;
                         let mut cursor = block.cursor();
                         let header = DataHeader::read(&mut cursor)?;
                         let offset = cursor.position() as BlockOffset;
                         let mut new_state = ScanState::Running {
-                            block_index: *next_block_index,
+                            block_index: next_block_index,
                             next_block_index: header.next_data_block,
                             block,
                             offset,
@@ -942,25 +2678,1740 @@ impl Aidb {
                         };
                         self.put_block(block_index, block);
                         Box::pin(self.execute_for_ptr(plan)).await
+                    } else {
+                        let mut new_state = ScanState::Initialized;
+                        swap(state, &mut new_state);
+                        let ScanState::Running {
+                            block_index, block, ..
+                        } = new_state
+                        else {
+                            unreachable!()
+                        };
+                        self.put_block(block_index, block);
+                        Ok(None)
                     }
                 }
             },
             PhysicalPlan::BTreeExact { .. } => unreachable!(),
             PhysicalPlan::BTreeRange { .. } => unreachable!(),
+            PhysicalPlan::HashLookup { .. } => unreachable!(),
+            PhysicalPlan::NullList { .. } => unreachable!(),
+            PhysicalPlan::Empty => Ok(None),
             PhysicalPlan::Projection { .. } => unreachable!(),
             PhysicalPlan::CartesianProduct { .. } => unreachable!(),
-            PhysicalPlan::Selection { constraints, inner } => {
+            PhysicalPlan::Selection {
+                constraints,
+                collation,
+                inner,
+            } => {
                 while let Some((row, ptr)) = Box::pin(self.execute_for_ptr(inner)).await? {
-                    if constraints.iter().all(|constraint| match constraint {
-                        SelectionConstraint::EqColumn(lhs, rhs) => row[*lhs] == row[*rhs],
-                        SelectionConstraint::EqConst(index, value) => row[*index] == *value,
-                    }) {
+                    if constraints
+                        .iter()
+                        .all(|constraint| constraint.check(&row, *collation))
+                    {
                         return Ok(Some((row, ptr)));
                     }
                 }
                 Ok(None)
             }
             PhysicalPlan::Limit { .. } => unreachable!(),
+            PhysicalPlan::Aggregate { .. } => unreachable!(),
+            PhysicalPlan::Having { .. } | PhysicalPlan::Analyze { .. } => unreachable!(),
+            PhysicalPlan::Sort { .. } => unreachable!(),
+            PhysicalPlan::Union { .. } => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use futures::{StreamExt, TryStreamExt};
+
+    use opendal::{Operator, services::MemoryConfig};
+
+    use super::*;
+    use crate::{CancelToken, TMP_DIR, btree::BTreeFanout, test::create_wide_table};
+
+    async fn query_rows(aidb: &mut Aidb, sql: &str) -> Vec<Row> {
+        let Response::Rows { rows, .. } = aidb.query(sql).await.unwrap() else {
+            panic!("rows expected");
+        };
+        rows
+    }
+
+    async fn query_plan(aidb: &mut Aidb, sql: &str) -> String {
+        let rows = query_rows(aidb, &format!("EXPLAIN {sql}")).await;
+        let Value::Text(plan) = &rows[0][0] else {
+            panic!("plan expected");
+        };
+        plan.clone()
+    }
+
+    #[tokio::test]
+    async fn test_where_const() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, x INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 10), (2, 20), (3, 30);")
+            .await
+            .unwrap();
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT * FROM t WHERE 1 = 2;")
+                .await
+                .len(),
+            0
+        );
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT * FROM t WHERE 1 = 1;")
+                .await
+                .len(),
+            3
+        );
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT * FROM t WHERE 2 <= 1;")
+                .await
+                .len(),
+            0
+        );
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT id FROM t WHERE id = 2 AND 1 = 1;").await,
+            [vec![Value::Integer(2)]]
+        );
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT id FROM t WHERE id = 2 AND 'a' = 'b';")
+                .await
+                .len(),
+            0
+        );
+        for sql in [
+            "SELECT * FROM t WHERE NULL = NULL;",
+            "SELECT * FROM t WHERE NULL = 1;",
+        ] {
+            assert_eq!(query_rows(&mut aidb, sql).await.len(), 0, "{sql}");
+        }
+        assert_eq!(
+            query_plan(&mut aidb, "SELECT id FROM t WHERE NULL = NULL").await,
+            "Π{$0} (false)"
+        );
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT COUNT(*) FROM t WHERE 1 = 2;").await,
+            [vec![Value::Integer(0)]]
+        );
+        assert_eq!(
+            query_plan(&mut aidb, "SELECT id FROM t WHERE 1 = 2").await,
+            "Π{$0} (false)"
+        );
+        let r = aidb.query("UPDATE t SET x = 0 WHERE 1 = 2;").await.unwrap();
+        assert!(matches!(r, Response::Meta { affected_rows: 0 }));
+        let r = aidb.query("DELETE FROM t WHERE 1 = 2;").await.unwrap();
+        assert!(matches!(r, Response::Meta { affected_rows: 0 }));
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT * FROM t WHERE x = 10;")
+                .await
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_or_not_unsupported() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, x INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 10), (2, 20);")
+            .await
+            .unwrap();
+        for (sql, message) in [
+            (
+                "SELECT * FROM t WHERE id = 1 OR x = 20;",
+                "OR is not supported",
+            ),
+            ("SELECT * FROM t WHERE NOT id = 1;", "NOT is not supported"),
+            (
+                "DELETE FROM t WHERE id = 1 OR id = 2;",
+                "OR is not supported",
+            ),
+            (
+                "UPDATE t SET x = 0 WHERE NOT x = 10;",
+                "NOT is not supported",
+            ),
+        ] {
+            let e = aidb
+                .query(sql)
+                .await
+                .unwrap_err()
+                .downcast::<Unsupported>()
+                .unwrap();
+            assert_eq!(e.to_string(), message, "{sql}");
+        }
+        assert_eq!(query_rows(&mut aidb, "SELECT * FROM t;").await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_merge_eq_consts() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, x INTEGER, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 10, 'a'), (2, 20, 'b'), (3, 10, 'c');")
+            .await
+            .unwrap();
+        for sql in [
+            "SELECT * FROM t WHERE id = 1 AND id = 2",
+            "SELECT * FROM t WHERE x = 10 AND id = 3 AND x = 20",
+        ] {
+            assert_eq!(query_rows(&mut aidb, sql).await.len(), 0);
+            assert!(query_plan(&mut aidb, sql).await.ends_with("(false)"));
+        }
+        let sql = "SELECT id FROM t WHERE id = 1 AND id = 1";
+        assert_eq!(query_rows(&mut aidb, sql).await, [vec![Value::Integer(1)]]);
+        let plan = query_plan(&mut aidb, sql).await;
+        assert_eq!(plan.matches("btree@").count(), 1, "{plan}");
+        assert!(!plan.contains('σ'), "{plan}");
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT id FROM t WHERE x = 10 AND x = 10;").await,
+            [vec![Value::Integer(1)], vec![Value::Integer(3)]]
+        );
+        // texts which differ may be equal under the collation, left to selection
+        aidb.set_variable("collation", Value::Text("utf8_general_ci".to_owned()));
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT id FROM t WHERE s = 'a' AND s = 'A';").await,
+            [vec![Value::Integer(1)]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_select_for_update() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 'a'), (2, 'b');")
+            .await
+            .unwrap();
+        let rows = query_rows(&mut aidb, "SELECT * FROM t;").await;
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT * FROM t FOR UPDATE;").await,
+            rows
+        );
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT * FROM t LOCK IN SHARE MODE;").await,
+            rows
+        );
+        aidb.query("START TRANSACTION;").await.unwrap();
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT s FROM t WHERE id = 2 FOR UPDATE;").await,
+            [vec![Value::Text("b".to_owned())]]
+        );
+        aidb.query("COMMIT;").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compare_coercion() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, r REAL, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 1.5, 'a'), (2, 2.0, '2'), (3, 3.5, 'c');")
+            .await
+            .unwrap();
+        async fn ids(aidb: &mut Aidb, where_: &str) -> Result<Vec<i64>> {
+            let Response::Rows { rows, .. } = aidb
+                .query(format!("SELECT id FROM t WHERE {where_};"))
+                .await?
+            else {
+                panic!("rows expected");
+            };
+            Ok(rows
+                .into_iter()
+                .map(|row| match row[0] {
+                    Value::Integer(id) => id,
+                    _ => panic!("integer expected"),
+                })
+                .collect())
+        }
+
+        // numeric text is parsed, integral REAL is an INTEGER and still uses the index
+        assert_eq!(ids(&mut aidb, "id = '2'").await.unwrap(), [2]);
+        assert_eq!(ids(&mut aidb, "id = ' 2 '").await.unwrap(), [2]);
+        assert_eq!(ids(&mut aidb, "id = 2.0").await.unwrap(), [2]);
+        assert!(
+            query_plan(&mut aidb, "SELECT id FROM t WHERE id = '2'")
+                .await
+                .contains("= 2)")
+        );
+        // other numbers are compared by value
+        assert_eq!(ids(&mut aidb, "id = 2.5").await.unwrap(), [0; 0]);
+        assert_eq!(ids(&mut aidb, "id <= 2.5").await.unwrap(), [1, 2]);
+        assert_eq!(ids(&mut aidb, "id <= '2.5'").await.unwrap(), [1, 2]);
+        assert_eq!(ids(&mut aidb, "r = 2").await.unwrap(), [2]);
+        assert_eq!(ids(&mut aidb, "r <= '2'").await.unwrap(), [1, 2]);
+        assert_eq!(ids(&mut aidb, "r <= id").await.unwrap(), [2]);
+        assert_eq!(ids(&mut aidb, "id IN ('1', 3.0)").await.unwrap(), [1, 3]);
+        assert_eq!(ids(&mut aidb, "1 = 1.0").await.unwrap(), [1, 2, 3]);
+        assert_eq!(ids(&mut aidb, "s = '2'").await.unwrap(), [2]);
+
+        for (where_, e) in [
+            ("id = 'abc'", "cannot compare INTEGER with TEXT 'abc'"),
+            ("r <= 'x'", "cannot compare REAL with TEXT 'x'"),
+            ("id IN (1, 'b')", "cannot compare INTEGER with TEXT 'b'"),
+            ("s = 2", "cannot compare TEXT with INTEGER"),
+            ("2.5 <= s", "cannot compare TEXT with REAL"),
+            ("s = id", "cannot compare TEXT with INTEGER"),
+            ("r <= s", "cannot compare REAL with TEXT"),
+        ] {
+            assert_eq!(
+                ids(&mut aidb, where_).await.unwrap_err().to_string(),
+                e,
+                "{where_}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hash_index() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, n INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 10), (2, 20), (3, 30);")
+            .await
+            .unwrap();
+        aidb.query("CREATE UNIQUE INDEX t_id ON t (id) USING HASH;")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (4, 40);").await.unwrap();
+        assert!(aidb.query("INSERT INTO t VALUES (3, 50);").await.is_err());
+
+        let sql = "SELECT n FROM t WHERE id = 2;";
+        assert!(query_plan(&mut aidb, sql).await.contains("hash@"));
+        assert_eq!(
+            query_rows(&mut aidb, sql).await,
+            vec![vec![Value::Integer(20)]]
+        );
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT n FROM t WHERE id = 4;").await,
+            vec![vec![Value::Integer(40)]]
+        );
+        assert!(
+            query_rows(&mut aidb, "SELECT n FROM t WHERE id = 5;")
+                .await
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hash_index_range() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, n INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("CREATE INDEX t_id ON t (id) USING HASH;")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 10), (2, 20), (3, 30);")
+            .await
+            .unwrap();
+
+        let sql = "SELECT n FROM t WHERE id <= 2;";
+        let plan = query_plan(&mut aidb, sql).await;
+        assert!(!plan.contains("hash@"));
+        assert!(plan.contains("≤"));
+        assert_eq!(
+            query_rows(&mut aidb, sql).await,
+            vec![vec![Value::Integer(10)], vec![Value::Integer(20)]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_non_unique_index() {
+        for using in ["BTREE", "HASH"] {
+            let mut aidb = Aidb::new_memory().await;
+            aidb.query("CREATE TABLE t (id INTEGER, n INTEGER);")
+                .await
+                .unwrap();
+            aidb.query("INSERT INTO t VALUES (1, 10), (2, 20), (1, 30);")
+                .await
+                .unwrap();
+            aidb.query(&format!("CREATE INDEX t_id ON t (id) USING {using};"))
+                .await
+                .unwrap();
+            aidb.query("INSERT INTO t VALUES (1, 40);").await.unwrap();
+
+            let mut rows = query_rows(&mut aidb, "SELECT n FROM t WHERE id = 1;").await;
+            rows.sort_by(|a, b| a[0].compare(&b[0]).unwrap());
+            assert_eq!(
+                rows,
+                vec![
+                    vec![Value::Integer(10)],
+                    vec![Value::Integer(30)],
+                    vec![Value::Integer(40)]
+                ]
+            );
+            assert!(
+                aidb.query("CREATE UNIQUE INDEX t_n ON t (id);")
+                    .await
+                    .is_err()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_table() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, s TEXT);")
+            .await
+            .unwrap();
+        // enough rows to span several data blocks
+        let values = (0..5000)
+            .map(|i| format!("({i}, 'text number {i}')"))
+            .join(", ");
+        aidb.query(format!("INSERT INTO t VALUES {values}, (5000, NULL);"))
+            .await
+            .unwrap();
+        let Response::Rows { columns, rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+
+        let (scan_columns, scan) = aidb.scan_table("t").await.unwrap();
+        let scan_rows = scan.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(
+            scan_columns.iter().map(|c| &c.name).collect::<Vec<_>>(),
+            columns.iter().map(|c| &c.name).collect::<Vec<_>>()
+        );
+        assert_eq!(scan_rows, rows);
+
+        // abandoning a scan halfway leaves the database usable
+        let (_, scan) = aidb.scan_table("t").await.unwrap();
+        assert_eq!(scan.take(10).count().await, 10);
+        assert_eq!(query_rows(&mut aidb, "SELECT * FROM t;").await, rows);
+        assert!(aidb.scan_table("missing").await.is_err());
+
+        let (_, stream) = aidb.query_stream("SELECT * FROM t;").await.unwrap();
+        assert_eq!(stream.try_collect::<Vec<_>>().await.unwrap(), rows);
+        let (columns, stream) = aidb.query_stream("DESCRIBE t;").await.unwrap();
+        assert_eq!((columns.len(), stream.count().await), (3, 2));
+        assert!(aidb.query_stream("DELETE FROM t;").await.is_err());
+        assert_eq!(
+            aidb.query_stream("SELECT * FROM t;")
+                .await
+                .unwrap()
+                .1
+                .count()
+                .await,
+            5001
+        );
+    }
+
+    #[tokio::test]
+    async fn test_union() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t1 (a INTEGER, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("CREATE TABLE t2 (b INTEGER);").await.unwrap();
+        aidb.query("INSERT INTO t1 VALUES (1, 'x'), (2, 'y'), (2, 'y');")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t2 VALUES (2), (3);").await.unwrap();
+
+        let Response::Rows { columns, rows, .. } = aidb
+            .query("SELECT a FROM t1 UNION SELECT b FROM t2;")
+            .await
+            .unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(columns[0].name, "a");
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::Integer(1)],
+                vec![Value::Integer(2)],
+                vec![Value::Integer(3)]
+            ]
+        );
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT a FROM t1 UNION ALL SELECT b FROM t2;").await,
+            [1, 2, 2, 2, 3].map(|v| vec![Value::Integer(v)])
+        );
+        assert_eq!(
+            query_rows(
+                &mut aidb,
+                "SELECT b FROM t2 UNION SELECT a FROM t1 WHERE a = 1 UNION ALL SELECT 3;"
+            )
+            .await,
+            [2, 3, 1, 3].map(|v| vec![Value::Integer(v)])
+        );
+        assert_eq!(
+            query_rows(
+                &mut aidb,
+                "SELECT 0.0 UNION SELECT -0.0 UNION SELECT 1.5 UNION SELECT 1.5;"
+            )
+            .await,
+            [vec![Value::Real(0.0)], vec![Value::Real(1.5)]]
+        );
+
+        assert!(
+            aidb.query("SELECT a, s FROM t1 UNION SELECT b FROM t2;")
+                .await
+                .is_err()
+        );
+        assert!(
+            aidb.query("SELECT s FROM t1 UNION SELECT b FROM t2;")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_in_subquery() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE orders (id INTEGER, customer_id INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("CREATE TABLE vip (id INTEGER, name TEXT);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO orders VALUES (1, 10), (2, 20), (3, 10), (4, 30);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO vip VALUES (10, 'a'), (30, 'b'), (40, 'c');")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            query_rows(
+                &mut aidb,
+                "SELECT id FROM orders WHERE customer_id IN (SELECT id FROM vip);"
+            )
+            .await,
+            [1, 3, 4].map(|v| vec![Value::Integer(v)])
+        );
+        assert_eq!(
+            query_rows(
+                &mut aidb,
+                "SELECT id FROM orders WHERE customer_id IN (SELECT id FROM vip WHERE name = 'z');"
+            )
+            .await,
+            Vec::<Row>::new()
+        );
+        assert_eq!(
+            query_rows(
+                &mut aidb,
+                "SELECT id FROM orders WHERE customer_id IN (20, 30);"
+            )
+            .await,
+            [2, 4].map(|v| vec![Value::Integer(v)])
+        );
+
+        assert!(
+            aidb.query("SELECT id FROM orders WHERE customer_id IN (SELECT * FROM vip);")
+                .await
+                .is_err()
+        );
+        assert!(
+            aidb.query("SELECT id FROM orders WHERE customer_id IN (SELECT name FROM vip);")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_select_const_expr() {
+        let mut aidb = Aidb::new_memory().await;
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT 1 + 2;").await,
+            vec![vec![Value::Integer(3)]]
+        );
+        assert_eq!(
+            query_rows(&mut aidb, r#"SELECT UPPER("ab"), LENGTH('abc') * 2 - 1;"#).await,
+            vec![vec![Value::Text("AB".to_owned()), Value::Integer(5)]]
+        );
+        assert_eq!(
+            query_rows(
+                &mut aidb,
+                "SELECT 1 + 2 * 3, (1 + 2) * 3, 1 / 2.0, NULL + 1;"
+            )
+            .await,
+            vec![vec![
+                Value::Integer(7),
+                Value::Integer(9),
+                Value::Real(0.5),
+                Value::Null
+            ]]
+        );
+        let Response::Rows { columns, .. } = aidb.query("SELECT (1 + 2) * 3;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(columns[0].name, "(1 + 2) * 3");
+
+        let e = aidb.query("SELECT a;").await.unwrap_err();
+        assert_eq!(e.to_string(), "table required");
+        let e = aidb.query("SELECT a + 1;").await.unwrap_err();
+        assert_eq!(e.to_string(), "table required");
+        assert!(aidb.query("SELECT 1 / 0;").await.is_err());
+        assert!(aidb.query("SELECT UPPER(1);").await.is_err());
+        for (sql, message) in [
+            ("SELECT NOPE(1);", "unknown function NOPE"),
+            ("SELECT NOPE(NULL);", "unknown function NOPE"),
+            ("SELECT NOPE();", "unknown function NOPE"),
+            (
+                "SELECT ABS(0 - 9223372036854775807 - 1);",
+                "integer overflow",
+            ),
+        ] {
+            let e = aidb.query(sql).await.unwrap_err();
+            assert_eq!(e.to_string(), message, "{sql}");
+        }
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT ABS(0 - 9223372036854775807), ABS(NULL);").await,
+            vec![vec![Value::Integer(i64::MAX), Value::Null]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_select_version() {
+        let mut aidb = Aidb::new_memory().await;
+        let rows = query_rows(&mut aidb, "SELECT VERSION(), version();").await;
+        let Value::Text(version) = &rows[0][0] else {
+            panic!("text expected");
+        };
+        assert!(
+            version.starts_with(concat!(env!("CARGO_PKG_VERSION"), "-")),
+            "{version}"
+        );
+        assert_eq!(rows[0][1], rows[0][0]);
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT @@version;").await,
+            [vec![Value::Text(version.clone())]]
+        );
+        assert!(aidb.query("SELECT VERSION(1);").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_group_by() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE students (name TEXT, class INTEGER, score INTEGER);")
+            .await
+            .unwrap();
+        aidb.query(
+            "INSERT INTO students VALUES ('a', 1, 5), ('b', 1, 7), ('c', 2, 9), ('d', 3, NULL), ('e', 3, 2);",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            query_rows(
+                &mut aidb,
+                "SELECT class, COUNT(*), COUNT(score), SUM(score), MAX(score) FROM students GROUP BY class;"
+            )
+            .await,
+            vec![
+                [1, 2, 2, 12, 7].map(Value::Integer).to_vec(),
+                [2, 1, 1, 9, 9].map(Value::Integer).to_vec(),
+                [3, 2, 1, 2, 2].map(Value::Integer).to_vec(),
+            ]
+        );
+        assert_eq!(
+            query_rows(
+                &mut aidb,
+                "SELECT class, COUNT(*) FROM students GROUP BY class HAVING COUNT(*) > 1;"
+            )
+            .await,
+            vec![
+                [1, 2].map(Value::Integer).to_vec(),
+                [3, 2].map(Value::Integer).to_vec(),
+            ]
+        );
+        assert_eq!(
+            query_rows(
+                &mut aidb,
+                "SELECT class FROM students GROUP BY class HAVING SUM(score) > 8;"
+            )
+            .await,
+            vec![vec![Value::Integer(1)], vec![Value::Integer(2)]]
+        );
+        assert_eq!(
+            query_rows(
+                &mut aidb,
+                "SELECT COUNT(*), AVG(score), MIN(name) FROM students WHERE class = 1;"
+            )
+            .await,
+            vec![vec![
+                Value::Integer(2),
+                Value::Real(6.),
+                Value::Text("a".to_owned())
+            ]]
+        );
+        assert_eq!(
+            query_rows(
+                &mut aidb,
+                "SELECT COUNT(*), SUM(score) FROM students WHERE class = 4;"
+            )
+            .await,
+            vec![vec![Value::Integer(0), Value::Null]]
+        );
+
+        let e = aidb
+            .query("SELECT name, COUNT(*) FROM students GROUP BY class;")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "column must appear in GROUP BY or an aggregate"
+        );
+        assert!(aidb.query("SELECT SUM(name) FROM students;").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let mut aidb = Aidb::new_memory().await;
+        for table in ["a", "b", "c", "d"] {
+            aidb.query(format!("CREATE TABLE {table} (id INTEGER);"))
+                .await
+                .unwrap();
+            let rows = (0..40).map(|i| vec![Value::Integer(i)]).collect();
+            aidb.insert(table, rows).await.unwrap();
+        }
+        let sql =
+            "SELECT a.id FROM a JOIN b ON b.id = b.id JOIN c ON c.id = c.id JOIN d ON d.id = d.id;";
+
+        // cancelled while joining, long before the 40^4 rows are produced
+        let checks = Arc::new(AtomicUsize::new(0));
+        let counter = checks.clone();
+        aidb.set_cancel_token(Some(CancelToken::with_check(move || {
+            counter.fetch_add(1, Ordering::Relaxed) >= 1000
+        })));
+        let e = aidb.query(sql).await.unwrap_err();
+        assert_eq!(e.to_string(), "query cancelled");
+        assert_eq!(checks.load(Ordering::Relaxed), 1001);
+
+        let token = CancelToken::new();
+        aidb.set_cancel_token(Some(token.clone()));
+        aidb.query("SELECT id FROM a;").await.unwrap();
+        token.cancel();
+        assert!(aidb.query("SELECT id FROM a;").await.is_err());
+        assert!(aidb.query("DELETE FROM a;").await.is_err());
+        aidb.set_cancel_token(None);
+        assert_eq!(query_rows(&mut aidb, "SELECT id FROM a;").await.len(), 40);
+    }
+
+    #[tokio::test]
+    async fn test_like_collation() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, name TEXT);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 'ABC'), (2, 'abcdef'), (3, 'xabc'), (4, NULL);")
+            .await
+            .unwrap();
+        let ids = async |aidb: &mut Aidb, sql: &str| {
+            query_rows(aidb, sql)
+                .await
+                .into_iter()
+                .map(|row| row[0].clone())
+                .collect_vec()
+        };
+        let like = "SELECT id FROM t WHERE name LIKE \"abc%\";";
+        let eq = "SELECT id FROM t WHERE name = 'abc';";
+        assert_eq!(ids(&mut aidb, like).await, [Value::Integer(2)]);
+        assert_eq!(ids(&mut aidb, eq).await, []);
+        assert_eq!(
+            query_plan(&mut aidb, like).await,
+            "Π{$0} (σ{$1 LIKE 'abc%'} (@2))"
+        );
+
+        aidb.set_variable("collation", Value::Text("utf8_general_ci".to_owned()));
+        assert_eq!(
+            ids(&mut aidb, like).await,
+            [Value::Integer(1), Value::Integer(2)]
+        );
+        assert_eq!(ids(&mut aidb, eq).await, [Value::Integer(1)]);
+        let e = aidb
+            .query("SELECT id FROM t WHERE id LIKE \"1\";")
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "datatype mismatch");
+
+        aidb.clear_variables();
+        assert_eq!(ids(&mut aidb, like).await, [Value::Integer(2)]);
+    }
+
+    #[tokio::test]
+    async fn test_like_multibyte() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (name TEXT);").await.unwrap();
+        aidb.query("INSERT INTO t VALUES ('张三'), ('张三丰'), ('李四'), ('a张c');")
+            .await
+            .unwrap();
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT name FROM t WHERE name LIKE \"张_\";").await,
+            [vec![Value::Text("张三".to_owned())]]
+        );
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT name FROM t WHERE name LIKE 'a_c';").await,
+            [vec![Value::Text("a张c".to_owned())]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_btree_range() {
+        let mut aidb = Aidb::new_memory().await;
+        // ranges span several leaves
+        aidb.btree_fanout = BTreeFanout { node: 8, leaf: 8 };
+        aidb.query("CREATE TABLE t (id INTEGER UNIQUE, n INTEGER);")
+            .await
+            .unwrap();
+        let rows = (0..100)
+            .map(|i| vec![Value::Integer(i), Value::Integer(i * 10)])
+            .collect_vec();
+        aidb.insert("t", rows).await.unwrap();
+        aidb.query("INSERT INTO t VALUES (NULL, 0);").await.unwrap();
+        let ids = async |aidb: &mut Aidb, where_: &str| {
+            let sql = format!("SELECT id FROM t WHERE {where_};");
+            let plan = query_plan(aidb, &sql).await;
+            let ids = query_rows(aidb, &sql)
+                .await
+                .into_iter()
+                .map(|row| match row[0] {
+                    Value::Integer(id) => id,
+                    _ => panic!("integer expected"),
+                })
+                .collect_vec();
+            (plan, ids)
+        };
+        let range = |plan: &str| {
+            assert_eq!(plan.matches("btree@").count(), 1, "{plan}");
+            plan.split_once(' ').unwrap().1.to_owned()
+        };
+
+        let (plan, rows) = ids(&mut aidb, "id >= 5 AND id <= 20").await;
+        assert!(!plan.contains('σ'), "{plan}");
+        assert!(plan.contains("(Included(5), Included(20))"), "{plan}");
+        assert_eq!(rows, (5..=20).collect_vec());
+
+        let (plan, rows) = ids(&mut aidb, "id > 5 AND 20 > id").await;
+        assert!(
+            range(&plan).contains("(Excluded(5), Excluded(20))"),
+            "{plan}"
+        );
+        assert_eq!(rows, (6..20).collect_vec());
+
+        // the tightest of several bounds on each side, excluded winning ties
+        let (plan, rows) = ids(&mut aidb, "5 < id AND id <= 20 AND id <= 30 AND id >= 5").await;
+        assert!(
+            range(&plan).contains("(Excluded(5), Included(20))"),
+            "{plan}"
+        );
+        assert_eq!(rows, (6..=20).collect_vec());
+
+        let (plan, rows) = ids(&mut aidb, "id > 95").await;
+        assert!(range(&plan).contains("(Excluded(95), Unbounded)"), "{plan}");
+        assert_eq!(rows, [96, 97, 98, 99]);
+
+        let (_, rows) = ids(&mut aidb, "id > 20 AND id < 10").await;
+        assert!(rows.is_empty());
+
+        // bounds on other columns are left to selection
+        let (plan, rows) = ids(&mut aidb, "id >= 5 AND id <= 20 AND n > 100").await;
+        assert!(plan.contains("σ{$1 > 100}"), "{plan}");
+        assert!(
+            range(&plan).contains("(Included(5), Included(20))"),
+            "{plan}"
+        );
+        assert_eq!(rows, (11..=20).collect_vec());
+
+        // an exact lookup is preferred
+        let (plan, rows) = ids(&mut aidb, "id >= 5 AND id = 7").await;
+        assert!(plan.contains("btree@") && plan.contains("= 7"), "{plan}");
+        assert!(plan.contains("σ{$0 ≥ 5}"), "{plan}");
+        assert_eq!(rows, [7]);
+    }
+
+    #[tokio::test]
+    async fn test_null_safe_eq() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, a INTEGER, b INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 1, 1), (2, 1, NULL), (3, NULL, NULL), (4, 2, 3);")
+            .await
+            .unwrap();
+        let ids = async |aidb: &mut Aidb, sql: &str| {
+            query_rows(aidb, sql)
+                .await
+                .into_iter()
+                .map(|row| row[0].clone())
+                .collect_vec()
+        };
+        assert_eq!(
+            ids(&mut aidb, "SELECT id FROM t WHERE a = b;").await,
+            [Value::Integer(1)]
+        );
+        let null_safe = "SELECT id FROM t WHERE a <=> b;";
+        assert_eq!(
+            ids(&mut aidb, null_safe).await,
+            [Value::Integer(1), Value::Integer(3)]
+        );
+        assert_eq!(
+            query_plan(&mut aidb, null_safe).await,
+            "Π{$0} (σ{$1 ≡ $2} (@2))"
+        );
+        assert_eq!(
+            ids(&mut aidb, "SELECT id FROM t WHERE b <=> NULL;").await,
+            [Value::Integer(2), Value::Integer(3)]
+        );
+        assert_eq!(ids(&mut aidb, "SELECT id FROM t WHERE b = NULL;").await, []);
+        assert_eq!(
+            ids(&mut aidb, "SELECT id FROM t WHERE 1 <=> a;").await,
+            [Value::Integer(1), Value::Integer(2)]
+        );
+        assert_eq!(
+            ids(&mut aidb, "SELECT id FROM t WHERE NULL <=> NULL;")
+                .await
+                .len(),
+            4
+        );
+        assert_eq!(
+            ids(&mut aidb, "SELECT id FROM t WHERE NULL <=> 1;").await,
+            []
+        );
+    }
+
+    #[tokio::test]
+    async fn test_not_equal() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, status INTEGER, b INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 0, 0), (2, 1, 0), (3, NULL, 1), (4, 2, NULL);")
+            .await
+            .unwrap();
+        let ids = async |aidb: &mut Aidb, sql: &str| {
+            query_rows(aidb, sql)
+                .await
+                .into_iter()
+                .map(|row| row[0].clone())
+                .collect_vec()
+        };
+        let ne = "SELECT id FROM t WHERE status != 0;";
+        assert_eq!(
+            ids(&mut aidb, ne).await,
+            [Value::Integer(2), Value::Integer(4)]
+        );
+        assert_eq!(query_plan(&mut aidb, ne).await, "Π{$0} (σ{$1 ≠ 0} (@2))");
+        assert_eq!(
+            ids(&mut aidb, "SELECT id FROM t WHERE 0 <> status;").await,
+            [Value::Integer(2), Value::Integer(4)]
+        );
+        // NULL on either side is unknown, so the row does not match
+        assert_eq!(
+            ids(&mut aidb, "SELECT id FROM t WHERE status <> b;").await,
+            [Value::Integer(2)]
+        );
+        assert_eq!(
+            ids(&mut aidb, "SELECT id FROM t WHERE status != NULL;").await,
+            []
+        );
+        assert_eq!(ids(&mut aidb, "SELECT id FROM t WHERE 1 <> 1;").await, []);
+        assert_eq!(
+            ids(&mut aidb, "SELECT id FROM t WHERE 1 != 2;").await.len(),
+            4
+        );
+        // an index is no help, the column is scanned
+        let ne = "SELECT status FROM t WHERE id <> 2;";
+        assert_eq!(query_plan(&mut aidb, ne).await, "Π{$1} (σ{$0 ≠ 2} (@2))");
+        assert_eq!(
+            query_rows(&mut aidb, ne).await,
+            [
+                vec![Value::Integer(0)],
+                vec![Value::Null],
+                vec![Value::Integer(2)]
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_like_escape() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (s TEXT);").await.unwrap();
+        aidb.query("INSERT INTO t VALUES ('50%'), ('500'), ('50!');")
+            .await
+            .unwrap();
+        let like = "SELECT s FROM t WHERE s LIKE \"50!%\" ESCAPE \"!\";";
+        assert_eq!(
+            query_rows(&mut aidb, like).await,
+            [vec![Value::Text("50%".to_owned())]]
+        );
+        assert_eq!(
+            query_plan(&mut aidb, like).await,
+            "Π{$0} (σ{$0 LIKE '50!%' ESCAPE '!'} (@2))"
+        );
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT s FROM t WHERE s LIKE '50!%';").await,
+            [vec![Value::Text("50!".to_owned())]]
+        );
+        assert!(
+            aidb.query("SELECT s FROM t WHERE s LIKE '50%' ESCAPE '!!';")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_select_null_indexed() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, k INTEGER UNIQUE, h INTEGER);")
+            .await
+            .unwrap();
+        for i in 0..10 {
+            // every third row has NULL keys
+            let key = if i % 3 == 0 {
+                "NULL".to_owned()
+            } else {
+                i.to_string()
+            };
+            aidb.query(format!("INSERT INTO t VALUES ({i}, {key}, {key});"))
+                .await
+                .unwrap();
+        }
+        aidb.query("CREATE INDEX th ON t (h) USING HASH;")
+            .await
+            .unwrap();
+        for column in ["k", "h"] {
+            let sql = format!("SELECT id FROM t WHERE {column} IS NULL;");
+            let tree = aidb.explain_tree(&sql).await.unwrap();
+            assert_eq!(tree.children[0].kind, "NullList");
+            let Response::Rows { rows, .. } = aidb.query(&sql).await.unwrap() else {
+                panic!("rows expected");
+            };
+            assert_eq!(
+                rows,
+                [0, 3, 6, 9].map(|id| vec![Value::Integer(id)]).to_vec()
+            );
+            let Response::Rows { rows, .. } = aidb
+                .query(format!("SELECT id FROM t WHERE {column} IS NOT NULL;"))
+                .await
+                .unwrap()
+            else {
+                panic!("rows expected");
+            };
+            assert_eq!(rows.len(), 6);
+            let Response::Rows { rows, .. } = aidb
+                .query(format!("SELECT id FROM t WHERE {column} = 4;"))
+                .await
+                .unwrap()
+            else {
+                panic!("rows expected");
+            };
+            assert_eq!(rows, vec![vec![Value::Integer(4)]]);
+        }
+        // NULLs don't collide in unique indices and leave with replaced rows
+        aidb.query("REPLACE INTO t VALUES (0, 4, 4);")
+            .await
+            .unwrap();
+        let Response::Rows { rows, .. } = aidb
+            .query("SELECT id FROM t WHERE k IS NULL;")
+            .await
+            .unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(
+            rows,
+            [0, 3, 6, 9].map(|id| vec![Value::Integer(id)]).to_vec()
+        );
+        assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_explain_tree() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE a (id INTEGER UNIQUE, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("CREATE TABLE b (a_id INTEGER, x REAL);")
+            .await
+            .unwrap();
+        let sql = "SELECT s, x FROM a JOIN b ON a.id = b.a_id WHERE a.id = 1;";
+        let tree = aidb.explain_tree(sql).await.unwrap();
+        assert_eq!(
+            (tree.kind.as_str(), tree.detail.as_str()),
+            ("Projection", "Π{$1, $3}")
+        );
+        let selection = &tree.children[0];
+        assert_eq!(
+            (selection.kind.as_str(), selection.detail.as_str()),
+            ("Selection", "σ{$0 = $2}")
+        );
+        let product = &selection.children[0];
+        assert_eq!(product.kind, "CartesianProduct");
+        assert_eq!(
+            product
+                .children
+                .iter()
+                .map(|node| (node.kind.as_str(), node.children.len()))
+                .collect_vec(),
+            [("BTreeExact", 0), ("Scan", 0)]
+        );
+        // the index lookup is cheaper than the scan it is joined with
+        assert!(product.children[0].estimated_cost < product.children[1].estimated_cost);
+        assert_eq!(tree.estimated_cost, product.estimated_cost);
+
+        assert_eq!(
+            tree,
+            aidb.explain_tree(format!("EXPLAIN {sql}")).await.unwrap()
+        );
+        let Response::Rows { rows, .. } = aidb.query(format!("EXPLAIN {sql}")).await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(
+            rows[0][0],
+            Value::Text("Π{$1, $3} (σ{$0 = $2} ((btree@none = 1) × (@none)))".to_owned())
+        );
+        assert!(aidb.explain_tree("DELETE FROM a;").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_validate_plan() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE a (id INTEGER UNIQUE, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO a VALUES (1, 'x'), (2, 'y');")
+            .await
+            .unwrap();
+        aidb.query("FLUSH TABLES;").await.unwrap();
+        let schema_block = aidb.superblock.first_schema_block.get().unwrap();
+
+        aidb.reset_block_io_log();
+        let sql = "SELECT s FROM a WHERE id = 1;";
+        let plan = aidb.validate(sql).await.unwrap();
+        assert_eq!(plan.as_deref(), Some("Π{$1} (btree@5 = 1)"));
+        let e = aidb.validate("SELECT t FROM a;").await.unwrap_err();
+        assert_eq!(e.to_string(), "column t not found");
+        // planning only needs the schema
+        assert_eq!(aidb.get_block_io_log().read, [schema_block].into());
+
+        assert_eq!(
+            aidb.validate("EXPLAIN SELECT s FROM a WHERE id = 1")
+                .await
+                .unwrap(),
+            plan
+        );
+        assert_eq!(
+            aidb.validate("INSERT INTO a VALUES (3, 'z')")
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(aidb.validate("SELECT s FROM b;").await.is_err());
+        assert!(aidb.validate("SELECT s FROM a WHERE").await.is_err());
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM a;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_join_null() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE a (id INTEGER, k INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("CREATE TABLE b (k INTEGER, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO a VALUES (1, 10), (2, NULL), (3, 30);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO b VALUES (10, 'x'), (NULL, 'y'), (NULL, 'z');")
+            .await
+            .unwrap();
+        aidb.query("CREATE TABLE b2 (k INTEGER);").await.unwrap();
+        aidb.query("INSERT INTO b2 VALUES (10), (NULL);")
+            .await
+            .unwrap();
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT a.id, b.s FROM a JOIN b ON a.k = b.k;").await,
+            vec![vec![Value::Integer(1), Value::Text("x".to_owned())]]
+        );
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT b.s FROM b JOIN b2 ON b.k = b2.k;").await,
+            vec![vec![Value::Text("x".to_owned())]]
+        );
+        assert_eq!(
+            query_rows(&mut aidb, "SELECT id FROM a WHERE k IN (NULL, 30);").await,
+            vec![vec![Value::Integer(3)]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cartesian_product() {
+        let mut aidb = Aidb::new_memory().await;
+        for (table, rows) in [("a", 1), ("b", 2), ("c", 3), ("e", 0)] {
+            aidb.query(format!("CREATE TABLE {table} (id INTEGER);"))
+                .await
+                .unwrap();
+            let rows = (1..=rows).map(|i| vec![Value::Integer(i)]).collect();
+            aidb.insert(table, rows).await.unwrap();
+        }
+
+        // the first table turns fastest
+        let rows = query_rows(
+            &mut aidb,
+            "SELECT a.id, b.id, c.id FROM a JOIN b ON b.id = b.id JOIN c ON c.id = c.id;",
+        )
+        .await;
+        let expected = (1..=3)
+            .flat_map(|c| (1..=2).map(move |b| [1, b, c].map(Value::Integer).to_vec()))
+            .collect_vec();
+        assert_eq!(rows, expected);
+        let rows = query_rows(&mut aidb, "SELECT c.id, b.id FROM c JOIN b ON b.id = b.id;").await;
+        let expected = (1..=2)
+            .flat_map(|b| (1..=3).map(move |c| [c, b].map(Value::Integer).to_vec()))
+            .collect_vec();
+        assert_eq!(rows, expected);
+
+        for sql in [
+            "SELECT b.id FROM e JOIN b ON b.id = b.id JOIN c ON c.id = c.id;",
+            "SELECT b.id FROM b JOIN e ON e.id = e.id JOIN c ON c.id = c.id;",
+            "SELECT b.id FROM b JOIN c ON c.id = c.id JOIN e ON e.id = e.id;",
+        ] {
+            assert!(query_rows(&mut aidb, sql).await.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_join_three_tables() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE a (id INTEGER, bid INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("CREATE TABLE b (id INTEGER, cid INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("CREATE TABLE c (id INTEGER, s TEXT);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO a VALUES (1, 10), (2, 20), (3, 30);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO b VALUES (10, 100), (20, 200);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO c VALUES (100, 'x'), (200, 'y');")
+            .await
+            .unwrap();
+        aidb.schemas.clear();
+        assert_eq!(
+            query_rows(
+                &mut aidb,
+                "SELECT a.id, s FROM a JOIN b ON a.bid = b.id JOIN c ON b.cid = c.id;"
+            )
+            .await,
+            vec![
+                vec![Value::Integer(1), Value::Text("x".to_owned())],
+                vec![Value::Integer(2), Value::Text("y".to_owned())],
+            ]
+        );
+        // planning borrows schemas in place, leaving all of them cached
+        assert!(
+            ["a", "b", "c"]
+                .iter()
+                .all(|t| aidb.schemas.contains_key(*t))
+        );
+        assert!(
+            aidb.query("SELECT * FROM a JOIN a ON a.id = a.id;")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_unfulls_block() {
+        let mut aidb = Aidb::new_memory().await;
+        let rows_per_block = create_wide_table(&mut aidb).await;
+        let rows = (0..rows_per_block as i64 + 10)
+            .map(|i| vec![Value::Integer(i); 120])
+            .collect_vec();
+        aidb.insert("t", rows).await.unwrap();
+        let first_block = aidb
+            .get_schema("t")
+            .await
+            .unwrap()
+            .data_block
+            .get()
+            .unwrap();
+        aidb.schemas.clear();
+        let is_full = async |aidb: &mut Aidb| {
+            let mut block = aidb.get_block(first_block).await.unwrap();
+            let header = DataHeader::read(&mut block.cursor()).unwrap();
+            aidb.put_block(first_block, block);
+            header.is_full
+        };
+        assert!(is_full(&mut aidb).await);
+
+        aidb.query("DELETE FROM t WHERE c0 = 5;").await.unwrap();
+        assert!(!is_full(&mut aidb).await);
+
+        aidb.set_reuse_free_slots(true);
+        let next_empty_block = aidb.superblock.next_empty_block;
+        aidb.insert("t", vec![vec![Value::Integer(1000); 120]])
+            .await
+            .unwrap();
+        assert_eq!(aidb.superblock.next_empty_block, next_empty_block);
+        let rows = aidb.select_with_ptr("t".to_owned()).await.unwrap();
+        let (_, ptr) = rows
+            .iter()
+            .find(|(row, _)| row[0] == Value::Integer(1000))
+            .unwrap();
+        assert_eq!(ptr.block, first_block);
+        assert!(is_full(&mut aidb).await);
+    }
+
+    #[tokio::test]
+    async fn test_max_rows() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER);").await.unwrap();
+        let rows = (0..100).map(|i| vec![Value::Integer(i)]).collect_vec();
+        aidb.insert("t", rows).await.unwrap();
+        aidb.set_max_rows(Some(30));
+        let Response::Rows {
+            rows, truncated, ..
+        } = aidb.query("SELECT * FROM t;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows.len(), 30);
+        assert!(truncated);
+        let Response::Rows {
+            rows, truncated, ..
+        } = aidb.query("SELECT * FROM t WHERE id <= 29;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows.len(), 30);
+        assert!(!truncated);
+        let Response::Rows {
+            rows, truncated, ..
+        } = aidb
+            .query("SELECT * FROM t UNION ALL SELECT * FROM t;")
+            .await
+            .unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows.len(), 30);
+        assert!(truncated);
+
+        aidb.set_max_rows(None);
+        let Response::Rows {
+            rows, truncated, ..
+        } = aidb.query("SELECT * FROM t;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows.len(), 100);
+        assert!(!truncated);
+    }
+
+    #[tokio::test]
+    async fn test_explain_analyze() {
+        let mut aidb = Aidb::new_memory().await;
+        let columns = (1..20).map(|i| format!(", c{i} INTEGER")).join("");
+        aidb.query(format!("CREATE TABLE t (id INTEGER UNIQUE{columns});"))
+            .await
+            .unwrap();
+        let rows = (0..5000).map(|i| vec![Value::Integer(i); 20]).collect_vec();
+        aidb.insert("t", rows).await.unwrap();
+        let analyze = async |aidb: &mut Aidb, sql: &str| {
+            aidb.blocks.clear();
+            let Response::Rows { columns, rows, .. } = aidb.query(sql).await.unwrap() else {
+                panic!("rows expected");
+            };
+            assert_eq!(
+                columns
+                    .iter()
+                    .map(|column| column.name.as_str())
+                    .collect_vec(),
+                ["operator", "rows", "lookups", "reads"]
+            );
+            rows
+        };
+        let indexed = analyze(
+            &mut aidb,
+            "EXPLAIN ANALYZE SELECT c1 FROM t WHERE id = 500;",
+        )
+        .await;
+        let scan = analyze(
+            &mut aidb,
+            "EXPLAIN ANALYZE SELECT c1 FROM t WHERE c1 = 500;",
+        )
+        .await;
+        let operators = |rows: &[Row]| {
+            rows.iter()
+                .map(|row| {
+                    let Value::Text(operator) = &row[0] else {
+                        panic!("text expected");
+                    };
+                    let kind = operator.trim_start().split(' ').next().unwrap();
+                    (
+                        operator.len() - operator.trim_start().len(),
+                        kind.to_owned(),
+                    )
+                })
+                .collect_vec()
+        };
+        assert_eq!(
+            operators(&indexed),
+            [(0, "Projection"), (2, "BTreeExact")].map(|(depth, kind)| (depth, kind.to_owned()))
+        );
+        assert_eq!(
+            operators(&scan),
+            [(0, "Projection"), (2, "Selection"), (4, "Scan")]
+                .map(|(depth, kind)| (depth, kind.to_owned()))
+        );
+        // one row out of 5000 scanned, counters include those of inner operators
+        assert_eq!(indexed[0][1], Value::Integer(1));
+        assert_eq!(scan[0][1], Value::Integer(1));
+        assert_eq!(scan[2][1], Value::Integer(5000));
+        assert_eq!(scan[0][2..], scan[2][2..]);
+        let Value::Integer(indexed_reads) = indexed[0][3] else {
+            panic!("integer expected");
+        };
+        let Value::Integer(scan_reads) = scan[0][3] else {
+            panic!("integer expected");
+        };
+        assert!(0 < indexed_reads && indexed_reads < scan_reads);
+    }
+
+    #[tokio::test]
+    async fn test_order_by() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, name TEXT, score INTEGER);")
+            .await
+            .unwrap();
+        aidb.query(
+            "INSERT INTO t VALUES (1, 'b', 20), (2, 'a', NULL), (3, 'c', 20), (4, 'a', 10);",
+        )
+        .await
+        .unwrap();
+        let ids = async |aidb: &mut Aidb, sql: &str| {
+            query_rows(aidb, sql)
+                .await
+                .into_iter()
+                .map(|row| row[0].clone())
+                .collect_vec()
+        };
+        let ints = |ids: &[i64]| ids.iter().copied().map(Value::Integer).collect_vec();
+        // NULL comes first, ties keep the order rows were read in
+        assert_eq!(
+            ids(&mut aidb, "SELECT id FROM t ORDER BY score;").await,
+            ints(&[2, 4, 1, 3])
+        );
+        assert_eq!(
+            ids(&mut aidb, "SELECT id FROM t ORDER BY score DESC, id DESC;").await,
+            ints(&[3, 1, 4, 2])
+        );
+        assert_eq!(
+            ids(&mut aidb, "SELECT id FROM t ORDER BY name ASC, score DESC;").await,
+            ints(&[4, 2, 1, 3])
+        );
+        assert_eq!(
+            ids(
+                &mut aidb,
+                "SELECT id, name FROM t ORDER BY 2, 1 DESC LIMIT 2;"
+            )
+            .await,
+            ints(&[4, 2])
+        );
+        assert_eq!(
+            query_plan(&mut aidb, "SELECT id FROM t ORDER BY score DESC LIMIT 1;").await,
+            "limit{1} (Π{$0} (τ{$2 DESC} (@2)))"
+        );
+        assert_eq!(
+            query_rows(
+                &mut aidb,
+                "SELECT name, COUNT(*) FROM t GROUP BY name ORDER BY COUNT(*) DESC, name;"
+            )
+            .await,
+            [
+                vec![Value::Text("a".to_owned()), Value::Integer(2)],
+                vec![Value::Text("b".to_owned()), Value::Integer(1)],
+                vec![Value::Text("c".to_owned()), Value::Integer(1)],
+            ]
+        );
+        assert!(aidb.query("SELECT id FROM t ORDER BY 3;").await.is_err());
+        assert!(
+            aidb.query("SELECT id FROM t ORDER BY id + 1;")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_order_by_index() {
+        let mut aidb = Aidb::new_memory().await;
+        // the scan follows leaves in both directions
+        aidb.btree_fanout = BTreeFanout { node: 8, leaf: 8 };
+        aidb.query("CREATE TABLE t (id INTEGER UNIQUE, n INTEGER);")
+            .await
+            .unwrap();
+        let rows = (0..100)
+            .map(|i| vec![Value::Integer(i * 37 % 100), Value::Integer(i)])
+            .collect_vec();
+        aidb.insert("t", rows).await.unwrap();
+        let ids = async |aidb: &mut Aidb, sql: &str| {
+            let plan = query_plan(aidb, sql).await;
+            let ids = query_rows(aidb, sql)
+                .await
+                .into_iter()
+                .map(|row| row[0].clone())
+                .collect_vec();
+            (plan, ids)
+        };
+        let ints = |ids: &mut dyn Iterator<Item = i64>| ids.map(Value::Integer).collect_vec();
+
+        let (plan, rows) = ids(&mut aidb, "SELECT id FROM t ORDER BY id;").await;
+        assert!(!plan.contains('τ'), "{plan}");
+        assert!(plan.ends_with("(Unbounded, Unbounded))"), "{plan}");
+        assert_eq!(rows, ints(&mut (0..100)));
+
+        let (plan, rows) = ids(&mut aidb, "SELECT id FROM t ORDER BY id DESC LIMIT 3;").await;
+        assert!(!plan.contains('τ'), "{plan}");
+        assert!(plan.ends_with("(Unbounded, Unbounded) DESC))"), "{plan}");
+        assert_eq!(rows, ints(&mut (97..100).rev()));
+
+        let (plan, rows) = ids(
+            &mut aidb,
+            "SELECT id FROM t WHERE id >= 10 AND id < 30 AND n > 50 ORDER BY id DESC;",
+        )
+        .await;
+        assert!(!plan.contains('τ'), "{plan}");
+        assert!(plan.contains("(Included(10), Excluded(30)) DESC"), "{plan}");
+        let mut expected = (51..100)
+            .map(|i| i * 37 % 100)
+            .filter(|id| (10..30).contains(id))
+            .sorted()
+            .rev();
+        assert_eq!(rows, ints(&mut expected));
+
+        // sorted when the order isn't that of the index
+        let plan = query_plan(&mut aidb, "SELECT id FROM t ORDER BY n;").await;
+        assert!(plan.contains("τ{$1}"), "{plan}");
+        let plan = query_plan(&mut aidb, "SELECT id FROM t ORDER BY id, n;").await;
+        assert!(plan.contains("τ{$0, $1}"), "{plan}");
+
+        // the index doesn't hold NULL, which comes first
+        aidb.query("INSERT INTO t VALUES (NULL, 100);")
+            .await
+            .unwrap();
+        let (plan, rows) = ids(&mut aidb, "SELECT id FROM t ORDER BY id;").await;
+        assert!(plan.contains("τ{$0}"), "{plan}");
+        assert_eq!(rows[0], Value::Null);
+        assert_eq!(rows[1..], ints(&mut (0..100)));
+        let (plan, rows) = ids(&mut aidb, "SELECT id FROM t WHERE id > 95 ORDER BY id;").await;
+        assert!(!plan.contains('τ'), "{plan}");
+        assert_eq!(rows, ints(&mut (96..100)));
+    }
+
+    #[tokio::test]
+    async fn test_order_by_spill() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.set_max_rows(None);
+        aidb.set_sort_buffer_rows(10);
+        aidb.query("CREATE TABLE t (id INTEGER, name TEXT);")
+            .await
+            .unwrap();
+        let values = (0..1000)
+            .map(|i| format!("({i}, 'n{}')", i * 7919 % 1000))
+            .join(", ");
+        aidb.query(format!("INSERT INTO t VALUES {values};"))
+            .await
+            .unwrap();
+        let rows = query_rows(&mut aidb, "SELECT name, id FROM t ORDER BY name DESC;").await;
+        assert_eq!(rows.len(), 1000);
+        let mut expected = (0..1000)
+            .map(|i| format!("n{}", i * 7919 % 1000))
+            .collect_vec();
+        expected.sort();
+        expected.reverse();
+        assert_eq!(
+            rows.iter().map(|row| row[0].clone()).collect_vec(),
+            expected.into_iter().map(Value::Text).collect_vec()
+        );
+        // runs were merged and removed
+        let tmp = aidb.op.list_with("tmp/").recursive(true).await.unwrap();
+        assert!(tmp.iter().all(|entry| entry.metadata().is_dir()));
+
+        // runs of a sort stopped early are removed by the next one that spills
+        let (_, stream) = aidb
+            .query_stream("SELECT id FROM t ORDER BY id DESC;")
+            .await
+            .unwrap();
+        let first = Box::pin(stream).next().await.unwrap().unwrap();
+        assert_eq!(first, [Value::Integer(999)]);
+        assert_eq!(aidb.spilled_sorts.len(), 1);
+        let rows = query_rows(&mut aidb, "SELECT id FROM t ORDER BY id;").await;
+        assert_eq!(
+            rows.into_iter().map(|row| row[0].clone()).collect_vec(),
+            (0..1000).map(Value::Integer).collect_vec()
+        );
+        assert!(aidb.spilled_sorts.is_empty());
+        let tmp = aidb.op.list_with("tmp/").recursive(true).await.unwrap();
+        assert!(tmp.iter().all(|entry| entry.metadata().is_dir()));
+    }
+
+    #[tokio::test]
+    async fn test_order_by_spill_scratch() {
+        async fn tmp(op: &Operator) -> Vec<String> {
+            let entries = op.list_with(TMP_DIR).recursive(true).await.unwrap();
+            entries
+                .into_iter()
+                .filter(|entry| entry.metadata().is_file())
+                .map(|entry| entry.path().to_owned())
+                .collect()
+        }
+
+        let op = Operator::from_config(MemoryConfig::default())
+            .unwrap()
+            .finish();
+        let mut aidb = Aidb::from_op(op.clone()).await.unwrap();
+        aidb.set_sort_buffer_rows(10);
+        aidb.query("CREATE TABLE t (id INTEGER);").await.unwrap();
+        let values = (0..100).map(|i| format!("({})", i * 37 % 100)).join(", ");
+        aidb.query(format!("INSERT INTO t VALUES {values};"))
+            .await
+            .unwrap();
+
+        // readers spill to the scratch storage of the instance they were opened from
+        let mut reader = aidb.open_reader().await.unwrap();
+        let (_, stream) = reader
+            .query_stream("SELECT id FROM t ORDER BY id;")
+            .await
+            .unwrap();
+        let mut stream = Box::pin(stream);
+        assert_eq!(stream.next().await.unwrap().unwrap(), [Value::Integer(0)]);
+        assert!(!tmp(&op).await.is_empty());
+        let rows = stream.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(rows.len(), 99);
+        assert!(tmp(&op).await.is_empty());
+
+        // a read-only instance never writes unless given a scratch storage
+        let mut read_only = Aidb::from_op_read_only(op.clone()).await.unwrap();
+        read_only.set_sort_buffer_rows(10);
+        let (_, stream) = read_only
+            .query_stream("SELECT id FROM t ORDER BY id DESC;")
+            .await
+            .unwrap();
+        let mut stream = Box::pin(stream);
+        assert_eq!(stream.next().await.unwrap().unwrap(), [Value::Integer(99)]);
+        assert!(tmp(&op).await.is_empty());
+        drop(stream);
+        let scratch = Operator::from_config(MemoryConfig::default())
+            .unwrap()
+            .finish();
+        read_only.set_scratch(Some(scratch.clone()));
+        let (_, stream) = read_only
+            .query_stream("SELECT id FROM t ORDER BY id DESC;")
+            .await
+            .unwrap();
+        let mut stream = Box::pin(stream);
+        assert_eq!(stream.next().await.unwrap().unwrap(), [Value::Integer(99)]);
+        assert!(!tmp(&scratch).await.is_empty());
+        assert!(tmp(&op).await.is_empty());
+        drop(stream);
+
+        // runs left behind are never archived and removed once the database is opened again
+        let (_, stream) = aidb
+            .query_stream("SELECT id FROM t ORDER BY id;")
+            .await
+            .unwrap();
+        Box::pin(stream).next().await.unwrap().unwrap();
+        assert!(!tmp(&op).await.is_empty());
+        let archive = aidb.save_archive(vec![]).await.unwrap();
+        let restored = Operator::from_config(MemoryConfig::default())
+            .unwrap()
+            .finish();
+        archive::load(&restored, &archive[..]).await.unwrap();
+        assert!(tmp(&restored).await.is_empty());
+        let mut restored = Aidb::from_op(restored).await.unwrap();
+        assert_eq!(
+            query_rows(&mut restored, "SELECT id FROM t;").await.len(),
+            100
+        );
+        drop(aidb);
+        Aidb::from_op(op.clone()).await.unwrap();
+        assert!(tmp(&op).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_columns() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT);")
+            .await
+            .unwrap();
+        aidb.query("INSERT INTO t VALUES (1, 'a'), (2, 'b');")
+            .await
+            .unwrap();
+        for sql in [
+            "SELECT id, id FROM t;",
+            "SELECT id, t.id FROM t WHERE id >= 1;",
+            "SELECT id, id FROM t ORDER BY id;",
+            "SELECT id, id FROM t GROUP BY id;",
+            "SELECT id, id FROM t UNION ALL SELECT id, id FROM t WHERE id = 0;",
+        ] {
+            let Response::Rows { columns, rows, .. } = aidb.query(sql).await.unwrap() else {
+                panic!("rows expected");
+            };
+            assert_eq!(columns.len(), 2, "{sql}");
+            assert!(
+                columns
+                    .iter()
+                    .all(|column| column.datatype == DataType::Integer)
+            );
+            assert_eq!(
+                rows,
+                [
+                    vec![Value::Integer(1), Value::Integer(1)],
+                    vec![Value::Integer(2), Value::Integer(2)],
+                ],
+                "{sql}"
+            );
         }
+        let Response::Rows { columns, rows, .. } =
+            aidb.query("SELECT name, *, name FROM t;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(
+            columns
+                .iter()
+                .map(|column| column.name.as_str())
+                .collect_vec(),
+            ["name", "id", "name", "name"]
+        );
+        assert_eq!(
+            rows[1],
+            [
+                Value::Text("b".to_owned()),
+                Value::Integer(2),
+                Value::Text("b".to_owned()),
+                Value::Text("b".to_owned()),
+            ]
+        );
     }
 }
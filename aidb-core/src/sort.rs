@@ -0,0 +1,354 @@
+use std::{
+    cmp::Ordering,
+    fmt::{Display, Formatter},
+    io::Cursor,
+    mem::take,
+    sync::atomic::{AtomicU64, Ordering as AtomicOrdering},
+};
+
+use binrw::{BinRead, BinWrite, binrw};
+use eyre::Result;
+use itertools::Itertools;
+use opendal::Operator;
+
+use crate::{Aidb, Collation, Row, TMP_DIR, Value, expr::RowExpr, storage::BLOCK_SIZE};
+
+/// Distinguishes temporary objects of sorts running at the same time on the same storage.
+static NEXT_SORT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Value of a spilled row, text and blobs are written inline.
+#[binrw]
+#[brw(little)]
+#[derive(Debug)]
+enum SpilledValue {
+    #[brw(magic = 0u8)]
+    Null,
+    #[brw(magic = 1u8)]
+    Integer(i64),
+    #[brw(magic = 2u8)]
+    Real(f64),
+    #[brw(magic = 3u8)]
+    Text {
+        #[br(temp)]
+        #[bw(calc = bytes.len() as u32)]
+        len: u32,
+        #[br(count = len)]
+        bytes: Vec<u8>,
+    },
+    #[brw(magic = 4u8)]
+    Blob {
+        #[br(temp)]
+        #[bw(calc = bytes.len() as u32)]
+        len: u32,
+        #[br(count = len)]
+        bytes: Vec<u8>,
+    },
+}
+
+#[binrw]
+#[brw(little)]
+#[derive(Debug)]
+struct SpilledRow {
+    #[br(temp)]
+    #[bw(calc = values.len() as u16)]
+    len: u16,
+    #[br(count = len)]
+    values: Vec<SpilledValue>,
+}
+
+impl From<Row> for SpilledRow {
+    fn from(row: Row) -> Self {
+        let values = row
+            .into_iter()
+            .map(|value| match value {
+                Value::Null => SpilledValue::Null,
+                Value::Integer(v) => SpilledValue::Integer(v),
+                Value::Real(v) => SpilledValue::Real(v),
+                Value::Text(s) => SpilledValue::Text {
+                    bytes: s.into_bytes(),
+                },
+                Value::Blob(bytes) => SpilledValue::Blob { bytes },
+                Value::Placeholder(_) => unreachable!(),
+            })
+            .collect();
+        Self { values }
+    }
+}
+
+impl TryFrom<SpilledRow> for Row {
+    type Error = eyre::Report;
+
+    fn try_from(row: SpilledRow) -> Result<Self> {
+        row.values
+            .into_iter()
+            .map(|value| match value {
+                SpilledValue::Null => Ok(Value::Null),
+                SpilledValue::Integer(v) => Ok(Value::Integer(v)),
+                SpilledValue::Real(v) => Ok(Value::Real(v)),
+                SpilledValue::Text { bytes } => Ok(Value::Text(String::from_utf8(bytes)?)),
+                SpilledValue::Blob { bytes } => Ok(Value::Blob(bytes)),
+            })
+            .collect()
+    }
+}
+
+/// Keys of ORDER BY, each descending if true, text compared under `collation`. NULL comes before
+/// any value in ascending order like MySQL.
+#[derive(Debug)]
+pub(crate) struct SortKeys {
+    pub(crate) keys: Vec<(RowExpr, bool)>,
+    pub(crate) collation: Collation,
+}
+
+impl SortKeys {
+    fn eval(&self, row: &Row) -> Result<Row> {
+        self.keys.iter().map(|(expr, _)| expr.eval(row)).collect()
+    }
+
+    /// Compare keys evaluated by [`SortKeys::eval`].
+    fn compare(&self, lhs: &Row, rhs: &Row) -> Ordering {
+        use Ordering::*;
+        for ((_, desc), (lhs, rhs)) in self.keys.iter().zip(lhs.iter().zip(rhs)) {
+            let ordering = match (lhs, rhs) {
+                (Value::Null, Value::Null) => Equal,
+                (Value::Null, _) => Less,
+                (_, Value::Null) => Greater,
+                (lhs, rhs) => self.collation.compare(lhs, rhs).unwrap_or(Equal),
+            };
+            let ordering = if *desc { ordering.reverse() } else { ordering };
+            if ordering != Equal {
+                return ordering;
+            }
+        }
+        Equal
+    }
+}
+
+impl Display for SortKeys {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.keys
+                .iter()
+                .map(|(expr, desc)| if *desc {
+                    format!("{expr} DESC")
+                } else {
+                    format!("{expr}")
+                })
+                .join(", ")
+        )
+    }
+}
+
+/// Sorted runs written to `tmp/sort-<id>/<run>/<chunk>` of the scratch storage, each chunk about
+/// a block of rows.
+#[derive(Debug)]
+pub(crate) struct Spill {
+    scratch: Operator,
+    id: u64,
+    /// number of chunks of each run
+    runs: Vec<usize>,
+}
+
+impl Spill {
+    fn prefix(&self) -> String {
+        format!("{TMP_DIR}sort-{}/", self.id)
+    }
+
+    fn path(&self, run: usize, chunk: usize) -> String {
+        format!("{}{run}/{chunk}", self.prefix())
+    }
+
+    async fn write_run(&mut self, keys: &SortKeys, mut rows: Vec<(Row, Row)>) -> Result<()> {
+        rows.sort_by(|(lhs, _), (rhs, _)| keys.compare(lhs, rhs));
+        let run = self.runs.len();
+        let mut chunks = 0;
+        let mut chunk = Cursor::new(vec![]);
+        for (_, row) in rows {
+            SpilledRow::from(row).write(&mut chunk)?;
+            if chunk.get_ref().len() >= BLOCK_SIZE {
+                self.scratch
+                    .write(&self.path(run, chunks), take(&mut chunk).into_inner())
+                    .await?;
+                chunks += 1;
+            }
+        }
+        if !chunk.get_ref().is_empty() {
+            self.scratch
+                .write(&self.path(run, chunks), chunk.into_inner())
+                .await?;
+            chunks += 1;
+        }
+        self.runs.push(chunks);
+        Ok(())
+    }
+
+    /// Move the next row of a run to its head, reading its next chunk when needed.
+    async fn advance_run(&self, keys: &SortKeys, index: usize, run: &mut Run) -> Result<()> {
+        if run.rows.len() == 0 && run.next_chunk < self.runs[index] {
+            let bytes = self
+                .scratch
+                .read(&self.path(index, run.next_chunk))
+                .await?
+                .to_vec();
+            let len = bytes.len() as u64;
+            let mut cursor = Cursor::new(bytes);
+            let mut rows = vec![];
+            while cursor.position() < len {
+                rows.push(SpilledRow::read(&mut cursor)?.try_into()?);
+            }
+            run.rows = rows.into_iter();
+            run.next_chunk += 1;
+        }
+        run.head = run
+            .rows
+            .next()
+            .map(|row| Ok::<_, eyre::Report>((keys.eval(&row)?, row)))
+            .transpose()?;
+        Ok(())
+    }
+}
+
+/// Run being merged, with the smallest row not returned yet and its keys.
+#[derive(Debug)]
+pub(crate) struct Run {
+    next_chunk: usize,
+    rows: std::vec::IntoIter<Row>,
+    head: Option<(Row, Row)>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) enum SortState {
+    #[default]
+    Initialized,
+    /// reading the inner plan, rows kept along with their keys
+    Buffering {
+        rows: Vec<(Row, Row)>,
+        spill: Option<Spill>,
+    },
+    /// all rows fit in memory
+    Sorted(std::vec::IntoIter<(Row, Row)>),
+    Merging {
+        spill: Spill,
+        runs: Vec<Run>,
+    },
+    Done,
+}
+
+impl SortState {
+    pub(crate) fn is_buffering(&self) -> bool {
+        matches!(self, SortState::Initialized | SortState::Buffering { .. })
+    }
+
+    /// Start over, leaving temporary objects to be removed by the next sort that spills.
+    pub(crate) fn reset(&mut self, db: &mut Aidb) {
+        if let SortState::Buffering {
+            spill: Some(spill), ..
+        }
+        | SortState::Merging { spill, .. } = take(self)
+        {
+            db.spilled_sorts.push(spill);
+        }
+    }
+}
+
+impl Aidb {
+    /// Buffer a row of the inner plan, spilling the buffer as a sorted run once it is full.
+    pub(crate) async fn sort_push(
+        &mut self,
+        keys: &SortKeys,
+        state: &mut SortState,
+        row: Row,
+    ) -> Result<()> {
+        if let SortState::Initialized = state {
+            *state = SortState::Buffering {
+                rows: vec![],
+                spill: None,
+            };
+        }
+        let SortState::Buffering { rows, spill } = state else {
+            unreachable!()
+        };
+        rows.push((keys.eval(&row)?, row));
+        if rows.len() >= self.sort_buffer_rows
+            && let Some(scratch) = &self.scratch
+        {
+            if spill.is_none() {
+                for stopped in take(&mut self.spilled_sorts) {
+                    stopped.scratch.remove_all(&stopped.prefix()).await?;
+                }
+                *spill = Some(Spill {
+                    scratch: scratch.clone(),
+                    id: NEXT_SORT_ID.fetch_add(1, AtomicOrdering::Relaxed),
+                    runs: vec![],
+                });
+            }
+            spill.as_mut().unwrap().write_run(keys, take(rows)).await?;
+        }
+        Ok(())
+    }
+
+    /// Sort what is left after the last row of the inner plan, merging spilled runs if any.
+    pub(crate) async fn sort_finish(
+        &mut self,
+        keys: &SortKeys,
+        state: &mut SortState,
+    ) -> Result<()> {
+        let (mut rows, spill) = match take(state) {
+            SortState::Initialized => (vec![], None),
+            SortState::Buffering { rows, spill } => (rows, spill),
+            _ => unreachable!(),
+        };
+        let Some(mut spill) = spill else {
+            rows.sort_by(|(lhs, _), (rhs, _)| keys.compare(lhs, rhs));
+            *state = SortState::Sorted(rows.into_iter());
+            return Ok(());
+        };
+        if !rows.is_empty() {
+            spill.write_run(keys, rows).await?;
+        }
+        let mut runs = vec![];
+        for index in 0..spill.runs.len() {
+            let mut run = Run {
+                next_chunk: 0,
+                rows: vec![].into_iter(),
+                head: None,
+            };
+            spill.advance_run(keys, index, &mut run).await?;
+            runs.push(run);
+        }
+        *state = SortState::Merging { spill, runs };
+        Ok(())
+    }
+
+    /// Next row in order once the inner plan is sorted.
+    pub(crate) async fn sort_next(
+        &mut self,
+        keys: &SortKeys,
+        state: &mut SortState,
+    ) -> Result<Option<Row>> {
+        match state {
+            SortState::Sorted(rows) => Ok(rows.next().map(|(_, row)| row)),
+            SortState::Merging { spill, runs } => {
+                // the earliest run wins a tie, as runs hold rows in the order they were read
+                let Some(index) = runs
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, run)| Some((index, &run.head.as_ref()?.0)))
+                    .min_by(|(_, lhs), (_, rhs)| keys.compare(lhs, rhs))
+                    .map(|(index, _)| index)
+                else {
+                    spill.scratch.remove_all(&spill.prefix()).await?;
+                    *state = SortState::Done;
+                    return Ok(None);
+                };
+                let (_, row) = runs[index].head.take().unwrap();
+                spill.advance_run(keys, index, &mut runs[index]).await?;
+                Ok(Some(row))
+            }
+            SortState::Done => Ok(None),
+            SortState::Initialized | SortState::Buffering { .. } => unreachable!(),
+        }
+    }
+}
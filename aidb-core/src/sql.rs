@@ -1,56 +1,106 @@
-use std::fmt::{Display, Formatter};
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    error::Error,
+    fmt::{Display, Formatter},
+};
 
 use eyre::{Result, eyre};
 use nom::{
     IResult, Parser,
     branch::alt,
     bytes::complete::{tag, tag_no_case},
-    character::complete::{alpha1, alphanumeric1, multispace0, multispace1, none_of, one_of},
-    combinator::{eof, fail, map, map_opt, map_res, opt, recognize, value},
-    error::ParseError,
-    multi::{fold_many0, many0, many0_count, many1, separated_list1},
+    character::complete::{
+        alpha1, alphanumeric1, hex_digit0, multispace0, multispace1, none_of, one_of,
+    },
+    combinator::{eof, fail, map, map_opt, not, opt, recognize, value},
+    error::{ErrorKind, FromExternalError, ParseError},
+    multi::{fold_many0, many0, many0_count, many1, separated_list0, separated_list1},
     number::complete::hex_u32,
     sequence::{delimited, preceded, separated_pair, terminated},
 };
 use nom_language::precedence::{Assoc, Operation, binary_op, precedence, unary_op};
 use tracing::trace;
 
-use crate::{Aidb, Column, DataType, Value, schema::IndexType};
+use crate::{
+    Aidb, Column, DataType, Value,
+    data::from_hex,
+    schema::{IndexType, TableComments},
+};
 
 #[derive(Debug, Clone)]
 pub enum SqlStmt {
     /// SHOW TABLES
     ShowTables,
+    /// SHOW TABLE STATUS
+    ShowTableStatus,
     /// DESCRIBE | DESC table
     Describe { table: String },
-    /// CREATE TABLE table (column datatype [UNIQUE], ...)
+    /// CHECK TABLE table
+    CheckTable { table: String },
+    /// CREATE TABLE [IF NOT EXISTS] table
+    /// (column datatype [UNIQUE | PRIMARY KEY] [AUTO_INCREMENT] [COMMENT 'text'], ...)
+    /// [COMMENT [=] 'text']
     CreateTable {
         table: String,
         columns: Vec<(Column, Option<IndexType>)>,
+        auto_increment: Option<String>,
+        if_not_exists: bool,
+        comments: TableComments,
+    },
+    /// DROP TABLE [IF EXISTS] table
+    DropTable { table: String, if_exists: bool },
+    /// REINDEX table | ALTER TABLE table REBUILD INDEX
+    Reindex { table: String },
+    /// CREATE [UNIQUE] INDEX index ON table (column) [USING BTREE | HASH]
+    CreateIndex {
+        table: String,
+        column: String,
+        type_: IndexType,
+        unique: bool,
     },
-    /// DROP TABLE table
-    DropTable { table: String },
     /// INSERT INTO table [(column, ...)] VALUES value, ...
+    /// [ON DUPLICATE KEY UPDATE column = value, ...]
     InsertInto {
         table: String,
         columns: Vec<String>,
         values: Vec<Vec<Value>>,
+        on_duplicate: Vec<(SqlCol, Value)>,
+    },
+    /// REPLACE INTO table [(column, ...)] VALUES value, ...
+    ReplaceInto {
+        table: String,
+        columns: Vec<String>,
+        values: Vec<Vec<Value>>,
     },
     /// SELECT column, ... [FROM table] [JOIN table ON condition ...] [WHERE condition]
+    /// [GROUP BY column, ... [HAVING condition]] [ORDER BY expr [ASC | DESC], ...] [LIMIT n]
+    /// [FOR UPDATE | LOCK IN SHARE MODE]
     Select {
         columns: Vec<SqlSelectTarget>,
         table: Option<String>,
         join_on: Vec<(String, SqlOn)>,
         where_: Option<SqlWhere>,
+        group_by: Option<SqlGroupBy>,
+        order_by: Vec<SqlOrderBy>,
         limit: Option<usize>,
     },
-    /// EXPLAIN SELECT ...
+    /// SELECT ... UNION [ALL] SELECT ...
+    Union {
+        left: Box<SqlStmt>,
+        right: Box<SqlStmt>,
+        all: bool,
+    },
+    /// EXPLAIN [ANALYZE] SELECT ...
     Explain {
         columns: Vec<SqlSelectTarget>,
         table: Option<String>,
         join_on: Vec<(String, SqlOn)>,
         where_: Option<SqlWhere>,
+        group_by: Option<SqlGroupBy>,
+        order_by: Vec<SqlOrderBy>,
         limit: Option<usize>,
+        analyze: bool,
     },
     /// UPDATE table SET column = value, ... [WHERE condition]
     Update {
@@ -71,6 +121,12 @@ pub enum SqlStmt {
     Commit,
     /// ROLLBACK
     Rollback,
+    /// SAVEPOINT name
+    Savepoint { name: String },
+    /// ROLLBACK TO [SAVEPOINT] name
+    RollbackTo { name: String },
+    /// RELEASE SAVEPOINT name
+    Release { name: String },
 }
 
 #[derive(Debug, Clone)]
@@ -93,6 +149,160 @@ pub enum SqlSelectTarget {
     Const(Value),
     Wildcard,
     Variable(String),
+    /// anything beyond a single column or constant
+    Expr(SqlExpr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqlBinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl Display for SqlBinaryOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SqlBinaryOp::Add => write!(f, "+"),
+            SqlBinaryOp::Sub => write!(f, "-"),
+            SqlBinaryOp::Mul => write!(f, "*"),
+            SqlBinaryOp::Div => write!(f, "/"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SqlExpr {
+    Column(SqlCol),
+    Const(Value),
+    Binary {
+        op: SqlBinaryOp,
+        lhs: Box<SqlExpr>,
+        rhs: Box<SqlExpr>,
+    },
+    /// FUNCTION(arg, ...)
+    Call {
+        function: String,
+        args: Vec<SqlExpr>,
+    },
+}
+
+impl Display for SqlExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SqlExpr::Column(SqlCol::Full { table, column }) => write!(f, "{table}.{column}"),
+            SqlExpr::Column(SqlCol::Short(column)) => write!(f, "{column}"),
+            SqlExpr::Const(value) => write!(f, "{value}"),
+            SqlExpr::Binary { op, lhs, rhs } => {
+                for (i, operand) in [lhs, rhs].into_iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " {op} ")?;
+                    }
+                    match operand.as_ref() {
+                        SqlExpr::Binary { .. } => write!(f, "({operand})")?,
+                        _ => write!(f, "{operand}")?,
+                    }
+                }
+                Ok(())
+            }
+            SqlExpr::Call { function, args } if args.is_empty() && function == "COUNT" => {
+                write!(f, "{function}(*)")
+            }
+            SqlExpr::Call { function, args } => write!(
+                f,
+                "{function}({})",
+                args.iter()
+                    .map(|arg| arg.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqlCmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Display for SqlCmpOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SqlCmpOp::Eq => write!(f, "="),
+            SqlCmpOp::Ne => write!(f, "<>"),
+            SqlCmpOp::Lt => write!(f, "<"),
+            SqlCmpOp::Le => write!(f, "<="),
+            SqlCmpOp::Gt => write!(f, ">"),
+            SqlCmpOp::Ge => write!(f, ">="),
+        }
+    }
+}
+
+/// Condition over arbitrary expressions, evaluated on rows after grouping.
+#[derive(Debug, Clone)]
+pub enum SqlCondition {
+    Cmp {
+        op: SqlCmpOp,
+        lhs: SqlExpr,
+        rhs: SqlExpr,
+    },
+    And(Box<SqlCondition>, Box<SqlCondition>),
+    Or(Box<SqlCondition>, Box<SqlCondition>),
+    Not(Box<SqlCondition>),
+}
+
+impl SqlCondition {
+    fn values_mut<'a>(&'a mut self, values: &mut Vec<&'a mut Value>) {
+        match self {
+            SqlCondition::Cmp { lhs, rhs, .. } => {
+                lhs.values_mut(values);
+                rhs.values_mut(values);
+            }
+            SqlCondition::And(lhs, rhs) | SqlCondition::Or(lhs, rhs) => {
+                lhs.values_mut(values);
+                rhs.values_mut(values);
+            }
+            SqlCondition::Not(condition) => condition.values_mut(values),
+        }
+    }
+}
+
+/// GROUP BY column, ... [HAVING condition]
+#[derive(Debug, Clone)]
+pub struct SqlGroupBy {
+    pub columns: Vec<SqlCol>,
+    pub having: Option<SqlCondition>,
+}
+
+/// Key of ORDER BY, an integer being the position of a selected column from 1.
+#[derive(Debug, Clone)]
+pub struct SqlOrderBy {
+    pub expr: SqlExpr,
+    pub desc: bool,
+}
+
+impl SqlExpr {
+    fn values_mut<'a>(&'a mut self, values: &mut Vec<&'a mut Value>) {
+        match self {
+            SqlExpr::Column(_) => {}
+            SqlExpr::Const(value) => values.push(value),
+            SqlExpr::Binary { lhs, rhs, .. } => {
+                lhs.values_mut(values);
+                rhs.values_mut(values);
+            }
+            SqlExpr::Call { args, .. } => {
+                for arg in args {
+                    arg.values_mut(values);
+                }
+            }
+        }
+    }
 }
 
 impl Display for SqlSelectTarget {
@@ -105,6 +315,7 @@ impl Display for SqlSelectTarget {
             SqlSelectTarget::Const(value) => write!(f, "{value}"),
             SqlSelectTarget::Wildcard => write!(f, "*"),
             SqlSelectTarget::Variable(v) => write!(f, "{v}"),
+            SqlSelectTarget::Expr(expr) => write!(f, "{expr}"),
         }
     }
 }
@@ -121,16 +332,50 @@ pub enum SqlRel {
         lhs: SqlColOrExpr,
         rhs: SqlColOrExpr,
     },
+    /// `!=` or `<>`
+    Ne {
+        lhs: SqlColOrExpr,
+        rhs: SqlColOrExpr,
+    },
+    /// `<=`, also `>=` with operands swapped
     Le {
         lhs: SqlColOrExpr,
         rhs: SqlColOrExpr,
     },
+    /// `<`, also `>` with operands swapped
+    Lt {
+        lhs: SqlColOrExpr,
+        rhs: SqlColOrExpr,
+    },
+    /// `<=>`, equality that holds for two NULLs
+    NullSafeEq {
+        lhs: SqlColOrExpr,
+        rhs: SqlColOrExpr,
+    },
+    /// column LIKE pattern [ESCAPE 'c'], backslash being the default escape
     Like {
         lhs: SqlCol,
         rhs: String,
+        escape: char,
+    },
+    In {
+        lhs: SqlCol,
+        rhs: SqlIn,
+    },
+    /// IS NULL, or IS NOT NULL if negated
+    IsNull {
+        lhs: SqlCol,
+        negated: bool,
     },
 }
 
+/// Right hand side of IN, subqueries are materialized into lists before planning.
+#[derive(Debug, Clone)]
+pub enum SqlIn {
+    List(Vec<Value>),
+    Select(Box<SqlStmt>),
+}
+
 #[derive(Debug, Clone)]
 pub enum SqlWhere {
     Rel(SqlRel),
@@ -139,8 +384,377 @@ pub enum SqlWhere {
     Not(Box<SqlWhere>),
 }
 
+impl SqlStmt {
+    /// Whether the statement may modify the schema or data.
+    pub(crate) fn is_mutating(&self) -> bool {
+        matches!(
+            self,
+            SqlStmt::CreateTable { .. }
+                | SqlStmt::DropTable { .. }
+                | SqlStmt::CreateIndex { .. }
+                | SqlStmt::Reindex { .. }
+                | SqlStmt::InsertInto { .. }
+                | SqlStmt::ReplaceInto { .. }
+                | SqlStmt::Update { .. }
+                | SqlStmt::DeleteFrom { .. }
+        )
+    }
+
+    /// Whether the statement only reads tables and may run on a read-only instance.
+    pub(crate) fn is_read(&self) -> bool {
+        matches!(
+            self,
+            SqlStmt::Select { .. }
+                | SqlStmt::Union { .. }
+                | SqlStmt::Explain { .. }
+                | SqlStmt::ShowTables
+                | SqlStmt::ShowTableStatus
+                | SqlStmt::Describe { .. }
+        )
+    }
+
+    /// Name of the statement for logging.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            SqlStmt::ShowTables => "SHOW TABLES",
+            SqlStmt::ShowTableStatus => "SHOW TABLE STATUS",
+            SqlStmt::Describe { .. } => "DESCRIBE",
+            SqlStmt::CheckTable { .. } => "CHECK TABLE",
+            SqlStmt::CreateTable { .. } => "CREATE TABLE",
+            SqlStmt::DropTable { .. } => "DROP TABLE",
+            SqlStmt::CreateIndex { .. } => "CREATE INDEX",
+            SqlStmt::Reindex { .. } => "REINDEX",
+            SqlStmt::InsertInto { .. } => "INSERT",
+            SqlStmt::ReplaceInto { .. } => "REPLACE",
+            SqlStmt::Select { .. } | SqlStmt::Union { .. } => "SELECT",
+            SqlStmt::Explain { .. } => "EXPLAIN",
+            SqlStmt::Update { .. } => "UPDATE",
+            SqlStmt::DeleteFrom { .. } => "DELETE",
+            SqlStmt::FlushTables => "FLUSH TABLES",
+            SqlStmt::StartTransaction => "START TRANSACTION",
+            SqlStmt::Commit => "COMMIT",
+            SqlStmt::Rollback => "ROLLBACK",
+            SqlStmt::Savepoint { .. } => "SAVEPOINT",
+            SqlStmt::RollbackTo { .. } => "ROLLBACK TO",
+            SqlStmt::Release { .. } => "RELEASE SAVEPOINT",
+        }
+    }
+
+    /// All values in order of appearance.
+    pub(crate) fn values_mut(&mut self) -> Vec<&mut Value> {
+        let mut values = vec![];
+        match self {
+            SqlStmt::InsertInto {
+                values: rows,
+                on_duplicate,
+                ..
+            } => {
+                values.extend(rows.iter_mut().flatten());
+                values.extend(on_duplicate.iter_mut().map(|(_, value)| value));
+            }
+            SqlStmt::ReplaceInto { values: rows, .. } => values.extend(rows.iter_mut().flatten()),
+            SqlStmt::Select {
+                columns,
+                where_,
+                group_by,
+                order_by,
+                ..
+            }
+            | SqlStmt::Explain {
+                columns,
+                where_,
+                group_by,
+                order_by,
+                ..
+            } => {
+                for column in columns {
+                    match column {
+                        SqlSelectTarget::Const(value) => values.push(value),
+                        SqlSelectTarget::Expr(expr) => expr.values_mut(&mut values),
+                        _ => {}
+                    }
+                }
+                if let Some(where_) = where_ {
+                    where_.values_mut(&mut values);
+                }
+                if let Some(SqlGroupBy {
+                    having: Some(having),
+                    ..
+                }) = group_by
+                {
+                    having.values_mut(&mut values);
+                }
+                for SqlOrderBy { expr, .. } in order_by {
+                    expr.values_mut(&mut values);
+                }
+            }
+            SqlStmt::Union { left, right, .. } => {
+                values.extend(left.values_mut());
+                values.extend(right.values_mut());
+            }
+            SqlStmt::Update { set, where_, .. } => {
+                values.extend(set.iter_mut().map(|(_, value)| value));
+                if let Some(where_) = where_ {
+                    where_.values_mut(&mut values);
+                }
+            }
+            SqlStmt::DeleteFrom {
+                where_: Some(where_),
+                ..
+            } => where_.values_mut(&mut values),
+            _ => {}
+        }
+        values
+    }
+}
+
+impl SqlWhere {
+    fn values_mut<'a>(&'a mut self, values: &mut Vec<&'a mut Value>) {
+        match self {
+            SqlWhere::Rel(
+                SqlRel::Eq { lhs, rhs }
+                | SqlRel::Ne { lhs, rhs }
+                | SqlRel::Le { lhs, rhs }
+                | SqlRel::Lt { lhs, rhs }
+                | SqlRel::NullSafeEq { lhs, rhs },
+            ) => {
+                for operand in [lhs, rhs] {
+                    if let SqlColOrExpr::Const(value) = operand {
+                        values.push(value);
+                    }
+                }
+            }
+            SqlWhere::Rel(SqlRel::Like { .. } | SqlRel::IsNull { .. }) => {}
+            SqlWhere::Rel(SqlRel::In { rhs, .. }) => match rhs {
+                SqlIn::List(list) => values.extend(list),
+                SqlIn::Select(stmt) => values.extend(stmt.values_mut()),
+            },
+            SqlWhere::And(lhs, rhs) | SqlWhere::Or(lhs, rhs) => {
+                lhs.values_mut(values);
+                rhs.values_mut(values);
+            }
+            SqlWhere::Not(clause) => clause.values_mut(values),
+        }
+    }
+}
+
+/// Statement with `?` placeholders to be bound by [`Aidb::query_params`].
+#[derive(Debug, Clone)]
+pub struct Prepared {
+    pub(crate) stmt: SqlStmt,
+    pub(crate) params: usize,
+}
+
+impl Prepared {
+    /// Number of parameters expected.
+    pub fn params(&self) -> usize {
+        self.params
+    }
+}
+
+/// Least recently used parsed statements keyed by exact SQL text.
+///
+/// Statements don't refer to the schema until planned, so entries stay valid across DDL.
+#[derive(Debug)]
+pub(crate) struct StmtCache {
+    capacity: usize,
+    entries: VecDeque<(String, SqlStmt)>,
+    /// number of times the parser actually ran
+    pub(crate) parses: usize,
+}
+
+impl StmtCache {
+    pub(crate) const DEFAULT_CAPACITY: usize = 64;
+
+    /// Longer SQL, e.g. bulk inserts, is unlikely to repeat and isn't cached.
+    const MAX_SQL_LEN: usize = 4096;
+
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+            parses: 0,
+        }
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.entries.truncate(capacity);
+    }
+
+    pub(crate) fn parse(&mut self, input: &str) -> Result<SqlStmt> {
+        if let Some(i) = self.entries.iter().position(|(sql, _)| sql == input) {
+            let entry = self.entries.remove(i).unwrap();
+            let stmt = entry.1.clone();
+            self.entries.push_front(entry);
+            return Ok(stmt);
+        }
+        self.parses += 1;
+        let stmt = Aidb::parse(input)?;
+        if self.capacity > 0 && input.len() <= Self::MAX_SQL_LEN {
+            self.entries.truncate(self.capacity - 1);
+            self.entries.push_front((input.to_owned(), stmt.clone()));
+        }
+        Ok(stmt)
+    }
+}
+
+/// SQL that failed to parse, with the byte offset of the unexpected input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub offset: usize,
+    pub near: String,
+}
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.near.is_empty() {
+            write!(f, "invalid SQL at {}: unexpected end of input", self.offset)
+        } else {
+            write!(f, "invalid SQL at {}: near \"{}\"", self.offset, self.near)
+        }
+    }
+}
+
+impl Error for SyntaxError {}
+
+impl SyntaxError {
+    fn new(input: &str, remain: &str) -> Self {
+        Self {
+            offset: input.len() - remain.len(),
+            near: remain.chars().take(16).collect(),
+        }
+    }
+
+    /// Error at the furthest failure while parsing `input`, or at `remain` if that's further.
+    fn furthest(input: &str, remain: &str) -> Self {
+        let start = input.as_ptr() as usize;
+        let offset = FURTHEST_ERROR.with(Cell::get).saturating_sub(start);
+        match input.get(offset..) {
+            Some(furthest) if offset <= input.len() && furthest.len() < remain.len() => {
+                Self::new(input, furthest)
+            }
+            _ => Self::new(input, remain),
+        }
+    }
+}
+
+/// SQL that is recognized but uses a feature the engine doesn't implement, reported instead of
+/// a [`SyntaxError`] when the statement fails to parse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unsupported {
+    pub feature: String,
+}
+
+impl Display for Unsupported {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is not supported", self.feature)
+    }
+}
+
+impl Error for Unsupported {}
+
+impl Unsupported {
+    /// Look for a known unimplemented construct in SQL that failed to parse.
+    fn detect(input: &str) -> Option<Self> {
+        let tokens = tokens(input);
+        let is = |i: usize, kw: &str| tokens.get(i).is_some_and(|t| t.eq_ignore_ascii_case(kw));
+        let feature = if is(0, "WITH") {
+            "WITH/CTE".to_owned()
+        } else if is(0, "ALTER") && is(1, "TABLE") {
+            "ALTER TABLE".to_owned()
+        } else if is(0, "CREATE") {
+            let i = if is(1, "OR") && is(2, "REPLACE") {
+                3
+            } else {
+                1
+            };
+            ["VIEW", "TRIGGER", "PROCEDURE", "FUNCTION"]
+                .into_iter()
+                .find(|kw| is(i, kw))
+                .map(|kw| format!("CREATE {kw}"))?
+        } else if (0..tokens.len()).any(|i| is(i, "OVER") && is(i + 1, "(")) {
+            "window function".to_owned()
+        } else {
+            ["INTERSECT", "EXCEPT"]
+                .into_iter()
+                .find(|kw| (0..tokens.len()).any(|i| is(i, kw)))?
+                .to_owned()
+        };
+        Some(Self { feature })
+    }
+}
+
+/// Words and punctuation of SQL, skipping string literals and quoted identifiers.
+fn tokens(input: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let mut chars = input.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '\'' | '"' | '`' => {
+                while chars.next_if(|&(_, q)| q != c).is_some() {}
+                chars.next();
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) = chars.next_if(|&(_, c)| c.is_alphanumeric() || c == '_') {
+                    end = i + c.len_utf8();
+                }
+                tokens.push(&input[start..end]);
+            }
+            c if c.is_whitespace() => {}
+            c => tokens.push(&input[start..start + c.len_utf8()]),
+        }
+    }
+    tokens
+}
+
+/// Split a script into statements at `;` outside string literals, dropping `--` and `#` line
+/// comments and statements left empty.
+pub fn split_statements(script: &str) -> Vec<String> {
+    let mut stmts = vec![];
+    let mut current = String::new();
+    let mut chars = script.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                current.push(c);
+                while let Some(d) = chars.next() {
+                    current.push(d);
+                    if d == '\\' {
+                        current.extend(chars.next());
+                    } else if d == c {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                chars.find(|&d| d == '\n');
+                current.push('\n');
+            }
+            '#' => {
+                chars.find(|&d| d == '\n');
+                current.push('\n');
+            }
+            ';' => stmts.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    stmts.push(current);
+    stmts
+        .into_iter()
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
 impl Aidb {
-    pub fn complete(input: impl AsRef<str>) -> String {
+    /// Suggest what comes next: a table or column name being typed, or else a keyword.
+    pub async fn complete(&mut self, input: impl AsRef<str>) -> String {
+        let input = input.as_ref();
+        if let Some(name) = self.complete_name(input).await {
+            return name;
+        }
         for (tail, hint) in [
             ("SELECT 1", "SELECT"),
             ("FROM a", "FROM"),
@@ -152,28 +766,124 @@ impl Aidb {
             ("INTO a(a) VALUES (1)", "INTO"),
             ("VALUES (1)", "VALUES"),
             ("TABLE a (a INTEGER)", "TABLE"),
+            ("INDEX a ON a (a)", "INDEX"),
+            ("USING HASH", "USING"),
             ("TABLES", "TABLES"),
             ("TRANSACTION", "TRANSACTION"),
             (")", ")"),
             (";", ";"),
         ] {
-            if stmt(&format!("{} {tail}", input.as_ref())).is_ok() {
+            if stmt(&format!("{input} {tail}")).is_ok() {
                 return hint.to_owned();
             }
         }
         "".to_owned()
     }
 
+    async fn complete_name(&mut self, input: &str) -> Option<String> {
+        const TABLE_KEYWORDS: [&str; 8] = [
+            "FROM", "JOIN", "INTO", "TABLE", "DESC", "DESCRIBE", "UPDATE", "REINDEX",
+        ];
+        const COLUMN_KEYWORDS: [&str; 9] = [
+            "SELECT", "WHERE", "AND", "OR", "NOT", "ON", "SET", "BY", "HAVING",
+        ];
+
+        let is_ident = |c: char| c.is_alphanumeric() || c == '_' || c == '.';
+        let words: Vec<&str> = input
+            .split(|c: char| !is_ident(c))
+            .filter(|w| !w.is_empty())
+            .collect();
+        let is_keyword =
+            |word: &str, keywords: &[&str]| keywords.iter().any(|kw| kw.eq_ignore_ascii_case(word));
+        let is_any_keyword =
+            |word: &str| is_keyword(word, &TABLE_KEYWORDS) || is_keyword(word, &COLUMN_KEYWORDS);
+
+        // the word being typed, empty right after a keyword
+        let partial = match input.chars().last() {
+            Some(c) if is_ident(c) && !is_any_keyword(words.last()?) => *words.last()?,
+            _ => "",
+        };
+        let context = words
+            .iter()
+            .rev()
+            .skip(if partial.is_empty() { 0 } else { 1 })
+            .find(|word| is_any_keyword(word))?;
+
+        let tables = self.table_columns().await.ok()?;
+        let candidates: Vec<String> = if is_keyword(context, &TABLE_KEYWORDS) {
+            tables.into_iter().map(|(table, _)| table).collect()
+        } else if let Some((table, _)) = partial.split_once('.') {
+            let (table, columns) = tables.into_iter().find(|(t, _)| t == table)?;
+            columns
+                .into_iter()
+                .map(|column| format!("{table}.{column}"))
+                .collect()
+        } else {
+            // prefer columns of tables already mentioned in the statement
+            let in_scope: Vec<_> = tables
+                .iter()
+                .filter(|(table, _)| words.contains(&table.as_str()))
+                .cloned()
+                .collect();
+            let tables = if in_scope.is_empty() {
+                tables
+            } else {
+                in_scope
+            };
+            tables
+                .into_iter()
+                .flat_map(|(_, columns)| columns)
+                .collect()
+        };
+        candidates
+            .into_iter()
+            .find(|name| name.starts_with(partial) && name != partial)
+    }
+
     pub(crate) fn parse(input: impl AsRef<str>) -> Result<SqlStmt> {
-        match stmt(input.as_ref()) {
-            Ok((remain, stmt)) => {
-                assert!(remain.is_empty());
-                Ok(stmt)
+        let prepared = Self::prepare(input)?;
+        if prepared.params > 0 {
+            return Err(eyre!("missing parameters"));
+        }
+        Ok(prepared.stmt)
+    }
+
+    /// Whether a statement only reads tables, e.g. SELECT, so that it may run on an instance
+    /// from [`Aidb::open_reader`]. Statements that don't parse are not.
+    pub fn is_read(input: impl AsRef<str>) -> bool {
+        Self::parse(input).is_ok_and(|stmt| stmt.is_read())
+    }
+
+    /// Whether a statement is COMMIT or ROLLBACK, which end the transaction in progress if any.
+    pub fn ends_transaction(input: impl AsRef<str>) -> bool {
+        Self::parse(input).is_ok_and(|stmt| matches!(stmt, SqlStmt::Commit | SqlStmt::Rollback))
+    }
+
+    /// Parse a statement which may contain `?` placeholders.
+    pub fn prepare(input: impl AsRef<str>) -> Result<Prepared> {
+        FURTHEST_ERROR.with(|furthest| furthest.set(0));
+        let result = stmt(input.as_ref());
+        if !matches!(result, Ok((remain, _)) if remain.is_empty())
+            && let Some(e) = Unsupported::detect(input.as_ref())
+        {
+            Err(e)?
+        }
+        match result {
+            Ok((remain, _)) if !remain.is_empty() => Err(SyntaxError::new(input.as_ref(), remain))?,
+            Ok((_, mut stmt)) => {
+                let mut params = 0;
+                for value in stmt.values_mut() {
+                    if let Value::Placeholder(i) = value {
+                        *i = params;
+                        params += 1;
+                    }
+                }
+                Ok(Prepared { stmt, params })
             }
             Err(e) => match e {
                 nom::Err::Error(e) => {
                     trace!(?e);
-                    Err(eyre!("invalid SQL"))
+                    Err(SyntaxError::furthest(input.as_ref(), e.input))?
                 }
                 _ => unreachable!(),
             },
@@ -205,7 +915,45 @@ fn paren<'a, T, E: ParseError<&'a str>>(
     delimited((tag("("), multispace0), parser, (multispace0, tag(")")))
 }
 
-type ParseResult<'a, T> = IResult<&'a str, T>;
+thread_local! {
+    /// Address of the furthest input any parser failed at, including the failures optional parts
+    /// recover from, which is where a statement stops making sense.
+    static FURTHEST_ERROR: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Error at the furthest position reached by any alternative.
+#[derive(Debug)]
+struct SqlParseError<'a> {
+    input: &'a str,
+}
+
+impl<'a> ParseError<&'a str> for SqlParseError<'a> {
+    fn from_error_kind(input: &'a str, _kind: ErrorKind) -> Self {
+        let address = input.as_ptr() as usize;
+        FURTHEST_ERROR.with(|furthest| furthest.set(furthest.get().max(address)));
+        Self { input }
+    }
+
+    fn append(_input: &'a str, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn or(self, other: Self) -> Self {
+        if other.input.len() <= self.input.len() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+impl<'a, E> FromExternalError<&'a str, E> for SqlParseError<'a> {
+    fn from_external_error(input: &'a str, kind: ErrorKind, _e: E) -> Self {
+        Self::from_error_kind(input, kind)
+    }
+}
+
+type ParseResult<'a, T> = IResult<&'a str, T, SqlParseError<'a>>;
 
 fn ident(input: &str) -> ParseResult<String> {
     map(
@@ -233,18 +981,26 @@ fn stmt(input: &str) -> ParseResult<SqlStmt> {
         multispace0,
         alt((
             show_tables,
+            show_table_status,
             describe,
+            check_table,
             create_table,
             drop_table,
+            create_index,
+            reindex,
             insert_into,
-            select,
+            replace_into,
+            union,
             explain,
             update,
             delete_from,
             flush_tables,
             start_transaction,
             commit,
+            rollback_to,
             rollback,
+            savepoint,
+            release,
         )),
         (multispace0, opt(tag(";")), multispace0, eof),
     )
@@ -271,6 +1027,7 @@ fn datatype(input: &str) -> ParseResult<DataType> {
                 tag_no_case("DOUBLE"),
             )),
         ),
+        value(Blob, tag_no_case("BLOB")),
         alt((
             value(Text, tag_no_case("TEXT")),
             value(
@@ -291,16 +1048,48 @@ fn datatype(input: &str) -> ParseResult<DataType> {
     .parse(input)
 }
 
-fn col_def(input: &str) -> ParseResult<(Column, Option<IndexType>)> {
+#[derive(Clone)]
+enum ColModifier {
+    Index(IndexType),
+    AutoIncrement,
+    Comment(String),
+}
+
+/// Column definition, whether it is AUTO_INCREMENT and its comment.
+fn col_def(input: &str) -> ParseResult<((Column, Option<IndexType>), bool, String)> {
     map(
         (
             separated_pair(ident, multispace1, datatype),
-            opt(preceded(
+            // in any order
+            many0(preceded(
                 multispace1,
-                value(IndexType::BTree, tag_no_case("UNIQUE")),
+                alt((
+                    value(
+                        ColModifier::Index(IndexType::BTree),
+                        alt((
+                            tag_no_case("UNIQUE"),
+                            recognize((tag_no_case("PRIMARY"), multispace1, tag_no_case("KEY"))),
+                        )),
+                    ),
+                    value(ColModifier::AutoIncrement, tag_no_case("AUTO_INCREMENT")),
+                    map(
+                        preceded((tag_no_case("COMMENT"), multispace0), text),
+                        ColModifier::Comment,
+                    ),
+                )),
             )),
         ),
-        |((name, datatype), index)| (Column { name, datatype }, index),
+        |((name, datatype), modifiers)| {
+            let (mut index, mut auto_increment, mut comment) = (None, false, String::new());
+            for modifier in modifiers {
+                match modifier {
+                    ColModifier::Index(type_) => index = index.or(Some(type_)),
+                    ColModifier::AutoIncrement => auto_increment = true,
+                    ColModifier::Comment(s) => comment = s,
+                }
+            }
+            ((Column { name, datatype }, index), auto_increment, comment)
+        },
     )
     .parse(input)
 }
@@ -313,6 +1102,18 @@ fn show_tables(input: &str) -> ParseResult<SqlStmt> {
     .parse(input)
 }
 
+fn show_table_status(input: &str) -> ParseResult<SqlStmt> {
+    value(
+        SqlStmt::ShowTableStatus,
+        (
+            kw_preceded("SHOW"),
+            kw_preceded("TABLE"),
+            tag_no_case("STATUS"),
+        ),
+    )
+    .parse(input)
+}
+
 fn describe(input: &str) -> ParseResult<SqlStmt> {
     map(
         preceded(alt((kw_preceded("DESCRIBE"), kw_preceded("DESC"))), ident),
@@ -321,28 +1122,105 @@ fn describe(input: &str) -> ParseResult<SqlStmt> {
     .parse(input)
 }
 
-fn create_table(input: &str) -> ParseResult<SqlStmt> {
+fn check_table(input: &str) -> ParseResult<SqlStmt> {
     map(
+        preceded((kw_preceded("CHECK"), kw_preceded("TABLE")), ident),
+        |table| SqlStmt::CheckTable { table },
+    )
+    .parse(input)
+}
+
+fn create_table(input: &str) -> ParseResult<SqlStmt> {
+    map_opt(
         preceded(
             (kw_preceded("CREATE"), kw_preceded("TABLE")),
             (
+                opt((kw_preceded("IF"), kw_preceded("NOT"), kw_preceded("EXISTS"))),
                 ident,
                 delimited(
                     (multispace0, tag("("), multispace0),
                     comma_list1(col_def),
                     (multispace0, tag(")")),
                 ),
+                opt(preceded(
+                    (
+                        multispace0,
+                        tag_no_case("COMMENT"),
+                        multispace0,
+                        opt((tag("="), multispace0)),
+                    ),
+                    text,
+                )),
             ),
         ),
-        |(table, columns)| SqlStmt::CreateTable { table, columns },
+        |(if_not_exists, table, columns, comment)| {
+            let mut auto_increment = columns
+                .iter()
+                .filter(|(_, auto_increment, _)| *auto_increment)
+                .map(|((column, _), _, _)| column.name.clone());
+            let first = auto_increment.next();
+            // at most one column is AUTO_INCREMENT
+            auto_increment.next().is_none().then(|| {
+                let (columns, column_comments) = columns
+                    .into_iter()
+                    .map(|(column, _, comment)| (column, comment))
+                    .unzip();
+                SqlStmt::CreateTable {
+                    table,
+                    columns,
+                    auto_increment: first,
+                    if_not_exists: if_not_exists.is_some(),
+                    comments: TableComments {
+                        table: comment.unwrap_or_default(),
+                        columns: column_comments,
+                    },
+                }
+            })
+        },
     )
     .parse(input)
 }
 
 fn drop_table(input: &str) -> ParseResult<SqlStmt> {
     map(
-        preceded((kw_preceded("DROP"), kw_preceded("TABLE")), ident),
-        |table| SqlStmt::DropTable { table },
+        preceded(
+            (kw_preceded("DROP"), kw_preceded("TABLE")),
+            (opt((kw_preceded("IF"), kw_preceded("EXISTS"))), ident),
+        ),
+        |(if_exists, table)| SqlStmt::DropTable {
+            table,
+            if_exists: if_exists.is_some(),
+        },
+    )
+    .parse(input)
+}
+
+fn index_type(input: &str) -> ParseResult<IndexType> {
+    alt((
+        value(IndexType::BTree, tag_no_case("BTREE")),
+        value(IndexType::Hash, tag_no_case("HASH")),
+    ))
+    .parse(input)
+}
+
+fn create_index(input: &str) -> ParseResult<SqlStmt> {
+    map(
+        preceded(
+            kw_preceded("CREATE"),
+            (
+                opt(kw_preceded("UNIQUE")),
+                preceded(kw_preceded("INDEX"), terminated(ident, kw("ON"))),
+                ident,
+                preceded(multispace0, paren(ident)),
+                opt(preceded(kw("USING"), index_type)),
+            ),
+        ),
+        |(unique, _index, table, column, type_)| SqlStmt::CreateIndex {
+            table,
+            column,
+            type_: type_.unwrap_or(IndexType::BTree),
+            unique: unique.is_some(),
+        },
     )
     .parse(input)
 }
@@ -356,31 +1234,53 @@ fn decimal(input: &str) -> ParseResult<&str> {
 }
 
 fn real(input: &str) -> ParseResult<f64> {
-    map_res(
-        alt((
-            // Case one: .42
-            recognize((
-                tag("."),
-                decimal,
-                opt((one_of("eE"), opt(one_of("+-")), decimal)),
-            )), // Case two: 42e42 and 42.42e42
-            recognize((
-                decimal,
-                opt(preceded(tag("."), decimal)),
-                one_of("eE"),
-                opt(one_of("+-")),
-                decimal,
-            )), // Case three: 42. and 42.42
-            recognize((decimal, tag("."), opt(decimal))),
-        )),
-        |s| s.parse(),
-    )
-    .parse(input)
+    map_opt(
+        recognize((
+            // negative like integers
+            opt(tag("-")),
+            alt((
+                // Case one: .42
+                recognize((
+                    tag("."),
+                    decimal,
+                    opt((one_of("eE"), opt(one_of("+-")), decimal)),
+                )), // Case two: 42e42 and 42.42e42
+                recognize((
+                    decimal,
+                    opt(preceded(tag("."), decimal)),
+                    one_of("eE"),
+                    opt(one_of("+-")),
+                    decimal,
+                )), // Case three: 42. and 42.42
+                recognize((decimal, tag("."), opt(decimal))),
+            )),
+        )),
+        // e.g. 1e999 overflows
+        |s: &str| s.parse().ok().filter(|f: &f64| f.is_finite()),
+    )
+    .parse(input)
 }
 
 fn text(input: &str) -> ParseResult<String> {
+    alt((quoted("'", "\\'"), quoted("\"", "\\\""))).parse(input)
+}
+
+/// String literal of exactly one character.
+fn escape_char(input: &str) -> ParseResult<char> {
+    map_opt(text, |s| {
+        let mut chars = s.chars();
+        chars.next().filter(|_| chars.next().is_none())
+    })
+    .parse(input)
+}
+
+/// String literal between `quote`s, `excluded` are the quote and the backslash.
+fn quoted<'a>(
+    quote: &'static str,
+    excluded: &'static str,
+) -> impl Parser<&'a str, Output = String, Error = SqlParseError<'a>> {
     delimited(
-        tag("'"),
+        tag(quote),
         fold_many0(
             alt((
                 preceded(
@@ -400,7 +1300,7 @@ fn text(input: &str) -> ParseResult<String> {
                         }),
                     )),
                 ),
-                none_of("\\'"),
+                none_of(excluded),
             )),
             String::new,
             |mut s, c| {
@@ -408,7 +1308,15 @@ fn text(input: &str) -> ParseResult<String> {
                 s
             },
         ),
-        tag("'"),
+        tag(quote),
+    )
+}
+
+/// Hex literal of a BLOB, e.g. `x'DEADBEEF'`.
+fn blob(input: &str) -> ParseResult<Vec<u8>> {
+    map_opt(
+        preceded(one_of("xX"), delimited(tag("'"), hex_digit0, tag("'"))),
+        from_hex,
     )
     .parse(input)
 }
@@ -416,6 +1324,8 @@ fn text(input: &str) -> ParseResult<String> {
 fn const_(input: &str) -> ParseResult<Value> {
     alt((
         value(Value::Null, tag_no_case("NULL")),
+        value(Value::Placeholder(0), tag("?")),
+        map(blob, Value::Blob),
         map(text, Value::Text),
         map(real, Value::Real),
         map(integer, Value::Integer),
@@ -427,6 +1337,21 @@ fn values(input: &str) -> ParseResult<Vec<Vec<Value>>> {
     comma_list1(paren(comma_list1(const_))).parse(input)
 }
 
+fn reindex(input: &str) -> ParseResult<SqlStmt> {
+    map(
+        alt((
+            preceded(kw_preceded("REINDEX"), ident),
+            delimited(
+                (kw_preceded("ALTER"), kw_preceded("TABLE")),
+                ident,
+                (kw("REBUILD"), tag_no_case("INDEX")),
+            ),
+        )),
+        |table| SqlStmt::Reindex { table },
+    )
+    .parse(input)
+}
+
 fn insert_into(input: &str) -> ParseResult<SqlStmt> {
     map(
         preceded(
@@ -435,9 +1360,38 @@ fn insert_into(input: &str) -> ParseResult<SqlStmt> {
                 ident,
                 opt(preceded(multispace0, paren(comma_list1(ident)))),
                 preceded(kw("VALUES"), values),
+                opt(preceded(
+                    (
+                        kw("ON"),
+                        kw_preceded("DUPLICATE"),
+                        kw_preceded("KEY"),
+                        kw_preceded("UPDATE"),
+                    ),
+                    assignments,
+                )),
             ),
         ),
-        |(table, columns, values)| SqlStmt::InsertInto {
+        |(table, columns, values, on_duplicate)| SqlStmt::InsertInto {
+            table,
+            columns: columns.unwrap_or_default(),
+            values,
+            on_duplicate: on_duplicate.unwrap_or_default(),
+        },
+    )
+    .parse(input)
+}
+
+fn replace_into(input: &str) -> ParseResult<SqlStmt> {
+    map(
+        preceded(
+            (kw_preceded("REPLACE"), kw_preceded("INTO")),
+            (
+                ident,
+                opt(preceded(multispace0, paren(comma_list1(ident)))),
+                preceded(kw("VALUES"), values),
+            ),
+        ),
+        |(table, columns, values)| SqlStmt::ReplaceInto {
             table,
             columns: columns.unwrap_or_default(),
             values,
@@ -469,6 +1423,13 @@ fn join_on(input: &str) -> ParseResult<(String, SqlOn)> {
 
 fn col_or_const(input: &str) -> ParseResult<SqlColOrExpr> {
     alt((
+        // a keyword and not a column, unlike e.g. `nullable`
+        value(
+            SqlColOrExpr::Const(Value::Null),
+            terminated(tag_no_case("NULL"), not(alt((alphanumeric1, tag("_"))))),
+        ),
+        // not the column x
+        map(blob, |b| SqlColOrExpr::Const(Value::Blob(b))),
         map(col, SqlColOrExpr::Column),
         map(const_, SqlColOrExpr::Const),
     ))
@@ -480,25 +1441,76 @@ fn where_rel(input: &str) -> ParseResult<SqlRel> {
         map(
             (
                 col_or_const,
-                delimited(multispace0, alt((tag("="), tag("<="))), multispace0),
+                delimited(
+                    multispace0,
+                    alt((
+                        tag("="),
+                        tag("<=>"),
+                        tag("<="),
+                        tag(">="),
+                        tag("!="),
+                        tag("<>"),
+                        tag("<"),
+                        tag(">"),
+                    )),
+                    multispace0,
+                ),
                 col_or_const,
             ),
             |(lhs, op, rhs)| match op {
                 "=" => SqlRel::Eq { lhs, rhs },
+                "<=>" => SqlRel::NullSafeEq { lhs, rhs },
+                "!=" | "<>" => SqlRel::Ne { lhs, rhs },
                 "<=" => SqlRel::Le { lhs, rhs },
+                ">=" => SqlRel::Le { lhs: rhs, rhs: lhs },
+                "<" => SqlRel::Lt { lhs, rhs },
+                ">" => SqlRel::Lt { lhs: rhs, rhs: lhs },
                 _ => unreachable!(),
             },
         ),
-        map(separated_pair(col, kw("LIKE"), text), |(lhs, rhs)| {
-            SqlRel::Like { lhs, rhs }
-        }),
+        map(
+            (
+                separated_pair(col, kw("LIKE"), text),
+                opt(preceded(kw_preceded("ESCAPE"), escape_char)),
+            ),
+            |((lhs, rhs), escape)| SqlRel::Like {
+                lhs,
+                rhs,
+                escape: escape.unwrap_or('\\'),
+            },
+        ),
+        map(
+            separated_pair(
+                col,
+                delimited(multispace1, tag_no_case("IN"), multispace0),
+                paren(alt((
+                    map(union, |stmt| SqlIn::Select(Box::new(stmt))),
+                    map(comma_list1(const_), SqlIn::List),
+                ))),
+            ),
+            |(lhs, rhs)| SqlRel::In { lhs, rhs },
+        ),
+        map(
+            (
+                col,
+                preceded(
+                    (multispace1, tag_no_case("IS"), multispace1),
+                    opt(terminated(tag_no_case("NOT"), multispace1)),
+                ),
+                terminated(tag_no_case("NULL"), not(alt((alphanumeric1, tag("_"))))),
+            ),
+            |(lhs, not, _)| SqlRel::IsNull {
+                lhs,
+                negated: not.is_some(),
+            },
+        ),
     ))
     .parse(input)
 }
 
 fn where_clause(input: &str) -> ParseResult<SqlWhere> {
     precedence(
-        unary_op(1, kw("NOT")),
+        unary_op(1, delimited(multispace0, tag_no_case("NOT"), multispace1)),
         fail(),
         alt((
             binary_op(2, Assoc::Left, kw("AND")),
@@ -528,18 +1540,186 @@ fn where_(input: &str) -> ParseResult<SqlWhere> {
     preceded(kw("WHERE"), where_clause).parse(input)
 }
 
+fn cmp_op(input: &str) -> ParseResult<SqlCmpOp> {
+    alt((
+        value(SqlCmpOp::Le, tag("<=")),
+        value(SqlCmpOp::Ge, tag(">=")),
+        value(SqlCmpOp::Ne, tag("<>")),
+        value(SqlCmpOp::Ne, tag("!=")),
+        value(SqlCmpOp::Eq, tag("=")),
+        value(SqlCmpOp::Lt, tag("<")),
+        value(SqlCmpOp::Gt, tag(">")),
+    ))
+    .parse(input)
+}
+
+fn condition(input: &str) -> ParseResult<SqlCondition> {
+    precedence(
+        unary_op(1, kw("NOT")),
+        fail(),
+        alt((
+            binary_op(2, Assoc::Left, kw("AND")),
+            binary_op(3, Assoc::Left, kw("OR")),
+        )),
+        alt((
+            map(
+                (expr, delimited(multispace0, cmp_op, multispace0), expr),
+                |(lhs, op, rhs)| SqlCondition::Cmp { op, lhs, rhs },
+            ),
+            delimited(tag("("), condition, tag(")")),
+        )),
+        |op: Operation<&str, &str, &str, SqlCondition>| -> Result<SqlCondition> {
+            use nom_language::precedence::Operation::*;
+            match op {
+                Prefix(_, condition) => Ok(SqlCondition::Not(Box::new(condition))),
+                Binary(lhs, op, rhs) => match op.to_uppercase().as_str() {
+                    "AND" => Ok(SqlCondition::And(Box::new(lhs), Box::new(rhs))),
+                    "OR" => Ok(SqlCondition::Or(Box::new(lhs), Box::new(rhs))),
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            }
+        },
+    )
+    .parse(input)
+}
+
+fn group_by(input: &str) -> ParseResult<SqlGroupBy> {
+    map(
+        (
+            preceded(
+                (kw("GROUP"), tag_no_case("BY"), multispace1),
+                comma_list1(col),
+            ),
+            opt(preceded(kw("HAVING"), condition)),
+        ),
+        |(columns, having)| SqlGroupBy { columns, having },
+    )
+    .parse(input)
+}
+
+fn order_by(input: &str) -> ParseResult<Vec<SqlOrderBy>> {
+    preceded(
+        (kw("ORDER"), tag_no_case("BY"), multispace1),
+        comma_list1(map(
+            (
+                expr,
+                opt(preceded(
+                    multispace1,
+                    terminated(
+                        alt((
+                            value(false, tag_no_case("ASC")),
+                            value(true, tag_no_case("DESC")),
+                        )),
+                        not(alt((alphanumeric1, tag("_")))),
+                    ),
+                )),
+            ),
+            |(expr, desc)| SqlOrderBy {
+                expr,
+                desc: desc.unwrap_or(false),
+            },
+        )),
+    )
+    .parse(input)
+}
+
 fn limit(input: &str) -> ParseResult<u64> {
     preceded(kw("LIMIT"), nom::character::complete::u64).parse(input)
 }
 
+/// Locking reads are accepted and ignored, statements run one at a time anyway.
+fn locking_read(input: &str) -> ParseResult<()> {
+    value(
+        (),
+        alt((
+            (kw("FOR"), tag_no_case("UPDATE")),
+            (
+                kw("LOCK"),
+                preceded(
+                    (kw_preceded("IN"), kw_preceded("SHARE")),
+                    tag_no_case("MODE"),
+                ),
+            ),
+        )),
+    )
+    .parse(input)
+}
+
+fn expr(input: &str) -> ParseResult<SqlExpr> {
+    let op = |ops: &'static str| delimited(multispace0, one_of(ops), multispace0);
+    precedence(
+        fail(),
+        fail(),
+        alt((
+            binary_op(1, Assoc::Left, op("*/")),
+            binary_op(2, Assoc::Left, op("+-")),
+        )),
+        alt((
+            map(
+                (
+                    ident,
+                    preceded(
+                        multispace0,
+                        delimited(
+                            (tag("("), multispace0),
+                            alt((
+                                // COUNT(*)
+                                value(vec![], tag("*")),
+                                separated_list0((multispace0, tag(","), multispace0), expr),
+                            )),
+                            (multispace0, tag(")")),
+                        ),
+                    ),
+                ),
+                |(function, args)| SqlExpr::Call {
+                    function: function.to_uppercase(),
+                    args,
+                },
+            ),
+            // NULL is not a column
+            value(
+                SqlExpr::Const(Value::Null),
+                terminated(tag_no_case("NULL"), not(alt((alphanumeric1, tag("_"))))),
+            ),
+            map(blob, |b| SqlExpr::Const(Value::Blob(b))),
+            map(col, SqlExpr::Column),
+            map(const_, SqlExpr::Const),
+            paren(expr),
+        )),
+        |op: Operation<char, char, char, SqlExpr>| -> Result<SqlExpr> {
+            use nom_language::precedence::Operation::*;
+            match op {
+                Binary(lhs, op, rhs) => Ok(SqlExpr::Binary {
+                    op: match op {
+                        '+' => SqlBinaryOp::Add,
+                        '-' => SqlBinaryOp::Sub,
+                        '*' => SqlBinaryOp::Mul,
+                        '/' => SqlBinaryOp::Div,
+                        _ => unreachable!(),
+                    },
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                }),
+                _ => unreachable!(),
+            }
+        },
+    )
+    .parse(input)
+}
+
 fn select_target(input: &str) -> ParseResult<SqlSelectTarget> {
     alt((
-        map(col, SqlSelectTarget::Column),
-        map(const_, SqlSelectTarget::Const),
-        value(SqlSelectTarget::Wildcard, tag("*")),
-        map(recognize((alt((tag("@@"), tag("@"))), ident)), |variable| {
-            SqlSelectTarget::Variable(variable.to_owned())
+        map(expr, |expr| match expr {
+            SqlExpr::Column(column) => SqlSelectTarget::Column(column),
+            SqlExpr::Const(value) => SqlSelectTarget::Const(value),
+            expr => SqlSelectTarget::Expr(expr),
         }),
+        value(SqlSelectTarget::Wildcard, tag("*")),
+        map(
+            recognize((alt((tag("@@"), tag("@"))), opt((ident, tag("."))), ident)),
+            |variable| SqlSelectTarget::Variable(variable.to_owned()),
+        ),
     ))
     .parse(input)
 }
@@ -553,40 +1733,89 @@ fn select(input: &str) -> ParseResult<SqlStmt> {
                 opt(from),
                 many0(join_on),
                 opt(where_),
+                opt(group_by),
+                opt(order_by),
                 opt(limit),
+                opt(locking_read),
             ),
         ),
-        |(columns, table, join_on, where_, limit)| SqlStmt::Select {
+        |(columns, table, join_on, where_, group_by, order_by, limit, _)| SqlStmt::Select {
             columns,
             table,
             join_on,
             where_,
+            group_by,
+            order_by: order_by.unwrap_or_default(),
             limit: limit.map(|limit| limit as usize),
         },
     )
     .parse(input)
 }
 
+fn union(input: &str) -> ParseResult<SqlStmt> {
+    map(
+        (
+            select,
+            many0((
+                preceded(
+                    kw("UNION"),
+                    opt(terminated(tag_no_case("ALL"), multispace1)),
+                ),
+                select,
+            )),
+        ),
+        |(first, rest)| {
+            rest.into_iter()
+                .fold(first, |left, (all, right)| SqlStmt::Union {
+                    left: Box::new(left),
+                    right: Box::new(right),
+                    all: all.is_some(),
+                })
+        },
+    )
+    .parse(input)
+}
+
 fn explain(input: &str) -> ParseResult<SqlStmt> {
-    map(preceded(kw_preceded("EXPLAIN"), select), |stmt| {
-        let SqlStmt::Select {
-            columns,
-            table,
-            join_on,
-            where_,
-            limit,
-        } = stmt
-        else {
-            unreachable!()
-        };
-        SqlStmt::Explain {
-            columns,
-            table,
-            join_on,
-            where_,
-            limit,
-        }
-    })
+    map(
+        preceded(
+            kw_preceded("EXPLAIN"),
+            (opt(kw_preceded("ANALYZE")), select),
+        ),
+        |(analyze, stmt)| {
+            let SqlStmt::Select {
+                columns,
+                table,
+                join_on,
+                where_,
+                group_by,
+                order_by,
+                limit,
+            } = stmt
+            else {
+                unreachable!()
+            };
+            SqlStmt::Explain {
+                columns,
+                table,
+                join_on,
+                where_,
+                group_by,
+                order_by,
+                limit,
+                analyze: analyze.is_some(),
+            }
+        },
+    )
+    .parse(input)
+}
+
+fn assignments(input: &str) -> ParseResult<Vec<(SqlCol, Value)>> {
+    comma_list1(separated_pair(
+        col,
+        (multispace0, tag("="), multispace0),
+        const_,
+    ))
     .parse(input)
 }
 
@@ -594,18 +1823,7 @@ fn update(input: &str) -> ParseResult<SqlStmt> {
     map(
         preceded(
             kw_preceded("UPDATE"),
-            separated_pair(
-                ident,
-                kw("SET"),
-                (
-                    comma_list1(separated_pair(
-                        col,
-                        (multispace0, tag("="), multispace0),
-                        const_,
-                    )),
-                    opt(where_),
-                ),
-            ),
+            separated_pair(ident, kw("SET"), (assignments, opt(where_))),
         ),
         |(table, (set, where_))| SqlStmt::Update { table, set, where_ },
     )
@@ -644,6 +1862,37 @@ fn rollback(input: &str) -> ParseResult<SqlStmt> {
     value(SqlStmt::Rollback, tag_no_case("ROLLBACK")).parse(input)
 }
 
+fn rollback_to(input: &str) -> ParseResult<SqlStmt> {
+    map(
+        preceded(
+            (
+                tag_no_case("ROLLBACK"),
+                kw("TO"),
+                opt(terminated(tag_no_case("SAVEPOINT"), multispace1)),
+            ),
+            ident,
+        ),
+        |name| SqlStmt::RollbackTo { name },
+    )
+    .parse(input)
+}
+
+fn savepoint(input: &str) -> ParseResult<SqlStmt> {
+    map(
+        preceded((tag_no_case("SAVEPOINT"), multispace1), ident),
+        |name| SqlStmt::Savepoint { name },
+    )
+    .parse(input)
+}
+
+fn release(input: &str) -> ParseResult<SqlStmt> {
+    map(
+        preceded((tag_no_case("RELEASE"), kw("SAVEPOINT")), ident),
+        |name| SqlStmt::Release { name },
+    )
+    .parse(input)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -682,4 +1931,215 @@ mod test {
             r#"Select { columns: [Column(Full { table: "students", column: "name" }), Column(Full { table: "classes", column: "class" })], table: Some("students"), join_on: [("classes", SqlOn { lhs: Full { table: "students", column: "id" }, rhs: Full { table: "classes", column: "student_id" } })], where_: Some(Rel(Like { lhs: Full { table: "students", column: "name" }, rhs: "张%" })) }"#
         );
     }
+
+    fn syntax_error(input: &str) -> SyntaxError {
+        Aidb::parse(input)
+            .unwrap_err()
+            .downcast::<SyntaxError>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let e = syntax_error("SELECT id FROM students garbage;");
+        assert_eq!(e.offset, 24);
+        assert_eq!(e.near, "garbage;");
+        let e = syntax_error("SELECT id FROM students; SELECT 1;");
+        assert_eq!(e.offset, 25);
+        let e = syntax_error("INSERT INTO students VALUES (1, );");
+        assert_eq!(e.offset, 32);
+        assert_eq!(e.to_string(), r#"invalid SQL at 32: near ");""#);
+        let e = syntax_error("SELECT id FROM students WHERE id =");
+        assert_eq!(e.offset, 34);
+        assert_eq!(e.near, "");
+        assert_eq!(
+            syntax_error("").to_string(),
+            "invalid SQL at 0: unexpected end of input"
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        for (sql, message) in [
+            (
+                "WITH t2 AS (SELECT * FROM t) SELECT * FROM t2;",
+                "WITH/CTE is not supported",
+            ),
+            (
+                "SELECT id, ROW_NUMBER() OVER (ORDER BY id) FROM t;",
+                "window function is not supported",
+            ),
+            (
+                "CREATE VIEW v AS SELECT * FROM t;",
+                "CREATE VIEW is not supported",
+            ),
+            (
+                "create or replace view v as select * from t",
+                "CREATE VIEW is not supported",
+            ),
+            (
+                "ALTER TABLE t ADD c INTEGER;",
+                "ALTER TABLE is not supported",
+            ),
+            (
+                "SELECT id FROM t INTERSECT SELECT id FROM u;",
+                "INTERSECT is not supported",
+            ),
+        ] {
+            let e = Aidb::parse(sql)
+                .unwrap_err()
+                .downcast::<Unsupported>()
+                .unwrap();
+            assert_eq!(e.to_string(), message, "{sql}");
+        }
+        // keywords inside literals or valid statements are left alone
+        syntax_error("SELECT 'WITH' FROM t garbage;");
+        syntax_error("SELECT * FROM t WHERE s = ' OVER (' garbage;");
+        assert!(Aidb::parse("SELECT over FROM t;").is_ok());
+    }
+
+    #[test]
+    fn test_sql_literal() {
+        // xorshift64, deterministic so failures reproduce
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let chars = [
+            '\'', '"', '\\', '\n', '\r', '\t', '\0', '\x7f', 'a', ' ', 'é', '字', '🦀',
+        ];
+        let mut values = vec![
+            Value::Integer(i64::MIN),
+            Value::Integer(i64::MAX),
+            Value::Real(-0.0),
+            Value::Real(f64::MAX),
+            Value::Real(f64::MIN_POSITIVE),
+            Value::Text("".to_owned()),
+        ];
+        for _ in 0..1000 {
+            let len = next() % 16;
+            values.push(match next() % 5 {
+                0 => Value::Null,
+                1 => Value::Integer(next() as i64),
+                2 => match f64::from_bits(next()) {
+                    f if f.is_finite() => Value::Real(f),
+                    _ => Value::Real(next() as i64 as f64 / 1024.0),
+                },
+                3 => Value::Text(
+                    (0..len)
+                        .map(|_| match next() % 4 {
+                            0 => char::from_u32(next() as u32 % 0x11_0000).unwrap_or('?'),
+                            _ => chars[next() as usize % chars.len()],
+                        })
+                        .collect(),
+                ),
+                _ => Value::Blob((0..len).map(|_| next() as u8).collect()),
+            });
+        }
+        for value in values {
+            let literal = value.to_sql_literal();
+            let (rest, parsed) = const_(&literal).unwrap_or_else(|e| panic!("{literal}: {e}"));
+            assert_eq!(rest, "", "{literal}");
+            assert_eq!(parsed, value, "{literal}");
+            // tells -0.0 from 0.0
+            assert_eq!(parsed.to_sql_literal(), literal);
+        }
+    }
+
+    #[test]
+    fn test_locking_read() {
+        let plain = format!(
+            "{:?}",
+            Aidb::parse("SELECT * FROM t WHERE id = 1 LIMIT 2;").unwrap()
+        );
+        for sql in [
+            "SELECT * FROM t WHERE id = 1 LIMIT 2 FOR UPDATE;",
+            "select * from t where id = 1 limit 2 for update",
+            "SELECT * FROM t WHERE id = 1 LIMIT 2 LOCK IN SHARE MODE;",
+        ] {
+            assert_eq!(format!("{:?}", Aidb::parse(sql).unwrap()), plain, "{sql}");
+        }
+        assert!(Aidb::parse("SELECT * FROM t FOR;").is_err());
+        assert!(Aidb::parse("SELECT * FROM t LOCK IN SHARE;").is_err());
+    }
+
+    #[test]
+    fn test_explain_analyze() {
+        for (sql, expected) in [
+            ("EXPLAIN SELECT * FROM t;", false),
+            ("EXPLAIN ANALYZE SELECT * FROM t;", true),
+            ("explain analyze select * from t", true),
+        ] {
+            let SqlStmt::Explain { analyze, .. } = Aidb::parse(sql).unwrap() else {
+                panic!("EXPLAIN expected");
+            };
+            assert_eq!(analyze, expected, "{sql}");
+        }
+        assert!(Aidb::parse("EXPLAIN ANALYZE;").is_err());
+    }
+
+    #[test]
+    fn test_split_statements() {
+        let script = "-- setup\nCREATE TABLE t (s TEXT);\n\nINSERT INTO t VALUES ('a;b'), (\"it\\\"s;\"); # done\n;\nSELECT * FROM t";
+        assert_eq!(
+            split_statements(script),
+            [
+                "CREATE TABLE t (s TEXT)",
+                "INSERT INTO t VALUES ('a;b'), (\"it\\\"s;\")",
+                "SELECT * FROM t",
+            ]
+        );
+        for stmt in split_statements(script) {
+            assert!(Aidb::parse(&stmt).is_ok(), "{stmt}");
+        }
+        assert!(split_statements(" ;\n-- only a comment; really").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE students (id INTEGER, name TEXT);")
+            .await
+            .unwrap();
+        assert!(aidb.validate("SELECT id FROM students;").await.is_ok());
+        assert!(
+            aidb.validate("SELECT id FROM students WHERE")
+                .await
+                .is_err()
+        );
+        assert!(
+            aidb.validate("SELECT id FROM students WHERE id = ?")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_complete() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE students (id INTEGER, name TEXT);")
+            .await
+            .unwrap();
+        aidb.query("CREATE TABLE classes (student_id INTEGER, class TEXT);")
+            .await
+            .unwrap();
+        assert_eq!(aidb.complete("SELECT name FROM stu").await, "students");
+        assert_eq!(aidb.complete("SELECT name FROM").await, "students");
+        assert_eq!(aidb.complete("DESC cl").await, "classes");
+        assert_eq!(
+            aidb.complete("SELECT * FROM students WHERE na").await,
+            "name"
+        );
+        assert_eq!(
+            aidb.complete("SELECT * FROM classes WHERE cl").await,
+            "class"
+        );
+        assert_eq!(aidb.complete("SELECT stu").await, "student_id");
+        assert_eq!(aidb.complete("SELECT students.n").await, "students.name");
+        assert_eq!(aidb.complete("SELECT name FROM students").await, "WHERE");
+        assert_eq!(aidb.complete("SELECT name FROM missing").await, "WHERE");
+    }
 }
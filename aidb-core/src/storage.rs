@@ -3,14 +3,18 @@ use std::{
     fmt::{Display, Formatter},
     io::Cursor,
     mem::swap,
+    num::NonZeroU64,
+    str::FromStr,
+    time::Duration,
 };
 
-use binrw::{BinWrite, binrw};
-use eyre::Result;
+use binrw::binrw;
+use eyre::{Report, Result, eyre};
+use opendal::{Operator, layers::RetryLayer};
 use serde::{Deserialize, Serialize};
 use tracing::{error, warn};
 
-use crate::Aidb;
+use crate::{Aidb, superblock::SUPERBLOCK_MIRROR};
 
 pub type BlockIndex = u64;
 pub type BlockOffset = u16;
@@ -18,9 +22,23 @@ pub type BlockOffset = u16;
 /// must be less than 64K (limited by u16)
 pub const BLOCK_SIZE: usize = 64 * 1024;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Block(Box<[u8; BLOCK_SIZE]>);
 
+/// Retry reads and writes failing with temporary errors, like timeouts and 503s of object
+/// stores, up to `max_retries` times with exponential backoff. Permanent errors fail at once,
+/// notably `NotFound` which tells a new database when loading the superblock.
+pub fn with_retry(op: Operator, max_retries: usize) -> Operator {
+    op.layer(
+        RetryLayer::new()
+            .with_max_times(max_retries)
+            .with_jitter()
+            .with_notify(|e: &opendal::Error, delay: Duration| {
+                warn!("retrying in {delay:?} after {e}");
+            }),
+    )
+}
+
 impl Block {
     pub(crate) fn cursor(&mut self) -> Cursor<&mut [u8]> {
         Cursor::new(self.0.as_mut_slice())
@@ -33,9 +51,48 @@ impl Block {
     }
 }
 
+/// Pointer to the head of a chain or tree of blocks, which may be none. Stored as the index of
+/// the block with 0 for none, which is no confusion on disk as nothing points at the superblock.
 #[binrw]
 #[brw(little)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct BlockPtr(
+    #[br(map = NonZeroU64::new)]
+    #[bw(map = |ptr: &Option<NonZeroU64>| ptr.map_or(0, NonZeroU64::get))]
+    Option<NonZeroU64>,
+);
+
+impl BlockPtr {
+    pub const NONE: BlockPtr = BlockPtr(None);
+
+    /// Pointer to a block other than the superblock.
+    pub fn to(index: BlockIndex) -> Self {
+        Self(Some(
+            NonZeroU64::new(index).expect("the superblock is never pointed at"),
+        ))
+    }
+
+    pub fn get(self) -> Option<BlockIndex> {
+        self.0.map(NonZeroU64::get)
+    }
+
+    pub fn is_some(self) -> bool {
+        self.0.is_some()
+    }
+}
+
+impl Display for BlockPtr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.get() {
+            Some(index) => write!(f, "{index}"),
+            None => write!(f, "none"),
+        }
+    }
+}
+
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DataPointer {
     pub block: BlockIndex,
     pub offset: BlockOffset,
@@ -47,10 +104,71 @@ impl Display for DataPointer {
     }
 }
 
+/// Where blocks are stored under the operator root, chosen when a database is created and kept
+/// in its superblock.
+#[binrw]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[brw(little, repr = u8)]
+pub enum Layout {
+    /// every block at `<index>`
+    #[default]
+    Flat = 0,
+    /// blocks at `block/<ab>/<cd>/<index>` with the lowest two bytes of the index in hex, so no
+    /// directory holds more than 256 entries per 64K blocks, the superblock stays at `0`
+    Sharded = 1,
+}
+
+impl Layout {
+    pub(crate) fn path(&self, index: BlockIndex) -> String {
+        match self {
+            _ if index == SUPERBLOCK_MIRROR => "0.mirror".to_owned(),
+            Layout::Sharded if index != 0 => format!(
+                "block/{:02x}/{:02x}/{index}",
+                index & 0xff,
+                (index >> 8) & 0xff
+            ),
+            _ => index.to_string(),
+        }
+    }
+}
+
+impl FromStr for Layout {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "flat" => Ok(Layout::Flat),
+            "sharded" => Ok(Layout::Sharded),
+            _ => Err(eyre!("unknown layout {s}")),
+        }
+    }
+}
+
+/// Role of a block in the database, see [`Aidb::block_types`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BlockType {
+    SuperBlock,
+    Schema,
+    Data,
+    BTree,
+    Hash,
+    /// list of rows whose indexed column is NULL
+    NullList,
+    Text,
+    /// chain of free text extents
+    TextFreeMap,
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct BlockIoLog {
     pub read: HashSet<BlockIndex>,
     pub written: HashSet<BlockIndex>,
+    /// number of block lookups, including those served by cache
+    pub lookups: usize,
+    /// number of physical block reads, including repeated reads of the same block
+    pub reads: usize,
+    /// number of physical block writes, including repeated writes of the same block
+    pub writes: usize,
 }
 
 impl Aidb {
@@ -62,28 +180,41 @@ impl Aidb {
     }
 
     pub(crate) async fn get_block(self: &mut Aidb, index: BlockIndex) -> Result<Block> {
+        self.log.lookups += 1;
         if let Some(b) = self.blocks.remove(&index) {
+            // the caller may change a block dirtied by earlier statements of the transaction
+            if self.blocks_dirty.contains(&index)
+                && let Some(undo) = &mut self.statement_undo
+            {
+                undo.keep(index, || b.clone());
+            }
             return Ok(b);
         }
         Ok(self.read_physical(index).await?)
     }
 
     pub(crate) fn put_block(self: &mut Aidb, index: BlockIndex, block: Block) {
-        self.blocks.insert(index, block);
+        if let Some(b) = self.blocks.insert(index, block)
+            && self.blocks_dirty.contains(&index)
+            && let Some(undo) = &mut self.statement_undo
+        {
+            undo.keep(index, || b);
+        }
     }
 
     pub(crate) fn mark_block_dirty(self: &mut Aidb, index: BlockIndex) {
-        self.blocks_dirty.insert(index);
+        if self.blocks_dirty.insert(index)
+            && let Some(undo) = &mut self.statement_undo
+        {
+            undo.dirtied(index);
+        }
     }
 
     pub(crate) async fn submit(self: &mut Aidb) -> Result<()> {
         if self.transaction_in_progress {
             // sync dirty superblock and schemas, but don't write physical blocks
             if self.superblock_dirty {
-                let mut block = Self::new_volatile_block();
-                self.superblock.write(&mut block.cursor()).unwrap();
-                self.put_block(0, block);
-                self.mark_block_dirty(0);
+                self.stage_superblock();
             }
 
             for table in self.schemas_dirty.clone() {
@@ -92,12 +223,13 @@ impl Aidb {
                 self.put_schema(table, schema);
             }
         } else {
-            if self.superblock_dirty {
+            // every commit bumps the sequence of the superblock, which tells readers to refresh
+            if self.superblock_dirty
+                || !self.schemas_dirty.is_empty()
+                || !self.blocks_dirty.is_empty()
+            {
                 self.superblock_dirty = false;
-                let mut block = Self::new_volatile_block();
-                self.superblock.write(&mut block.cursor()).unwrap();
-                self.put_block(0, block);
-                self.mark_block_dirty(0);
+                self.stage_superblock();
             }
 
             let mut schemas_dirty = HashSet::new();
@@ -107,6 +239,9 @@ impl Aidb {
                 self.save_schema(&schema).await?;
                 self.put_schema(table, schema);
             }
+            if let Some(map) = &mut self.schema_map {
+                map.modified = false;
+            }
 
             let mut blocks_dirty = HashSet::new();
             swap(&mut self.blocks_dirty, &mut blocks_dirty);
@@ -120,21 +255,45 @@ impl Aidb {
         Ok(())
     }
 
+    /// Write everything committed so far to storage. Changes of a transaction in progress are
+    /// kept until COMMIT and never written by a flush.
+    ///
+    /// Every block write of OpenDAL closes the object it writes, so data is as durable as the
+    /// backend makes a closed object once this returns: `fs` and `monoiofs` fsync each block file,
+    /// object stores such as `s3` have persisted it, `memory` loses everything with the process.
+    /// Statements outside a transaction are flushed as they finish, this is for embedders that
+    /// want an explicit durability point, e.g. before exiting.
+    pub async fn flush(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.submit().await
+    }
+
     pub fn new_volatile_block() -> Block {
         Block(vec![0; BLOCK_SIZE].into_boxed_slice().try_into().unwrap())
     }
 
+    /// Read a block from storage, a stored object of the wrong size is an error unless
+    /// recovery mode pads or truncates it.
     pub async fn read_physical(&mut self, index: BlockIndex) -> opendal::Result<Block> {
-        let buffer = self.op.read(&index.to_string()).await?;
+        let buffer = self.op.read(&self.superblock.layout.path(index)).await?;
         let mut v = buffer.to_vec();
-        if v.len() < BLOCK_SIZE {
-            warn!("file size is smaller than block size, padding with zero");
-        } else if v.len() > BLOCK_SIZE {
-            error!("file size is larger than block size, truncating");
+        if v.len() != BLOCK_SIZE {
+            let message = format!("block {index} has {} bytes, expected {BLOCK_SIZE}", v.len());
+            if !self.recovery {
+                return Err(opendal::Error::new(opendal::ErrorKind::Unexpected, message));
+            }
+            if v.len() < BLOCK_SIZE {
+                warn!("{message}, padding with zero");
+            } else {
+                error!("{message}, truncating");
+            }
         }
         v.resize(BLOCK_SIZE, 0);
         let block = Block(v.into_boxed_slice().try_into().unwrap());
         self.log.read.insert(index);
+        self.log.reads += 1;
         Ok(block)
     }
 
@@ -143,11 +302,23 @@ impl Aidb {
         index: BlockIndex,
         block: &Block,
     ) -> opendal::Result<()> {
-        self.op.write(&index.to_string(), block.0.to_vec()).await?;
+        self.op
+            .write(&self.superblock.layout.path(index), block.0.to_vec())
+            .await?;
         self.log.written.insert(index);
+        self.log.writes += 1;
+        if let Some(archived) = &mut self.archived {
+            archived.insert(index);
+        }
         Ok(())
     }
 
+    /// Pad or truncate blocks of the wrong size instead of failing, to salvage a damaged database.
+    /// Use [`Aidb::from_op_recovery`] for a damaged superblock, which is read while opening.
+    pub fn set_recovery(&mut self, recovery: bool) {
+        self.recovery = recovery;
+    }
+
     pub(crate) fn reset_block_io_log(self: &mut Aidb) {
         self.log = BlockIoLog::default();
     }
@@ -156,3 +327,390 @@ impl Aidb {
         self.log.clone()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use opendal::{
+        ErrorKind,
+        raw::{
+            Access, Layer, LayeredAccess, OpList, OpRead, OpWrite, RpDelete, RpList, RpRead,
+            RpWrite,
+        },
+        services::MemoryConfig,
+    };
+
+    use binrw::{BinRead, BinWrite};
+
+    use super::*;
+    use crate::{Response, Value};
+
+    /// Fails the next `failures` reads with a temporary error, counting every read.
+    #[derive(Debug, Clone, Default)]
+    struct FlakyLayer {
+        failures: Arc<AtomicUsize>,
+        reads: Arc<AtomicUsize>,
+    }
+
+    #[derive(Debug)]
+    struct FlakyAccessor<A> {
+        inner: A,
+        layer: FlakyLayer,
+    }
+
+    impl<A: Access> Layer<A> for FlakyLayer {
+        type LayeredAccess = FlakyAccessor<A>;
+
+        fn layer(&self, inner: A) -> Self::LayeredAccess {
+            FlakyAccessor {
+                inner,
+                layer: self.clone(),
+            }
+        }
+    }
+
+    impl<A: Access> LayeredAccess for FlakyAccessor<A> {
+        type Inner = A;
+        type Reader = A::Reader;
+        type Writer = A::Writer;
+        type Lister = A::Lister;
+        type Deleter = A::Deleter;
+        type BlockingReader = A::BlockingReader;
+        type BlockingWriter = A::BlockingWriter;
+        type BlockingLister = A::BlockingLister;
+        type BlockingDeleter = A::BlockingDeleter;
+
+        fn inner(&self) -> &A {
+            &self.inner
+        }
+
+        async fn read(&self, path: &str, args: OpRead) -> opendal::Result<(RpRead, A::Reader)> {
+            self.layer.reads.fetch_add(1, Ordering::SeqCst);
+            let failures = self.layer.failures.load(Ordering::SeqCst);
+            if failures > 0 {
+                self.layer.failures.store(failures - 1, Ordering::SeqCst);
+                return Err(
+                    opendal::Error::new(ErrorKind::Unexpected, "service unavailable")
+                        .set_temporary(),
+                );
+            }
+            self.inner.read(path, args).await
+        }
+
+        async fn write(&self, path: &str, args: OpWrite) -> opendal::Result<(RpWrite, A::Writer)> {
+            self.inner.write(path, args).await
+        }
+
+        async fn delete(&self) -> opendal::Result<(RpDelete, A::Deleter)> {
+            self.inner.delete().await
+        }
+
+        async fn list(&self, path: &str, args: OpList) -> opendal::Result<(RpList, A::Lister)> {
+            self.inner.list(path, args).await
+        }
+
+        fn blocking_read(
+            &self,
+            path: &str,
+            args: OpRead,
+        ) -> opendal::Result<(RpRead, A::BlockingReader)> {
+            self.inner.blocking_read(path, args)
+        }
+
+        fn blocking_write(
+            &self,
+            path: &str,
+            args: OpWrite,
+        ) -> opendal::Result<(RpWrite, A::BlockingWriter)> {
+            self.inner.blocking_write(path, args)
+        }
+
+        fn blocking_delete(&self) -> opendal::Result<(RpDelete, A::BlockingDeleter)> {
+            self.inner.blocking_delete()
+        }
+
+        fn blocking_list(
+            &self,
+            path: &str,
+            args: OpList,
+        ) -> opendal::Result<(RpList, A::BlockingLister)> {
+            self.inner.blocking_list(path, args)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let flaky = FlakyLayer::default();
+        let op = Operator::from_config(MemoryConfig::default())
+            .unwrap()
+            .finish()
+            .layer(flaky.clone());
+        let op = with_retry(op, 3);
+        let mut aidb = Aidb::from_op(op.clone()).await.unwrap();
+        aidb.query("CREATE TABLE t (id INTEGER);").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (1);").await.unwrap();
+
+        // fails twice, then succeeds
+        flaky.failures.store(2, Ordering::SeqCst);
+        let reads = flaky.reads.load(Ordering::SeqCst);
+        let mut aidb = Aidb::from_op(op.clone()).await.unwrap();
+        assert_eq!(flaky.reads.load(Ordering::SeqCst), reads + 3);
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, [vec![Value::Integer(1)]]);
+
+        // missing blocks are not retried
+        let reads = flaky.reads.load(Ordering::SeqCst);
+        let e = aidb.read_physical(1000).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        assert_eq!(flaky.reads.load(Ordering::SeqCst), reads + 1);
+    }
+
+    #[test]
+    fn test_block_ptr() {
+        let mut bytes = vec![];
+        BlockPtr::NONE.write(&mut Cursor::new(&mut bytes)).unwrap();
+        assert_eq!(bytes, 0u64.to_le_bytes());
+        let ptr = BlockPtr::read(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(ptr, BlockPtr::NONE);
+        assert_eq!(ptr.get(), None);
+
+        let ptr = BlockPtr::read(&mut Cursor::new(7u64.to_le_bytes())).unwrap();
+        assert_eq!(ptr, BlockPtr::to(7));
+        assert_eq!(ptr.get(), Some(7));
+        assert_eq!(ptr.to_string(), "7");
+        assert_eq!(BlockPtr::NONE.to_string(), "none");
+    }
+
+    #[test]
+    #[should_panic(expected = "the superblock is never pointed at")]
+    fn test_block_ptr_to_superblock() {
+        BlockPtr::to(0);
+    }
+
+    #[tokio::test]
+    async fn test_null_block_ptrs() {
+        let op = Operator::from_config(MemoryConfig::default())
+            .unwrap()
+            .finish();
+        let mut aidb = Aidb::from_op(op.clone()).await.unwrap();
+        assert_eq!(aidb.superblock.first_schema_block, BlockPtr::NONE);
+        assert_eq!(aidb.superblock.next_text_block, BlockPtr::NONE);
+        assert_eq!(aidb.superblock.text_free_map, BlockPtr::NONE);
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, x INTEGER);")
+            .await
+            .unwrap();
+        aidb.query("CREATE INDEX tx ON t (x) USING HASH;")
+            .await
+            .unwrap();
+        aidb.flush().await.unwrap();
+
+        // pointers of the empty table stay none once stored
+        let mut aidb = Aidb::from_op(op).await.unwrap();
+        let schema = aidb.get_schema("t").await.unwrap();
+        assert_eq!(schema.data_block, BlockPtr::NONE);
+        assert_eq!(schema.insert_block, BlockPtr::NONE);
+        assert!(
+            schema
+                .indices
+                .iter()
+                .all(|index| index.block == BlockPtr::NONE)
+        );
+        assert_eq!(schema.null_list(1), BlockPtr::NONE);
+        aidb.put_schema("t".to_owned(), schema);
+
+        // and are never followed to the superblock
+        aidb.reset_block_io_log();
+        for sql in [
+            "SELECT * FROM t;",
+            "SELECT * FROM t WHERE id = 0;",
+            "SELECT * FROM t WHERE id > 0;",
+            "SELECT * FROM t WHERE x = 0;",
+            "SELECT * FROM t WHERE x IS NULL;",
+        ] {
+            let Response::Rows { rows, .. } = aidb.query(sql).await.unwrap() else {
+                panic!("rows expected");
+            };
+            assert!(rows.is_empty(), "{sql}");
+        }
+        assert_eq!(aidb.get_block_io_log().lookups, 0);
+
+        aidb.query("INSERT INTO t VALUES (1, NULL);").await.unwrap();
+        let schema = aidb.get_schema("t").await.unwrap();
+        assert!(schema.data_block.get().is_some_and(|index| index != 0));
+        assert_eq!(schema.insert_block, schema.data_block);
+        assert!(schema.indices[0].block.is_some());
+        assert_eq!(schema.indices[1].block, BlockPtr::NONE);
+        assert!(schema.null_list(1).is_some());
+        aidb.put_schema("t".to_owned(), schema);
+        assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_read_physical_size() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.op.write("1", vec![1u8; 16]).await.unwrap();
+        aidb.op
+            .write("2", vec![2u8; BLOCK_SIZE + 16])
+            .await
+            .unwrap();
+
+        let e = aidb.read_physical(1).await.unwrap_err();
+        assert!(e.to_string().contains("block 1 has 16 bytes"));
+        let e = aidb.read_physical(2).await.unwrap_err();
+        assert!(
+            e.to_string()
+                .contains(&format!("block 2 has {} bytes", BLOCK_SIZE + 16))
+        );
+
+        aidb.set_recovery(true);
+        let block = aidb.read_physical(1).await.unwrap();
+        assert_eq!(block.0[..16], [1; 16]);
+        assert!(block.0[16..].iter().all(|b| *b == 0));
+        let block = aidb.read_physical(2).await.unwrap();
+        assert!(block.0.iter().all(|b| *b == 2));
+    }
+
+    #[tokio::test]
+    async fn test_open_recovery() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (a INTEGER);").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (1);").await.unwrap();
+        let op = aidb.op.clone();
+        let mut superblock = op.read("0").await.unwrap().to_vec();
+        superblock.truncate(BLOCK_SIZE / 2);
+        op.write("0", superblock).await.unwrap();
+
+        let e = Aidb::from_op(op.clone()).await.unwrap_err();
+        assert!(e.to_string().contains("block 0 has"));
+
+        let aidb = Aidb::from_op_recovery(op, false).await.unwrap();
+        let mut reader = aidb.open_reader().await.unwrap();
+        assert!(reader.recovery);
+        let Response::Rows { rows, .. } = reader.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, [vec![Value::Integer(1)]]);
+    }
+
+    #[tokio::test]
+    async fn test_submit_on_commit() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (a INTEGER, b INTEGER);")
+            .await
+            .unwrap();
+
+        aidb.reset_block_io_log();
+        for i in 0..1000 {
+            aidb.query(format!("INSERT INTO t VALUES ({i}, {i});"))
+                .await
+                .unwrap();
+        }
+        // every statement flushes the blocks it touched
+        assert!(aidb.get_block_io_log().writes >= 1000);
+
+        aidb.query("START TRANSACTION;").await.unwrap();
+        aidb.reset_block_io_log();
+        for i in 0..1000 {
+            aidb.query(format!("INSERT INTO t VALUES ({i}, {i});"))
+                .await
+                .unwrap();
+        }
+        assert_eq!(aidb.get_block_io_log().writes, 0);
+        aidb.query("COMMIT;").await.unwrap();
+        // each dirty block is written once
+        let log = aidb.get_block_io_log();
+        assert_eq!(log.writes, log.written.len());
+        assert!(log.writes < 10);
+        assert!(aidb.blocks_dirty.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_flush() {
+        let op = Operator::from_config(MemoryConfig::default())
+            .unwrap()
+            .finish();
+        let mut aidb = Aidb::from_op(op.clone()).await.unwrap();
+        aidb.query("CREATE TABLE t (id INTEGER);").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (1);").await.unwrap();
+        aidb.flush().await.unwrap();
+        aidb.query("START TRANSACTION;").await.unwrap();
+        aidb.query("INSERT INTO t VALUES (2);").await.unwrap();
+        aidb.flush().await.unwrap();
+
+        // only committed rows are stored
+        let mut reopened = Aidb::from_op(op.clone()).await.unwrap();
+        let Response::Rows { rows, .. } = reopened.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, [vec![Value::Integer(1)]]);
+
+        aidb.query("COMMIT;").await.unwrap();
+        aidb.flush().await.unwrap();
+        let mut reopened = Aidb::from_op(op).await.unwrap();
+        let Response::Rows { rows, .. } = reopened.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, [vec![Value::Integer(1)], vec![Value::Integer(2)]]);
+    }
+
+    #[tokio::test]
+    async fn test_sharded_layout() {
+        let op = Operator::from_config(MemoryConfig::default())
+            .unwrap()
+            .finish();
+        let mut aidb = Aidb::from_op_with_layout(op.clone(), Layout::Sharded)
+            .await
+            .unwrap();
+        aidb.query("CREATE TABLE t (id INTEGER PRIMARY KEY, s TEXT);")
+            .await
+            .unwrap();
+        let rows = (0..5000)
+            .map(|i| vec![Value::Integer(i), Value::Text(format!("{i:0>100}"))])
+            .collect();
+        aidb.insert("t", rows).await.unwrap();
+        assert!(aidb.superblock.next_empty_block > 10);
+
+        let paths = op
+            .list_with("/")
+            .recursive(true)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path().to_owned())
+            .filter(|path| !path.ends_with('/'))
+            .collect::<Vec<_>>();
+        assert!(paths.contains(&"0".to_owned()));
+        assert!(paths.contains(&"block/01/00/1".to_owned()));
+        assert!(
+            paths
+                .iter()
+                .all(|path| path == "0" || path.starts_with("block/"))
+        );
+
+        // the layout is read from the superblock, whatever is asked for
+        let mut aidb = Aidb::from_op_with_layout(op.clone(), Layout::Flat)
+            .await
+            .unwrap();
+        assert_eq!(aidb.superblock.layout, Layout::Sharded);
+        let Response::Rows { rows, .. } = aidb
+            .query("SELECT s FROM t WHERE id = 4321;")
+            .await
+            .unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, [vec![Value::Text(format!("{:0>100}", 4321))]]);
+        assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
+
+        archive::erase_all(&op, archive::EraseConfirmation)
+            .await
+            .unwrap();
+        assert!(op.list_with("/").recursive(true).await.unwrap().is_empty());
+    }
+}
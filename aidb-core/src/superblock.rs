@@ -1,48 +1,246 @@
-use binrw::{BinRead, binrw};
-use eyre::Result;
+use binrw::{BinRead, BinWrite, binrw};
+use eyre::{Result, eyre};
 use opendal::ErrorKind;
+use tracing::warn;
 
-use crate::{Aidb, BlockIndex, storage::BlockOffset};
+use crate::{
+    Aidb, BlockIndex,
+    storage::{BlockOffset, BlockPtr, Layout},
+};
+
+/// Block holding the copy of the superblock, stored as `0.mirror` whatever the layout.
+pub(crate) const SUPERBLOCK_MIRROR: BlockIndex = BlockIndex::MAX;
+
+/// Magic of superblocks written before [`Aidb::FORMAT_VERSION`] was stored.
+const UNVERSIONED_MAGIC: [u8; 4] = *b"aidb";
 
 #[binrw]
 #[derive(Debug, Clone)]
-#[brw(little, magic = b"aidb")]
+#[brw(little, magic = b"AIDB")]
 pub struct SuperBlock {
+    #[br(temp, assert(version == Aidb::FORMAT_VERSION))]
+    #[bw(calc = Aidb::FORMAT_VERSION)]
+    version: u32,
     pub(crate) next_empty_block: BlockIndex,
-    pub(crate) first_schema_block: BlockIndex,
-    pub(crate) first_journal_block: BlockIndex,
-    pub(crate) next_text_block: BlockIndex,
+    pub(crate) first_schema_block: BlockPtr,
+    pub(crate) first_journal_block: BlockPtr,
+    /// text block being filled
+    pub(crate) next_text_block: BlockPtr,
     pub(crate) next_text_offset: BlockOffset,
+    /// head of the chain of free text extents
+    pub(crate) text_free_map: BlockPtr,
+    pub(crate) layout: Layout,
+    /// whether a copy is written to [`SUPERBLOCK_MIRROR`] along with the superblock
+    #[br(map = |v: u8| v != 0u8)]
+    #[bw(map = |v: &bool| if *v {1u8} else {0u8})]
+    pub(crate) mirror: bool,
+    /// bumped by every write, the newer of the superblock and its mirror is loaded
+    pub(crate) sequence: u64,
+    /// bumped by every archive saved, an incremental archive applies over the one of the
+    /// previous epoch
+    pub(crate) archive_epoch: u64,
 }
 
 impl Default for SuperBlock {
     fn default() -> Self {
         Self {
             next_empty_block: 1,
-            first_schema_block: 0,
-            first_journal_block: 0,
-            next_text_block: 0,
+            first_schema_block: BlockPtr::NONE,
+            first_journal_block: BlockPtr::NONE,
+            next_text_block: BlockPtr::NONE,
             next_text_offset: 0,
+            text_free_map: BlockPtr::NONE,
+            layout: Layout::Flat,
+            mirror: false,
+            sequence: 0,
+            archive_epoch: 0,
         }
     }
 }
 
 impl Aidb {
     pub(crate) async fn load_superblock(self: &mut Aidb) -> Result<()> {
-        match self.read_physical(0).await {
-            Ok(mut block) => {
-                let mut cursor = block.cursor();
-                self.superblock = SuperBlock::read(&mut cursor)?;
-            }
-            Err(e) if e.kind() == ErrorKind::NotFound => {
-                self.mark_superblock_dirty();
+        let mirror = match self.read_superblock(0).await {
+            // the mirror is only read when there is one or the superblock is lost
+            Ok(Some(superblock)) if !superblock.mirror => {
+                self.superblock = superblock;
+                return Ok(());
             }
+            Ok(Some(superblock)) => match self.read_superblock(SUPERBLOCK_MIRROR).await {
+                Ok(Some(mirror)) if mirror.sequence > superblock.sequence => {
+                    warn!("superblock is older than its mirror, loading the mirror");
+                    mirror
+                }
+                Ok(_) => {
+                    self.superblock = superblock;
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("mirror of the superblock is unreadable: {e}");
+                    self.superblock = superblock;
+                    return Ok(());
+                }
+            },
+            Ok(None) => match self.read_superblock(SUPERBLOCK_MIRROR).await {
+                Ok(Some(mirror)) => {
+                    warn!("superblock is missing, loading its mirror");
+                    mirror
+                }
+                // a new database
+                _ => {
+                    self.mark_superblock_dirty();
+                    return Ok(());
+                }
+            },
+            Err(e) => match self.read_superblock(SUPERBLOCK_MIRROR).await {
+                Ok(Some(mirror)) => {
+                    warn!("superblock is unreadable: {e}, loading its mirror");
+                    mirror
+                }
+                _ => Err(e)?,
+            },
+        };
+        self.superblock = mirror;
+        // block 0 is repaired by the next write
+        self.mark_superblock_dirty();
+        Ok(())
+    }
+
+    /// Read the superblock or its mirror, `None` if it doesn't exist. Other versions of the format
+    /// are refused before anything else is read.
+    async fn read_superblock(self: &mut Aidb, index: BlockIndex) -> Result<Option<SuperBlock>> {
+        let mut block = match self.read_physical(index).await {
+            Ok(block) => block,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
             Err(e) => Err(e)?,
+        };
+        let (magic, version) = <([u8; 4], u32)>::read_le(&mut block.cursor())?;
+        if magic == UNVERSIONED_MAGIC {
+            return Err(eyre!(
+                "database predates format versions and cannot be opened, this version reads \
+                 format {}",
+                Aidb::FORMAT_VERSION
+            ));
         }
-        Ok(())
+        if magic == *b"AIDB" && version != Aidb::FORMAT_VERSION {
+            return Err(eyre!(
+                "database is in format {version}, this version reads format {}",
+                Aidb::FORMAT_VERSION
+            ));
+        }
+        Ok(Some(SuperBlock::read(&mut block.cursor())?))
+    }
+
+    /// Put the superblock into block 0 and its mirror to be written with other dirty blocks.
+    pub(crate) fn stage_superblock(self: &mut Aidb) {
+        self.superblock.sequence += 1;
+        let mut block = Self::new_volatile_block();
+        self.superblock.write(&mut block.cursor()).unwrap();
+        if self.superblock.mirror {
+            self.put_block(SUPERBLOCK_MIRROR, block.clone());
+            self.mark_block_dirty(SUPERBLOCK_MIRROR);
+        }
+        self.put_block(0, block);
+        self.mark_block_dirty(0);
+    }
+
+    /// Keep a copy of the superblock in `0.mirror`, written along with it, so that the database
+    /// still opens when block 0 is damaged. The setting is stored in the database, turning it off
+    /// deletes the copy.
+    pub async fn set_superblock_mirror(self: &mut Aidb, mirror: bool) -> Result<()> {
+        if self.read_only {
+            return Err(eyre!("database is read-only"));
+        }
+        self.superblock.mirror = mirror;
+        self.mark_superblock_dirty();
+        if !mirror {
+            self.blocks.remove(&SUPERBLOCK_MIRROR);
+            self.blocks_dirty.remove(&SUPERBLOCK_MIRROR);
+            self.op
+                .delete(&self.superblock.layout.path(SUPERBLOCK_MIRROR))
+                .await?;
+        }
+        self.submit().await
     }
 
     pub(crate) fn mark_superblock_dirty(self: &mut Aidb) {
         self.superblock_dirty = true
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Response, storage::BLOCK_SIZE};
+
+    async fn count(aidb: &mut Aidb) -> usize {
+        let Response::Rows { rows, .. } = aidb.query("SELECT * FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        rows.len()
+    }
+
+    #[tokio::test]
+    async fn test_superblock_mirror() {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.set_superblock_mirror(true).await.unwrap();
+        aidb.query("CREATE TABLE t (id INTEGER);").await.unwrap();
+        let op = aidb.op.clone();
+        let stale = op.read("0").await.unwrap().to_vec();
+        aidb.query("INSERT INTO t VALUES (1), (2);").await.unwrap();
+
+        // block 0 overwritten with garbage or cut short by a failed write
+        for damaged in [vec![0xff; BLOCK_SIZE], vec![0; 100]] {
+            op.write("0", damaged).await.unwrap();
+            let mut reopened = Aidb::from_op(op.clone()).await.unwrap();
+            assert_eq!(count(&mut reopened).await, 2);
+        }
+        // a superblock left behind by a failed write loses to its newer mirror
+        op.write("0", stale).await.unwrap();
+        let mut reopened = Aidb::from_op(op.clone()).await.unwrap();
+        assert_eq!(count(&mut reopened).await, 2);
+        reopened.query("INSERT INTO t VALUES (3);").await.unwrap();
+        assert_eq!(
+            op.read("0").await.unwrap().to_vec(),
+            op.read("0.mirror").await.unwrap().to_vec()
+        );
+
+        reopened.set_superblock_mirror(false).await.unwrap();
+        assert!(!op.exists("0.mirror").await.unwrap());
+        let mut reopened = Aidb::from_op(op).await.unwrap();
+        assert_eq!(count(&mut reopened).await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_format_version() {
+        let aidb = Aidb::new_memory().await;
+        let op = aidb.op.clone();
+        let block = op.read("0").await.unwrap().to_vec();
+        assert_eq!(&block[..4], b"AIDB");
+        assert_eq!(block[4..8], Aidb::FORMAT_VERSION.to_le_bytes());
+
+        let mut newer = block.clone();
+        newer[4..8].copy_from_slice(&(Aidb::FORMAT_VERSION + 1).to_le_bytes());
+        op.write("0", newer).await.unwrap();
+        let e = Aidb::from_op(op.clone()).await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "database is in format {}, this version reads format {}",
+                Aidb::FORMAT_VERSION + 1,
+                Aidb::FORMAT_VERSION
+            )
+        );
+
+        // superblocks of before versions start with the magic followed by next_empty_block
+        let mut unversioned = block;
+        unversioned[..4].copy_from_slice(b"aidb");
+        unversioned[4..12].copy_from_slice(&1u64.to_le_bytes());
+        op.write("0", unversioned).await.unwrap();
+        let e = Aidb::from_op_read_only(op).await.unwrap_err();
+        assert!(
+            e.to_string()
+                .starts_with("database predates format versions")
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashSet;
+
+use binrw::{BinRead, BinWrite, binrw};
+use eyre::Result;
+
+use crate::{
+    Aidb,
+    storage::{BLOCK_SIZE, BlockIndex, BlockOffset, BlockPtr, DataPointer},
+};
+
+const TEXT_FREE_N: usize = (BLOCK_SIZE - 10) / 14;
+
+/// Unused bytes of a text block, left by deleted or updated text and by the tail of a block
+/// that didn't fit the next text.
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TextExtent {
+    block: BlockIndex,
+    offset: BlockOffset,
+    len: u32,
+}
+
+impl TextExtent {
+    fn end(&self) -> usize {
+        self.offset as usize + self.len as usize
+    }
+}
+
+/// Block of the chain of free text extents starting at [`crate::SuperBlock`], new text is put
+/// into the first extent large enough before the text block being filled.
+#[binrw]
+#[brw(little)]
+#[derive(Debug)]
+struct TextFreeMap {
+    next: BlockPtr,
+    #[br(temp)]
+    #[bw(calc = extents.len() as u16)]
+    len: u16,
+    #[br(count = len)]
+    #[bw(assert(extents.len() <= TEXT_FREE_N))]
+    extents: Vec<TextExtent>,
+}
+
+impl Aidb {
+    async fn read_text_free_map(&mut self, index: BlockIndex) -> Result<TextFreeMap> {
+        let mut block = self.get_block(index).await?;
+        let map = TextFreeMap::read(&mut block.cursor())?;
+        self.put_block(index, block);
+        Ok(map)
+    }
+
+    async fn write_text_free_map(&mut self, index: BlockIndex, map: TextFreeMap) -> Result<()> {
+        let mut block = self.get_block(index).await?;
+        map.write(&mut block.cursor())?;
+        self.put_block(index, block);
+        self.mark_block_dirty(index);
+        Ok(())
+    }
+
+    /// Take `len` bytes from the first free extent large enough.
+    pub(crate) async fn alloc_free_text(&mut self, len: usize) -> Result<Option<DataPointer>> {
+        let mut next = self.superblock.text_free_map;
+        while let Some(index) = next.get() {
+            let mut map = self.read_text_free_map(index).await?;
+            if let Some(i) = map.extents.iter().position(|e| e.len as usize >= len) {
+                let extent = &mut map.extents[i];
+                let ptr = DataPointer {
+                    block: extent.block,
+                    offset: extent.offset,
+                };
+                if extent.len as usize == len {
+                    map.extents.remove(i);
+                } else {
+                    extent.offset += len as BlockOffset;
+                    extent.len -= len as u32;
+                }
+                self.write_text_free_map(index, map).await?;
+                return Ok(Some(ptr));
+            }
+            next = map.next;
+        }
+        Ok(None)
+    }
+
+    /// Return bytes of a text block to the free extents, merging them with adjacent extents or
+    /// giving them back to the text block being filled.
+    pub(crate) async fn free_text(
+        &mut self,
+        block: BlockIndex,
+        offset: BlockOffset,
+        len: usize,
+    ) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let mut freed = TextExtent {
+            block,
+            offset,
+            len: len as u32,
+        };
+        let mut vacant = None;
+        let mut last = None;
+        let mut next = self.superblock.text_free_map;
+        while let Some(index) = next.get() {
+            let mut map = self.read_text_free_map(index).await?;
+            let before = map.extents.len();
+            map.extents.retain(|e| {
+                if e.block != freed.block {
+                    true
+                } else if e.end() == freed.offset as usize {
+                    freed.offset = e.offset;
+                    freed.len += e.len;
+                    false
+                } else if freed.end() == e.offset as usize {
+                    freed.len += e.len;
+                    false
+                } else {
+                    true
+                }
+            });
+            if vacant.is_none() && map.extents.len() < TEXT_FREE_N {
+                vacant = Some(index);
+            }
+            last = Some(index);
+            next = map.next;
+            if map.extents.len() != before {
+                self.write_text_free_map(index, map).await?;
+            }
+        }
+
+        if self.superblock.next_text_block.get() == Some(freed.block)
+            && freed.end() == self.superblock.next_text_offset as usize
+        {
+            self.superblock.next_text_offset = freed.offset;
+            self.mark_superblock_dirty();
+            return Ok(());
+        }
+        match vacant {
+            Some(index) => {
+                let mut map = self.read_text_free_map(index).await?;
+                map.extents.push(freed);
+                self.write_text_free_map(index, map).await
+            }
+            None => {
+                let (index, mut block) = self.new_block();
+                TextFreeMap {
+                    next: BlockPtr::NONE,
+                    extents: vec![freed],
+                }
+                .write(&mut block.cursor())?;
+                self.put_block(index, block);
+                self.mark_block_dirty(index);
+                match last {
+                    None => {
+                        self.superblock.text_free_map = BlockPtr::to(index);
+                        self.mark_superblock_dirty();
+                        Ok(())
+                    }
+                    Some(last) => {
+                        let mut map = self.read_text_free_map(last).await?;
+                        map.next = BlockPtr::to(index);
+                        self.write_text_free_map(last, map).await
+                    }
+                }
+            }
+        }
+    }
+
+    /// Check that the chain of free text extents terminates and each extent lies in a block.
+    pub(crate) async fn check_text_free_map(
+        &mut self,
+        visited: &mut HashSet<BlockIndex>,
+        problems: &mut Vec<String>,
+    ) -> Result<()> {
+        let context = "text free map";
+        let mut next = self.superblock.text_free_map;
+        while let Some(index) = next.get()
+            && self.check_block(index, visited, context, problems)
+        {
+            let map = match self.read_text_free_map(index).await {
+                Ok(map) => map,
+                Err(e) => {
+                    problems.push(format!("{context}: block {index} is unreadable: {e}"));
+                    break;
+                }
+            };
+            for extent in &map.extents {
+                if extent.block == 0
+                    || extent.block >= self.superblock.next_empty_block
+                    || extent.end() > BLOCK_SIZE
+                {
+                    problems.push(format!(
+                        "{context}: {} bytes at @{}:{} are out of range",
+                        extent.len, extent.block, extent.offset
+                    ));
+                }
+            }
+            next = map.next;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Response, Value};
+
+    async fn setup() -> Aidb {
+        let mut aidb = Aidb::new_memory().await;
+        aidb.query("CREATE TABLE t (id INTEGER, s TEXT);")
+            .await
+            .unwrap();
+        aidb
+    }
+
+    #[tokio::test]
+    async fn test_reuse_block_tail() {
+        let mut aidb = setup().await;
+        let text = |len: usize| Value::Text("x".repeat(len));
+        aidb.insert("t", vec![vec![Value::Integer(1), text(40000)]])
+            .await
+            .unwrap();
+        // doesn't fit after the first text, leaving its tail free
+        aidb.insert("t", vec![vec![Value::Integer(2), text(30000)]])
+            .await
+            .unwrap();
+        let blocks = aidb.superblock.next_empty_block;
+        // the free map takes a block of its own, the text goes into the tail
+        aidb.insert("t", vec![vec![Value::Integer(3), text(20000)]])
+            .await
+            .unwrap();
+        assert_eq!(aidb.superblock.next_empty_block, blocks);
+        let Response::Rows { rows, .. } = aidb.query("SELECT s FROM t;").await.unwrap() else {
+            panic!("rows expected");
+        };
+        assert_eq!(
+            rows,
+            [40000, 30000, 20000].map(|len| vec![text(len)]).to_vec()
+        );
+        assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_reuse_deleted_text() {
+        let mut aidb = setup().await;
+        let s = |i: i64| format!("{i:0>1000}");
+        for round in 0..5 {
+            let rows = (0..200)
+                .map(|i| vec![Value::Integer(i), Value::Text(s(round * 1000 + i))])
+                .collect();
+            aidb.insert("t", rows).await.unwrap();
+            // delete every other row, then update the rest to text of the same length
+            aidb.query("DELETE FROM t WHERE id <= 99;").await.unwrap();
+            aidb.query(format!("UPDATE t SET s = '{}';", s(round)))
+                .await
+                .unwrap();
+            aidb.query("DELETE FROM t;").await.unwrap();
+        }
+        let blocks = aidb.superblock.next_empty_block;
+        let rows = (0..200)
+            .map(|i| vec![Value::Integer(i), Value::Text(s(i))])
+            .collect();
+        aidb.insert("t", rows).await.unwrap();
+        // 200 KB of text fits into the blocks freed by earlier rounds
+        assert_eq!(aidb.superblock.next_empty_block, blocks);
+        let Response::Rows { rows, .. } =
+            aidb.query("SELECT s FROM t WHERE id = 123;").await.unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(rows, [vec![Value::Text(s(123))]]);
+        assert_eq!(aidb.check_integrity().await.unwrap(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_free_text_merge() {
+        let mut aidb = Aidb::new_memory().await;
+        let block = aidb.new_block().0;
+        aidb.free_text(block, 100, 50).await.unwrap();
+        aidb.free_text(block, 200, 50).await.unwrap();
+        aidb.free_text(block, 150, 50).await.unwrap();
+        let map = aidb
+            .read_text_free_map(aidb.superblock.text_free_map.get().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            map.extents,
+            [TextExtent {
+                block,
+                offset: 100,
+                len: 150
+            }]
+        );
+        let ptr = aidb.alloc_free_text(150).await.unwrap().unwrap();
+        assert_eq!((ptr.block, ptr.offset), (block, 100));
+        assert_eq!(aidb.alloc_free_text(1).await.unwrap(), None);
+    }
+}
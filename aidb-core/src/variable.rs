@@ -0,0 +1,146 @@
+use itertools::Itertools;
+
+use crate::{Aidb, Value};
+
+/// System variables clients query with `SELECT @@name`, before overrides.
+fn defaults(aidb: &Aidb) -> Vec<(&'static str, Value)> {
+    let text = |s: &str| Value::Text(s.to_owned());
+    vec![
+        ("auto_increment_increment", Value::Integer(1)),
+        ("autocommit", Value::Integer(1)),
+        ("character_set_client", text("utf8mb4")),
+        ("character_set_connection", text("utf8mb4")),
+        ("character_set_results", text("utf8mb4")),
+        ("character_set_server", text("utf8mb4")),
+        ("collation_connection", text("utf8mb4_bin")),
+        ("collation_server", text("utf8mb4_bin")),
+        ("init_connect", text("")),
+        ("interactive_timeout", Value::Integer(28800)),
+        ("lower_case_table_names", Value::Integer(0)),
+        ("max_allowed_packet", Value::Integer(67108864)),
+        ("net_write_timeout", Value::Integer(60)),
+        ("performance_schema", Value::Integer(0)),
+        ("sql_mode", text("")),
+        ("system_time_zone", text("UTC")),
+        ("time_zone", text("SYSTEM")),
+        ("transaction_isolation", text("SERIALIZABLE")),
+        (
+            "transaction_read_only",
+            Value::Integer(aidb.read_only as i64),
+        ),
+        ("version", Value::Text(Aidb::version())),
+        ("version_comment", text("aidb")),
+        ("wait_timeout", Value::Integer(28800)),
+    ]
+}
+
+/// Lowercase name without `@@` and scope, user variables keep their `@`.
+fn normalize(name: &str) -> String {
+    let name = name.to_lowercase();
+    let Some(name) = name.strip_prefix("@@") else {
+        return name;
+    };
+    let name = ["session.", "global.", "local."]
+        .iter()
+        .find_map(|scope| name.strip_prefix(scope))
+        .unwrap_or(name);
+    match name {
+        "tx_isolation" => "transaction_isolation",
+        "tx_read_only" => "transaction_read_only",
+        "collation" => "collation_connection",
+        name => name,
+    }
+    .to_owned()
+}
+
+impl Aidb {
+    /// Override a variable, `@name` for user variables and `@@name` or `name` for system ones.
+    pub fn set_variable(&mut self, name: &str, value: Value) {
+        let name = if name.starts_with('@') {
+            normalize(name)
+        } else {
+            normalize(&format!("@@{name}"))
+        };
+        self.variables.insert(name, value);
+    }
+
+    /// Drop all overrides, e.g. when the session setting them ends.
+    pub fn clear_variables(&mut self) {
+        self.variables.clear();
+    }
+
+    /// Value of `@name` or `@@name`, NULL if unknown.
+    pub fn variable(&self, name: &str) -> Value {
+        let name = normalize(name);
+        if let Some(value) = self.variables.get(&name) {
+            return value.clone();
+        }
+        defaults(self)
+            .into_iter()
+            .find(|(default, _)| *default == name)
+            .map_or(Value::Null, |(_, value)| value)
+    }
+
+    /// All system variables with overrides, sorted by name.
+    pub fn variables(&self) -> Vec<(String, Value)> {
+        let overrides = self
+            .variables
+            .iter()
+            .filter(|(name, _)| !name.starts_with('@'))
+            .map(|(name, value)| (name.clone(), value.clone()));
+        defaults(self)
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), value))
+            .filter(|(name, _)| !self.variables.contains_key(name))
+            .chain(overrides)
+            .sorted_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DataType, Response};
+
+    #[tokio::test]
+    async fn test_select_variable() {
+        let mut aidb = Aidb::new_memory().await;
+        let Response::Rows { columns, rows, .. } = aidb
+            .query(
+                "SELECT @@version_comment, @@max_allowed_packet, @@character_set_client, @@session.tx_isolation, @@autocommit, @unset;",
+            )
+            .await
+            .unwrap()
+        else {
+            panic!("rows expected");
+        };
+        assert_eq!(columns[1].datatype, DataType::Integer);
+        assert_eq!(
+            rows,
+            vec![vec![
+                Value::Text("aidb".to_owned()),
+                Value::Integer(67108864),
+                Value::Text("utf8mb4".to_owned()),
+                Value::Text("SERIALIZABLE".to_owned()),
+                Value::Integer(1),
+                Value::Null,
+            ]]
+        );
+
+        aidb.set_variable("SESSION.autocommit", Value::Integer(0));
+        aidb.set_variable("@@sql_mode", Value::Text("ANSI".to_owned()));
+        aidb.set_variable("@x", Value::Integer(42));
+        assert_eq!(aidb.variable("@@GLOBAL.AUTOCOMMIT"), Value::Integer(0));
+        assert_eq!(aidb.variable("@@sql_mode"), Value::Text("ANSI".to_owned()));
+        assert_eq!(aidb.variable("@x"), Value::Integer(42));
+        assert_eq!(aidb.variable("@@x"), Value::Null);
+        let variables = aidb.variables();
+        assert!(variables.is_sorted_by_key(|(name, _)| name.clone()));
+        assert!(variables.contains(&("sql_mode".to_owned(), Value::Text("ANSI".to_owned()))));
+        assert!(variables.iter().all(|(name, _)| !name.starts_with('@')));
+
+        aidb.clear_variables();
+        assert_eq!(aidb.variable("@@autocommit"), Value::Integer(1));
+    }
+}
@@ -1,23 +1,289 @@
 use std::{
-    io::{Read, Write, empty},
+    collections::HashMap,
+    fmt::Write as _,
+    io::{Cursor, Read, Write, empty},
     sync::Arc,
 };
 
-use eyre::Result;
+use eyre::{OptionExt, Result, eyre};
 use futures::{StreamExt, lock::Mutex, prelude::*};
 use opendal::Operator;
+use sha2::{Digest, Sha256};
+use tracing::warn;
 
-/// Erase all data accessible by the operator. ALL DATA WILL BE LOST FOREVER!
-pub async fn erase_all(op: &Operator) -> Result<()> {
+/// Entry written last into every archive, listing the SHA-256 of each file in the format of
+/// `sha256sum`. [`load`] writes nothing unless all files match it.
+pub const MANIFEST: &str = ".manifest.sha256";
+
+#[cfg(not(any(feature = "zstd", feature = "lz4")))]
+compile_error!("at least one of the features zstd and lz4 is required");
+
+/// Compression of the tar archive. Each codec is behind the feature of the same name, [`load`]
+/// restores archives of any enabled codec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Codec {
+    /// Codec of [`save`], zstd unless only lz4 is enabled.
+    #[cfg(feature = "zstd")]
+    pub const DEFAULT: Codec = Codec::Zstd;
+    #[cfg(not(feature = "zstd"))]
+    pub const DEFAULT: Codec = Codec::Lz4;
+
+    /// File extension of archives compressed with the codec.
+    pub const fn extension(self) -> &'static str {
+        match self {
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => "tar.zst",
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => "tar.lz4",
+        }
+    }
+
+    /// Magic number the compressed stream starts with.
+    const fn magic(self) -> [u8; 4] {
+        match self {
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => [0x28, 0xb5, 0x2f, 0xfd],
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => [0x04, 0x22, 0x4d, 0x18],
+        }
+    }
+
+    fn detect(magic: [u8; 4]) -> Result<Codec> {
+        [
+            #[cfg(feature = "zstd")]
+            Codec::Zstd,
+            #[cfg(feature = "lz4")]
+            Codec::Lz4,
+        ]
+        .into_iter()
+        .find(|codec| codec.magic() == magic)
+        .ok_or_else(|| eyre!("archive is not compressed with an enabled codec"))
+    }
+}
+
+/// File extension of archives made by [`save`].
+pub const EXTENSION: &str = Codec::DEFAULT.extension();
+
+enum Encoder<W: Write> {
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, W>),
+    #[cfg(feature = "lz4")]
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(w: W, codec: Codec) -> Result<Self> {
+        Ok(match codec {
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Encoder::Zstd(zstd::Encoder::new(w, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(w)),
+        })
+    }
+
+    fn finish(self) -> Result<W> {
+        Ok(match self {
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.finish()?,
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(encoder) => encoder.finish()?,
+        })
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.flush(),
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Tar archive being written, with the manifest of the files appended so far.
+struct Writer<W: Write> {
+    archive: tar::Builder<Encoder<W>>,
+    manifest: String,
+}
+
+impl<W: Write> Writer<W> {
+    fn new(w: W, codec: Codec) -> Result<Self> {
+        Ok(Self {
+            archive: tar::Builder::new(Encoder::new(w, codec)?),
+            manifest: String::new(),
+        })
+    }
+
+    fn append_dir(&mut self, path: &str) -> Result<()> {
+        let mut header = tar::Header::new_ustar();
+        header.set_path(path)?;
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_cksum();
+        self.archive.append(&header, empty())?;
+        Ok(())
+    }
+
+    fn append_file(&mut self, path: &str, buffer: impl Read, len: usize) -> Result<()> {
+        let mut header = tar::Header::new_ustar();
+        header.set_path(path)?;
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(len as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        self.archive.append(&header, buffer)?;
+        Ok(())
+    }
+
+    fn append_object(&mut self, path: &str, buffer: opendal::Buffer) -> Result<()> {
+        let checksum = checksum(&buffer.to_vec());
+        writeln!(self.manifest, "{checksum}  {path}")?;
+        self.append_file(path, buffer.clone(), buffer.len())
+    }
+
+    fn finish(mut self) -> Result<W> {
+        let manifest = std::mem::take(&mut self.manifest);
+        self.append_file(MANIFEST, manifest.as_bytes(), manifest.len())?;
+        self.archive.into_inner()?.finish()
+    }
+}
+
+fn checksum(data: &[u8]) -> String {
+    Sha256::digest(data).iter().fold(String::new(), |mut s, b| {
+        write!(s, "{b:02x}").unwrap();
+        s
+    })
+}
+
+/// Check every file against the manifest, so that a truncated or corrupted archive is rejected
+/// as a whole.
+fn verify(manifest: &str, files: &[(String, Vec<u8>)]) -> Result<()> {
+    let mut checksums = HashMap::new();
+    for line in manifest.lines() {
+        let (checksum, path) = line
+            .split_once("  ")
+            .ok_or_else(|| eyre!("malformed manifest line: {line}"))?;
+        checksums.insert(path, checksum);
+    }
+    for (path, buffer) in files {
+        let expected = checksums
+            .remove(path.as_str())
+            .ok_or_else(|| eyre!("{path} is not in the manifest"))?;
+        if checksum(buffer) != expected {
+            return Err(eyre!(
+                "{path} is corrupt, its checksum does not match the manifest"
+            ));
+        }
+    }
+    if let Some(path) = checksums.into_keys().next() {
+        return Err(eyre!(
+            "{path} is in the manifest but missing from the archive"
+        ));
+    }
+    Ok(())
+}
+
+/// The magic number read to detect the codec, put back in front of the rest of the stream.
+type Rewound<R> = std::io::Chain<Cursor<[u8; 4]>, R>;
+
+enum Decoder<R: Read> {
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Decoder<'static, std::io::BufReader<Rewound<R>>>),
+    #[cfg(feature = "lz4")]
+    Lz4(lz4_flex::frame::FrameDecoder<Rewound<R>>),
+}
+
+impl<R: Read> Decoder<R> {
+    fn new(mut r: R) -> Result<Self> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        let codec = Codec::detect(magic)?;
+        let r = Cursor::new(magic).chain(r);
+        Ok(match codec {
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Decoder::Zstd(zstd::Decoder::new(r)?),
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => Decoder::Lz4(lz4_flex::frame::FrameDecoder::new(r)),
+        })
+    }
+
+    fn into_inner(self) -> R {
+        match self {
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(decoder) => decoder.finish().into_inner().into_inner().1,
+            #[cfg(feature = "lz4")]
+            Decoder::Lz4(decoder) => decoder.into_inner().into_inner().1,
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(decoder) => decoder.read(buf),
+            #[cfg(feature = "lz4")]
+            Decoder::Lz4(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+/// Proof that the caller really means to erase everything under the operator root, required by
+/// [`erase_all`].
+#[derive(Debug, Clone, Copy)]
+pub struct EraseConfirmation;
+
+/// Erase all data accessible by the operator, including subdirectories and anything else sharing
+/// the operator root. ALL DATA WILL BE LOST FOREVER!
+///
+/// ```compile_fail
+/// # async fn f(op: opendal::Operator) {
+/// archive::erase_all(&op).await.unwrap();
+/// # }
+/// ```
+pub async fn erase_all(op: &Operator, _confirm: EraseConfirmation) -> Result<()> {
+    let info = op.info();
+    warn!(
+        "erasing everything under {}://{}",
+        info.scheme(),
+        info.root()
+    );
+    erase(op).await
+}
+
+pub(crate) async fn erase(op: &Operator) -> Result<()> {
     op.delete_try_stream(op.lister_with("/").recursive(true).await?)
         .await?;
     Ok(())
 }
 
-/// Save all the data accessible by the operator to a tar.lz4 archive.
+/// Save all the data accessible by the operator to a tar archive compressed with
+/// [`Codec::DEFAULT`], named with [`EXTENSION`].
 pub async fn save<W: Write>(op: &Operator, w: W) -> Result<W> {
-    let archive = tar::Builder::new(zstd::Encoder::new(w, zstd::DEFAULT_COMPRESSION_LEVEL)?);
-    let archive = Arc::new(Mutex::new(archive));
+    save_with_codec(op, w, Codec::DEFAULT).await
+}
+
+/// Save all the data accessible by the operator to a tar archive compressed with `codec`.
+pub async fn save_with_codec<W: Write>(op: &Operator, w: W, codec: Codec) -> Result<W> {
+    let archive = Arc::new(Mutex::new(Writer::new(w, codec)?));
     op.lister_with("/")
         .recursive(true)
         .await?
@@ -29,37 +295,78 @@ pub async fn save<W: Write>(op: &Operator, w: W) -> Result<W> {
                 async move {
                     let path = entry.path();
                     if path.ends_with('/') {
-                        let mut header = tar::Header::new_ustar();
-                        header.set_path(path)?;
-                        header.set_entry_type(tar::EntryType::Directory);
-                        header.set_mode(0o755);
-                        header.set_cksum();
-                        let mut archive = archive.lock().await;
-                        archive.append(&header, empty())?;
+                        archive.lock().await.append_dir(path)?;
                     } else {
                         let buffer = op.read(path).await?;
-                        let mut header = tar::Header::new_ustar();
-                        header.set_path(path)?;
-                        header.set_entry_type(tar::EntryType::Regular);
-                        header.set_size(buffer.len() as u64);
-                        header.set_mode(0o644);
-                        header.set_cksum();
-                        let mut archive = archive.lock().await;
-                        archive.append(&header, buffer)?;
+                        archive.lock().await.append_object(path, buffer)?;
                     }
                     Result::Ok(())
                 }
             }
         })
         .await?;
-    let archive = Arc::into_inner(archive).unwrap().into_inner();
-    Ok(archive.into_inner()?.finish()?)
+    Arc::into_inner(archive).unwrap().into_inner().finish()
+}
+
+/// Save only the objects at `paths` to a tar archive compressed with [`Codec::DEFAULT`], e.g. those
+/// changed since a full archive made by [`save`]. Loading it after the full archive restores the
+/// objects to their latest version, objects deleted since are not recorded.
+pub async fn save_objects<W: Write>(
+    op: &Operator,
+    paths: impl IntoIterator<Item = String>,
+    w: W,
+) -> Result<W> {
+    save_objects_with_codec(op, paths, w, Codec::DEFAULT).await
 }
 
-/// Load archived data into the operator and leave other data intact.
+/// Save only the objects at `paths` to a tar archive compressed with `codec`.
+pub async fn save_objects_with_codec<W: Write>(
+    op: &Operator,
+    paths: impl IntoIterator<Item = String>,
+    w: W,
+    codec: Codec,
+) -> Result<W> {
+    let mut archive = Writer::new(w, codec)?;
+    for path in paths {
+        let buffer = op.read(&path).await?;
+        archive.append_object(&path, buffer)?;
+    }
+    archive.finish()
+}
+
+/// Load archived data into the operator and leave other data intact. The codec is detected from
+/// the archive, which is verified against its [`MANIFEST`] before anything is written.
 pub async fn load<R: Read>(op: &Operator, r: R) -> Result<R> {
-    todo!();
-    Ok(r)
+    let mut archive = tar::Archive::new(Decoder::new(r)?);
+    let mut dirs = vec![];
+    let mut files = vec![];
+    let mut manifest = None;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => dirs.push(path),
+            tar::EntryType::Regular => {
+                let mut buffer = vec![];
+                entry.read_to_end(&mut buffer)?;
+                if path == MANIFEST {
+                    manifest = Some(String::from_utf8(buffer)?);
+                } else {
+                    files.push((path, buffer));
+                }
+            }
+            _ => {}
+        }
+    }
+    let manifest = manifest.ok_or_eyre("archive has no manifest, it may be truncated")?;
+    verify(&manifest, &files)?;
+    for path in dirs {
+        op.create_dir(&path).await?;
+    }
+    for (path, buffer) in files {
+        op.write(&path, buffer).await?;
+    }
+    Ok(archive.into_inner().into_inner())
 }
 
 #[cfg(test)]
@@ -88,16 +395,21 @@ mod tests {
         v
     }
 
-    async fn init() -> Operator {
+    fn memory() -> Operator {
         static INIT: Once = Once::new();
         INIT.call_once(env_logger::init);
-        let op = Operator::from_config(MemoryConfig::default())
+        Operator::from_config(MemoryConfig::default())
             .unwrap()
             .layer(LoggingLayer::default())
-            .finish();
+            .finish()
+    }
+
+    async fn init() -> Operator {
+        let op = memory();
         op.write("1", "Hello, world!").await.unwrap();
         op.write("2", data2()).await.unwrap();
         op.write("3", data3()).await.unwrap();
+        op.write("block/01/00/1", "nested").await.unwrap();
         op
     }
 
@@ -108,29 +420,176 @@ mod tests {
         );
         assert_eq!(op.read("2").await.unwrap().to_vec(), data2());
         assert_eq!(op.read("3").await.unwrap().to_vec(), data3());
+        assert_eq!(op.read("block/01/00/1").await.unwrap().to_vec(), b"nested");
     }
 
     #[tokio::test]
     async fn test_erase_all() {
         let op = init().await;
-        erase_all(&op).await.unwrap();
+        erase_all(&op, EraseConfirmation).await.unwrap();
         let files = op.list_with("/").recursive(true).await.unwrap();
         assert!(files.is_empty());
     }
 
-    #[tokio::test]
-    async fn test_save_load() {
+    async fn save_load(codec: Codec) {
         let op = init().await;
         let mut v = Vec::<u8>::new();
-        save(&op, Cursor::new(&mut v)).await.unwrap();
-        assert!(v.len() > 0);
-        tokio::fs::write("/home/nkid00/Zhuan/courses/db/aidb/1.tar.zst", &v)
+        save_with_codec(&op, Cursor::new(&mut v), codec)
             .await
             .unwrap();
+        assert!(v.starts_with(&codec.magic()));
+        // keep the archive for inspection with AIDB_DUMP_ARCHIVE=path
+        if let Ok(path) = std::env::var("AIDB_DUMP_ARCHIVE") {
+            tokio::fs::write(path, &v).await.unwrap();
+        }
         let v_clone = v.clone();
-        erase_all(&op).await.unwrap();
+
+        let fresh = memory();
+        load(&fresh, Cursor::new(&mut v)).await.unwrap();
+        check_data(&fresh).await;
+        let files = |op: Operator| async move {
+            let mut files = op
+                .list_with("/")
+                .recursive(true)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.path().to_owned())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+        assert_eq!(files(fresh).await, files(op.clone()).await);
+
+        erase_all(&op, EraseConfirmation).await.unwrap();
         load(&op, Cursor::new(&mut v)).await.unwrap();
         assert_eq!(v, v_clone);
         check_data(&op).await;
     }
+
+    #[tokio::test]
+    async fn test_save_load() {
+        let op = init().await;
+        let mut v = Vec::<u8>::new();
+        save(&op, Cursor::new(&mut v)).await.unwrap();
+        assert!(v.starts_with(&Codec::DEFAULT.magic()));
+        assert_eq!(EXTENSION, Codec::DEFAULT.extension());
+        save_load(Codec::DEFAULT).await;
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn test_save_load_zstd() {
+        save_load(Codec::Zstd).await;
+        assert_eq!(Codec::Zstd.extension(), "tar.zst");
+    }
+
+    #[cfg(feature = "lz4")]
+    #[tokio::test]
+    async fn test_save_load_lz4() {
+        save_load(Codec::Lz4).await;
+        assert_eq!(Codec::Lz4.extension(), "tar.lz4");
+    }
+
+    #[tokio::test]
+    async fn test_save_objects() {
+        let op = init().await;
+        let base = save(&op, vec![]).await.unwrap();
+        op.write("1", "Goodbye, world!").await.unwrap();
+        op.write("block/01/00/2", "new").await.unwrap();
+        let paths = ["1".to_owned(), "block/01/00/2".to_owned()];
+        let increment = save_objects(&op, paths, vec![]).await.unwrap();
+        assert!(increment.len() * 100 < base.len());
+
+        let fresh = memory();
+        load(&fresh, &base[..]).await.unwrap();
+        load(&fresh, &increment[..]).await.unwrap();
+        assert_eq!(fresh.read("1").await.unwrap().to_vec(), b"Goodbye, world!");
+        assert_eq!(fresh.read("block/01/00/2").await.unwrap().to_vec(), b"new");
+        assert_eq!(fresh.read("2").await.unwrap().to_vec(), data2());
+        assert_eq!(fresh.read("3").await.unwrap().to_vec(), data3());
+
+        let paths = ["missing".to_owned()];
+        assert!(save_objects(&op, paths, vec![]).await.is_err());
+    }
+
+    /// Decompress an archive, change the tar stream and compress it again.
+    fn tamper(archive: &[u8], f: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let mut tar = vec![];
+        Decoder::new(archive)
+            .unwrap()
+            .read_to_end(&mut tar)
+            .unwrap();
+        f(&mut tar);
+        let mut encoder = Encoder::new(vec![], Codec::DEFAULT).unwrap();
+        encoder.write_all(&tar).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_manifest() {
+        let op = init().await;
+        let archive = save(&op, vec![]).await.unwrap();
+        let fresh = memory();
+        load(&fresh, &archive[..]).await.unwrap();
+        check_data(&fresh).await;
+        // recompressing leaves an untouched archive valid
+        let untouched = tamper(&archive, |_| {});
+        load(&memory(), &untouched[..]).await.unwrap();
+
+        // flip a byte in the middle of file 3
+        let corrupt = tamper(&archive, |tar| {
+            let data = data3();
+            let start = tar.windows(512).position(|w| w == &data[..512]).unwrap();
+            tar[start + data.len() / 2] ^= 1;
+        });
+        let fresh = memory();
+        let e = load(&fresh, &corrupt[..]).await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "3 is corrupt, its checksum does not match the manifest"
+        );
+        assert!(
+            fresh
+                .list_with("/")
+                .recursive(true)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        // cut the archive before the manifest
+        let truncated = tamper(&archive, |tar| {
+            let name = tar
+                .windows(MANIFEST.len())
+                .position(|w| w == MANIFEST.as_bytes())
+                .unwrap();
+            tar.truncate(name);
+        });
+        let e = load(&fresh, &truncated[..]).await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "archive has no manifest, it may be truncated"
+        );
+        assert!(
+            fresh
+                .list_with("/")
+                .recursive(true)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_unknown_codec() {
+        let op = memory();
+        let e = load(&op, Cursor::new(b"not an archive")).await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "archive is not compressed with an enabled codec"
+        );
+        assert!(load(&op, Cursor::new(b"no")).await.is_err());
+        assert!(op.list_with("/").recursive(true).await.unwrap().is_empty());
+    }
 }
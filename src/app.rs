@@ -6,13 +6,15 @@ use std::{
     rc::Rc,
 };
 
-use crate::worker::{Worker, WorkerRequest, WorkerResponse};
+use crate::worker::{Backend, Worker, WorkerRequest, WorkerResponse};
 
-use aidb_core::{BlockIoLog, Response};
+use aidb_core::{BlockIoLog, BlockType, Response};
 use futures::{SinkExt, StreamExt, lock::Mutex};
 use gloo_worker::Spawnable;
 use itertools::Itertools;
 use leptos::{either::either, html, logging::log, prelude::*, task::spawn_local};
+use leptos_use::signal_debounced;
+use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 use web_sys::{ScrollBehavior, ScrollToOptions};
 
@@ -21,22 +23,127 @@ enum BlockStatus {
     Normal,
     Read,
     Written,
+    /// accesses accumulated across queries in heatmap mode
+    Hot {
+        reads: u32,
+        writes: u32,
+    },
+}
+
+/// heat added by one access, a quarter of all heat decays after every query
+const HEAT_PER_ACCESS: u32 = 8;
+
+impl BlockStatus {
+    fn class(&self) -> &'static str {
+        let level = |heat: u32| heat.div_ceil(HEAT_PER_ACCESS).min(3);
+        match *self {
+            BlockStatus::Normal => "bg-slate-50",
+            BlockStatus::Read => "bg-sky-100",
+            BlockStatus::Written => "bg-orange-100",
+            BlockStatus::Hot { writes, .. } if writes > 0 => [
+                "bg-slate-50",
+                "bg-orange-100",
+                "bg-orange-200",
+                "bg-orange-300",
+            ][level(writes) as usize],
+            BlockStatus::Hot { reads, .. } => {
+                ["bg-slate-50", "bg-sky-100", "bg-sky-200", "bg-sky-300"][level(reads) as usize]
+            }
+        }
+    }
+}
+
+/// Name and ring around blocks of each role, in the order of the legend.
+const BLOCK_TYPES: [(BlockType, &str, &str); 8] = [
+    (BlockType::SuperBlock, "superblock", "ring-2 ring-inset ring-red-400"),
+    (BlockType::Schema, "schema", "ring-2 ring-inset ring-violet-400"),
+    (BlockType::Data, "data", "ring-2 ring-inset ring-green-400"),
+    (BlockType::BTree, "B-tree", "ring-2 ring-inset ring-blue-400"),
+    (BlockType::Hash, "hash", "ring-2 ring-inset ring-cyan-400"),
+    (BlockType::NullList, "NULL list", "ring-2 ring-inset ring-slate-400"),
+    (BlockType::Text, "text", "ring-2 ring-inset ring-yellow-400"),
+    (BlockType::TextFreeMap, "text free map", "ring-2 ring-inset ring-amber-700"),
+];
+
+fn block_type_class(block_type: Option<BlockType>) -> &'static str {
+    BLOCK_TYPES
+        .iter()
+        .find(|(t, ..)| Some(*t) == block_type)
+        .map_or("", |(.., class)| class)
 }
 
 #[derive(Debug, Clone)]
 struct BlockList {
     blocks: BTreeMap<u64, BlockStatus>,
+    /// role of blocks reported with the last query, unused blocks are absent
+    types: BTreeMap<u64, BlockType>,
+    heatmap: bool,
 }
 
 impl BlockList {
     fn new() -> Self {
         Self {
             blocks: (0..200).map(|i| (i, BlockStatus::Normal)).collect(),
+            types: BTreeMap::new(),
+            heatmap: false,
+        }
+    }
+
+    fn set_heatmap(&mut self, heatmap: bool) {
+        self.heatmap = heatmap;
+        for (_, status) in self.blocks.iter_mut() {
+            *status = BlockStatus::Normal;
         }
     }
 
+    /// Blocks with their status and role, as shown in the grid.
+    fn cells(&self) -> Vec<(u64, BlockStatus, Option<BlockType>)> {
+        self.blocks
+            .iter()
+            .map(|(&i, &status)| (i, status, self.types.get(&i).copied()))
+            .collect()
+    }
+
+    fn set_types(&mut self, types: BTreeMap<u64, BlockType>) {
+        for &i in types.keys() {
+            self.blocks.entry(i).or_insert(BlockStatus::Normal);
+        }
+        self.types = types;
+    }
+
     fn update(&mut self, log: BlockIoLog) {
         use BlockStatus::*;
+        if self.heatmap {
+            for (_, status) in self.blocks.iter_mut() {
+                let decay = |heat: u32| heat * 3 / 4;
+                *status = match *status {
+                    Hot { reads, writes } if decay(reads) + decay(writes) > 0 => Hot {
+                        reads: decay(reads),
+                        writes: decay(writes),
+                    },
+                    _ => Normal,
+                };
+            }
+            for (b, read, written) in log
+                .read
+                .into_iter()
+                .map(|b| (b, HEAT_PER_ACCESS, 0))
+                .chain(log.written.into_iter().map(|b| (b, 0, HEAT_PER_ACCESS)))
+            {
+                let status = self.blocks.entry(b).or_insert(Normal);
+                *status = match *status {
+                    Hot { reads, writes } => Hot {
+                        reads: reads + read,
+                        writes: writes + written,
+                    },
+                    _ => Hot {
+                        reads: read,
+                        writes: written,
+                    },
+                };
+            }
+            return;
+        }
         for (_, status) in self.blocks.iter_mut() {
             *status = Normal;
         }
@@ -49,7 +156,7 @@ impl BlockList {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Chat {
     id: usize,
     request: String,
@@ -80,7 +187,7 @@ impl Chat {
                     { format!("Query OK, {affected_rows} rows affected ({:.3} sec)", self.duration) }
                 </div>
             },
-            Some(Ok(Response::Rows { columns, rows })) => {
+            Some(Ok(Response::Rows { columns, rows, truncated })) => {
                 either! {rows.is_empty(),
                     true => view! {
                         <div class="my-2 p-2 self-start">
@@ -124,6 +231,7 @@ impl Chat {
                                 } else {
                                     format!("{} rows in set ({:.3} sec)", len, self.duration)
                                 } }
+                                { truncated.then_some(", result truncated") }
                             </div>
                         }
                     }
@@ -148,7 +256,9 @@ impl Chat {
     }
 }
 
-#[derive(Debug, Clone)]
+const CHAT_HISTORY_KEY: &str = "aidb-chat-history";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct ChatHistory {
     chats: Vec<Chat>,
     next_id: usize,
@@ -180,6 +290,100 @@ impl ChatHistory {
         };
         chat.respond(id, response, duration);
     }
+
+    /// Serialize into `store`, dropping the oldest chats until it accepts.
+    /// Returns the number of chats kept.
+    fn save_with(&self, mut store: impl FnMut(&str) -> bool) -> usize {
+        let mut history = self.clone();
+        // a chat still waiting for its response can't be restored
+        history.chats.retain(|chat| chat.response.is_some());
+        loop {
+            let json = serde_json::to_string(&history).unwrap();
+            if store(&json) || history.chats.is_empty() {
+                return history.chats.len();
+            }
+            history.chats.remove(0);
+        }
+    }
+
+    fn restore_from(json: &str) -> Option<Self> {
+        serde_json::from_str(json).ok()
+    }
+
+    /// Save into local storage, trimming the history if the quota is exceeded.
+    pub fn save(&self) {
+        let Some(storage) = window().local_storage().ok().flatten() else {
+            return;
+        };
+        let kept = self.save_with(|json| storage.set_item(CHAT_HISTORY_KEY, json).is_ok());
+        if kept < self.chats.len() {
+            log!("chat history trimmed to {kept} chats");
+        }
+    }
+
+    /// Restore from local storage, or start over.
+    pub fn restore() -> Self {
+        window()
+            .local_storage()
+            .ok()
+            .flatten()
+            .and_then(|storage| storage.get_item(CHAT_HISTORY_KEY).ok().flatten())
+            .and_then(|json| Self::restore_from(&json))
+            .unwrap_or_else(Self::new)
+    }
+}
+
+/// What a keypress of Enter in the SQL input does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum EnterAction {
+    /// Shift+Enter continues editing on a new line
+    Newline,
+    /// Enter submits each statement separated by `;`, Ctrl/Cmd+Enter the whole buffer as one
+    Submit(Vec<String>),
+}
+
+impl EnterAction {
+    fn new(input: &str, shift: bool, force: bool) -> Self {
+        if shift {
+            return Self::Newline;
+        }
+        let input = input.trim();
+        if input.is_empty() {
+            Self::Submit(vec![])
+        } else if force {
+            let stmt = input.trim_end_matches(';').trim_end();
+            Self::Submit(vec![format!("{stmt};")])
+        } else {
+            Self::Submit(
+                input
+                    .split(';')
+                    .map(str::trim)
+                    .filter(|stmt| !stmt.is_empty())
+                    .map(|stmt| format!("{stmt};"))
+                    .collect(),
+            )
+        }
+    }
+}
+
+/// Numbers completion requests so that hints for outdated input are dropped.
+#[derive(Debug, Clone, Default)]
+struct CompletionSeq {
+    latest: usize,
+    input: String,
+}
+
+impl CompletionSeq {
+    fn next(&mut self, input: String) -> usize {
+        self.latest += 1;
+        self.input = input;
+        self.latest
+    }
+
+    /// Whether a response is for the latest request and the input hasn't changed since.
+    fn is_current(&self, seq: usize, input: &str) -> bool {
+        seq == self.latest && self.input == input
+    }
 }
 
 #[component]
@@ -187,29 +391,76 @@ pub fn App() -> impl IntoView {
     let worker = Rc::new(Mutex::new(Worker::spawner().spawn("./worker.js")));
 
     let (blocks, set_blocks) = signal(BlockList::new());
-    let (chat, set_chat) = signal(ChatHistory::new());
+    let (chat, set_chat) = signal(ChatHistory::restore());
     let (input, set_input) = signal(String::new());
     let (hint, set_hint) = signal("".to_string());
+    let (syntax_error, set_syntax_error) = signal(None::<String>);
     let input_ref = NodeRef::<html::Code>::new();
+    let debounced_input = signal_debounced(input, 150.);
+    let completion_seq = StoredValue::new(CompletionSeq::default());
+
+    Effect::new(move |_| {
+        chat.with(|chat| {
+            if chat.chats.last().is_none_or(|c| c.response.is_some()) {
+                chat.save();
+            }
+        })
+    });
+
+    spawn_local({
+        let worker = worker.clone();
+        async move {
+            let mut worker = worker.lock().await;
+            worker
+                .send(WorkerRequest::Open {
+                    backend: Backend::IndexedDb,
+                })
+                .await
+                .unwrap();
+            let Some(WorkerResponse::Opened { backend }) = worker.next().await else {
+                panic!("unexpected response from worker");
+            };
+            log!("database opened in {backend:?}");
+        }
+    });
 
     Effect::new({
         let worker = worker.clone();
         move |_| {
-            let input = input();
-            if input.is_empty() {
+            let sql = debounced_input.get();
+            let seq = completion_seq
+                .try_update_value(|seq| seq.next(sql.clone()))
+                .unwrap();
+            if sql.is_empty() {
                 set_hint("SQL Input".to_owned());
+                set_syntax_error(None);
                 return;
             }
-            log!("complete: {:?}", input);
+            log!("complete: {:?}", sql);
             spawn_local({
                 let worker = worker.clone();
                 async move {
                     let mut worker = worker.lock().await;
-                    worker.send(WorkerRequest::Completion(input)).await.unwrap();
-                    let Some(WorkerResponse::Completion(hint)) = worker.next().await else {
+                    worker
+                        .send(WorkerRequest::Completion {
+                            seq,
+                            sql: sql.clone(),
+                        })
+                        .await
+                        .unwrap();
+                    let Some(WorkerResponse::Completion { seq, hint }) = worker.next().await else {
+                        panic!("unexpected response from worker");
+                    };
+                    worker.send(WorkerRequest::Validate(sql)).await.unwrap();
+                    let Some(WorkerResponse::Validation { ok, message }) = worker.next().await
+                    else {
                         panic!("unexpected response from worker");
                     };
-                    set_hint(hint);
+                    let current = input.get_untracked();
+                    if completion_seq.with_value(|s| s.is_current(seq, &current)) {
+                        set_hint(hint);
+                        set_syntax_error((!ok).then_some(message));
+                    }
                 }
             });
         }
@@ -289,11 +540,14 @@ pub fn App() -> impl IntoView {
                 };
                 match response {
                     WorkerResponse::Query {
-                        response: Ok((response, log)),
+                        response: Ok((response, log, types)),
                         duration,
                     } => {
                         set_chat.update(|chat| chat.respond(Ok(response), duration));
-                        set_blocks.update(|bl| bl.update(log));
+                        set_blocks.update(|bl| {
+                            bl.update(log);
+                            bl.set_types(types);
+                        });
                     }
                     WorkerResponse::Query {
                         response: Err(e),
@@ -325,19 +579,25 @@ pub fn App() -> impl IntoView {
             <div class="w-[25%] h-[100vh] sticky top-0 flex flex-col justify-start items-center">
                 <h2 class="m-4 text-lg"> "Blocks" </h2>
                 <div class="z-0 grid grid-cols-8 gap-2 justify-start justify-items-center content-start place-content-center overflow-hidden">
-                    <For each=move || { blocks().blocks.clone() } key=|f| {
+                    <For each=move || { blocks().cells() } key=|f| {
                         let mut hasher = DefaultHasher::new();
                         f.hash(&mut hasher);
                         hasher.finish()
-                    } children={ |(name, status)| { view! {
-                        <div class={ "w-10 h-10 flex justify-center items-center rounded ".to_owned() + match status {
-                            BlockStatus::Normal => "bg-slate-50",
-                            BlockStatus::Read => "bg-sky-100",
-                            BlockStatus::Written => "bg-orange-100",
-                        } }> <code> { name } </code> </div>
+                    } children={ |(name, status, block_type)| { view! {
+                        <div class={ format!("w-10 h-10 flex justify-center items-center rounded {} {}", status.class(), block_type_class(block_type)) }> <code> { name } </code> </div>
                     } } } />
                 </div>
+                <div class="mx-8 mt-6 self-stretch flex flex-row flex-wrap justify-start gap-x-4 gap-y-2 text-sm">
+                    { BLOCK_TYPES.iter().map(|(_, name, class)| view! {
+                        <div class="flex flex-row items-center gap-1">
+                            <div class={ format!("w-3 h-3 rounded-sm {class}") }></div> { *name }
+                        </div>
+                    }).collect_vec() }
+                </div>
                 <div class="m-8 self-stretch flex flex-row justify-stretch items-center gap-2">
+                    <button class="flex-1 px-4 py-2 bg-gray-200 hover:bg-gray-300 active:bg-gray-400 rounded"
+                        class=("bg-gray-400", move || blocks().heatmap)
+                        on:click=move |_| set_blocks.update(|bl| bl.set_heatmap(!bl.heatmap))> "Heatmap" </button>
                     <button class="flex-1 px-4 py-2 bg-gray-200 hover:bg-gray-300 active:bg-gray-400 rounded"> "Save" </button>
                     <button class="flex-1 px-4 py-2 bg-gray-200 hover:bg-gray-300 active:bg-gray-400 rounded"> "Load" </button>
                 </div>
@@ -356,7 +616,10 @@ pub fn App() -> impl IntoView {
                             ev.prevent_default();
                             focus_input();
                         }>
-                            <code class="h-auto text-wrap break-all outline-none" contenteditable node_ref=input_ref on:mousedown=|ev| {
+                            <code class="h-auto whitespace-pre-wrap break-all outline-none"
+                                class=(["underline", "decoration-wavy", "decoration-red-500"], move || syntax_error().is_some())
+                                title=move || syntax_error().unwrap_or_default()
+                                contenteditable node_ref=input_ref on:mousedown=|ev| {
                                 ev.stop_propagation();
                             } on:input=move |_| {
                                 let input_element = input_ref.get_untracked().unwrap();
@@ -368,20 +631,21 @@ pub fn App() -> impl IntoView {
                                 }
                                 update_input(text);
                             } on:keydown=move |ev| {
-                                if ev.key() == "Enter" {
-                                    ev.prevent_default();
-                                    let input = input.get_untracked();
-                                    if input.is_empty() {
-                                        return;
-                                    }
-                                    let input_element = input_ref.get_untracked().unwrap();
-                                    input_element.set_text_content(Some(""));
-                                    set_input("".to_owned());
-                                    for stmt in input.split(";") {
-                                        if stmt.is_empty() {
-                                            continue;
+                                if ev.key() != "Enter" {
+                                    return;
+                                }
+                                ev.prevent_default();
+                                let force = ev.ctrl_key() || ev.meta_key();
+                                match EnterAction::new(&input.get_untracked(), ev.shift_key(), force) {
+                                    EnterAction::Newline => paste_input("\n".to_owned()),
+                                    EnterAction::Submit(stmts) if stmts.is_empty() => {}
+                                    EnterAction::Submit(stmts) => {
+                                        let input_element = input_ref.get_untracked().unwrap();
+                                        input_element.set_text_content(Some(""));
+                                        set_input("".to_owned());
+                                        for stmt in stmts {
+                                            submit_input(stmt);
                                         }
-                                        submit_input(format!("{};", stmt.trim()));
                                     }
                                 }
                             } on:paste=move |ev| {
@@ -390,7 +654,7 @@ pub fn App() -> impl IntoView {
                                 let Some(clipboard) = ev.clipboard_data().and_then(|c| c.get_data("text/plain").ok()) else {
                                     return;
                                 };
-                                paste_input(clipboard);
+                                paste_input(clipboard.replace("\r\n", "\n"));
                             }>
                                 "\u{feff}"  // ZERO WIDTH NO-BREAK SPACE to make caret visible
                             </code>
@@ -421,3 +685,151 @@ fn main() {
     })
     .forget();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chat_history_save() {
+        let mut history = ChatHistory::new();
+        for i in 0..3 {
+            history.submit(format!("SELECT {i};"));
+            history.respond(Ok(Response::Meta { affected_rows: i }), 0.5);
+        }
+        history.submit("SELECT 3;".to_owned());
+
+        let mut saved = String::new();
+        assert_eq!(
+            history.save_with(|json| {
+                saved = json.to_owned();
+                true
+            }),
+            3
+        );
+        let restored = ChatHistory::restore_from(&saved).unwrap();
+        assert_eq!(restored.next_id, history.next_id);
+        assert_eq!(
+            restored.chats.iter().map(|c| &c.request).collect_vec(),
+            ["SELECT 0;", "SELECT 1;", "SELECT 2;"]
+        );
+
+        // quota only fits one chat, the oldest ones go first
+        let full = saved.len();
+        assert_eq!(
+            history.save_with(|json| {
+                saved = json.to_owned();
+                json.len() < full / 2
+            }),
+            1
+        );
+        let restored = ChatHistory::restore_from(&saved).unwrap();
+        assert_eq!(restored.chats.len(), 1);
+        assert_eq!(restored.chats[0].request, "SELECT 2;");
+
+        assert_eq!(history.save_with(|_| false), 0);
+        assert!(ChatHistory::restore_from("{").is_none());
+    }
+
+    #[test]
+    fn test_block_heatmap() {
+        let log = |read: &[u64], written: &[u64]| BlockIoLog {
+            read: read.iter().copied().collect(),
+            written: written.iter().copied().collect(),
+            lookups: 0,
+            reads: 0,
+            writes: 0,
+        };
+        let mut blocks = BlockList::new();
+        blocks.update(log(&[1], &[2]));
+        blocks.update(log(&[3], &[]));
+        // without heatmap only the last query is shown
+        assert_eq!(blocks.blocks[&1], BlockStatus::Normal);
+        assert_eq!(blocks.blocks[&3], BlockStatus::Read);
+
+        blocks.set_heatmap(true);
+        assert_eq!(blocks.blocks[&3], BlockStatus::Normal);
+        blocks.update(log(&[1, 2], &[2]));
+        blocks.update(log(&[1], &[]));
+        assert_eq!(
+            blocks.blocks[&1],
+            BlockStatus::Hot {
+                reads: HEAT_PER_ACCESS * 3 / 4 + HEAT_PER_ACCESS,
+                writes: 0
+            }
+        );
+        assert_eq!(
+            blocks.blocks[&2],
+            BlockStatus::Hot {
+                reads: HEAT_PER_ACCESS * 3 / 4,
+                writes: HEAT_PER_ACCESS * 3 / 4
+            }
+        );
+        assert_eq!(blocks.blocks[&1].class(), "bg-sky-200");
+        assert_eq!(blocks.blocks[&2].class(), "bg-orange-100");
+
+        // untouched blocks cool down until they are normal again
+        for _ in 0..10 {
+            blocks.update(log(&[], &[]));
+        }
+        assert_eq!(blocks.blocks[&1], BlockStatus::Normal);
+        assert_eq!(blocks.blocks[&2], BlockStatus::Normal);
+    }
+
+    #[test]
+    fn test_block_types() {
+        let mut blocks = BlockList::new();
+        blocks.set_types(BTreeMap::from([
+            (0, BlockType::SuperBlock),
+            (1, BlockType::Schema),
+            (300, BlockType::Data),
+        ]));
+        let cells = blocks.cells();
+        assert_eq!(cells[0], (0, BlockStatus::Normal, Some(BlockType::SuperBlock)));
+        assert_eq!(cells[2], (2, BlockStatus::Normal, None));
+        // blocks beyond the grid are added when they are in use
+        assert_eq!(
+            cells.last(),
+            Some(&(300, BlockStatus::Normal, Some(BlockType::Data)))
+        );
+        assert_eq!(block_type_class(cells[0].2), "ring-2 ring-inset ring-red-400");
+        assert_eq!(block_type_class(None), "");
+    }
+
+    #[test]
+    fn test_enter_action() {
+        let input = "CREATE TABLE t (\n    id INTEGER,\n    s TEXT\n)";
+        assert_eq!(EnterAction::new(input, true, false), EnterAction::Newline);
+        assert_eq!(EnterAction::new(input, true, true), EnterAction::Newline);
+        assert_eq!(
+            EnterAction::new(input, false, false),
+            EnterAction::Submit(vec![format!("{input};")])
+        );
+        assert_eq!(
+            EnterAction::new("SELECT 1; SELECT 2;\n", false, false),
+            EnterAction::Submit(vec!["SELECT 1;".to_owned(), "SELECT 2;".to_owned()])
+        );
+        // force-submit keeps the buffer whole, e.g. for `;` inside strings
+        assert_eq!(
+            EnterAction::new("SELECT 'a;b';", false, true),
+            EnterAction::Submit(vec!["SELECT 'a;b';".to_owned()])
+        );
+        assert_eq!(
+            EnterAction::new(" \n ", false, true),
+            EnterAction::Submit(vec![])
+        );
+    }
+
+    #[test]
+    fn test_completion_seq() {
+        let mut seq = CompletionSeq::default();
+        let first = seq.next("SELECT".to_owned());
+        assert!(seq.is_current(first, "SELECT"));
+        let second = seq.next("SELECT name".to_owned());
+        assert!(!seq.is_current(first, "SELECT"));
+        assert!(!seq.is_current(first, "SELECT name"));
+        assert!(seq.is_current(second, "SELECT name"));
+        // input changed while the request was in flight
+        assert!(!seq.is_current(second, "SELECT name FROM"));
+    }
+}
@@ -1,25 +1,56 @@
-use aidb_core::{Aidb, BlockIoLog, Response};
+use std::collections::BTreeMap;
+
+use aidb_core::{Aidb, BlockIoLog, BlockType, CancelToken, Response};
 
 use futures::{SinkExt, StreamExt};
 use gloo_worker::Registrable;
 use gloo_worker::reactor::{ReactorScope, reactor};
-use js_sys::global;
+use js_sys::{Promise, Uint8Array, global};
 use leptos::logging::log;
 use serde::{Deserialize, Serialize};
-use wasm_bindgen::JsValue;
-use web_sys::WorkerGlobalScope;
+use wasm_bindgen::{JsCast, JsValue, closure::Closure};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{IdbDatabase, IdbRequest, IdbTransactionMode, WorkerGlobalScope};
+
+/// Where the worker keeps the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Backend {
+    /// lost on reload
+    Memory,
+    /// archived into IndexedDB after every modification
+    IndexedDb,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum WorkerRequest {
-    Completion(String),
+    /// Replace the current database, always answered with the backend actually used.
+    Open {
+        backend: Backend,
+    },
+    Completion {
+        seq: usize,
+        sql: String,
+    },
+    Validate(String),
     Query(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum WorkerResponse {
-    Completion(String),
+    Opened {
+        backend: Backend,
+    },
+    Completion {
+        seq: usize,
+        hint: String,
+    },
+    Validation {
+        ok: bool,
+        message: String,
+    },
     Query {
-        response: Result<(Response, BlockIoLog), String>,
+        /// along with the blocks accessed and the role of every block after the query
+        response: Result<(Response, BlockIoLog, BTreeMap<u64, BlockType>), String>,
         duration: f64,
     },
 }
@@ -32,20 +63,140 @@ fn now() -> f64 {
     worker_global_scope().performance().unwrap().now()
 }
 
+/// milliseconds before a query is cancelled, the worker can't be interrupted otherwise
+const QUERY_TIMEOUT: f64 = 30_000.;
+
+const IDB_NAME: &str = "aidb";
+const IDB_STORE: &str = "archive";
+const IDB_KEY: &str = "archive";
+
+/// Wait for an IndexedDB request to succeed.
+async fn idb_wait(request: &IdbRequest) -> Result<JsValue, JsValue> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        request.set_onsuccess(Some(&resolve));
+        request.set_onerror(Some(&reject));
+    });
+    JsFuture::from(promise).await?;
+    request.result()
+}
+
+async fn idb_open() -> Result<IdbDatabase, JsValue> {
+    let factory = worker_global_scope()
+        .indexed_db()?
+        .ok_or_else(|| JsValue::from_str("IndexedDB is unavailable"))?;
+    let request = factory.open_with_u32(IDB_NAME, 1)?;
+    let upgrade = Closure::<dyn FnMut()>::new({
+        let request = request.clone();
+        move || {
+            let db: IdbDatabase = request.result().unwrap().unchecked_into();
+            db.create_object_store(IDB_STORE).unwrap();
+        }
+    });
+    request.set_onupgradeneeded(Some(upgrade.as_ref().unchecked_ref()));
+    let db = idb_wait(&request).await?;
+    Ok(db.unchecked_into())
+}
+
+async fn idb_load(db: &IdbDatabase) -> Result<Option<Vec<u8>>, JsValue> {
+    let store = db
+        .transaction_with_str(IDB_STORE)?
+        .object_store(IDB_STORE)?;
+    let archive = idb_wait(&store.get(&JsValue::from_str(IDB_KEY))?).await?;
+    Ok((!archive.is_undefined()).then(|| Uint8Array::new(&archive).to_vec()))
+}
+
+async fn idb_store(db: &IdbDatabase, archive: &[u8]) -> Result<(), JsValue> {
+    let store = db
+        .transaction_with_str_and_mode(IDB_STORE, IdbTransactionMode::Readwrite)?
+        .object_store(IDB_STORE)?;
+    let request = store.put_with_key(&Uint8Array::from(archive), &JsValue::from_str(IDB_KEY))?;
+    idb_wait(&request).await?;
+    Ok(())
+}
+
+/// Open the database on the backend, falling back to memory if it is unavailable.
+async fn open(backend: Backend) -> (Aidb, Option<IdbDatabase>) {
+    if backend == Backend::IndexedDb {
+        match idb_open().await {
+            Ok(db) => match idb_load(&db).await {
+                Ok(Some(archive)) => match Aidb::new_memory_from_archive(&archive[..]).await {
+                    Ok(aidb) => return (aidb, Some(db)),
+                    Err(e) => log!("failed to restore database: {e}"),
+                },
+                Ok(None) => return (Aidb::new_memory().await, Some(db)),
+                Err(e) => log!("failed to load database: {e:?}"),
+            },
+            Err(e) => log!("failed to open IndexedDB: {e:?}"),
+        }
+    }
+    (Aidb::new_memory().await, None)
+}
+
 #[reactor]
 pub async fn Worker(mut scope: ReactorScope<WorkerRequest, WorkerResponse>) {
     log!("new database");
     let mut aidb = Aidb::new_memory().await;
+    let mut idb = None;
     while let Some(request) = scope.next().await {
         match request {
-            WorkerRequest::Completion(sql) => {
-                let hint = Aidb::complete(sql);
-                scope.send(WorkerResponse::Completion(hint)).await.unwrap();
+            WorkerRequest::Open { backend } => {
+                (aidb, idb) = open(backend).await;
+                let backend = if idb.is_some() {
+                    Backend::IndexedDb
+                } else {
+                    Backend::Memory
+                };
+                log!("opened database in {backend:?}");
+                scope
+                    .send(WorkerResponse::Opened { backend })
+                    .await
+                    .unwrap();
+            }
+            WorkerRequest::Completion { seq, sql } => {
+                let hint = aidb.complete(sql).await;
+                scope
+                    .send(WorkerResponse::Completion { seq, hint })
+                    .await
+                    .unwrap();
+            }
+            WorkerRequest::Validate(sql) => {
+                let (ok, message) = match aidb.validate(sql).await {
+                    Ok(_) => (true, String::new()),
+                    Err(e) => (false, e.to_string()),
+                };
+                scope
+                    .send(WorkerResponse::Validation { ok, message })
+                    .await
+                    .unwrap();
             }
             WorkerRequest::Query(sql) => {
                 let time_start = now();
+                aidb.set_cancel_token(Some(CancelToken::with_check(move || {
+                    now() - time_start > QUERY_TIMEOUT
+                })));
                 let response = aidb.query_log_blocks(sql).await;
                 let duration = (now() - time_start) / 1000.;
+                let response = match response {
+                    Ok((response, log)) => {
+                        let types = aidb.block_types().await.unwrap_or_else(|e| {
+                            log!("failed to classify blocks: {e}");
+                            BTreeMap::new()
+                        });
+                        Ok((response, log, types))
+                    }
+                    Err(e) => Err(e),
+                };
+                if let (Some(db), Ok((Response::Meta { .. }, _, _))) = (&idb, &response) {
+                    let archive = aidb.save_archive(vec![]).await;
+                    match archive {
+                        Ok(archive) => {
+                            if let Err(e) = idb_store(db, &archive).await {
+                                log!("failed to persist database: {e:?}");
+                            }
+                        }
+                        Err(e) => log!("failed to archive database: {e}"),
+                    }
+                }
                 scope
                     .send(WorkerResponse::Query {
                         response: response.map_err(|e| e.to_string()),